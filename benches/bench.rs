@@ -11,7 +11,7 @@ fn bench_picklefile(c: &mut Criterion, filename: &str) {
     // Run the benchmark
     c.bench_function(filename, |b| {
         b.iter(|| {
-            let mut reader = quick_pickle::reader::Reader::new(&*contents);
+            let mut reader = quick_pickle::slice_reader::SliceReader::new(&contents);
             let _ = reader.par_collect_events().unwrap();
         })
     });
@@ -0,0 +1,279 @@
+//! A module to read pickle events from asynchronous streams
+//!
+//! The [`AsyncReader`] reads the raw bytes of each opcode and its argument without
+//! blocking, as described by the [`opcodes::Arg`] table, then decodes them with a
+//! [`Reader`]: events, payloads, limits and errors are the same. Payloads are bounded by
+//! the limits before being read, as in the blocking reader.
+
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt};
+
+use crate::{
+    errors::Error,
+    opcodes::{self, Arg},
+    reader::{Event, Limits, RawLen, Reader, Spanned, raw_len},
+};
+
+pub struct AsyncReader<R> {
+    reader: R,
+    pos: usize,
+    limits: Limits,
+    /// Raw bytes of the opcode being read
+    raw: Vec<u8>,
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncReader<R> {
+    pub fn new(reader: R) -> Self {
+        AsyncReader {
+            reader,
+            pos: 0,
+            limits: Limits::default(),
+            raw: Vec::new(),
+        }
+    }
+
+    /// Sets the limits checked while reading
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Gets the offset of the next opcode in the input
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Reads the next event along with the offsets of its opcode and payload end
+    ///
+    /// See [`Reader::read_event_with_span`].
+    pub async fn read_event_with_span(
+        &mut self,
+        buf: &mut Vec<u8>,
+    ) -> Result<Spanned<Event>, Error> {
+        let start = self.pos;
+        let value = self.read_event(buf).await?;
+        Ok(Spanned {
+            value,
+            start,
+            end: self.pos,
+        })
+    }
+
+    /// Reads the next event, see [`Reader::read_event`]
+    pub async fn read_event(&mut self, buf: &mut Vec<u8>) -> Result<Event, Error> {
+        let start = self.pos;
+        self.raw.clear();
+        if let Err(e) = self.read_raw().await {
+            let opcode = self.raw.first().copied().unwrap_or(0);
+            return Err(Error::from(e).at(start, opcode));
+        }
+        self.pos += self.raw.len();
+        Reader::new_at(&self.raw[..], start)
+            .with_limits(self.limits)
+            .read_event(buf)
+    }
+
+    /// Reads an opcode and its argument, or as much as is available
+    ///
+    /// Invalid opcodes or lengths are left to the [`Reader`] to report.
+    async fn read_raw(&mut self) -> std::io::Result<()> {
+        loop {
+            let needs = match raw_len(&self.raw, self.pos, &self.limits) {
+                RawLen::Complete(_) => return Ok(()),
+                RawLen::Needs(needs) => needs,
+            };
+            let arg = self.raw.first().and_then(|&op| opcodes::info(op));
+            let read = match arg.map(|info| info.arg) {
+                Some(Arg::Line | Arg::TwoLines) => self.read_line().await?,
+                _ => self.read_up_to(needs as u64).await?,
+            };
+            if read == 0 {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Reads `len` bytes unless the input ends before, without trusting `len` for the allocation
+    async fn read_up_to(&mut self, len: u64) -> std::io::Result<usize> {
+        (&mut self.reader)
+            .take(len)
+            .read_to_end(&mut self.raw)
+            .await
+    }
+
+    async fn read_line(&mut self) -> std::io::Result<usize> {
+        let limit = self.limits.max_payload.saturating_add(1) as u64;
+        (&mut self.reader)
+            .take(limit)
+            .read_until(b'\n', &mut self.raw)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorKind;
+
+    type Events = Vec<(Spanned<Event>, Vec<u8>)>;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    /// Reads all events with their payloads, or the first error
+    async fn read_all<R: AsyncBufRead + Unpin>(
+        reader: &mut AsyncReader<R>,
+    ) -> Result<Events, Error> {
+        let mut buf = Vec::new();
+        let mut events = Vec::new();
+        loop {
+            let event = reader.read_event_with_span(&mut buf).await?;
+            events.push((event, std::mem::take(&mut buf)));
+            if event.value == Event::Stop {
+                return Ok(events);
+            }
+        }
+    }
+
+    /// Reads all events with the blocking reader
+    fn read_all_sync(data: &[u8]) -> Result<Events, Error> {
+        Reader::new(data)
+            .events()
+            .map(|e| {
+                e.map(|e| {
+                    let (start, end) = (e.start, e.end);
+                    (
+                        Spanned {
+                            value: e.event,
+                            start,
+                            end,
+                        },
+                        e.payload,
+                    )
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_same_as_reader() -> Result<(), Error> {
+        for path in [
+            "/ints.pickle",
+            "/dict.pickle",
+            "/benches/data/manystrings.pickle",
+        ] {
+            let data = std::fs::read(format!("{}{path}", env!("CARGO_MANIFEST_DIR")))?;
+            let expected = read_all_sync(&data)?;
+            // a tiny buffer splits opcodes and payloads across reads
+            let stream = tokio::io::BufReader::with_capacity(7, &data[..]);
+            let events = block_on(read_all(&mut AsyncReader::new(stream)))?;
+            assert_eq!(events, expected, "{path}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_text_and_truncated() -> Result<(), Error> {
+        // pickle.dumps([2**40, 'a', len], protocol=0)
+        let data = b"(lp0\nL1099511627776L\naVa\np1\nac__builtin__\nlen\np2\na.";
+        let mut reader = AsyncReader::new(&data[..]);
+        let events = block_on(read_all(&mut reader))?;
+        assert_eq!(events, read_all_sync(data)?);
+        assert!(
+            events
+                .iter()
+                .any(|(_, payload)| payload == b"__builtin__len")
+        );
+
+        // end of input
+        let mut buf = Vec::new();
+        assert_eq!(block_on(reader.read_event(&mut buf))?, Event::Stop);
+        assert_eq!(reader.position(), data.len());
+
+        // truncated payload
+        let mut reader = AsyncReader::new(&b"\x80\x02X\x05\x00\x00\x00ab"[..]);
+        let err = block_on(read_all(&mut reader)).unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+        assert_eq!(
+            (err.offset(), err.opcode_name()),
+            (Some(2), Some("BINUNICODE"))
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() {
+        let read = |data: &'static [u8], limits: Limits| {
+            let mut reader = AsyncReader::new(data).with_limits(limits);
+            block_on(read_all(&mut reader))
+                .map(|_| ())
+                .unwrap_err()
+                .into_kind()
+        };
+
+        // BINBYTES8 claiming an exabyte is neither allocated nor read
+        let data = b"\x80\x04\x8e\x00\x00\x00\x00\x00\x00\x00\x10abc.";
+        let limits = Limits {
+            max_payload: 1024,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            read(data, limits),
+            ErrorKind::PayloadTooLarge { max: 1024, .. }
+        ));
+
+        // negative BINSTRING length
+        let kind = read(b"\x80\x02T\xff\xff\xff\xffabc.", Limits::default());
+        assert!(matches!(kind, ErrorKind::Unexpected { .. }));
+
+        let limits = Limits {
+            max_payload: 4,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            read(b"S'abcdef'\n.", limits),
+            ErrorKind::PayloadTooLarge { len: 5, max: 4 }
+        ));
+
+        let data = b"\x80\x04\x95\x02\x00\x00\x00\x00\x00\x00\x00N.";
+        let limits = Limits {
+            max_frame: 1,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            read(data, limits),
+            ErrorKind::FrameTooLarge { len: 2, max: 1 }
+        ));
+        let limits = Limits {
+            max_total: 12,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            read(data, limits),
+            ErrorKind::InputTooLarge { max: 12 }
+        ));
+
+        // the end of the input is not past a limit of the input size
+        let limits = Limits {
+            max_total: data.len(),
+            ..Limits::default()
+        };
+        let mut reader = AsyncReader::new(&data[..]).with_limits(limits);
+        assert_eq!(block_on(read_all(&mut reader)).unwrap().len(), 4);
+        let event = block_on(reader.read_event(&mut Vec::new())).unwrap();
+        assert_eq!(event, Event::Stop);
+    }
+}
@@ -0,0 +1,267 @@
+//! A module to represent arbitrary precision integers
+//!
+//! Pickle stores large integers (LONG1, LONG4) as little-endian two's complement bytes.
+
+use std::fmt;
+
+use crate::errors::{Error, ErrorKind};
+
+/// An integer of arbitrary size, stored as little-endian two's complement bytes
+///
+/// The bytes are kept without redundant sign bytes, so equal integers have equal bytes.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct BigInt(Vec<u8>);
+
+impl BigInt {
+    /// Maximum number of digits of a decimal string, as python's `sys.get_int_max_str_digits()`
+    ///
+    /// Decimal strings are converted in quadratic time.
+    pub const MAX_DECIMAL_DIGITS: usize = 4300;
+
+    /// Creates a new integer out of little-endian two's complement bytes
+    pub fn from_signed_bytes_le(bytes: &[u8]) -> Self {
+        BigInt(minimal(bytes).to_vec())
+    }
+
+    /// Gets the shortest little-endian two's complement bytes, as python writes them
+    pub fn as_signed_bytes_le(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.0.last().is_some_and(|b| b & 0x80 != 0)
+    }
+
+    /// Converts to an `i64` if the value fits
+    pub fn to_i64(&self) -> Option<i64> {
+        decode_le::<8>(&self.0).map(i64::from_le_bytes)
+    }
+
+    /// Converts to an `i128` if the value fits
+    pub fn to_i128(&self) -> Option<i128> {
+        decode_le::<16>(&self.0).map(i128::from_le_bytes)
+    }
+
+    /// Parses a decimal string, as found in the text LONG opcode
+    ///
+    /// Strings of more than [`BigInt::MAX_DECIMAL_DIGITS`] digits are an error.
+    pub fn parse_decimal(s: &[u8]) -> Result<Self, Error> {
+        let (negative, digits) = match s {
+            [b'-', rest @ ..] => (true, rest),
+            [b'+', rest @ ..] => (false, rest),
+            _ => (false, s),
+        };
+        if digits.is_empty() || !digits.iter().all(u8::is_ascii_digit) {
+            let s = String::from_utf8_lossy(s);
+            return Err(Error::unexpected("an integer", format!("{s:?}")));
+        }
+        if digits.len() > Self::MAX_DECIMAL_DIGITS {
+            return Err(
+                ErrorKind::Invalid("integer string exceeds the limit of 4300 digits").into(),
+            );
+        }
+        // unsigned little-endian magnitude
+        let mut mag: Vec<u8> = Vec::new();
+        for &d in digits {
+            let mut carry = (d - b'0') as u32;
+            for byte in mag.iter_mut() {
+                let v = *byte as u32 * 10 + carry;
+                *byte = v as u8;
+                carry = v >> 8;
+            }
+            if carry > 0 {
+                mag.push(carry as u8);
+            }
+        }
+        // sign bit room
+        if mag.last().is_none_or(|b| b & 0x80 != 0) {
+            mag.push(0);
+        }
+        if negative {
+            negate(&mut mag);
+        }
+        mag.truncate(minimal(&mag).len());
+        Ok(BigInt(mag))
+    }
+}
+
+impl From<i64> for BigInt {
+    fn from(v: i64) -> Self {
+        let bytes = v.to_le_bytes();
+        BigInt(minimal(&bytes).to_vec())
+    }
+}
+
+impl From<i128> for BigInt {
+    fn from(v: i128) -> Self {
+        let bytes = v.to_le_bytes();
+        BigInt(minimal(&bytes).to_vec())
+    }
+}
+
+impl fmt::Display for BigInt {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if let Some(v) = self.to_i128() {
+            return write!(f, "{v}");
+        }
+        let mut mag = self.0.clone();
+        let negative = self.is_negative();
+        if negative {
+            negate(&mut mag);
+        }
+        // repeatedly divide the magnitude by 10^9 to get decimal chunks
+        let mut chunks = Vec::new();
+        while mag.iter().any(|b| *b != 0) {
+            let mut rem = 0u64;
+            for byte in mag.iter_mut().rev() {
+                let v = (rem << 8) | *byte as u64;
+                *byte = (v / 1_000_000_000) as u8;
+                rem = v % 1_000_000_000;
+            }
+            chunks.push(rem);
+        }
+        if negative {
+            f.write_str("-")?;
+        }
+        let mut chunks = chunks.iter().rev();
+        if let Some(first) = chunks.next() {
+            write!(f, "{first}")?;
+        }
+        for chunk in chunks {
+            write!(f, "{chunk:09}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "num-bigint")]
+impl From<&BigInt> for num_bigint::BigInt {
+    fn from(v: &BigInt) -> Self {
+        num_bigint::BigInt::from_signed_bytes_le(&v.0)
+    }
+}
+
+#[cfg(feature = "num-bigint")]
+impl From<&num_bigint::BigInt> for BigInt {
+    fn from(v: &num_bigint::BigInt) -> Self {
+        BigInt::from_signed_bytes_le(&v.to_signed_bytes_le())
+    }
+}
+
+/// Sign-extends or truncates little-endian two's complement bytes to N bytes
+///
+/// Returns `None` if the value doesn't fit.
+pub(crate) fn decode_le<const N: usize>(bytes: &[u8]) -> Option<[u8; N]> {
+    let negative = bytes.last().is_some_and(|b| b & 0x80 != 0);
+    let fill = if negative { 0xff } else { 0 };
+    let mut out = [fill; N];
+    if bytes.len() > N {
+        let (low, high) = bytes.split_at(N);
+        if high.iter().any(|b| *b != fill) || (low[N - 1] & 0x80 != 0) != negative {
+            return None;
+        }
+        out.copy_from_slice(low);
+    } else {
+        out[..bytes.len()].copy_from_slice(bytes);
+    }
+    Some(out)
+}
+
+/// Strips redundant sign bytes, as python's `encode_long` does
+pub(crate) fn minimal(bytes: &[u8]) -> &[u8] {
+    let mut len = bytes.len();
+    while len > 1 {
+        let last = bytes[len - 1];
+        let prev = bytes[len - 2];
+        if (last == 0 && prev & 0x80 == 0) || (last == 0xff && prev & 0x80 != 0) {
+            len -= 1;
+        } else {
+            break;
+        }
+    }
+    if len == 1 && bytes[0] == 0 {
+        // python encodes 0 as an empty string
+        len = 0;
+    }
+    &bytes[..len]
+}
+
+/// Two's complement negation in place
+fn negate(bytes: &mut [u8]) {
+    let mut carry = true;
+    for byte in bytes.iter_mut() {
+        let (v, c) = (!*byte).overflowing_add(carry as u8);
+        *byte = v;
+        carry = c;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_to_i64() {
+        assert_eq!(BigInt::from_signed_bytes_le(b"").to_i64(), Some(0));
+        assert_eq!(BigInt::from_signed_bytes_le(b"\xff").to_i64(), Some(-1));
+        assert_eq!(
+            BigInt::from_signed_bytes_le(b"\xff\x00").to_i64(),
+            Some(255)
+        );
+        // 2**63
+        let big = BigInt::from_signed_bytes_le(b"\x00\x00\x00\x00\x00\x00\x00\x80\x00");
+        assert_eq!(big.to_i64(), None);
+        assert_eq!(big.to_i128(), Some(1 << 63));
+    }
+
+    #[test]
+    fn test_decimal() {
+        let s = b"-1267650600228229401496703205376";
+        let big = BigInt::parse_decimal(s).unwrap();
+        assert_eq!(big.to_string().as_bytes(), s);
+        let s = b"340282366920938463463374607431768211456"; // 2**128
+        let big = BigInt::parse_decimal(s).unwrap();
+        assert_eq!(big.to_i128(), None);
+        assert_eq!(big.to_string().as_bytes(), s);
+
+        assert!(BigInt::parse_decimal(b"12a").is_err());
+        assert!(BigInt::parse_decimal(b"-").is_err());
+        let s = b"9".repeat(BigInt::MAX_DECIMAL_DIGITS);
+        assert_eq!(BigInt::parse_decimal(&s).unwrap().to_string().as_bytes(), s);
+        let err = BigInt::parse_decimal(&b"9".repeat(1 << 20)).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Invalid(_)));
+    }
+
+    #[test]
+    fn test_eq() {
+        // LONG1 payloads with redundant sign bytes
+        assert_eq!(
+            BigInt::from_signed_bytes_le(b"\x01\x00"),
+            BigInt::from_signed_bytes_le(b"\x01")
+        );
+        assert_eq!(
+            BigInt::from_signed_bytes_le(b"\xff\xff"),
+            BigInt::from(-1i64)
+        );
+        assert_eq!(BigInt::from_signed_bytes_le(b"\x00"), BigInt::from(0i64));
+        assert_eq!(
+            BigInt::parse_decimal(b"-0").unwrap(),
+            BigInt::parse_decimal(b"00").unwrap()
+        );
+        assert_ne!(
+            BigInt::from_signed_bytes_le(b"\xff\x00"),
+            BigInt::from_signed_bytes_le(b"\xff")
+        );
+    }
+
+    #[test]
+    fn test_minimal() {
+        assert_eq!(BigInt::from(0i64).as_signed_bytes_le(), b"");
+        assert_eq!(BigInt::from(255i64).as_signed_bytes_le(), b"\xff\x00");
+        assert_eq!(BigInt::from(-256i64).as_signed_bytes_le(), b"\x00\xff");
+        assert_eq!(
+            BigInt::from(1i64 << 40).as_signed_bytes_le(),
+            b"\0\0\0\0\0\x01"
+        );
+    }
+}
@@ -0,0 +1,152 @@
+//! Command line tools for pickle files
+//!
+//! ```text
+//! quick-pickle dis <file>               disassemble a pickle like `python -m pickletools`
+//! quick-pickle json <file> [options]    convert a pickle to JSON
+//! quick-pickle from-json <file> [--protocol N]
+//!                                       write a JSON file as a pickle on stdout
+//! ```
+
+use std::{
+    io::{BufWriter, Write},
+    process::ExitCode,
+};
+
+use quick_pickle::{
+    Encoding, Error, Pickler, dis,
+    json::{self, BytesRepr, FloatRepr, KeyRepr, ObjectRepr, Options, SeqRepr},
+    reader::Reader,
+    writer::DEFAULT_PROTOCOL,
+};
+
+const USAGE: &str = "\
+usage: quick-pickle dis <file>
+       quick-pickle json <file> [options]
+       quick-pickle from-json <file> [--protocol N]
+
+json options:
+  --bytes base64|hex|array      bytes and bytearray (default: base64)
+  --tuples array|tagged         tuples (default: array)
+  --sets array|tagged           sets and frozensets (default: array)
+  --keys stringify|pairs|error  dicts with non-str keys (default: stringify)
+  --floats null|string|error    NaN and infinite floats (default: null)
+  --objects tagged|null|error   globals and objects built by python code (default: tagged)
+  --encoding ascii|latin1|utf8|bytes
+                                python 2 str (default: ascii)
+
+from-json options:
+  --protocol N                  pickle protocol, from 0 to 5 (default: 4)";
+
+fn usage() -> ! {
+    eprintln!("{USAGE}");
+    std::process::exit(2);
+}
+
+/// Parses the options of the json command
+fn json_options(args: &[String]) -> Option<Options> {
+    let mut options = Options::default();
+    for pair in args.chunks(2) {
+        let [name, value] = pair else {
+            return None;
+        };
+        let seq = |value: &str| match value {
+            "array" => Some(SeqRepr::Array),
+            "tagged" => Some(SeqRepr::Tagged),
+            _ => None,
+        };
+        match name.as_str() {
+            "--bytes" => {
+                options.bytes = match value.as_str() {
+                    "base64" => BytesRepr::Base64,
+                    "hex" => BytesRepr::Hex,
+                    "array" => BytesRepr::Array,
+                    _ => return None,
+                }
+            }
+            "--tuples" => options.tuples = seq(value)?,
+            "--sets" => options.sets = seq(value)?,
+            "--keys" => {
+                options.keys = match value.as_str() {
+                    "stringify" => KeyRepr::Stringify,
+                    "pairs" => KeyRepr::Pairs,
+                    "error" => KeyRepr::Error,
+                    _ => return None,
+                }
+            }
+            "--floats" => {
+                options.floats = match value.as_str() {
+                    "null" => FloatRepr::Null,
+                    "string" => FloatRepr::String,
+                    "error" => FloatRepr::Error,
+                    _ => return None,
+                }
+            }
+            "--objects" => {
+                options.objects = match value.as_str() {
+                    "tagged" => ObjectRepr::Tagged,
+                    "null" => ObjectRepr::Null,
+                    "error" => ObjectRepr::Error,
+                    _ => return None,
+                }
+            }
+            "--encoding" => {
+                options.encoding = match value.as_str() {
+                    "ascii" => Encoding::Ascii,
+                    "latin1" => Encoding::Latin1,
+                    "utf8" => Encoding::Utf8,
+                    "bytes" => Encoding::Bytes,
+                    _ => return None,
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(options)
+}
+
+fn run(args: &[String]) -> Result<(), Error> {
+    let stdout = std::io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    match args {
+        [command, path] if command == "dis" => {
+            let mut reader = Reader::open(path)?;
+            let res = dis::dis(&mut reader, &mut out);
+            out.flush()?;
+            res
+        }
+        [command, path, rest @ ..] if command == "json" => {
+            let Some(options) = json_options(rest) else {
+                usage();
+            };
+            let mut reader = Reader::open(path)?;
+            json::to_writer(&mut reader, &mut out, &options)?;
+            writeln!(out)?;
+            out.flush()?;
+            Ok(())
+        }
+        [command, path, rest @ ..] if command == "from-json" => {
+            let protocol = match rest {
+                [] => DEFAULT_PROTOCOL,
+                [name, protocol] if name == "--protocol" => {
+                    protocol.parse().unwrap_or_else(|_| usage())
+                }
+                _ => usage(),
+            };
+            let json = std::fs::read_to_string(path)?;
+            let value = json::from_str(&json)?;
+            Pickler::new(out, protocol)?.dump(&value)
+        }
+        _ => usage(),
+    }
+}
+
+fn main() -> ExitCode {
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    match run(&args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,1560 @@
+//! A module to deserialize rust types out of pickles with serde
+//!
+//! Python objects are mapped to the serde data model as follows:
+//! - `None` is a unit or a missing `Option`
+//! - `list`, `tuple`, `set` and `frozenset` are sequences
+//! - `dict` is a map or a struct
+//! - `str` and `bytes` are strings and byte buffers
+//! - an object rebuilt with BUILD is deserialized from its state (usually its `__dict__`)
+//!   and an object filled with items (e.g. `collections.OrderedDict`) from its items
+//!
+//! Pickle is not read top-down: a tuple of up to three items, a reduced or a built object is
+//! only known once its items have been read, and until the STOP the outermost object may
+//! still become an item of another one. So the [`Deserializer`] first runs the pickle machine
+//! on the [`Event`]s of its reader up to the STOP, only linking each container to the
+//! objects nested in it, then walks them top-down with the visitors. Strings and bytes are
+//! borrowed from the payloads of the events, which [`from_slice`] does not copy out of the
+//! input, and objects referenced again from the memo are visited again at each reference
+//! rather than copied.
+//!
+//! ```
+//! # fn main() -> Result<(), quick_pickle::Error> {
+//! // pickle.dumps(('a', b'xy'), protocol=4)
+//! let data = b"\x80\x04\x95\x0c\x00\x00\x00\x00\x00\x00\x00\x8c\x01a\x94C\x02xy\x94\x86\x94.";
+//! let (name, raw): (&str, &[u8]) = quick_pickle::from_slice(data)?;
+//! assert_eq!((name, raw), ("a", &b"xy"[..]));
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Values already loaded by an [`Unpickler`](crate::Unpickler) are deserialized with
+//! [`from_value`], or by borrowing their strings and bytes with [`from_value_ref`].
+
+use std::{
+    borrow::Cow, cell::Cell, collections::HashMap, io::BufRead, mem, str::from_utf8, sync::Arc,
+};
+
+use serde::de::{
+    self, Deserialize, DeserializeOwned, IntoDeserializer, Unexpected, Visitor,
+    value::BorrowedStrDeserializer,
+};
+
+use crate::{
+    bigint::BigInt,
+    errors::{Error, ErrorKind},
+    reader::{Event, Limits, Reader, missing_stop},
+    safety::SafetyPolicy,
+    slice_reader::SliceReader,
+    unpickler::{Encoding, check_container, decode_py2_str, decode_str, pairs},
+    value::Value,
+};
+
+impl de::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        ErrorKind::Custom(msg.to_string()).into()
+    }
+}
+
+/// A source of pickle events for the [`Deserializer`]
+///
+/// Payloads borrowed from the input for `'de` are deserialized without being copied, as those
+/// of a [`SliceReader`]. A [`Reader`] copies them out of its input.
+pub trait Read<'de> {
+    /// Reads the next event and its payload, see [`Reader::read_event`]
+    fn read_event(&mut self) -> Result<(Event, Cow<'de, [u8]>), Error>;
+
+    /// Gets the offset of the next opcode in the input
+    fn position(&self) -> usize;
+
+    /// Gets the last opcode read
+    fn opcode(&self) -> u8;
+
+    fn limits(&self) -> &Limits;
+
+    /// Checks if the whole input has been read
+    fn at_eof(&mut self) -> Result<bool, Error>;
+}
+
+impl<'de, R: BufRead> Read<'de> for Reader<R> {
+    fn read_event(&mut self) -> Result<(Event, Cow<'de, [u8]>), Error> {
+        let mut buf = Vec::new();
+        let event = Reader::read_event(self, &mut buf)?;
+        Ok((event, Cow::Owned(buf)))
+    }
+
+    fn position(&self) -> usize {
+        Reader::position(self)
+    }
+
+    fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    fn limits(&self) -> &Limits {
+        Reader::limits(self)
+    }
+
+    fn at_eof(&mut self) -> Result<bool, Error> {
+        Reader::at_eof(self)
+    }
+}
+
+impl<'de> Read<'de> for SliceReader<'de> {
+    fn read_event(&mut self) -> Result<(Event, Cow<'de, [u8]>), Error> {
+        SliceReader::read_event(self)
+    }
+
+    fn position(&self) -> usize {
+        SliceReader::position(self)
+    }
+
+    fn opcode(&self) -> u8 {
+        self.opcode
+    }
+
+    fn limits(&self) -> &Limits {
+        SliceReader::limits(self)
+    }
+
+    fn at_eof(&mut self) -> Result<bool, Error> {
+        Ok(SliceReader::at_eof(self))
+    }
+}
+
+/// A serde deserializer reading pickles
+///
+/// Each deserialized value is read from the next pickle of the input. The limits of the
+/// reader apply, [`Limits::max_copies`] to the items visited again through memo references.
+/// Persistent ids are an error: load them with an [`Unpickler`](crate::Unpickler) and its
+/// [`PersistentLoader`](crate::PersistentLoader), then use [`from_value`].
+pub struct Deserializer<'de, R> {
+    read: R,
+    /// Objects of the pickle being read, which the stack and the memo hold the index of
+    nodes: Vec<Node<'de>>,
+    stack: Vec<usize>,
+    metastack: Vec<Vec<usize>>,
+    memo: HashMap<u32, usize>,
+    policy: Option<SafetyPolicy>,
+    /// Out-of-band buffers, if given
+    buffers: Option<std::vec::IntoIter<Arc<[u8]>>>,
+    encoding: Encoding,
+}
+
+impl<R: BufRead> Deserializer<'_, Reader<R>> {
+    pub fn new(reader: R) -> Self {
+        Deserializer::from_reader(Reader::new(reader))
+    }
+}
+
+impl<'de> Deserializer<'de, SliceReader<'de>> {
+    /// Creates a deserializer borrowing strings and bytes from `data`
+    pub fn from_slice(data: &'de [u8]) -> Self {
+        Deserializer::from_reader(SliceReader::new(data))
+    }
+}
+
+impl<'de, R: Read<'de>> Deserializer<'de, R> {
+    /// Creates a deserializer reading the events of a configured [`Reader`] or [`SliceReader`]
+    pub fn from_reader(read: R) -> Self {
+        Deserializer {
+            read,
+            nodes: Vec::new(),
+            stack: Vec::new(),
+            metastack: Vec::new(),
+            memo: HashMap::new(),
+            policy: None,
+            buffers: None,
+            encoding: Encoding::default(),
+        }
+    }
+
+    /// Rejects the globals which the policy does not allow, see [`Unpickler::with_policy`]
+    ///
+    /// [`Unpickler::with_policy`]: crate::Unpickler::with_policy
+    pub fn with_policy(mut self, policy: SafetyPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Sets how python 2 str are decoded, see [`Unpickler::with_encoding`]
+    ///
+    /// [`Unpickler::with_encoding`]: crate::Unpickler::with_encoding
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets the out-of-band buffers consumed, in order, by NEXT_BUFFER opcodes (protocol 5)
+    pub fn with_buffers<I, B>(mut self, buffers: I) -> Self
+    where
+        I: IntoIterator<Item = B>,
+        B: Into<Arc<[u8]>>,
+    {
+        let buffers = buffers.into_iter().map(Into::into).collect::<Vec<_>>();
+        self.buffers = Some(buffers.into_iter());
+        self
+    }
+
+    pub fn into_inner(self) -> R {
+        self.read
+    }
+
+    /// Checks that the input ends after the pickles deserialized so far
+    pub fn end(&mut self) -> Result<(), Error> {
+        let pos = self.read.position();
+        if !self.read.at_eof().map_err(|e| e.at(pos, 0))? {
+            return Err(Error::from(ErrorKind::TrailingBytes).at(pos, 0));
+        }
+        Ok(())
+    }
+
+    /// Loads the next pickle and walks its top level object
+    fn walk<T, F>(&mut self, f: F) -> Result<T, Error>
+    where
+        F: FnOnce(Nested<'_, 'de>) -> Result<T, Error>,
+    {
+        let root = self.load()?;
+        let tree = Tree {
+            nodes: &self.nodes,
+            open: vec![Cell::new(false); self.nodes.len()],
+            visited: Cell::new(0),
+            limits: *self.read.limits(),
+        };
+        f(Nested {
+            tree: &tree,
+            index: root,
+            depth: 0,
+        })
+    }
+
+    /// Runs the pickle machine until STOP and returns the node of the top level object
+    fn load(&mut self) -> Result<usize, Error> {
+        self.nodes.clear();
+        self.nodes.push(Node::None);
+        self.stack.clear();
+        self.metastack.clear();
+        self.memo.clear();
+        loop {
+            let start = self.read.position();
+            let (event, payload) = self.read.read_event()?;
+            if let Event::Stop = event {
+                // a STOP faked at the end of the input is not consumed
+                if self.read.position() == start {
+                    return Err(missing_stop(start));
+                }
+                break;
+            }
+            let opcode = self.read.opcode();
+            self.process(event, payload)
+                .map_err(|e| e.at(start, opcode))?;
+        }
+        let root = self.pop()?;
+        if !self.stack.is_empty() || !self.metastack.is_empty() {
+            return Err(ErrorKind::Invalid("stack not empty after STOP").into());
+        }
+        Ok(root)
+    }
+
+    fn process(&mut self, event: Event, payload: Cow<'de, [u8]>) -> Result<(), Error> {
+        let limits = *self.read.limits();
+        let max_container = limits.max_container;
+        match event {
+            Event::Proto(_) | Event::Frame(_) | Event::Stop => (),
+
+            // Stack manipulation
+            Event::Mark => {
+                let stack = mem::take(&mut self.stack);
+                self.metastack.push(stack);
+            }
+            Event::Pop => {
+                if self.stack.pop().is_none() {
+                    self.pop_mark()?;
+                }
+            }
+            Event::PopMark => {
+                self.pop_mark()?;
+            }
+            Event::Dup => {
+                let top = self.top()?;
+                self.stack.push(top);
+            }
+
+            // Basic types
+            Event::None => self.push(Node::None),
+            Event::Bool(b) => self.push(Node::Bool(b)),
+            Event::Int(i) => self.push(Node::Int(i)),
+            Event::BinInt(i) => self.push(Node::Int(i as i64)),
+            Event::BinInt1(i) => self.push(Node::Int(i as i64)),
+            Event::BinInt2(i) => self.push(Node::Int(i as i64)),
+            Event::Long(i) => self.push(Node::Int(i)),
+            Event::BigLong { .. } => {
+                self.push(Node::BigInt(BigInt::from_signed_bytes_le(&payload)))
+            }
+            Event::Float(f) => self.push(Node::Float(f)),
+
+            // Strings and bytes
+            Event::String { .. } | Event::BinString { .. } | Event::ShortBinString { .. } => {
+                let node = match decode_py2_str(&event, &payload, self.encoding)? {
+                    Value::Str(s) => Node::Str(Cow::Owned(s)),
+                    Value::Bytes(b) => Node::Bytes(Cow::Owned(b)),
+                    _ => unreachable!("python 2 str are decoded as str or bytes"),
+                };
+                self.push(node);
+            }
+            Event::Unicode { .. }
+            | Event::BinUnicode { .. }
+            | Event::ShortBinUnicode { .. }
+            | Event::BinUnicode8 { .. } => {
+                let s = match payload {
+                    Cow::Borrowed(buf) => decode_str(&event, buf)?,
+                    Cow::Owned(buf) => Cow::Owned(decode_str(&event, &buf)?.into_owned()),
+                };
+                self.push(Node::Str(s));
+            }
+            Event::BinBytes { .. }
+            | Event::ShortBinBytes { .. }
+            | Event::BinBytes8 { .. }
+            | Event::ByteArray8 { .. } => self.push(Node::Bytes(payload)),
+
+            // Collections
+            Event::EmptyTuple => self.push(Node::Tuple(Vec::new())),
+            Event::Tuple => {
+                let items = self.pop_mark()?;
+                check_container(items.len(), max_container)?;
+                self.push(Node::Tuple(items));
+            }
+            Event::Tuple1 => {
+                let items = self.pop_n(1)?;
+                self.push(Node::Tuple(items));
+            }
+            Event::Tuple2 => {
+                let items = self.pop_n(2)?;
+                self.push(Node::Tuple(items));
+            }
+            Event::Tuple3 => {
+                let items = self.pop_n(3)?;
+                self.push(Node::Tuple(items));
+            }
+            Event::EmptyList => self.push(Node::List(Vec::new())),
+            Event::List => {
+                let items = self.pop_mark()?;
+                check_container(items.len(), max_container)?;
+                self.push(Node::List(items));
+            }
+            Event::Append => {
+                let item = self.pop()?;
+                let list = self.top_list()?;
+                list.push(item);
+                check_container(list.len(), max_container)?;
+            }
+            Event::Appends => {
+                let items = self.pop_mark()?;
+                let list = self.top_list()?;
+                list.extend(items);
+                check_container(list.len(), max_container)?;
+            }
+            Event::EmptyDict => self.push(Node::Dict(Vec::new())),
+            Event::Dict => {
+                let items = pairs(self.pop_mark()?)?;
+                check_container(items.len(), max_container)?;
+                self.push(Node::Dict(items));
+            }
+            Event::SetItem => {
+                let value = self.pop()?;
+                let key = self.pop()?;
+                let dict = self.top_dict()?;
+                dict.push((key, value));
+                check_container(dict.len(), max_container)?;
+            }
+            Event::SetItems => {
+                let items = pairs(self.pop_mark()?)?;
+                let dict = self.top_dict()?;
+                dict.extend(items);
+                check_container(dict.len(), max_container)?;
+            }
+            Event::EmptySet => self.push(Node::Set(Vec::new())),
+            Event::AdditItems => {
+                let items = self.pop_mark()?;
+                let top = self.top()?;
+                match &mut self.nodes[top] {
+                    Node::Set(set) => {
+                        set.extend(items);
+                        check_container(set.len(), max_container)?;
+                    }
+                    node => return Err(Error::unexpected("a set", node.type_name())),
+                }
+            }
+            Event::FrozenSet => {
+                let items = self.pop_mark()?;
+                check_container(items.len(), max_container)?;
+                self.push(Node::FrozenSet(items));
+            }
+
+            // Memo operations
+            Event::Get(id) => self.get(id as u32)?,
+            Event::BinGet(id) => self.get(id as u32)?,
+            Event::LongBinGet(id) => self.get(id)?,
+            Event::Put(id) => self.put(id as u32)?,
+            Event::BinPut(id) => self.put(id as u32)?,
+            Event::LongBinPut(id) => self.put(id)?,
+            Event::Memoize => self.put(self.memo.len() as u32)?,
+
+            // Object construction, only the state or the items of objects are deserialized
+            Event::Global { module_len, .. } => {
+                self.check_global(&payload, module_len as usize)?;
+                self.push(Node::Global);
+            }
+            Event::StackGlobal => {
+                let name = self.pop()?;
+                let module = self.pop()?;
+                let (Node::Str(module), Node::Str(name)) = (&self.nodes[module], &self.nodes[name])
+                else {
+                    let found = format!(
+                        "{} and {}",
+                        self.nodes[module].type_name(),
+                        self.nodes[name].type_name()
+                    );
+                    return Err(Error::unexpected("str module and name", found));
+                };
+                self.check_policy(module, name)?;
+                self.push(Node::Global);
+            }
+            Event::Reduce => {
+                self.pop_n(2)?;
+                self.push(Node::Reduce);
+            }
+            Event::Build => {
+                let state = self.pop()?;
+                let top = self.top()?;
+                self.nodes[top] = Node::Build { state };
+            }
+            Event::Inst { module_len, .. } => {
+                self.check_global(&payload, module_len as usize)?;
+                self.pop_mark()?;
+                self.push(Node::Object);
+            }
+            Event::Obj => {
+                if self.pop_mark()?.is_empty() {
+                    return Err(ErrorKind::StackUnderflow.into());
+                }
+                self.push(Node::Object);
+            }
+            Event::NewObj => {
+                self.pop_n(2)?;
+                self.push(Node::Object);
+            }
+            Event::NewObjEx => {
+                self.pop_n(3)?;
+                self.push(Node::Object);
+            }
+
+            // Persistent objects
+            Event::PersId { .. } | Event::BinPersId => {
+                return Err(ErrorKind::Invalid(
+                    "persistent id found but no persistent loader was given",
+                )
+                .into());
+            }
+
+            // Extensions
+            Event::Ext1(_) => return Err(ErrorKind::OpCode(0x82).into()),
+            Event::Ext2(_) => return Err(ErrorKind::OpCode(0x83).into()),
+            Event::Ext4(_) => return Err(ErrorKind::OpCode(0x84).into()),
+
+            // Protocol 5
+            Event::NextBuffer => {
+                let buffers = self.buffers.as_mut().ok_or(ErrorKind::Invalid(
+                    "out-of-band buffer referenced but no buffers were given",
+                ))?;
+                let data = buffers
+                    .next()
+                    .ok_or(ErrorKind::Invalid("not enough out-of-band buffers"))?;
+                self.push(Node::Buffer(data));
+            }
+            Event::ReadonlyBuffer => {
+                let top = self.top()?;
+                match &self.nodes[top] {
+                    Node::Buffer(_) | Node::Bytes(_) => (),
+                    node => return Err(Error::unexpected("a buffer", node.type_name())),
+                }
+            }
+        }
+        if self.stack.len() > limits.max_stack || self.metastack.len() > limits.max_stack {
+            let max = limits.max_stack;
+            return Err(ErrorKind::StackTooDeep { max }.into());
+        }
+        Ok(())
+    }
+
+    fn push(&mut self, node: Node<'de>) {
+        self.stack.push(self.nodes.len());
+        self.nodes.push(node);
+    }
+
+    fn pop(&mut self) -> Result<usize, Error> {
+        self.stack.pop().ok_or(ErrorKind::StackUnderflow.into())
+    }
+
+    fn pop_n(&mut self, n: usize) -> Result<Vec<usize>, Error> {
+        let start = self
+            .stack
+            .len()
+            .checked_sub(n)
+            .ok_or(ErrorKind::StackUnderflow)?;
+        Ok(self.stack.split_off(start))
+    }
+
+    /// Pops all the objects pushed since the last mark
+    fn pop_mark(&mut self) -> Result<Vec<usize>, Error> {
+        let stack = self.metastack.pop().ok_or(ErrorKind::MissingMark)?;
+        Ok(mem::replace(&mut self.stack, stack))
+    }
+
+    fn top(&self) -> Result<usize, Error> {
+        self.stack
+            .last()
+            .copied()
+            .ok_or(ErrorKind::StackUnderflow.into())
+    }
+
+    /// Gets the list to append to, replacing objects by the list of their items
+    fn top_list(&mut self) -> Result<&mut Vec<usize>, Error> {
+        let top = self.top()?;
+        let index = match self.nodes[top] {
+            Node::List(_) => top,
+            Node::Extend { items } if matches!(self.nodes[items], Node::List(_)) => items,
+            Node::Extend { .. } => return Err(ErrorKind::Invalid("cannot append to object").into()),
+            Node::Reduce | Node::Object | Node::Build { .. } => {
+                self.extend(top, Node::List(Vec::new()))
+            }
+            ref node => return Err(Error::unexpected("a list or an object", node.type_name())),
+        };
+        match &mut self.nodes[index] {
+            Node::List(list) => Ok(list),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Gets the dict to set items on, replacing objects by the dict of their items
+    fn top_dict(&mut self) -> Result<&mut Vec<(usize, usize)>, Error> {
+        let top = self.top()?;
+        let index = match self.nodes[top] {
+            Node::Dict(_) => top,
+            Node::Extend { items } if matches!(self.nodes[items], Node::Dict(_)) => items,
+            Node::Extend { .. } => {
+                return Err(ErrorKind::Invalid("cannot set item on object").into());
+            }
+            Node::Reduce | Node::Object | Node::Build { .. } => {
+                self.extend(top, Node::Dict(Vec::new()))
+            }
+            ref node => return Err(Error::unexpected("a dict or an object", node.type_name())),
+        };
+        match &mut self.nodes[index] {
+            Node::Dict(dict) => Ok(dict),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Replaces an object by an `Extend` with empty `items`, and returns the index of the items
+    fn extend(&mut self, object: usize, items: Node<'de>) -> usize {
+        let index = self.nodes.len();
+        self.nodes.push(items);
+        self.nodes[object] = Node::Extend { items: index };
+        index
+    }
+
+    fn get(&mut self, id: u32) -> Result<(), Error> {
+        let index = *self.memo.get(&id).ok_or(ErrorKind::Memo(id))?;
+        self.stack.push(index);
+        Ok(())
+    }
+
+    fn put(&mut self, id: u32) -> Result<(), Error> {
+        let max = self.read.limits().max_memo;
+        if self.memo.len() >= max && !self.memo.contains_key(&id) {
+            return Err(ErrorKind::MemoTooLarge { max }.into());
+        }
+        let top = self.top()?;
+        self.memo.insert(id, top);
+        Ok(())
+    }
+
+    /// Checks a global whose module and name are in `payload`
+    fn check_global(&self, payload: &[u8], module_len: usize) -> Result<(), Error> {
+        let module = from_utf8(&payload[..module_len])?;
+        let name = from_utf8(&payload[module_len..])?;
+        self.check_policy(module, name)
+    }
+
+    fn check_policy(&self, module: &str, name: &str) -> Result<(), Error> {
+        if let Some(policy) = &self.policy
+            && !policy.is_allowed(module, name)
+        {
+            return Err(ErrorKind::Forbidden {
+                module: module.to_string(),
+                name: name.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+}
+
+/// Deserializes a `T` from a pickle reader
+pub fn from_reader<R: BufRead, T: DeserializeOwned>(reader: R) -> Result<T, Error> {
+    T::deserialize(&mut Deserializer::new(reader))
+}
+
+/// Deserializes a `T` from pickle bytes, borrowing its strings and bytes from them
+///
+/// Bytes after the STOP opcode of the pickle are an error.
+pub fn from_slice<'de, T: Deserialize<'de>>(data: &'de [u8]) -> Result<T, Error> {
+    let mut deserializer = Deserializer::from_slice(data);
+    let value = T::deserialize(&mut deserializer)?;
+    deserializer.end()?;
+    Ok(value)
+}
+
+/// Deserializes a `T` from an unpickled value
+pub fn from_value<T: DeserializeOwned>(value: Value) -> Result<T, Error> {
+    T::deserialize(value)
+}
+
+/// Deserializes a `T` borrowing strings and bytes from an unpickled value
+pub fn from_value_ref<'de, T: Deserialize<'de>>(value: &'de Value) -> Result<T, Error> {
+    T::deserialize(value)
+}
+
+impl<'de, R: Read<'de>> de::Deserializer<'de> for &mut Deserializer<'de, R> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.walk(|root| root.deserialize_any(visitor))
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        self.walk(|root| root.deserialize_option(visitor))
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.walk(|root| root.deserialize_newtype_struct(name, visitor))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        self.walk(|root| root.deserialize_enum(name, variants, visitor))
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+/// An object read by the [`Deserializer`]
+///
+/// Containers hold the indexes of the objects nested in them, strings and bytes the payloads
+/// of their events. Python objects are only kept for the state or items they are deserialized
+/// from.
+#[derive(Debug)]
+enum Node<'de> {
+    None,
+    Bool(bool),
+    Int(i64),
+    BigInt(BigInt),
+    Float(f64),
+    Str(Cow<'de, str>),
+    Bytes(Cow<'de, [u8]>),
+    Buffer(Arc<[u8]>),
+    List(Vec<usize>),
+    Tuple(Vec<usize>),
+    Dict(Vec<(usize, usize)>),
+    Set(Vec<usize>),
+    FrozenSet(Vec<usize>),
+    Global,
+    Reduce,
+    Object,
+    Build { state: usize },
+    Extend { items: usize },
+}
+
+/// Index of the `None` node each pickle starts with, the content of a `(variant,)` tuple
+const NONE_NODE: usize = 0;
+
+impl Node<'_> {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Node::None => "None",
+            Node::Bool(_) => "bool",
+            Node::Int(_) | Node::BigInt(_) => "int",
+            Node::Float(_) => "float",
+            Node::Str(_) => "str",
+            Node::Bytes(_) => "bytes",
+            Node::Buffer(_) => "buffer",
+            Node::List(_) => "list",
+            Node::Tuple(_) => "tuple",
+            Node::Dict(_) => "dict",
+            Node::Set(_) => "set",
+            Node::FrozenSet(_) => "frozenset",
+            Node::Global => "global",
+            Node::Reduce => "reduced object",
+            Node::Object => "object",
+            Node::Build { .. } => "built object",
+            Node::Extend { .. } => "extended object",
+        }
+    }
+
+    fn unexpected(&self) -> Unexpected<'_> {
+        match self {
+            Node::None => Unexpected::Unit,
+            Node::Bool(b) => Unexpected::Bool(*b),
+            Node::Int(i) => Unexpected::Signed(*i),
+            Node::BigInt(_) => Unexpected::Other("big integer"),
+            Node::Float(f) => Unexpected::Float(*f),
+            Node::Str(s) => Unexpected::Str(s),
+            Node::Bytes(b) => Unexpected::Bytes(b),
+            Node::Buffer(data) => Unexpected::Bytes(data),
+            Node::List(_) | Node::Tuple(_) | Node::Set(_) | Node::FrozenSet(_) => Unexpected::Seq,
+            Node::Dict(_) => Unexpected::Map,
+            Node::Global => Unexpected::Other("global"),
+            Node::Reduce => Unexpected::Other("reduce"),
+            Node::Object => Unexpected::Other("object"),
+            Node::Build { .. } => Unexpected::Other("built object"),
+            Node::Extend { .. } => Unexpected::Other("extended object"),
+        }
+    }
+}
+
+/// The objects of a pickle being deserialized
+struct Tree<'a, 'de> {
+    nodes: &'a [Node<'de>],
+    /// Whether each object is being deserialized, to reject recursive objects
+    open: Vec<Cell<bool>>,
+    /// Number of items of the containers visited
+    visited: Cell<usize>,
+    limits: Limits,
+}
+
+/// An object of a [`Tree`], nested `depth` levels deep
+#[derive(Clone, Copy)]
+struct Nested<'a, 'de> {
+    tree: &'a Tree<'a, 'de>,
+    index: usize,
+    depth: usize,
+}
+
+impl<'a, 'de> Nested<'a, 'de> {
+    fn node(&self) -> &'a Node<'de> {
+        &self.tree.nodes[self.index]
+    }
+
+    fn nested(&self, index: usize) -> Self {
+        Nested {
+            tree: self.tree,
+            index,
+            depth: self.depth + 1,
+        }
+    }
+
+    /// Visits the `len` items of a container or an object
+    ///
+    /// Each object is nested in a single container, unless it is referenced again from the
+    /// memo: the items visited beyond the number of objects are copies.
+    fn visit<T, F>(&self, len: usize, f: F) -> Result<T, Error>
+    where
+        F: FnOnce() -> Result<T, Error>,
+    {
+        let Limits {
+            max_copies,
+            max_depth,
+            ..
+        } = self.tree.limits;
+        if self.depth >= max_depth {
+            return Err(ErrorKind::NestingTooDeep { max: max_depth }.into());
+        }
+        let visited = self.tree.visited.get().saturating_add(len);
+        if visited > self.tree.nodes.len().saturating_add(max_copies) {
+            return Err(ErrorKind::TooManyCopies { max: max_copies }.into());
+        }
+        self.tree.visited.set(visited);
+        let open = &self.tree.open[self.index];
+        if open.replace(true) {
+            return Err(ErrorKind::Invalid("recursive objects are not supported").into());
+        }
+        let result = f();
+        open.set(false);
+        result
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Nested<'_, 'de> {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.node() {
+            Node::None => visitor.visit_unit(),
+            Node::Bool(b) => visitor.visit_bool(*b),
+            Node::Int(i) => visitor.visit_i64(*i),
+            Node::BigInt(i) => visit_bigint(i, visitor),
+            Node::Float(f) => visitor.visit_f64(*f),
+            Node::Str(Cow::Borrowed(s)) => visitor.visit_borrowed_str(s),
+            Node::Str(Cow::Owned(s)) => visitor.visit_str(s),
+            Node::Bytes(Cow::Borrowed(b)) => visitor.visit_borrowed_bytes(b),
+            Node::Bytes(Cow::Owned(b)) => visitor.visit_bytes(b),
+            Node::Buffer(data) => visitor.visit_bytes(data),
+            Node::List(items) | Node::Tuple(items) | Node::Set(items) | Node::FrozenSet(items) => {
+                self.visit(items.len(), || {
+                    visit_seq(items.iter().map(|&item| self.nested(item)), visitor)
+                })
+            }
+            Node::Dict(items) => self.visit(items.len() * 2, || {
+                let items = items
+                    .iter()
+                    .map(|&(key, value)| (self.nested(key), self.nested(value)));
+                visit_map(items, visitor)
+            }),
+            Node::Build { state: items } | Node::Extend { items } => {
+                self.visit(1, || self.nested(*items).deserialize_any(visitor))
+            }
+            node => Err(de::Error::invalid_type(node.unexpected(), &visitor)),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self.node() {
+            Node::None => visitor.visit_none(),
+            _ => visitor.visit_some(self),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self.node() {
+            Node::Str(Cow::Borrowed(variant)) => {
+                visitor.visit_enum(BorrowedStrDeserializer::new(variant))
+            }
+            Node::Str(Cow::Owned(variant)) => {
+                visitor.visit_enum(variant.as_str().into_deserializer())
+            }
+            Node::Dict(items) if items.len() == 1 => self.visit(2, || {
+                let (variant, content) = items[0];
+                visitor.visit_enum(Enum {
+                    variant: self.nested(variant),
+                    content: self.nested(content),
+                })
+            }),
+            Node::Tuple(items) if matches!(items.len(), 1 | 2) => self.visit(items.len(), || {
+                visitor.visit_enum(Enum {
+                    variant: self.nested(items[0]),
+                    content: self.nested(items.get(1).copied().unwrap_or(NONE_NODE)),
+                })
+            }),
+            node => Err(de::Error::invalid_type(
+                node.unexpected(),
+                &"str, dict with a single key or tuple",
+            )),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+impl Value {
+    fn unexpected(&self) -> Unexpected<'_> {
+        match self {
+            Value::None => Unexpected::Unit,
+            Value::Bool(b) => Unexpected::Bool(*b),
+            Value::Int(i) => Unexpected::Signed(*i),
+            Value::BigInt(_) => Unexpected::Other("big integer"),
+            Value::Float(f) => Unexpected::Float(*f),
+            Value::Str(s) => Unexpected::Str(s),
+            Value::Bytes(b) => Unexpected::Bytes(b),
+            Value::Buffer { data, .. } => Unexpected::Bytes(data),
+            Value::List(_) | Value::Tuple(_) | Value::Set(_) | Value::FrozenSet(_) => {
+                Unexpected::Seq
+            }
+            Value::Dict(_) => Unexpected::Map,
+            Value::Global { .. } => Unexpected::Other("global"),
+            Value::Reduce { .. } => Unexpected::Other("reduce"),
+            Value::Object { .. } => Unexpected::Other("object"),
+            Value::Build { .. } => Unexpected::Other("built object"),
+            Value::Extend { .. } => Unexpected::Other("extended object"),
+        }
+    }
+}
+
+impl<'de> IntoDeserializer<'de, Error> for Value {
+    type Deserializer = Value;
+
+    fn into_deserializer(self) -> Value {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::None => visitor.visit_unit(),
+            Value::Bool(b) => visitor.visit_bool(b),
+            Value::Int(i) => visitor.visit_i64(i),
+            Value::BigInt(i) => visit_bigint(&i, visitor),
+            Value::Float(f) => visitor.visit_f64(f),
+            Value::Str(s) => visitor.visit_string(s),
+            Value::Bytes(b) => visitor.visit_byte_buf(b),
+            Value::Buffer { data, .. } => visitor.visit_bytes(&data),
+            Value::List(items)
+            | Value::Tuple(items)
+            | Value::Set(items)
+            | Value::FrozenSet(items) => visit_seq(items.into_iter(), visitor),
+            Value::Dict(items) => visit_map(items.into_iter(), visitor),
+            Value::Build { state, .. } => state.deserialize_any(visitor),
+            Value::Extend { items, .. } => items.deserialize_any(visitor),
+            v => Err(de::Error::invalid_type(v.unexpected(), &visitor)),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::None => visitor.visit_none(),
+            v => visitor.visit_some(v),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self {
+            // unit variant
+            Value::Str(variant) => visitor.visit_enum(variant.into_deserializer()),
+            // externally tagged variant, {variant: content}
+            Value::Dict(mut items) if items.len() == 1 => {
+                let (variant, content) = items.pop().expect("one item");
+                visitor.visit_enum(Enum { variant, content })
+            }
+            // variant as a tuple, (variant,) or (variant, content)
+            Value::Tuple(mut items) if matches!(items.len(), 1 | 2) => {
+                let content = if items.len() == 2 {
+                    items.pop().expect("two items")
+                } else {
+                    Value::None
+                };
+                let variant = items.pop().expect("one item");
+                visitor.visit_enum(Enum { variant, content })
+            }
+            v => Err(de::Error::invalid_type(
+                v.unexpected(),
+                &"str, dict with a single key or tuple",
+            )),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+fn visit_bigint<'de, V: Visitor<'de>>(i: &BigInt, visitor: V) -> Result<V::Value, Error> {
+    match i.to_i128() {
+        Some(i) => visitor.visit_i128(i),
+        None => Err(de::Error::invalid_value(
+            Unexpected::Other("big integer"),
+            &"an integer fitting in an i128",
+        )),
+    }
+}
+
+fn visit_seq<'de, I, V>(items: I, visitor: V) -> Result<V::Value, Error>
+where
+    I: ExactSizeIterator,
+    I::Item: Item<'de>,
+    V: Visitor<'de>,
+{
+    let mut seq = SeqAccess { items, index: 0 };
+    let value = visitor.visit_seq(&mut seq)?;
+    seq.end()?;
+    Ok(value)
+}
+
+fn visit_map<'de, I, K, V>(items: I, visitor: V) -> Result<V::Value, Error>
+where
+    I: ExactSizeIterator<Item = (K, K)>,
+    K: Item<'de>,
+    V: Visitor<'de>,
+{
+    let mut map = MapAccess {
+        items,
+        index: 0,
+        value: None,
+    };
+    let value = visitor.visit_map(&mut map)?;
+    map.end()?;
+    Ok(value)
+}
+
+/// The `None` content of a `(variant,)` tuple deserialized by reference
+static NONE: Value = Value::None;
+
+impl<'de> IntoDeserializer<'de, Error> for &'de Value {
+    type Deserializer = &'de Value;
+
+    fn into_deserializer(self) -> &'de Value {
+        self
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &'de Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::None => visitor.visit_unit(),
+            Value::Bool(b) => visitor.visit_bool(*b),
+            Value::Int(i) => visitor.visit_i64(*i),
+            Value::BigInt(i) => visit_bigint(i, visitor),
+            Value::Float(f) => visitor.visit_f64(*f),
+            Value::Str(s) => visitor.visit_borrowed_str(s),
+            Value::Bytes(b) => visitor.visit_borrowed_bytes(b),
+            Value::Buffer { data, .. } => visitor.visit_borrowed_bytes(data),
+            Value::List(items)
+            | Value::Tuple(items)
+            | Value::Set(items)
+            | Value::FrozenSet(items) => visit_seq(items.iter(), visitor),
+            Value::Dict(items) => visit_map(items.iter().map(|(k, v)| (k, v)), visitor),
+            Value::Build { state, .. } => state.as_ref().deserialize_any(visitor),
+            Value::Extend { items, .. } => items.as_ref().deserialize_any(visitor),
+            v => Err(de::Error::invalid_type(v.unexpected(), &visitor)),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Error> {
+        match self {
+            Value::None => visitor.visit_none(),
+            v => visitor.visit_some(v),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Error> {
+        match self {
+            Value::Str(variant) => visitor.visit_enum(BorrowedStrDeserializer::new(variant)),
+            Value::Dict(items) if items.len() == 1 => {
+                let (variant, content) = &items[0];
+                visitor.visit_enum(Enum { variant, content })
+            }
+            Value::Tuple(items) if matches!(items.len(), 1 | 2) => visitor.visit_enum(Enum {
+                variant: &items[0],
+                content: items.get(1).unwrap_or(&NONE),
+            }),
+            v => Err(de::Error::invalid_type(
+                v.unexpected(),
+                &"str, dict with a single key or tuple",
+            )),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+/// A value deserialized by value, by reference or from a [`Tree`]
+trait Item<'de>: de::Deserializer<'de, Error = Error> + de::VariantAccess<'de, Error = Error> {
+    /// Describes the item as a dict key in errors
+    fn describe(&self) -> String;
+}
+
+impl<'de> Item<'de> for Value {
+    fn describe(&self) -> String {
+        describe(self)
+    }
+}
+
+impl<'de> Item<'de> for &'de Value {
+    fn describe(&self) -> String {
+        describe(self)
+    }
+}
+
+impl<'de> Item<'de> for Nested<'_, 'de> {
+    fn describe(&self) -> String {
+        match self.node() {
+            Node::Str(s) => format!("'{s}'"),
+            Node::Int(i) => i.to_string(),
+            node => node.type_name().to_string(),
+        }
+    }
+}
+
+fn describe(value: &Value) -> String {
+    match value {
+        Value::Str(s) => format!("'{s}'"),
+        Value::Int(i) => i.to_string(),
+        value => value.type_name().to_string(),
+    }
+}
+
+/// Sequence access adding the item index to errors
+struct SeqAccess<I> {
+    items: I,
+    index: usize,
+}
+
+impl<I: ExactSizeIterator> SeqAccess<I> {
+    fn end(self) -> Result<(), Error> {
+        match self.items.len() {
+            0 => Ok(()),
+            rest => Err(de::Error::invalid_length(
+                self.index + rest,
+                &format!("{} items", self.index).as_str(),
+            )),
+        }
+    }
+}
+
+impl<'de, I> de::SeqAccess<'de> for SeqAccess<I>
+where
+    I: ExactSizeIterator,
+    I::Item: Item<'de>,
+{
+    type Error = Error;
+
+    fn next_element_seed<S: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, Error> {
+        let Some(item) = self.items.next() else {
+            return Ok(None);
+        };
+        let index = self.index;
+        self.index += 1;
+        seed.deserialize(item)
+            .map(Some)
+            .map_err(|e| e.with_context(format!("while decoding item {index}")))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+/// Map access adding the key to errors
+struct MapAccess<I, K> {
+    items: I,
+    index: usize,
+    /// The value of the last key, with the key description
+    value: Option<(String, K)>,
+}
+
+impl<I: ExactSizeIterator, K> MapAccess<I, K> {
+    fn end(self) -> Result<(), Error> {
+        match self.items.len() {
+            0 => Ok(()),
+            rest => Err(de::Error::invalid_length(
+                self.index + rest,
+                &format!("{} entries", self.index).as_str(),
+            )),
+        }
+    }
+}
+
+impl<'de, I, K> de::MapAccess<'de> for MapAccess<I, K>
+where
+    I: ExactSizeIterator<Item = (K, K)>,
+    K: Item<'de>,
+{
+    type Error = Error;
+
+    fn next_key_seed<S: de::DeserializeSeed<'de>>(
+        &mut self,
+        seed: S,
+    ) -> Result<Option<S::Value>, Error> {
+        let Some((key, value)) = self.items.next() else {
+            return Ok(None);
+        };
+        self.index += 1;
+        let desc = key.describe();
+        let key = seed
+            .deserialize(key)
+            .map_err(|e| e.with_context(format!("while decoding dict key {desc}")))?;
+        self.value = Some((desc, value));
+        Ok(Some(key))
+    }
+
+    fn next_value_seed<S: de::DeserializeSeed<'de>>(&mut self, seed: S) -> Result<S::Value, Error> {
+        let (desc, value) = self.value.take().expect("next_key_seed called first");
+        seed.deserialize(value)
+            .map_err(|e| e.with_context(format!("while decoding dict value for key {desc}")))
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.items.len())
+    }
+}
+
+struct Enum<V> {
+    variant: V,
+    content: V,
+}
+
+impl<'de, V: Item<'de>> de::EnumAccess<'de> for Enum<V> {
+    type Error = Error;
+    type Variant = V;
+
+    fn variant_seed<S: de::DeserializeSeed<'de>>(self, seed: S) -> Result<(S::Value, V), Error> {
+        let variant = seed.deserialize(self.variant)?;
+        Ok((variant, self.content))
+    }
+}
+
+macro_rules! variant_access {
+    ($(<$($lt:lifetime),*> $ty:ty),*) => {
+        $(
+            impl<$($lt),*> de::VariantAccess<'de> for $ty {
+                type Error = Error;
+
+                fn unit_variant(self) -> Result<(), Error> {
+                    Deserialize::deserialize(self)
+                }
+
+                fn newtype_variant_seed<S: de::DeserializeSeed<'de>>(
+                    self,
+                    seed: S,
+                ) -> Result<S::Value, Error> {
+                    seed.deserialize(self)
+                }
+
+                fn tuple_variant<V: Visitor<'de>>(
+                    self,
+                    _len: usize,
+                    visitor: V,
+                ) -> Result<V::Value, Error> {
+                    de::Deserializer::deserialize_any(self, visitor)
+                }
+
+                fn struct_variant<V: Visitor<'de>>(
+                    self,
+                    _fields: &'static [&'static str],
+                    visitor: V,
+                ) -> Result<V::Value, Error> {
+                    de::Deserializer::deserialize_any(self, visitor)
+                }
+            }
+        )*
+    };
+}
+
+variant_access!(<'de> Value, <'de> &'de Value, <'a, 'de> Nested<'a, 'de>);
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Config {
+        name: String,
+        weights: Vec<f64>,
+        shape: (u32, u32),
+        bias: Option<i64>,
+        raw: serde_bytes_like::Bytes,
+        mode: Mode,
+    }
+
+    #[derive(Debug, Deserialize, PartialEq)]
+    enum Mode {
+        Fast,
+        Slow { factor: u8 },
+    }
+
+    mod serde_bytes_like {
+        use serde::de::{Deserialize, Deserializer, Visitor};
+
+        #[derive(Debug, PartialEq)]
+        pub struct Bytes(pub Vec<u8>);
+
+        impl<'de> Deserialize<'de> for Bytes {
+            fn deserialize<D: Deserializer<'de>>(d: D) -> Result<Self, D::Error> {
+                struct V;
+                impl<'de> Visitor<'de> for V {
+                    type Value = Bytes;
+                    fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+                        f.write_str("bytes")
+                    }
+                    fn visit_bytes<E>(self, v: &[u8]) -> Result<Bytes, E> {
+                        Ok(Bytes(v.to_vec()))
+                    }
+                    fn visit_byte_buf<E>(self, v: Vec<u8>) -> Result<Bytes, E> {
+                        Ok(Bytes(v))
+                    }
+                }
+                d.deserialize_byte_buf(V)
+            }
+        }
+    }
+
+    #[test]
+    fn test_from_slice_struct() -> Result<(), Error> {
+        // pickle.dumps({'name': 'a', 'weights': [0.5, 1.5], 'shape': (2, 3), 'bias': None,
+        //               'raw': b'xy', 'mode': {'Slow': {'factor': 3}}}, protocol=4)
+        let data = b"\x80\x04\x95p\x00\x00\x00\x00\x00\x00\x00}\x94(\x8c\x04name\x94\x8c\x01a\x94\x8c\x07weights\x94]\x94(G?\xe0\x00\x00\x00\x00\x00\x00G?\xf8\x00\x00\x00\x00\x00\x00e\x8c\x05shape\x94K\x02K\x03\x86\x94\x8c\x04bias\x94N\x8c\x03raw\x94C\x02xy\x94\x8c\x04mode\x94}\x94\x8c\x04Slow\x94}\x94\x8c\x06factor\x94K\x03ssu.";
+        let config: Config = from_slice(data)?;
+        assert_eq!(
+            config,
+            Config {
+                name: "a".into(),
+                weights: vec![0.5, 1.5],
+                shape: (2, 3),
+                bias: None,
+                raw: serde_bytes_like::Bytes(b"xy".to_vec()),
+                mode: Mode::Slow { factor: 3 },
+            }
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_context() {
+        #[derive(Debug, Deserialize)]
+        #[allow(dead_code)]
+        struct Model {
+            weights: Vec<f64>,
+        }
+
+        let value = Value::Dict(vec![(
+            Value::Str("weights".into()),
+            Value::List(vec![Value::Float(1.0), Value::Str("x".into())]),
+        )]);
+        let err = from_value::<Model>(value).unwrap_err();
+        assert_eq!(
+            err.context(),
+            [
+                "while decoding item 1",
+                "while decoding dict value for key 'weights'"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_from_slice_unit_variant() -> Result<(), Error> {
+        // pickle.dumps(['Fast'], protocol=4)
+        let data = b"\x80\x04\x95\x0b\x00\x00\x00\x00\x00\x00\x00]\x94\x8c\x04Fast\x94a.";
+        let modes: Vec<Mode> = from_slice(data)?;
+        assert_eq!(modes, [Mode::Fast]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_value_ref() -> Result<(), Error> {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Entry<'a> {
+            name: &'a str,
+            #[serde(borrow)]
+            raw: &'a [u8],
+            mode: Mode,
+        }
+
+        // pickle.dumps([{'name': 'a', 'raw': b'xy', 'mode': ('Slow', {'factor': 3})}], protocol=4)
+        let data = b"\x80\x04\x95<\x00\x00\x00\x00\x00\x00\x00]\x94}\x94(\x8c\x04name\x94\x8c\x01a\x94\x8c\x03raw\x94C\x02xy\x94\x8c\x04mode\x94\x8c\x04Slow\x94}\x94\x8c\x06factor\x94K\x03s\x86\x94ua.";
+        let value = crate::load(&data[..])?;
+        let entries: Vec<Entry> = from_value_ref(&value)?;
+        assert_eq!(
+            entries,
+            [Entry {
+                name: "a",
+                raw: b"xy",
+                mode: Mode::Slow { factor: 3 },
+            }]
+        );
+
+        let err = from_value_ref::<Vec<Entry>>(&Value::List(vec![Value::Int(1)])).unwrap_err();
+        assert_eq!(err.context(), ["while decoding item 0"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_slice_borrowed() -> Result<(), Error> {
+        #[derive(Debug, Deserialize, PartialEq)]
+        struct Entry<'a> {
+            name: &'a str,
+            #[serde(borrow)]
+            raw: &'a [u8],
+            mode: Mode,
+        }
+
+        // pickle.dumps([{'name': 'a', 'raw': b'xy', 'mode': ('Slow', {'factor': 3})}], protocol=4)
+        let data = b"\x80\x04\x95<\x00\x00\x00\x00\x00\x00\x00]\x94}\x94(\x8c\x04name\x94\x8c\x01a\x94\x8c\x03raw\x94C\x02xy\x94\x8c\x04mode\x94\x8c\x04Slow\x94}\x94\x8c\x06factor\x94K\x03s\x86\x94ua.";
+        let entries: Vec<Entry> = from_slice(data)?;
+        assert_eq!(
+            entries,
+            [Entry {
+                name: "a",
+                raw: b"xy",
+                mode: Mode::Slow { factor: 3 },
+            }]
+        );
+        assert!(data.as_ptr_range().contains(&entries[0].name.as_ptr()));
+        Ok(())
+    }
+
+    #[test]
+    fn test_trailing_bytes() -> Result<(), Error> {
+        let err = from_slice::<i64>(b"K\x01.K").unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::TrailingBytes));
+        assert_eq!(err.offset(), Some(3));
+
+        // pickles written one after the other
+        let mut deserializer = Deserializer::from_slice(b"K\x01.\x8c\x01a.");
+        assert_eq!(i64::deserialize(&mut deserializer)?, 1);
+        assert_eq!(<&str>::deserialize(&mut deserializer)?, "a");
+        deserializer.end()?;
+
+        let err = from_slice::<i64>(b"K\x01").unwrap_err();
+        assert_eq!(err.kind().to_string(), "pickle ended without a STOP opcode");
+        Ok(())
+    }
+
+    #[test]
+    fn test_memo_references() -> Result<(), Error> {
+        // a = [1]; pickle.dumps([a, a], protocol=2), with the items of a appended after PUT
+        let data = b"\x80\x02]q\x00(]q\x01K\x01ah\x01e.";
+        assert_eq!(from_slice::<Vec<Vec<i64>>>(data)?, [[1], [1]]);
+        assert_eq!(from_reader::<_, Vec<Vec<i64>>>(&data[..])?, [[1], [1]]);
+
+        // l = []; l.append(l)
+        let err = from_slice::<serde::de::IgnoredAny>(b"\x80\x02]q\x00h\x00a.").unwrap_err();
+        assert_eq!(
+            err.kind().to_string(),
+            "Invalid object: recursive objects are not supported"
+        );
+
+        // a = [1] * 4; b = [a] * 4; pickle.dumps([b] * 4, protocol=2)
+        let data =
+            b"\x80\x02]q\x00(]q\x01(]q\x02(K\x01K\x01K\x01K\x01eh\x02h\x02h\x02eh\x01h\x01h\x01e.";
+        let nested: Vec<Vec<Vec<u8>>> = from_slice(data)?;
+        assert_eq!(nested, vec![vec![vec![1; 4]; 4]; 4]);
+        let limits = Limits {
+            max_copies: 10,
+            ..Limits::default()
+        };
+        let mut deserializer =
+            Deserializer::from_reader(SliceReader::new(data).with_limits(limits));
+        let err = Vec::<Vec<Vec<u8>>>::deserialize(&mut deserializer).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::TooManyCopies { max: 10 }));
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() -> Result<(), Error> {
+        // pickle.dumps([[[1]]], protocol=2)
+        let data = b"\x80\x02]q\x00]q\x01]q\x02K\x01aaa.";
+        let load = |max_depth| {
+            let limits = Limits {
+                max_depth,
+                ..Limits::default()
+            };
+            let reader = Reader::new(&data[..]).with_limits(limits);
+            Vec::<Vec<Vec<u8>>>::deserialize(&mut Deserializer::from_reader(reader))
+        };
+        assert_eq!(load(3)?, [[[1]]]);
+        let err = load(2).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::NestingTooDeep { max: 2 }));
+
+        let limits = Limits {
+            max_container: 1,
+            ..Limits::default()
+        };
+        let reader = SliceReader::new(b"(K\x01K\x02l.").with_limits(limits);
+        let err = Vec::<u8>::deserialize(&mut Deserializer::from_reader(reader)).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::ContainerTooLarge { max: 1 }
+        ));
+        assert_eq!(err.offset(), Some(5));
+        Ok(())
+    }
+
+    #[test]
+    fn test_objects() -> Result<(), Error> {
+        // pickle.dumps(collections.OrderedDict(a=1), protocol=2)
+        let data = b"\x80\x02ccollections\nOrderedDict\nq\x00)Rq\x01X\x01\x00\x00\x00aq\x02K\x01s.";
+        let dict: HashMap<&str, i64> = from_slice(data)?;
+        assert_eq!(dict, HashMap::from([("a", 1)]));
+
+        let policy = SafetyPolicy::new();
+        let err = HashMap::<String, i64>::deserialize(
+            &mut Deserializer::from_slice(data).with_policy(policy),
+        )
+        .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Forbidden { .. }));
+
+        let err = from_slice::<i64>(b"\x80\x02c__main__\nA\n)\x81.").unwrap_err();
+        assert_eq!(err.to_string(), "invalid type: object, expected i64");
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_reader_file() -> Result<(), Error> {
+        let file = std::fs::File::open(concat!(env!("CARGO_MANIFEST_DIR"), "/dict.pickle"))?;
+        let dict: HashMap<String, serde::de::IgnoredAny> =
+            from_reader(std::io::BufReader::new(file))?;
+        assert!(!dict.is_empty());
+        Ok(())
+    }
+}
@@ -0,0 +1,225 @@
+//! A module to decode pickle events from bytes pushed as they arrive
+//!
+//! The [`Decoder`] does no IO: input is given with [`Decoder::feed`] and
+//! [`Decoder::next_event`] either decodes an event, exactly as [`Reader::read_event`] would,
+//! or tells how many more bytes it needs. An opcode split across several `feed`s is
+//! decoded once its argument is complete, as described by the [`opcodes::Arg`] table.
+//!
+//! ```
+//! use quick_pickle::{decoder::{Decoded, Decoder}, reader::Event};
+//!
+//! let mut decoder = Decoder::new();
+//! let mut buf = Vec::new();
+//! decoder.feed(b"\x80\x04\x8c\x05ab");
+//! assert_eq!(decoder.next_event(&mut buf)?, Decoded::Event(Event::Proto(4)));
+//! assert_eq!(decoder.next_event(&mut buf)?, Decoded::Needs(3));
+//! decoder.feed(b"cde.");
+//! assert_eq!(decoder.next_event(&mut buf)?, Decoded::Event(Event::ShortBinUnicode { len: 5 }));
+//! assert_eq!(buf, b"abcde");
+//! assert_eq!(decoder.next_event(&mut buf)?, Decoded::Event(Event::Stop));
+//! # Ok::<(), quick_pickle::Error>(())
+//! ```
+//!
+//! [`opcodes::Arg`]: crate::opcodes::Arg
+
+use crate::{
+    errors::Error,
+    reader::{Event, Limits, RawLen, Reader, raw_len},
+};
+
+/// The result of [`Decoder::next_event`]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Decoded {
+    Event(Event),
+    /// At least this many more bytes must be fed to decode the next event
+    Needs(usize),
+}
+
+#[derive(Debug, Default)]
+pub struct Decoder {
+    /// Input fed but not decoded yet, from `start`
+    data: Vec<u8>,
+    start: usize,
+    /// Offset of `data[start]` in the input
+    pos: usize,
+    limits: Limits,
+    finished: bool,
+}
+
+impl Decoder {
+    pub fn new() -> Self {
+        Decoder::default()
+    }
+
+    /// Sets the limits checked while decoding, see [`Reader::with_limits`]
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Gets the offset of the next opcode in the input
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Gets the input fed but not decoded yet
+    pub fn buffered(&self) -> &[u8] {
+        &self.data[self.start..]
+    }
+
+    /// Appends input
+    pub fn feed(&mut self, bytes: &[u8]) {
+        if self.start > 0 {
+            self.data.drain(..self.start);
+            self.start = 0;
+        }
+        self.data.extend_from_slice(bytes);
+    }
+
+    /// Marks the end of the input
+    ///
+    /// Events are then decoded as by a [`Reader`] at the end of its input: STOP is faked when
+    /// nothing is left, and an incomplete opcode is an error.
+    pub fn finish(&mut self) {
+        self.finished = true;
+    }
+
+    /// Decodes the next event, pushing its payload into `buf` as [`Reader::read_event`]
+    pub fn next_event(&mut self, buf: &mut Vec<u8>) -> Result<Decoded, Error> {
+        let raw = &self.data[self.start..];
+        let len = match raw_len(raw, self.pos, &self.limits) {
+            RawLen::Complete(len) => len,
+            RawLen::Needs(needs) if !self.finished => return Ok(Decoded::Needs(needs)),
+            RawLen::Needs(_) => raw.len(),
+        };
+        let mut reader = Reader::new_at(&raw[..len], self.pos).with_limits(self.limits);
+        let event = reader.read_event(buf)?;
+        self.start += reader.pos - self.pos;
+        self.pos = reader.pos;
+        Ok(Decoded::Event(event))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorKind;
+
+    #[test]
+    fn test_same_as_reader() -> Result<(), Error> {
+        for path in ["/ints.pickle", "/dict.pickle"] {
+            let data = std::fs::read(format!("{}{path}", env!("CARGO_MANIFEST_DIR")))?;
+            let mut reader = Reader::new(&data[..]);
+            let mut expected = reader.events();
+
+            // fed one byte at a time
+            let mut decoder = Decoder::new();
+            let mut input = data.iter();
+            let mut buf = Vec::new();
+            loop {
+                match decoder.next_event(&mut buf)? {
+                    Decoded::Needs(_) => decoder.feed(&[*input.next().unwrap()]),
+                    Decoded::Event(event) => {
+                        let expected = expected.next().unwrap()?;
+                        assert_eq!((event, &buf), (expected.event, &expected.payload));
+                        assert_eq!(decoder.position(), expected.end);
+                        buf.clear();
+                        if event == Event::Stop {
+                            break;
+                        }
+                    }
+                }
+            }
+            assert!(input.next().is_none());
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_needs() -> Result<(), Error> {
+        let mut decoder = Decoder::new();
+        let mut buf = Vec::new();
+        assert_eq!(decoder.next_event(&mut buf)?, Decoded::Needs(1));
+        // BININT
+        decoder.feed(b"J\x01");
+        assert_eq!(decoder.next_event(&mut buf)?, Decoded::Needs(3));
+        decoder.feed(b"\x00\x00\x00c__builtin__\nl");
+        assert_eq!(
+            decoder.next_event(&mut buf)?,
+            Decoded::Event(Event::BinInt(1))
+        );
+        // lines need at least one more byte until their newline
+        assert_eq!(decoder.next_event(&mut buf)?, Decoded::Needs(1));
+        decoder.feed(b"en\nN");
+        assert!(matches!(
+            decoder.next_event(&mut buf)?,
+            Decoded::Event(Event::Global { .. })
+        ));
+        assert_eq!(decoder.buffered(), b"N");
+
+        // the end of the input fakes a STOP
+        assert_eq!(decoder.next_event(&mut buf)?, Decoded::Event(Event::None));
+        assert_eq!(decoder.next_event(&mut buf)?, Decoded::Needs(1));
+        decoder.finish();
+        assert_eq!(decoder.next_event(&mut buf)?, Decoded::Event(Event::Stop));
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits_and_truncated() {
+        let mut buf = Vec::new();
+        // BINBYTES8 claiming an exabyte is rejected without waiting for it
+        let limits = Limits {
+            max_payload: 1024,
+            ..Limits::default()
+        };
+        let mut decoder = Decoder::new().with_limits(limits);
+        decoder.feed(b"\x8e\x00\x00\x00\x00\x00\x00\x00\x10");
+        let err = decoder.next_event(&mut buf).unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::PayloadTooLarge { max: 1024, .. }
+        ));
+
+        let mut decoder = Decoder::new();
+        decoder.feed(b"X\x05\x00\x00\x00ab");
+        assert_eq!(decoder.next_event(&mut buf).unwrap(), Decoded::Needs(3));
+        decoder.finish();
+        let err = decoder.next_event(&mut buf).unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+        assert_eq!(err.offset(), Some(0));
+
+        // the end of the input is not past a limit of the input size
+        let limits = Limits {
+            max_total: 1,
+            ..Limits::default()
+        };
+        let mut decoder = Decoder::new().with_limits(limits);
+        decoder.feed(b"N");
+        assert_eq!(
+            decoder.next_event(&mut buf).unwrap(),
+            Decoded::Event(Event::None)
+        );
+        assert_eq!(decoder.next_event(&mut buf).unwrap(), Decoded::Needs(1));
+        decoder.feed(b".");
+        let err = decoder.next_event(&mut buf).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::InputTooLarge { max: 1 }));
+        let mut decoder = Decoder::new().with_limits(limits);
+        decoder.feed(b"N");
+        decoder.finish();
+        assert_eq!(
+            decoder.next_event(&mut buf).unwrap(),
+            Decoded::Event(Event::None)
+        );
+        assert_eq!(
+            decoder.next_event(&mut buf).unwrap(),
+            Decoded::Event(Event::Stop)
+        );
+    }
+}
@@ -0,0 +1,382 @@
+//! A module to disassemble pickles like python's `pickletools.dis`
+//!
+//! Each opcode is rendered on its own line with its offset, its code, its name indented by
+//! the number of open marks, and its decoded argument. The stack and the memo are emulated
+//! to report mark positions and memo misuses.
+
+use std::{
+    collections::HashSet,
+    io::{BufRead, Write},
+};
+
+use crate::{
+    bigint::BigInt,
+    errors::{Error, ErrorKind},
+    opcodes,
+    reader::{Event, Reader, trim_line},
+    unpickler::{raw_unicode_unescape, unescape_string, unquote},
+    writer::float_repr,
+};
+
+/// Number of spaces by which each MARK level is indented
+const INDENT: usize = 4;
+
+/// Disassembles the pickle read by `reader` up to the first STOP
+///
+/// The output is the same as `pickletools.dis`. As in python, the line of an invalid opcode
+/// is written before the error is returned.
+pub fn dis<R: BufRead, W: Write>(reader: &mut Reader<R>, out: &mut W) -> Result<(), Error> {
+    let mut buf = Vec::new();
+    // emulated stack, true for marks
+    let mut stack = Vec::new();
+    let mut markstack = Vec::new();
+    let mut memo = HashSet::new();
+    let mut maxproto = 0;
+    loop {
+        buf.clear();
+        let event = reader.read_event_with_span(&mut buf)?;
+        if event.start == event.end {
+            // the reader fakes a STOP at the end of the input
+            let eof = std::io::Error::new(
+                std::io::ErrorKind::UnexpectedEof,
+                "pickle exhausted before seeing STOP",
+            );
+            return Err(eof.into());
+        }
+        let opcode = reader.opcode;
+        let info = opcodes::info(opcode).expect("opcode decoded by the reader");
+        maxproto = maxproto.max(info.proto);
+        let arg = arg_repr(event.value, opcode, &buf);
+
+        write!(out, "{:5}: ", event.start)?;
+        write!(
+            out,
+            "{:<4} {}{}",
+            code_repr(opcode),
+            " ".repeat(INDENT * markstack.len()),
+            info.name
+        )?;
+
+        let mut error = None;
+        let mut numtopop = info.pops as usize;
+        let mut markmsg = None;
+        if info.pops_mark || (info.name == "POP" && stack.last() == Some(&true)) {
+            match markstack.pop() {
+                Some(pos) => {
+                    markmsg = Some(format!("(MARK at {pos})"));
+                    // pop everything at and after the topmost mark
+                    while let Some(false) = stack.pop() {}
+                    if !info.pops_mark {
+                        numtopop = 0;
+                    }
+                }
+                None => {
+                    error = Some(ErrorKind::MissingMark);
+                    markmsg = Some("no MARK exists on stack".to_string());
+                }
+            }
+        }
+
+        // memo usage
+        match event.value {
+            Event::Put(_) | Event::BinPut(_) | Event::LongBinPut(_) | Event::Memoize => {
+                let id = match event.value {
+                    Event::Put(id) => id as u32,
+                    Event::BinPut(id) => id as u32,
+                    Event::LongBinPut(id) => id,
+                    _ => {
+                        markmsg = Some(format!("(as {})", memo.len()));
+                        memo.len() as u32
+                    }
+                };
+                if memo.contains(&id) {
+                    error = Some(ErrorKind::Invalid("memo key already defined"));
+                } else if stack.is_empty() {
+                    error = Some(ErrorKind::StackUnderflow);
+                } else if stack.last() == Some(&true) {
+                    error = Some(ErrorKind::Invalid("can't store markobject in the memo"));
+                } else {
+                    memo.insert(id);
+                }
+            }
+            Event::Get(id) if !memo.contains(&(id as u32)) => {
+                error = Some(ErrorKind::Memo(id as u32));
+            }
+            Event::BinGet(id) if !memo.contains(&(id as u32)) => {
+                error = Some(ErrorKind::Memo(id as u32));
+            }
+            Event::LongBinGet(id) if !memo.contains(&id) => error = Some(ErrorKind::Memo(id)),
+            _ => (),
+        }
+
+        if arg.is_some() || markmsg.is_some() {
+            // align arguments
+            write!(
+                out,
+                "{}",
+                " ".repeat(10usize.saturating_sub(info.name.len()))
+            )?;
+            if let Some(arg) = &arg {
+                write!(out, " {arg}")?;
+            }
+            if let Some(markmsg) = &markmsg {
+                write!(out, " {markmsg}")?;
+            }
+        }
+        writeln!(out)?;
+
+        if let Some(kind) = error {
+            return Err(Error::from(kind).at(event.start, opcode));
+        }
+
+        // stack effects
+        if stack.len() < numtopop {
+            return Err(Error::from(ErrorKind::StackUnderflow).at(event.start, opcode));
+        }
+        stack.truncate(stack.len() - numtopop);
+        if let Event::Mark = event.value {
+            markstack.push(event.start);
+            stack.push(true);
+        } else {
+            stack.extend((0..info.pushes).map(|_| false));
+        }
+
+        if let Event::Stop = event.value {
+            break;
+        }
+    }
+    writeln!(out, "highest protocol among opcodes = {maxproto}")?;
+    if !stack.is_empty() {
+        return Err(ErrorKind::Invalid("stack not empty after STOP").into());
+    }
+    Ok(())
+}
+
+/// Formats the opcode like python's `repr` of its one character code, without quotes
+fn code_repr(opcode: u8) -> String {
+    match opcode {
+        0x20..0x7f => (opcode as char).to_string(),
+        _ => format!("\\x{opcode:02x}"),
+    }
+}
+
+/// Formats the argument of an opcode like python's `repr`
+fn arg_repr(event: Event, opcode: u8, payload: &[u8]) -> Option<String> {
+    let line = || String::from_utf8_lossy(payload.strip_suffix(b"\n").unwrap_or(payload));
+    Some(match event {
+        Event::Proto(p) => p.to_string(),
+        Event::Frame(len) => len.to_string(),
+        // NEWTRUE and NEWFALSE have no argument
+        Event::Bool(_) if opcode != 0x49 => return None,
+        Event::Bool(true) => "True".to_string(),
+        Event::Bool(false) => "False".to_string(),
+        Event::Int(i) => i.to_string(),
+        Event::BinInt(i) => i.to_string(),
+        Event::BinInt1(i) => i.to_string(),
+        Event::BinInt2(i) => i.to_string(),
+        Event::Long(i) => i.to_string(),
+        Event::BigLong { .. } => BigInt::from_signed_bytes_le(payload).to_string(),
+        Event::Float(f) => float_repr(f),
+        Event::String { .. } => match unquote(payload).and_then(unescape_string) {
+            Ok(s) => latin1_repr(&s),
+            Err(_) => str_repr(&line()),
+        },
+        Event::BinString { .. } | Event::ShortBinString { .. } => latin1_repr(payload),
+        Event::Unicode { .. } => match raw_unicode_unescape(trim_line(payload)) {
+            Ok(s) => str_repr(&s),
+            Err(_) => str_repr(&line()),
+        },
+        Event::PersId { .. } => str_repr(&String::from_utf8_lossy(payload)),
+        Event::BinUnicode { .. } | Event::ShortBinUnicode { .. } | Event::BinUnicode8 { .. } => {
+            str_repr(&String::from_utf8_lossy(payload))
+        }
+        Event::BinBytes { .. } | Event::ShortBinBytes { .. } | Event::BinBytes8 { .. } => {
+            bytes_repr(payload)
+        }
+        Event::ByteArray8 { .. } => format!("bytearray({})", bytes_repr(payload)),
+        Event::Global { module_len, .. } | Event::Inst { module_len, .. } => {
+            let (module, name) = payload.split_at(module_len as usize);
+            let module = String::from_utf8_lossy(module);
+            let name = String::from_utf8_lossy(name);
+            str_repr(&format!("{module} {name}"))
+        }
+        Event::Get(id) | Event::Put(id) => id.to_string(),
+        Event::BinGet(id) | Event::BinPut(id) => id.to_string(),
+        Event::LongBinGet(id) | Event::LongBinPut(id) => id.to_string(),
+        Event::Ext1(code) => code.to_string(),
+        Event::Ext2(code) => code.to_string(),
+        Event::Ext4(code) => code.to_string(),
+        _ => return None,
+    })
+}
+
+/// Formats a python 2 str, decoded as latin-1, like python's `repr`
+fn latin1_repr(bytes: &[u8]) -> String {
+    str_repr(&bytes.iter().map(|b| *b as char).collect::<String>())
+}
+
+/// Formats a string like python's `repr`
+fn str_repr(s: &str) -> String {
+    let quote = if s.contains('\'') && !s.contains('"') {
+        '"'
+    } else {
+        '\''
+    };
+    let mut repr = String::with_capacity(s.len() + 2);
+    repr.push(quote);
+    for c in s.chars() {
+        match c {
+            '\\' => repr.push_str("\\\\"),
+            '\n' => repr.push_str("\\n"),
+            '\r' => repr.push_str("\\r"),
+            '\t' => repr.push_str("\\t"),
+            c if c == quote => {
+                repr.push('\\');
+                repr.push(c);
+            }
+            c if !is_printable(c) => match c as u32 {
+                n @ ..0x100 => repr.push_str(&format!("\\x{n:02x}")),
+                n @ ..0x10000 => repr.push_str(&format!("\\u{n:04x}")),
+                n => repr.push_str(&format!("\\U{n:08x}")),
+            },
+            c => repr.push(c),
+        }
+    }
+    repr.push(quote);
+    repr
+}
+
+/// Approximates python's `str.isprintable` for a single character
+fn is_printable(c: char) -> bool {
+    !c.is_control()
+        && !matches!(
+            c,
+            '\u{a0}'
+                | '\u{ad}'
+                | '\u{1680}'
+                | '\u{2000}'..='\u{200f}'
+                | '\u{2028}'..='\u{202f}'
+                | '\u{205f}'..='\u{2064}'
+                | '\u{3000}'
+                | '\u{feff}'
+        )
+}
+
+/// Formats bytes like python's `repr`
+fn bytes_repr(bytes: &[u8]) -> String {
+    let quote = if bytes.contains(&b'\'') && !bytes.contains(&b'"') {
+        b'"'
+    } else {
+        b'\''
+    };
+    let mut repr = String::with_capacity(bytes.len() + 3);
+    repr.push('b');
+    repr.push(quote as char);
+    for &b in bytes {
+        match b {
+            b'\\' => repr.push_str("\\\\"),
+            b'\n' => repr.push_str("\\n"),
+            b'\r' => repr.push_str("\\r"),
+            b'\t' => repr.push_str("\\t"),
+            b if b == quote => {
+                repr.push('\\');
+                repr.push(b as char);
+            }
+            0x20..0x7f => repr.push(b as char),
+            b => repr.push_str(&format!("\\x{b:02x}")),
+        }
+    }
+    repr.push(quote as char);
+    repr
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dis_str(data: &[u8]) -> Result<String, Error> {
+        let mut out = Vec::new();
+        dis(&mut Reader::new(data), &mut out)?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[test]
+    fn test_dis_protocol_4() -> Result<(), Error> {
+        // pickle.dumps([1, 'a', (1, 2), {'x': b'y'}, 2**70, 0.5, None, True], protocol=4)
+        let data = b"\x80\x04\x952\x00\x00\x00\x00\x00\x00\x00]\x94(K\x01\x8c\x01a\x94K\x01K\x02\x86\x94}\x94\x8c\x01x\x94C\x01y\x94s\x8a\t\x00\x00\x00\x00\x00\x00\x00\x00@G?\xe0\x00\x00\x00\x00\x00\x00N\x88e.";
+        let expected = "    0: \\x80 PROTO      4
+    2: \\x95 FRAME      50
+   11: ]    EMPTY_LIST
+   12: \\x94 MEMOIZE    (as 0)
+   13: (    MARK
+   14: K        BININT1    1
+   16: \\x8c     SHORT_BINUNICODE 'a'
+   19: \\x94     MEMOIZE    (as 1)
+   20: K        BININT1    1
+   22: K        BININT1    2
+   24: \\x86     TUPLE2
+   25: \\x94     MEMOIZE    (as 2)
+   26: }        EMPTY_DICT
+   27: \\x94     MEMOIZE    (as 3)
+   28: \\x8c     SHORT_BINUNICODE 'x'
+   31: \\x94     MEMOIZE    (as 4)
+   32: C        SHORT_BINBYTES b'y'
+   35: \\x94     MEMOIZE    (as 5)
+   36: s        SETITEM
+   37: \\x8a     LONG1      1180591620717411303424
+   48: G        BINFLOAT   0.5
+   57: N        NONE
+   58: \\x88     NEWTRUE
+   59: e        APPENDS    (MARK at 13)
+   60: .    STOP
+highest protocol among opcodes = 4
+";
+        assert_eq!(dis_str(data)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dis_protocol_0() -> Result<(), Error> {
+        // pickle.dumps([1, "it's", collections.OrderedDict], protocol=0)
+        let data = b"(lp0\nI1\naVit's\np1\naccollections\nOrderedDict\np2\na.";
+        let expected = "    0: (    MARK
+    1: l        LIST       (MARK at 0)
+    2: p    PUT        0
+    5: I    INT        1
+    8: a    APPEND
+    9: V    UNICODE    \"it's\"
+   15: p    PUT        1
+   18: a    APPEND
+   19: c    GLOBAL     'collections OrderedDict'
+   44: p    PUT        2
+   47: a    APPEND
+   48: .    STOP
+highest protocol among opcodes = 0
+";
+        assert_eq!(dis_str(data)?, expected);
+
+        // escapes are decoded
+        let data = b"(S'it\\'s\\x41\\n'\nVcaf\xe9\\u20ac\\u005c\nt.";
+        let expected = "    0: (    MARK
+    1: S        STRING     \"it'sA\\n\"
+   16: V        UNICODE    'café€\\\\'
+   34: t        TUPLE      (MARK at 0)
+   35: .    STOP
+highest protocol among opcodes = 0
+";
+        assert_eq!(dis_str(data)?, expected);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dis_errors() {
+        // memo key never stored, the line is still written
+        let mut out = Vec::new();
+        let err = dis(&mut Reader::new(&b"\x80\x02h\x01."[..]), &mut out).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Memo(1)));
+        assert!(out.ends_with(b"    2: h    BINGET     1\n"));
+
+        // no STOP
+        assert!(dis_str(b"\x80\x02N").is_err());
+    }
+}
@@ -1,27 +1,301 @@
-// #[derive(Debug)]
-pub enum Error {
+//! A module for the errors raised while reading or writing pickles
+
+use std::fmt;
+
+use crate::opcodes;
+
+/// The kind of an [`Error`]
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ErrorKind {
     Io(std::io::Error),
+    /// Unsupported protocol version
     Protocol(u8),
-    /// Unsupported opcode
+    /// Unsupported opcode, or opcode not allowed in the current protocol
     OpCode(u8),
     Str(std::str::Utf8Error),
     Float(std::num::ParseFloatError),
+    /// Tried to pop from an empty stack
+    StackUnderflow,
+    /// Tried to pop a mark while there is none
+    MissingMark,
+    /// Memo key referenced before being stored
+    Memo(u32),
+    /// An opcode argument or a stack object is not of the expected kind
+    Unexpected {
+        expected: &'static str,
+        found: String,
+    },
+    /// Unexpected object for the current opcode
+    Invalid(&'static str),
+    /// Global rejected by the [`SafetyPolicy`](crate::SafetyPolicy)
+    Forbidden {
+        module: String,
+        name: String,
+    },
+    /// A payload exceeds [`Limits::max_payload`](crate::reader::Limits::max_payload)
+    PayloadTooLarge {
+        len: usize,
+        max: usize,
+    },
+    /// The input exceeds [`Limits::max_total`](crate::reader::Limits::max_total)
+    InputTooLarge {
+        max: usize,
+    },
+    /// A FRAME exceeds [`Limits::max_frame`](crate::reader::Limits::max_frame)
+    FrameTooLarge {
+        len: u64,
+        max: usize,
+    },
+    /// The memo exceeds [`Limits::max_memo`](crate::reader::Limits::max_memo)
+    MemoTooLarge {
+        max: usize,
+    },
+    /// The stack exceeds [`Limits::max_stack`](crate::reader::Limits::max_stack)
+    StackTooDeep {
+        max: usize,
+    },
+    /// A container exceeds [`Limits::max_container`](crate::reader::Limits::max_container)
+    ContainerTooLarge {
+        max: usize,
+    },
+    /// Shared objects exceed [`Limits::max_copies`](crate::reader::Limits::max_copies) once
+    /// copied at each of their references
+    TooManyCopies {
+        max: usize,
+    },
+    /// Nested values exceed [`Limits::max_depth`](crate::reader::Limits::max_depth)
+    NestingTooDeep {
+        max: usize,
+    },
+    /// Bytes left after the STOP opcode of a pickle expected to span the whole input
+    TrailingBytes,
+    /// Error raised by a serde implementation
+    Custom(String),
+    /// Invalid zip archive, e.g. a PyTorch checkpoint
+    #[cfg(feature = "torch")]
+    Zip(zip::result::ZipError),
+}
+
+impl fmt::Display for ErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ErrorKind::Io(error) => error.fmt(f),
+            ErrorKind::Protocol(p) => write!(f, "Unsupported protocol: {p}"),
+            ErrorKind::OpCode(op) => match opcodes::name(*op) {
+                Some(name) => write!(f, "Unsupported opcode: {name}"),
+                None => write!(f, "Unknown opcode: 0x{op:02x}"),
+            },
+            ErrorKind::Str(error) => error.fmt(f),
+            ErrorKind::Float(error) => error.fmt(f),
+            ErrorKind::StackUnderflow => write!(f, "Stack underflow"),
+            ErrorKind::MissingMark => write!(f, "Could not find mark"),
+            ErrorKind::Memo(id) => write!(f, "Memo value not found at index {id}"),
+            ErrorKind::Unexpected { expected, found } => {
+                write!(f, "Expected {expected}, found {found}")
+            }
+            ErrorKind::Invalid(msg) => write!(f, "Invalid object: {msg}"),
+            ErrorKind::Forbidden { module, name } => {
+                write!(f, "Global {module}.{name} is not allowed")
+            }
+            ErrorKind::PayloadTooLarge { len, max } => {
+                write!(f, "Payload of {len} bytes exceeds the limit of {max} bytes")
+            }
+            ErrorKind::InputTooLarge { max } => write!(f, "Input exceeds the limit of {max} bytes"),
+            ErrorKind::FrameTooLarge { len, max } => {
+                write!(f, "Frame of {len} bytes exceeds the limit of {max} bytes")
+            }
+            ErrorKind::MemoTooLarge { max } => write!(f, "Memo exceeds the limit of {max} entries"),
+            ErrorKind::StackTooDeep { max } => {
+                write!(f, "Stack exceeds the limit of {max} objects")
+            }
+            ErrorKind::ContainerTooLarge { max } => {
+                write!(f, "Container exceeds the limit of {max} items")
+            }
+            ErrorKind::TooManyCopies { max } => {
+                write!(f, "Shared objects exceed the limit of {max} copied values")
+            }
+            ErrorKind::NestingTooDeep { max } => {
+                write!(f, "Nesting exceeds the limit of {max} levels")
+            }
+            ErrorKind::TrailingBytes => write!(f, "Trailing bytes after STOP"),
+            ErrorKind::Custom(msg) => f.write_str(msg),
+            #[cfg(feature = "torch")]
+            ErrorKind::Zip(error) => error.fmt(f),
+        }
+    }
+}
+
+/// An error with the position at which it occurred
+///
+/// Besides its [`ErrorKind`], an error knows the byte offset and the opcode being
+/// processed when it was raised (if any), and a chain of context messages added while
+/// it bubbled up, from the innermost to the outermost.
+pub struct Error(Box<Inner>);
+
+struct Inner {
+    kind: ErrorKind,
+    offset: Option<usize>,
+    opcode: Option<u8>,
+    context: Vec<String>,
+}
+
+impl Error {
+    pub fn kind(&self) -> &ErrorKind {
+        &self.0.kind
+    }
+
+    pub fn into_kind(self) -> ErrorKind {
+        self.0.kind
+    }
+
+    /// Gets the offset of the opcode being processed
+    pub fn offset(&self) -> Option<usize> {
+        self.0.offset
+    }
+
+    /// Gets the opcode being processed
+    pub fn opcode(&self) -> Option<u8> {
+        self.0.opcode
+    }
+
+    /// Gets the name of the opcode being processed, e.g. `"BINUNICODE"`
+    pub fn opcode_name(&self) -> Option<&'static str> {
+        self.0.opcode.and_then(opcodes::name)
+    }
+
+    /// Gets the context messages, from the innermost to the outermost
+    pub fn context(&self) -> &[String] {
+        &self.0.context
+    }
+
+    /// Adds a context message, e.g. "while decoding dict value for key 'weights'"
+    pub fn with_context<C: Into<String>>(mut self, context: C) -> Self {
+        self.0.context.push(context.into());
+        self
+    }
+
+    /// Sets the position of the error unless it is already known
+    pub(crate) fn at(mut self, offset: usize, opcode: u8) -> Self {
+        if self.0.offset.is_none() {
+            self.0.offset = Some(offset);
+            self.0.opcode = Some(opcode);
+        }
+        self
+    }
+
+    /// Moves the position of the error, for errors raised over a part of the input
+    pub(crate) fn shifted(mut self, by: usize) -> Self {
+        if let Some(offset) = &mut self.0.offset {
+            *offset += by;
+        }
+        self
+    }
+
+    pub(crate) fn unexpected<T: fmt::Display>(expected: &'static str, found: T) -> Self {
+        ErrorKind::Unexpected {
+            expected,
+            found: found.to_string(),
+        }
+        .into()
+    }
+}
+
+impl From<ErrorKind> for Error {
+    fn from(kind: ErrorKind) -> Self {
+        Error(Box::new(Inner {
+            kind,
+            offset: None,
+            opcode: None,
+            context: Vec::new(),
+        }))
+    }
 }
 
 impl From<std::io::Error> for Error {
     fn from(v: std::io::Error) -> Self {
-        Self::Io(v)
+        ErrorKind::Io(v).into()
     }
 }
 
-impl std::fmt::Debug for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Error::Io(error) => error.fmt(f),
-            Error::Protocol(p) => write!(f, "Unsupported protocol: 0x{p:x?}"),
-            Error::OpCode(op) => write!(f, "Unsupported opcode: 0x{op:x}"),
-            Error::Str(error) => error.fmt(f),
-            Error::Float(error) => error.fmt(f),
+impl From<std::str::Utf8Error> for Error {
+    fn from(v: std::str::Utf8Error) -> Self {
+        ErrorKind::Str(v).into()
+    }
+}
+
+impl From<std::num::ParseFloatError> for Error {
+    fn from(v: std::num::ParseFloatError) -> Self {
+        ErrorKind::Float(v).into()
+    }
+}
+
+#[cfg(feature = "torch")]
+impl From<zip::result::ZipError> for Error {
+    fn from(v: zip::result::ZipError) -> Self {
+        ErrorKind::Zip(v).into()
+    }
+}
+
+impl fmt::Debug for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.kind.fmt(f)?;
+        match (self.0.offset, self.opcode_name(), self.0.opcode) {
+            (Some(offset), Some(name), _) => write!(f, " at offset {offset} ({name})")?,
+            (Some(offset), None, Some(op)) => write!(f, " at offset {offset} (0x{op:02x})")?,
+            (Some(offset), ..) => write!(f, " at offset {offset}")?,
+            (None, ..) => (),
+        }
+        for context in &self.0.context {
+            write!(f, ", {context}")?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.0.kind {
+            ErrorKind::Io(error) => Some(error),
+            ErrorKind::Str(error) => Some(error),
+            ErrorKind::Float(error) => Some(error),
+            #[cfg(feature = "torch")]
+            ErrorKind::Zip(error) => Some(error),
+            _ => None,
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_display() {
+        let err = Error::from(ErrorKind::Memo(3))
+            .at(42, 0x68)
+            .with_context("while decoding item 1")
+            .with_context("while decoding dict value for key 'weights'");
+        assert_eq!(
+            err.to_string(),
+            "Memo value not found at index 3 at offset 42 (BINGET), while decoding item 1, \
+             while decoding dict value for key 'weights'"
+        );
+        assert_eq!(err.opcode_name(), Some("BINGET"));
+
+        // the innermost position is kept
+        let err = err.at(0, 0x80);
+        assert_eq!(err.offset(), Some(42));
+    }
+
+    #[test]
+    fn test_size() {
+        assert_eq!(std::mem::size_of::<Error>(), std::mem::size_of::<usize>());
+    }
+}
@@ -0,0 +1,1808 @@
+//! A module to convert pickles to JSON, and JSON to pickle values
+//!
+//! The pickle machine is emulated on the [`Event`]s of a [`Reader`], but objects are rendered
+//! as JSON text as soon as they are complete instead of being rebuilt as [`Value`]s: a list
+//! only holds the text of its items, so huge containers stay compact. Complete items are
+//! rendered in place in their container, only memoized objects keep a copy of their text for
+//! later references, which is copied at each of them up to [`Limits::max_copies`] values.
+//!
+//! The pickle is not streamed in the order of the JSON text though: until the STOP, the
+//! outermost object may still become an item of another one (e.g. a list followed by TUPLE2),
+//! so its text is held in memory and only written at the end.
+//!
+//! Python objects without a JSON equivalent are mapped according to the [`Options`]. Objects
+//! built by calling python code are tagged with the opcode which built them:
+//!
+//! - `{"__global__": "module.name"}` for a class or a function
+//! - `{"__reduce__": [callable, args]}` for `callable(*args)`
+//! - `{"__newobj__": [class, args]}` or `{"__newobj__": [class, args, kwargs]}` for
+//!   `class.__new__(class, *args, **kwargs)`
+//! - `{"__build__": [object, state]}` for `object.__setstate__(state)`
+//! - `{"__extend__": [object, items]}` for an object filled with `append` or `__setitem__`,
+//!   e.g. a `collections.OrderedDict`
+//!
+//! [`from_str`] parses JSON using the same tags back into a [`Value`].
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt::Write as _,
+    io::{BufRead, Write},
+    mem,
+    str::from_utf8,
+};
+
+use crate::{
+    bigint::BigInt,
+    errors::{Error, ErrorKind},
+    reader::{Event, Limits, Reader},
+    unpickler::{Encoding, decode_py2_str, decode_str},
+    value::Value,
+};
+
+/// Representation of `bytes` and `bytearray`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BytesRepr {
+    /// A base64 string, with padding
+    #[default]
+    Base64,
+    /// A lowercase hexadecimal string
+    Hex,
+    /// An array of integers
+    Array,
+}
+
+/// Representation of tuples, sets and frozensets
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SeqRepr {
+    /// An array
+    #[default]
+    Array,
+    /// An array tagged with the python type, e.g. `{"__tuple__": [1, 2]}`
+    Tagged,
+}
+
+/// Representation of dicts having keys which are not strings
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum KeyRepr {
+    /// Keys are replaced by their JSON text, e.g. `1` becomes `"1"`
+    #[default]
+    Stringify,
+    /// All dicts are arrays of `[key, value]` pairs
+    Pairs,
+    /// Non-string keys are an error
+    Error,
+}
+
+/// Representation of NaN and infinite floats, which JSON lacks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum FloatRepr {
+    /// `null`
+    #[default]
+    Null,
+    /// `"NaN"`, `"Infinity"` or `"-Infinity"`
+    String,
+    /// Non-finite floats are an error
+    Error,
+}
+
+/// Representation of the objects built by calling python code
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ObjectRepr {
+    /// An object tagged with the opcode which built it, e.g. `{"__reduce__": [callable, args]}`
+    #[default]
+    Tagged,
+    /// `null`
+    Null,
+    /// Globals are an error
+    Error,
+}
+
+/// Options of the JSON conversion
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Options {
+    pub bytes: BytesRepr,
+    pub tuples: SeqRepr,
+    pub sets: SeqRepr,
+    pub keys: KeyRepr,
+    pub floats: FloatRepr,
+    pub objects: ObjectRepr,
+    /// Decoding of python 2 str, see [`Unpickler::with_encoding`](crate::Unpickler::with_encoding)
+    pub encoding: Encoding,
+}
+
+/// Converts the pickle read by `reader` up to the first STOP, and writes it as JSON
+///
+/// The items of the outermost object are written at the end, without being first copied into
+/// a single string.
+pub fn to_writer<R: BufRead, W: Write>(
+    reader: &mut Reader<R>,
+    out: &mut W,
+    options: &Options,
+) -> Result<(), Error> {
+    let mut converter = Converter {
+        options,
+        limits: *reader.limits(),
+        buf: Vec::new(),
+        stack: Vec::new(),
+        metastack: Vec::new(),
+        memo: HashMap::new(),
+        dups: Vec::new(),
+        copies: 0,
+    };
+    loop {
+        converter.buf.clear();
+        let start = reader.position();
+        let event = reader.read_event(&mut converter.buf)?;
+        if let Event::Stop = event {
+            break;
+        }
+        let opcode = reader.opcode;
+        converter.process(event).map_err(|e| e.at(start, opcode))?;
+    }
+    // the outermost object is not closed, it is written without being rendered first
+    let root = converter.stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+    if !converter.stack.is_empty() || !converter.metastack.is_empty() {
+        return Err(ErrorKind::Invalid("stack not empty after STOP").into());
+    }
+    // the memo is not needed anymore
+    match root {
+        Node::Open(open) => write_items(&open, options, out)?,
+        node => out.write_all(converter.json(node, &mut Size::default())?.as_bytes())?,
+    }
+    Ok(())
+}
+
+/// Converts the pickle read by `reader` up to the first STOP into a JSON string
+pub fn to_string<R: BufRead>(reader: &mut Reader<R>, options: &Options) -> Result<String, Error> {
+    let mut json = Vec::new();
+    to_writer(reader, &mut json, options)?;
+    Ok(String::from_utf8(json).expect("JSON text is UTF-8"))
+}
+
+/// An object on the emulated stack
+#[derive(Debug, Clone)]
+enum Node {
+    /// A python str, kept as is as it may be a dict key or a global name
+    Str(String),
+    /// A complete object, rendered as JSON
+    Json(String, Size),
+    /// An object which may still be modified
+    Open(Box<Open>),
+    /// Another reference to an object which may still be modified, pushed by GET or DUP
+    ///
+    /// It is rendered once used, so that it sees the items added to the object until then.
+    Ref(Key),
+}
+
+/// The number of values of a rendered object, counted when it is copied, and its nesting depth
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+struct Size {
+    values: usize,
+    depth: usize,
+}
+
+impl Size {
+    /// The size of a single value, e.g. a number or a string
+    const VALUE: Size = Size {
+        values: 1,
+        depth: 0,
+    };
+
+    /// Adds the size of an item
+    fn add(&mut self, item: Size) {
+        self.values = self.values.saturating_add(item.values);
+        self.depth = self.depth.max(item.depth);
+    }
+
+    /// Gets the size of a container of items of this total size
+    fn nest(self) -> Size {
+        Size {
+            values: self.values.saturating_add(1),
+            depth: self.depth + 1,
+        }
+    }
+}
+
+impl Node {
+    /// Gets the size of a complete object
+    fn size(&self) -> Size {
+        match self {
+            Node::Str(_) => Size::VALUE,
+            Node::Json(_, size) => *size,
+            Node::Open(open) => open.size(),
+            Node::Ref(_) => unreachable!("references are rendered before being copied"),
+        }
+    }
+}
+
+/// A memo id, or an object duplicated by DUP
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Memo(u32),
+    Dup(usize),
+}
+
+/// A container or an object built by python code, which items or state may be added to
+#[derive(Debug, Clone)]
+struct Open {
+    /// The rendered object, `None` for builtin containers
+    object: Option<String>,
+    object_size: Size,
+    /// The kind of the items, `None` if nothing has been added to the object
+    kind: Option<Kind>,
+    /// The rendered items, separated by commas
+    body: String,
+    len: usize,
+    /// The total size of the items
+    items: Size,
+    /// Memo ids (and duplicates) to fill once the object is complete
+    memo: Vec<Key>,
+    /// Whether the object has been rendered for a memo reference before being complete
+    referenced: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    List,
+    Dict,
+    Set,
+}
+
+impl Open {
+    fn new(kind: Kind) -> Self {
+        Open {
+            object: None,
+            object_size: Size::default(),
+            kind: Some(kind),
+            body: String::new(),
+            len: 0,
+            items: Size::default(),
+            memo: Vec::new(),
+            referenced: false,
+        }
+    }
+
+    fn object(object: String, size: Size) -> Self {
+        Open {
+            object: Some(object),
+            object_size: size,
+            kind: None,
+            body: String::new(),
+            len: 0,
+            items: Size::default(),
+            memo: Vec::new(),
+            referenced: false,
+        }
+    }
+
+    /// Gets the size of the object with the items added to it
+    fn size(&self) -> Size {
+        match (&self.object, self.kind) {
+            (None, _) => self.items.nest(),
+            (Some(_), None) => self.object_size,
+            (Some(_), Some(_)) => {
+                let mut size = self.object_size;
+                size.add(self.items.nest());
+                size.nest()
+            }
+        }
+    }
+
+    /// Checks that the object can still be modified
+    fn modify(&mut self) -> Result<&mut Self, Error> {
+        if self.referenced {
+            return Err(ErrorKind::Invalid("recursive objects are not supported").into());
+        }
+        Ok(self)
+    }
+
+    /// Starts a new item in the body
+    fn separate(&mut self) {
+        if self.len > 0 {
+            self.body.push(',');
+        }
+        self.len += 1;
+    }
+}
+
+/// A memo entry
+#[derive(Debug)]
+enum Memo {
+    Done(Node),
+    /// An object which is not complete yet
+    Pending,
+}
+
+struct Converter<'a> {
+    options: &'a Options,
+    limits: Limits,
+    buf: Vec<u8>,
+    stack: Vec<Node>,
+    metastack: Vec<Vec<Node>>,
+    memo: HashMap<u32, Memo>,
+    /// Objects duplicated by DUP, as memo entries
+    dups: Vec<Memo>,
+    /// Number of values copied for memo references
+    copies: usize,
+}
+
+impl Converter<'_> {
+    fn process(&mut self, event: Event) -> Result<(), Error> {
+        let limits = self.limits;
+        match event {
+            Event::Proto(_) | Event::Frame(_) | Event::Stop => (),
+
+            // Stack manipulation
+            Event::Mark => {
+                let stack = mem::take(&mut self.stack);
+                self.metastack.push(stack);
+            }
+            Event::Pop => match self.stack.pop() {
+                None => {
+                    self.pop_mark()?;
+                }
+                // a reference is dropped without being rendered
+                Some(Node::Ref(_)) => (),
+                Some(node) => {
+                    self.close(node)?;
+                }
+            },
+            Event::PopMark => {
+                self.pop_mark()?;
+            }
+            Event::Dup => {
+                // both are the same object, as with a memo GET
+                let key = Key::Dup(self.dups.len());
+                match self.share_top(key)? {
+                    Memo::Pending => {
+                        self.dups.push(Memo::Pending);
+                        self.stack.push(Node::Ref(key));
+                    }
+                    Memo::Done(node) => {
+                        self.count_copy(node.size())?;
+                        self.stack.push(node);
+                    }
+                }
+            }
+
+            // Basic types
+            Event::None => self.push_json("null".to_string()),
+            Event::Bool(b) => self.push_json(b.to_string()),
+            Event::Int(i) => self.push_json(i.to_string()),
+            Event::BinInt(i) => self.push_json(i.to_string()),
+            Event::BinInt1(i) => self.push_json(i.to_string()),
+            Event::BinInt2(i) => self.push_json(i.to_string()),
+            Event::Long(i) => self.push_json(i.to_string()),
+            Event::BigLong { .. } => {
+                let big = BigInt::from_signed_bytes_le(&self.buf);
+                self.push_json(big.to_string());
+            }
+            Event::Float(f) => {
+                let json = self.float(f)?;
+                self.push_json(json);
+            }
+
+            // Strings and bytes
+            Event::String { .. } | Event::BinString { .. } | Event::ShortBinString { .. } => {
+                match decode_py2_str(&event, &self.buf, self.options.encoding)? {
+                    Value::Bytes(bytes) => {
+                        self.buf = bytes;
+                        let json = self.bytes();
+                        self.push_json(json);
+                    }
+                    Value::Str(s) => self.stack.push(Node::Str(s)),
+                    _ => unreachable!(),
+                }
+            }
+            Event::Unicode { .. }
+            | Event::BinUnicode { .. }
+            | Event::ShortBinUnicode { .. }
+            | Event::BinUnicode8 { .. } => {
+                let s = decode_str(&event, &self.buf)?.into_owned();
+                self.stack.push(Node::Str(s));
+            }
+            Event::BinBytes { .. }
+            | Event::ShortBinBytes { .. }
+            | Event::BinBytes8 { .. }
+            | Event::ByteArray8 { .. } => {
+                let json = self.bytes();
+                self.push_json(json);
+            }
+
+            // Collections
+            Event::EmptyTuple => self.push_tuple(Vec::new())?,
+            Event::Tuple => {
+                let items = self.pop_mark()?;
+                check_container(items.len(), limits.max_container)?;
+                self.push_tuple(items)?;
+            }
+            Event::Tuple1 => {
+                let items = self.pop_n(1)?;
+                self.push_tuple(items)?;
+            }
+            Event::Tuple2 => {
+                let items = self.pop_n(2)?;
+                self.push_tuple(items)?;
+            }
+            Event::Tuple3 => {
+                let items = self.pop_n(3)?;
+                self.push_tuple(items)?;
+            }
+            Event::EmptyList => self.stack.push(Node::Open(Box::new(Open::new(Kind::List)))),
+            Event::List => {
+                let items = self.pop_mark()?;
+                let mut list = Open::new(Kind::List);
+                self.extend(&mut list, items, limits.max_container)?;
+                self.stack.push(Node::Open(Box::new(list)));
+            }
+            Event::Append => {
+                let item = self.pop()?;
+                self.add_items(Kind::List, vec![item], limits.max_container)?;
+            }
+            Event::Appends => {
+                let items = self.pop_mark()?;
+                self.add_items(Kind::List, items, limits.max_container)?;
+            }
+            Event::EmptyDict => self.stack.push(Node::Open(Box::new(Open::new(Kind::Dict)))),
+            Event::Dict => {
+                let items = self.pop_mark()?;
+                let mut dict = Open::new(Kind::Dict);
+                self.extend(&mut dict, items, limits.max_container)?;
+                self.stack.push(Node::Open(Box::new(dict)));
+            }
+            Event::SetItem => {
+                let items = self.pop_n(2)?;
+                self.add_items(Kind::Dict, items, limits.max_container)?;
+            }
+            Event::SetItems => {
+                let items = self.pop_mark()?;
+                self.add_items(Kind::Dict, items, limits.max_container)?;
+            }
+            Event::EmptySet => self.stack.push(Node::Open(Box::new(Open::new(Kind::Set)))),
+            Event::AdditItems => {
+                let items = self.pop_mark()?;
+                self.add_items(Kind::Set, items, limits.max_container)?;
+            }
+            Event::FrozenSet => {
+                let items = self.pop_mark()?;
+                check_container(items.len(), limits.max_container)?;
+                let (items, size) = self.array(items)?;
+                let json = self.tagged("__frozenset__", self.options.sets, &items);
+                self.stack.push(Node::Json(json, size));
+            }
+
+            // Memo operations
+            Event::Get(id) => self.get(id as u32)?,
+            Event::BinGet(id) => self.get(id as u32)?,
+            Event::LongBinGet(id) => self.get(id)?,
+            Event::Put(id) => self.put(id as u32, limits.max_memo)?,
+            Event::BinPut(id) => self.put(id as u32, limits.max_memo)?,
+            Event::LongBinPut(id) => self.put(id, limits.max_memo)?,
+            Event::Memoize => self.put(self.memo.len() as u32, limits.max_memo)?,
+
+            // Object construction
+            Event::Global { module_len, .. } => {
+                let module = from_utf8(&self.buf[..module_len as usize])?;
+                let name = from_utf8(&self.buf[module_len as usize..])?;
+                let json = self.global(module, name)?;
+                self.push_json(json);
+            }
+            Event::StackGlobal => {
+                let name = self.pop()?;
+                let module = self.pop()?;
+                let (Node::Str(module), Node::Str(name)) = (&module, &name) else {
+                    return Err(Error::unexpected("str module and name", "other objects"));
+                };
+                let json = self.global(module, name)?;
+                self.push_json(json);
+            }
+            Event::Reduce => {
+                let items = self.pop_n(2)?;
+                let (json, size) = self.object("__reduce__", items)?;
+                self.stack
+                    .push(Node::Open(Box::new(Open::object(json, size))));
+            }
+            Event::Build => {
+                let state = self.pop()?;
+                let mut size = Size::default();
+                let state = self.json(state, &mut size)?;
+                let options = self.options;
+                let open = self.top_open()?;
+                let object = render_items(open, options)?;
+                size.add(open.size());
+                let json = self.tagged_object("__build__", &[object, state]);
+                let open = self.top_open()?;
+                open.object = Some(json);
+                open.object_size = size.nest();
+                open.kind = None;
+                open.body.clear();
+                open.len = 0;
+                open.items = Size::default();
+                let size = open.size();
+                self.check_depth(size)?;
+            }
+            Event::Inst { module_len, .. } => {
+                let module = from_utf8(&self.buf[..module_len as usize])?;
+                let name = from_utf8(&self.buf[module_len as usize..])?;
+                let class = self.global(module, name)?;
+                let args = self.pop_mark()?;
+                let (args, mut size) = self.tuple(args)?;
+                size.add(Size::VALUE);
+                let json = self.tagged_object("__newobj__", &[class, args]);
+                self.stack
+                    .push(Node::Open(Box::new(Open::object(json, size.nest()))));
+            }
+            Event::Obj => {
+                let mut args = self.pop_mark()?;
+                if args.is_empty() {
+                    return Err(ErrorKind::StackUnderflow.into());
+                }
+                let class = args.remove(0);
+                let (args, mut size) = self.tuple(args)?;
+                let class = self.json(class, &mut size)?;
+                let json = self.tagged_object("__newobj__", &[class, args]);
+                self.stack
+                    .push(Node::Open(Box::new(Open::object(json, size.nest()))));
+            }
+            Event::NewObj => {
+                let items = self.pop_n(2)?;
+                let (json, size) = self.object("__newobj__", items)?;
+                self.stack
+                    .push(Node::Open(Box::new(Open::object(json, size))));
+            }
+            Event::NewObjEx => {
+                let items = self.pop_n(3)?;
+                let (json, size) = self.object("__newobj__", items)?;
+                self.stack
+                    .push(Node::Open(Box::new(Open::object(json, size))));
+            }
+
+            // Persistent objects
+            Event::PersId { .. } => return Err(ErrorKind::OpCode(0x50).into()),
+            Event::BinPersId => return Err(ErrorKind::OpCode(0x51).into()),
+
+            // Extensions
+            Event::Ext1(_) => return Err(ErrorKind::OpCode(0x82).into()),
+            Event::Ext2(_) => return Err(ErrorKind::OpCode(0x83).into()),
+            Event::Ext4(_) => return Err(ErrorKind::OpCode(0x84).into()),
+
+            // Protocol 5
+            Event::NextBuffer => return Err(ErrorKind::OpCode(0x97).into()),
+            Event::ReadonlyBuffer => return Err(ErrorKind::OpCode(0x98).into()),
+        }
+        if self.stack.len() > limits.max_stack || self.metastack.len() > limits.max_stack {
+            let max = limits.max_stack;
+            return Err(ErrorKind::StackTooDeep { max }.into());
+        }
+        match self.stack.last() {
+            Some(Node::Ref(_)) | None => Ok(()),
+            Some(node) => self.check_depth(node.size()),
+        }
+    }
+
+    /// Pushes a single value, e.g. a number
+    fn push_json(&mut self, json: String) {
+        self.stack.push(Node::Json(json, Size::VALUE));
+    }
+
+    /// Pops the top of the stack, completing it if it is still open
+    fn pop(&mut self) -> Result<Node, Error> {
+        let node = self.stack.pop().ok_or(ErrorKind::StackUnderflow)?;
+        self.close(node)
+    }
+
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Node>, Error> {
+        let start = self
+            .stack
+            .len()
+            .checked_sub(n)
+            .ok_or(ErrorKind::StackUnderflow)?;
+        Ok(self.stack.split_off(start))
+    }
+
+    /// Pops all the objects pushed since the last mark
+    fn pop_mark(&mut self) -> Result<Vec<Node>, Error> {
+        let stack = self.metastack.pop().ok_or(ErrorKind::MissingMark)?;
+        Ok(mem::replace(&mut self.stack, stack))
+    }
+
+    /// Gets the position of the top of the stack, or of the object still being built which
+    /// it references, as a metastack level and an index
+    fn top_slot(&mut self) -> Result<(usize, usize), Error> {
+        let top = self.stack.last().ok_or(ErrorKind::StackUnderflow)?;
+        if let Node::Ref(key) = *top {
+            match self.copy(key)? {
+                None => return self.locate(key),
+                // a complete object is modified as a copy
+                Some(node) => *self.stack.last_mut().unwrap() = node,
+            }
+        }
+        Ok((self.metastack.len(), self.stack.len() - 1))
+    }
+
+    fn slot(&mut self, (level, index): (usize, usize)) -> &mut Node {
+        match self.metastack.get_mut(level) {
+            Some(stack) => &mut stack[index],
+            None => &mut self.stack[index],
+        }
+    }
+
+    /// Finds the object still being built which a key references
+    fn locate(&self, key: Key) -> Result<(usize, usize), Error> {
+        self.metastack
+            .iter()
+            .chain([&self.stack])
+            .enumerate()
+            .find_map(|(level, stack)| {
+                let index = stack.iter().position(
+                    |node| matches!(node, Node::Open(open) if open.memo.contains(&key)),
+                )?;
+                Some((level, index))
+            })
+            .ok_or_else(|| match key {
+                Key::Memo(id) => ErrorKind::Memo(id).into(),
+                Key::Dup(_) => ErrorKind::Invalid("duplicated object was discarded").into(),
+            })
+    }
+
+    fn entry(&self, key: Key) -> Result<&Memo, Error> {
+        match key {
+            Key::Memo(id) => self.memo.get(&id).ok_or(ErrorKind::Memo(id).into()),
+            Key::Dup(index) => Ok(&self.dups[index]),
+        }
+    }
+
+    /// Copies a complete object for another reference to it, `None` if it is still being built
+    fn copy(&mut self, key: Key) -> Result<Option<Node>, Error> {
+        let size = match self.entry(key)? {
+            Memo::Done(node) => node.size(),
+            Memo::Pending => return Ok(None),
+        };
+        // counted before being copied, as the copies of nested references grow exponentially
+        self.count_copy(size)?;
+        let Memo::Done(node) = self.entry(key)? else {
+            unreachable!("the entry is complete");
+        };
+        Ok(Some(node.clone()))
+    }
+
+    fn check_depth(&self, size: Size) -> Result<(), Error> {
+        let max = self.limits.max_depth;
+        if size.depth > max {
+            return Err(ErrorKind::NestingTooDeep { max }.into());
+        }
+        Ok(())
+    }
+
+    fn count_copy(&mut self, size: Size) -> Result<(), Error> {
+        let max = self.limits.max_copies;
+        self.copies = self.copies.saturating_add(size.values);
+        if self.copies > max {
+            return Err(ErrorKind::TooManyCopies { max }.into());
+        }
+        Ok(())
+    }
+
+    /// Gets the top of the stack as an object which can be modified
+    fn top_open(&mut self) -> Result<&mut Open, Error> {
+        let slot = self.top_slot()?;
+        let top = self.slot(slot);
+        if let Node::Json(json, size) = top {
+            // e.g. a global whose state is set
+            let object = mem::take(json);
+            *top = Node::Open(Box::new(Open::object(object, *size)));
+        }
+        match top {
+            Node::Open(open) => open.modify(),
+            _ => Err(Error::unexpected("an object", "str")),
+        }
+    }
+
+    /// Adds items (or key-value pairs) to the object on top of the stack
+    fn add_items(&mut self, kind: Kind, items: Vec<Node>, max: usize) -> Result<(), Error> {
+        let slot = self.top_slot()?;
+        let Node::Open(open) = self.slot(slot) else {
+            return Err(Error::unexpected(kind.expected(), "a complete object"));
+        };
+        let open = open.modify()?;
+        // the object is taken out while the items are rendered, its memo ids are left for the
+        // items referencing it
+        let placeholder = Open {
+            memo: open.memo.clone(),
+            ..Open::new(kind)
+        };
+        let mut open = mem::replace(open, placeholder);
+        let res = match (open.kind, &open.object) {
+            (Some(k), _) if k == kind => Ok(()),
+            (None, Some(_)) => {
+                open.kind = Some(kind);
+                Ok(())
+            }
+            (Some(_), Some(_)) => {
+                // e.g. items set on an object whose state was set
+                let object = render_items(&open, self.options)?;
+                open = Open {
+                    memo: mem::take(&mut open.memo),
+                    ..Open::object(object, open.size())
+                };
+                open.kind = Some(kind);
+                Ok(())
+            }
+            (Some(k), None) => Err(Error::unexpected(kind.expected(), k.name())),
+            (None, None) => unreachable!("builtin containers have a kind"),
+        };
+        let res = res
+            .and_then(|()| self.extend(&mut open, items, max))
+            .and_then(|()| self.check_depth(open.size()));
+        let Node::Open(slot) = self.slot(slot) else {
+            unreachable!("the placeholder stays in place");
+        };
+        open.referenced |= slot.referenced;
+        **slot = open;
+        res?;
+        slot.modify().map(|_| ())
+    }
+
+    /// Renders items (or key-value pairs) in the body of an object
+    fn extend(&mut self, open: &mut Open, items: Vec<Node>, max: usize) -> Result<(), Error> {
+        if open.kind == Some(Kind::Dict) {
+            if !items.len().is_multiple_of(2) {
+                return Err(ErrorKind::Invalid("odd number of items for dict").into());
+            }
+            let mut items = items.into_iter();
+            while let (Some(key), Some(value)) = (items.next(), items.next()) {
+                let key = self.key(key, &mut open.items)?;
+                open.separate();
+                if self.options.keys == KeyRepr::Pairs {
+                    write!(open.body, "[{key},").unwrap();
+                    self.write_json(value, &mut open.body, &mut open.items)?;
+                    open.body.push(']');
+                } else {
+                    write!(open.body, "{key}:").unwrap();
+                    self.write_json(value, &mut open.body, &mut open.items)?;
+                }
+            }
+        } else {
+            for item in items {
+                open.separate();
+                self.write_json(item, &mut open.body, &mut open.items)?;
+            }
+        }
+        check_container(open.len, max)
+    }
+
+    fn get(&mut self, id: u32) -> Result<(), Error> {
+        // an object still being built is rendered once used, e.g. a list referenced by one of
+        // its items
+        let key = Key::Memo(id);
+        let node = self.copy(key)?.unwrap_or(Node::Ref(key));
+        self.stack.push(node);
+        Ok(())
+    }
+
+    /// Stores the top of the stack in the memo, once it is complete
+    fn put(&mut self, id: u32, max: usize) -> Result<(), Error> {
+        if self.memo.len() >= max && !self.memo.contains_key(&id) {
+            return Err(ErrorKind::MemoTooLarge { max }.into());
+        }
+        let memo = self.share_top(Key::Memo(id))?;
+        self.memo.insert(id, memo);
+        Ok(())
+    }
+
+    /// Makes a key reference the object on top of the stack
+    ///
+    /// The entry is pending if the object may still be modified, it is then filled once the
+    /// object is complete.
+    fn share_top(&mut self, key: Key) -> Result<Memo, Error> {
+        let slot = match self.stack.last().ok_or(ErrorKind::StackUnderflow)? {
+            Node::Open(_) => (self.metastack.len(), self.stack.len() - 1),
+            Node::Ref(target) => match self.entry(*target)? {
+                Memo::Pending => self.locate(*target)?,
+                Memo::Done(node) => return Ok(Memo::Done(node.clone())),
+            },
+            node => return Ok(Memo::Done(node.clone())),
+        };
+        let Node::Open(open) = self.slot(slot) else {
+            unreachable!("references are located on open objects");
+        };
+        open.memo.push(key);
+        Ok(Memo::Pending)
+    }
+
+    /// Completes an open object and fills the memo ids referencing it, or renders a reference
+    fn close(&mut self, node: Node) -> Result<Node, Error> {
+        let mut open = match node {
+            Node::Open(open) => open,
+            Node::Ref(key) => return self.deref(key),
+            node => return Ok(node),
+        };
+        let keys = mem::take(&mut open.memo);
+        let node = self.render(*open)?;
+        for key in keys {
+            let memo = Memo::Done(node.clone());
+            match key {
+                Key::Memo(id) => {
+                    self.memo.insert(id, memo);
+                }
+                Key::Dup(index) => self.dups[index] = memo,
+            }
+        }
+        Ok(node)
+    }
+
+    /// Renders a reference to an object
+    ///
+    /// An object still being built is rendered as it is, and cannot be modified anymore.
+    fn deref(&mut self, key: Key) -> Result<Node, Error> {
+        if let Some(node) = self.copy(key)? {
+            return Ok(node);
+        }
+        let slot = self.locate(key)?;
+        let options = self.options;
+        let Node::Open(open) = self.slot(slot) else {
+            unreachable!("references are located on open objects");
+        };
+        open.referenced = true;
+        let size = open.size();
+        let json = render_items(open, options)?;
+        self.count_copy(size)?;
+        Ok(Node::Json(json, size))
+    }
+
+    fn render(&self, open: Open) -> Result<Node, Error> {
+        let json = render_items(&open, self.options)?;
+        Ok(Node::Json(json, open.size()))
+    }
+
+    /// Renders a node as JSON, adding its size to the `total` of its container
+    fn json(&mut self, node: Node, total: &mut Size) -> Result<String, Error> {
+        let node = self.close(node)?;
+        total.add(node.size());
+        match node {
+            Node::Str(s) => Ok(str_json(&s)),
+            Node::Json(json, _) => Ok(json),
+            Node::Open(_) | Node::Ref(_) => unreachable!("closed nodes are rendered"),
+        }
+    }
+
+    /// Renders a node as JSON at the end of `body`
+    ///
+    /// Objects which are not memoized are rendered in place rather than copied.
+    fn write_json(&mut self, node: Node, body: &mut String, total: &mut Size) -> Result<(), Error> {
+        match node {
+            Node::Open(open) if open.memo.is_empty() => {
+                total.add(open.size());
+                let (start, end) = rendering(&open, self.options)?;
+                body.push_str(&start);
+                if let Some(end) = end {
+                    body.push_str(&open.body);
+                    body.push_str(&end);
+                }
+            }
+            node => body.push_str(&self.json(node, total)?),
+        }
+        Ok(())
+    }
+
+    /// Renders a node as a JSON object key
+    fn key(&mut self, node: Node, total: &mut Size) -> Result<String, Error> {
+        let node = self.close(node)?;
+        total.add(node.size());
+        match (node, self.options.keys) {
+            (Node::Str(s), _) => Ok(str_json(&s)),
+            (Node::Json(json, _), KeyRepr::Pairs) => Ok(json),
+            (Node::Json(json, _), KeyRepr::Stringify) => Ok(str_json(&json)),
+            (Node::Json(json, _), KeyRepr::Error) => Err(Error::unexpected("a str dict key", json)),
+            (Node::Open(_) | Node::Ref(_), _) => unreachable!("closed nodes are rendered"),
+        }
+    }
+
+    /// Renders nodes as a JSON array, with its size
+    fn array(&mut self, items: Vec<Node>) -> Result<(String, Size), Error> {
+        let mut json = String::from("[");
+        let mut size = Size::default();
+        for (i, item) in items.into_iter().enumerate() {
+            if i > 0 {
+                json.push(',');
+            }
+            json.push_str(&self.json(item, &mut size)?);
+        }
+        json.push(']');
+        Ok((json, size.nest()))
+    }
+
+    fn tuple(&mut self, items: Vec<Node>) -> Result<(String, Size), Error> {
+        let (items, size) = self.array(items)?;
+        Ok((self.tagged("__tuple__", self.options.tuples, &items), size))
+    }
+
+    fn push_tuple(&mut self, items: Vec<Node>) -> Result<(), Error> {
+        let (json, size) = self.tuple(items)?;
+        self.stack.push(Node::Json(json, size));
+        Ok(())
+    }
+
+    fn tagged(&self, tag: &str, repr: SeqRepr, items: &str) -> String {
+        match repr {
+            SeqRepr::Array => items.to_string(),
+            SeqRepr::Tagged => format!("{{\"{tag}\":{items}}}"),
+        }
+    }
+
+    /// Renders an object built by python code out of its arguments, with its size
+    fn object(&mut self, tag: &str, items: Vec<Node>) -> Result<(String, Size), Error> {
+        let mut size = Size::default();
+        let items = items
+            .into_iter()
+            .map(|item| self.json(item, &mut size))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok((self.tagged_object(tag, &items), size.nest()))
+    }
+
+    fn tagged_object(&self, tag: &str, items: &[String]) -> String {
+        tagged_object(tag, items, self.options)
+    }
+
+    fn global(&self, module: &str, name: &str) -> Result<String, Error> {
+        match self.options.objects {
+            ObjectRepr::Tagged => Ok(format!(
+                "{{\"__global__\":{}}}",
+                str_json(&format!("{module}.{name}"))
+            )),
+            ObjectRepr::Null => Ok("null".to_string()),
+            ObjectRepr::Error => Err(Error::unexpected(
+                "a builtin object",
+                format!("global {module}.{name}"),
+            )),
+        }
+    }
+
+    fn float(&self, f: f64) -> Result<String, Error> {
+        if f.is_finite() {
+            return Ok(format!("{f:?}"));
+        }
+        let s = match f {
+            f if f.is_nan() => "NaN",
+            f if f > 0. => "Infinity",
+            _ => "-Infinity",
+        };
+        match self.options.floats {
+            FloatRepr::Null => Ok("null".to_string()),
+            FloatRepr::String => Ok(format!("\"{s}\"")),
+            FloatRepr::Error => Err(Error::unexpected("a finite float", s)),
+        }
+    }
+
+    fn bytes(&self) -> String {
+        match self.options.bytes {
+            BytesRepr::Base64 => format!("\"{}\"", base64(&self.buf)),
+            BytesRepr::Hex => {
+                let mut json = String::with_capacity(self.buf.len() * 2 + 2);
+                json.push('"');
+                for b in &self.buf {
+                    let _ = write!(json, "{b:02x}");
+                }
+                json.push('"');
+                json
+            }
+            BytesRepr::Array => {
+                let mut json = String::from("[");
+                for (i, b) in self.buf.iter().enumerate() {
+                    if i > 0 {
+                        json.push(',');
+                    }
+                    let _ = write!(json, "{b}");
+                }
+                json.push(']');
+                json
+            }
+        }
+    }
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Kind::List => "list",
+            Kind::Dict => "dict",
+            Kind::Set => "set",
+        }
+    }
+
+    fn expected(self) -> &'static str {
+        match self {
+            Kind::List => "a list or an object",
+            Kind::Dict => "a dict or an object",
+            Kind::Set => "a set",
+        }
+    }
+}
+
+/// Renders an object with the items added to it
+fn render_items(open: &Open, options: &Options) -> Result<String, Error> {
+    let (start, end) = rendering(open, options)?;
+    let Some(end) = end else {
+        return Ok(start.into_owned());
+    };
+    let mut json = String::with_capacity(start.len() + open.body.len() + end.len());
+    json.push_str(&start);
+    json.push_str(&open.body);
+    json.push_str(&end);
+    Ok(json)
+}
+
+/// Writes an object with the items added to it
+fn write_items<W: Write>(open: &Open, options: &Options, out: &mut W) -> Result<(), Error> {
+    let (start, end) = rendering(open, options)?;
+    out.write_all(start.as_bytes())?;
+    if let Some(end) = end {
+        out.write_all(open.body.as_bytes())?;
+        out.write_all(end.as_bytes())?;
+    }
+    Ok(())
+}
+
+/// Gets the JSON text of an object before and after its body, or `None` after it if the body
+/// is not rendered
+fn rendering<'a>(
+    open: &'a Open,
+    options: &Options,
+) -> Result<(Cow<'a, str>, Option<Cow<'static, str>>), Error> {
+    let brackets = open.kind.map(|kind| match kind {
+        Kind::List => ("[", "]"),
+        Kind::Dict if options.keys == KeyRepr::Pairs => ("[", "]"),
+        Kind::Dict => ("{", "}"),
+        Kind::Set => match options.sets {
+            SeqRepr::Array => ("[", "]"),
+            SeqRepr::Tagged => ("{\"__set__\":[", "]}"),
+        },
+    });
+    match (&open.object, brackets) {
+        (None, Some((start, end))) => Ok((start.into(), Some(end.into()))),
+        (Some(object), None) => Ok((object.into(), None)),
+        (Some(_), Some(_)) if options.objects == ObjectRepr::Null => Ok(("null".into(), None)),
+        (Some(object), Some((start, end))) => Ok((
+            format!("{{\"__extend__\":[{object},{start}").into(),
+            Some(format!("{end}]}}").into()),
+        )),
+        (None, None) => Err(ErrorKind::Invalid("empty object").into()),
+    }
+}
+
+fn tagged_object(tag: &str, items: &[String], options: &Options) -> String {
+    match options.objects {
+        ObjectRepr::Null => "null".to_string(),
+        // globals are rejected before any object is built
+        ObjectRepr::Tagged | ObjectRepr::Error => format!("{{\"{tag}\":[{}]}}", items.join(",")),
+    }
+}
+
+fn check_container(len: usize, max: usize) -> Result<(), Error> {
+    if len > max {
+        return Err(ErrorKind::ContainerTooLarge { max }.into());
+    }
+    Ok(())
+}
+
+/// Renders a string as a JSON string
+fn str_json(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if c < ' ' => {
+                let _ = write!(json, "\\u{:04x}", c as u32);
+            }
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut s = String::with_capacity(bytes.len().div_ceil(3) * 4);
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                s.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                s.push('=');
+            }
+        }
+    }
+    s
+}
+
+/// Maximum nesting of JSON arrays and objects
+const MAX_DEPTH: usize = 512;
+
+/// Parses JSON into a [`Value`], decoding tagged objects
+///
+/// Besides the tags of the objects built by python code, `{"__tuple__": [...]}`,
+/// `{"__set__": [...]}`, `{"__frozenset__": [...]}`, `{"__bytes__": "base64"}` and
+/// `{"__dict__": [[key, value], ...]}` (for non-string keys) are decoded. Arguments of
+/// `__reduce__` and `__newobj__` are converted to tuples.
+///
+/// Use a [`Pickler`](crate::Pickler) to write the value as a pickle.
+pub fn from_str(json: &str) -> Result<Value, Error> {
+    let mut parser = Parser {
+        json: json.as_bytes(),
+        pos: 0,
+        depth: 0,
+    };
+    let value = parser.value().and_then(|value| {
+        parser.skip_whitespace();
+        match parser.peek() {
+            None => Ok(value),
+            Some(_) => Err(parser.unexpected("the end of the input")),
+        }
+    });
+    value.map_err(|e| e.with_context(format!("at byte {} of the JSON input", parser.pos)))
+}
+
+struct Parser<'a> {
+    json: &'a [u8],
+    pos: usize,
+    depth: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.json.get(self.pos).copied()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn unexpected(&self, expected: &'static str) -> Error {
+        match from_utf8(&self.json[self.pos..])
+            .ok()
+            .and_then(|s| s.chars().next())
+        {
+            Some(c) => Error::unexpected(expected, format!("{c:?}")),
+            None => Error::unexpected(expected, "the end of the input"),
+        }
+    }
+
+    fn expect(&mut self, byte: u8, expected: &'static str) -> Result<(), Error> {
+        self.skip_whitespace();
+        if self.peek() != Some(byte) {
+            return Err(self.unexpected(expected));
+        }
+        self.pos += 1;
+        Ok(())
+    }
+
+    fn literal(&mut self, literal: &[u8], value: Value) -> Result<Value, Error> {
+        if !self.json[self.pos..].starts_with(literal) {
+            return Err(self.unexpected("a JSON value"));
+        }
+        self.pos += literal.len();
+        Ok(value)
+    }
+
+    fn value(&mut self) -> Result<Value, Error> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => self.nested(Parser::object),
+            Some(b'[') => self.nested(|p| p.array().map(Value::List)),
+            Some(b'"') => self.string().map(Value::Str),
+            Some(b't') => self.literal(b"true", Value::Bool(true)),
+            Some(b'f') => self.literal(b"false", Value::Bool(false)),
+            Some(b'n') => self.literal(b"null", Value::None),
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            _ => Err(self.unexpected("a JSON value")),
+        }
+    }
+
+    fn nested<F>(&mut self, f: F) -> Result<Value, Error>
+    where
+        F: FnOnce(&mut Self) -> Result<Value, Error>,
+    {
+        if self.depth == MAX_DEPTH {
+            return Err(ErrorKind::Invalid("JSON nesting is too deep").into());
+        }
+        self.depth += 1;
+        let value = f(self)?;
+        self.depth -= 1;
+        Ok(value)
+    }
+
+    fn array(&mut self) -> Result<Vec<Value>, Error> {
+        self.expect(b'[', "'['")?;
+        let mut items = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(items);
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(items);
+                }
+                _ => return Err(self.unexpected("',' or ']'")),
+            }
+        }
+    }
+
+    fn object(&mut self) -> Result<Value, Error> {
+        self.expect(b'{', "'{'")?;
+        let mut pairs = Vec::new();
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return tagged(pairs);
+        }
+        loop {
+            self.skip_whitespace();
+            if self.peek() != Some(b'"') {
+                return Err(self.unexpected("a string key"));
+            }
+            let key = self.string()?;
+            self.expect(b':', "':'")?;
+            pairs.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return tagged(pairs);
+                }
+                _ => return Err(self.unexpected("',' or '}'")),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, Error> {
+        self.pos += 1;
+        let mut s = String::new();
+        loop {
+            let start = self.pos;
+            while let Some(b) = self.peek()
+                && b != b'"'
+                && b != b'\\'
+                && b >= b' '
+            {
+                self.pos += 1;
+            }
+            s.push_str(from_utf8(&self.json[start..self.pos])?);
+            match self.peek() {
+                Some(b'"') => {
+                    self.pos += 1;
+                    return Ok(s);
+                }
+                Some(b'\\') => {
+                    self.pos += 1;
+                    let c = match self.peek() {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => {
+                            self.pos += 1;
+                            s.push(self.unicode_escape()?);
+                            continue;
+                        }
+                        _ => return Err(self.unexpected("an escape sequence")),
+                    };
+                    self.pos += 1;
+                    s.push(c);
+                }
+                _ => return Err(self.unexpected("'\"'")),
+            }
+        }
+    }
+
+    /// Decodes the code point of a `\u` escape, combining surrogate pairs
+    fn unicode_escape(&mut self) -> Result<char, Error> {
+        let high = self.hex4()?;
+        let code = if (0xd800..0xdc00).contains(&high) {
+            if !self.json[self.pos..].starts_with(b"\\u") {
+                return Err(self.unexpected("a low surrogate"));
+            }
+            self.pos += 2;
+            let low = self.hex4()?;
+            if !(0xdc00..0xe000).contains(&low) {
+                return Err(Error::unexpected(
+                    "a low surrogate",
+                    format!("\\u{low:04x}"),
+                ));
+            }
+            0x10000 + ((high - 0xd800) << 10) + (low - 0xdc00)
+        } else {
+            high
+        };
+        char::from_u32(code).ok_or_else(|| Error::unexpected("a unicode scalar", code))
+    }
+
+    fn hex4(&mut self) -> Result<u32, Error> {
+        let hex = self
+            .json
+            .get(self.pos..self.pos + 4)
+            .and_then(|hex| from_utf8(hex).ok())
+            .and_then(|hex| u32::from_str_radix(hex, 16).ok())
+            .ok_or_else(|| self.unexpected("4 hexadecimal digits"))?;
+        self.pos += 4;
+        Ok(hex)
+    }
+
+    fn number(&mut self) -> Result<Value, Error> {
+        let start = self.pos;
+        let digits = |p: &mut Self| {
+            let start = p.pos;
+            while let Some(b'0'..=b'9') = p.peek() {
+                p.pos += 1;
+            }
+            p.pos > start
+        };
+        if self.peek() == Some(b'-') {
+            self.pos += 1;
+        }
+        if !digits(self) {
+            return Err(self.unexpected("a digit"));
+        }
+        let mut float = false;
+        if self.peek() == Some(b'.') {
+            self.pos += 1;
+            float = true;
+            if !digits(self) {
+                return Err(self.unexpected("a digit"));
+            }
+        }
+        if let Some(b'e' | b'E') = self.peek() {
+            self.pos += 1;
+            float = true;
+            if let Some(b'+' | b'-') = self.peek() {
+                self.pos += 1;
+            }
+            if !digits(self) {
+                return Err(self.unexpected("a digit"));
+            }
+        }
+        let number = &self.json[start..self.pos];
+        if float {
+            return Ok(Value::Float(from_utf8(number)?.parse()?));
+        }
+        match atoi::atoi::<i64>(number) {
+            Some(i) => Ok(Value::Int(i)),
+            None => BigInt::parse_decimal(number).map(Value::BigInt),
+        }
+    }
+}
+
+/// Decodes a JSON object, which is a dict unless it has a single tag key
+fn tagged(mut pairs: Vec<(String, Value)>) -> Result<Value, Error> {
+    const TAGS: [&str; 10] = [
+        "__tuple__",
+        "__set__",
+        "__frozenset__",
+        "__bytes__",
+        "__dict__",
+        "__global__",
+        "__reduce__",
+        "__newobj__",
+        "__build__",
+        "__extend__",
+    ];
+    if pairs.len() != 1 || !TAGS.contains(&pairs[0].0.as_str()) {
+        let items = pairs.into_iter().map(|(k, v)| (Value::Str(k), v));
+        return Ok(Value::Dict(items.collect()));
+    }
+    let (tag, value) = pairs.pop().expect("a single pair");
+    let array = |value: Value| match value {
+        Value::List(items) => Ok(items),
+        value => Err(Error::unexpected("an array", value.type_name())),
+    };
+    let tuple = |value: Value| match value {
+        Value::List(items) => Value::Tuple(items),
+        value => value,
+    };
+    let value = match tag.as_str() {
+        "__tuple__" => Value::Tuple(array(value)?),
+        "__set__" => Value::Set(array(value)?),
+        "__frozenset__" => Value::FrozenSet(array(value)?),
+        "__bytes__" => match value {
+            Value::Str(s) => {
+                Value::Bytes(from_base64(&s).ok_or_else(|| Error::unexpected("base64", s))?)
+            }
+            value => return Err(Error::unexpected("a base64 string", value.type_name())),
+        },
+        "__dict__" => Value::Dict(
+            array(value)?
+                .into_iter()
+                .map(|pair| match <[Value; 2]>::try_from(array(pair)?) {
+                    Ok([key, value]) => Ok((key, value)),
+                    Err(pair) => Err(Error::unexpected("a [key, value] pair", pair.len())),
+                })
+                .collect::<Result<_, Error>>()?,
+        ),
+        "__global__" => match value {
+            Value::Str(s) => match s.rsplit_once('.') {
+                Some((module, name)) => Value::Global {
+                    module: module.to_string(),
+                    name: name.to_string(),
+                },
+                None => return Err(Error::unexpected("'module.name'", s)),
+            },
+            value => {
+                return Err(Error::unexpected(
+                    "a 'module.name' string",
+                    value.type_name(),
+                ));
+            }
+        },
+        _ => {
+            let items = array(value)?;
+            let len = items.len();
+            let mut items = items.into_iter().map(Box::new);
+            let mut next = || items.next().expect("length checked");
+            match (tag.as_str(), len) {
+                ("__reduce__", 2) => Value::Reduce {
+                    callable: next(),
+                    args: Box::new(tuple(*next())),
+                },
+                ("__newobj__", 2 | 3) => Value::Object {
+                    class: next(),
+                    args: Box::new(tuple(*next())),
+                    kwargs: (len == 3).then(next),
+                },
+                ("__build__", 2) => Value::Build {
+                    object: next(),
+                    state: next(),
+                },
+                ("__extend__", 2) => Value::Extend {
+                    object: next(),
+                    items: next(),
+                },
+                _ => {
+                    return Err(Error::unexpected(
+                        "2 or 3 items",
+                        format!("{len} for {tag}"),
+                    ));
+                }
+            }
+        }
+    };
+    Ok(value)
+}
+
+fn from_base64(s: &str) -> Option<Vec<u8>> {
+    let s = s.trim_end_matches('=');
+    let mut bytes = Vec::with_capacity(s.len() * 3 / 4);
+    let (mut acc, mut bits) = (0u32, 0);
+    for c in s.bytes() {
+        let v = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            _ => return None,
+        };
+        acc = (acc << 6 | v as u32) & 0xffff;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            bytes.push((acc >> bits) as u8);
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn convert(data: &[u8], options: &Options) -> Result<String, Error> {
+        to_string(&mut Reader::new(data), options)
+    }
+
+    #[test]
+    fn test_convert() -> Result<(), Error> {
+        // pickle.dumps([1, 'a', (1, 2), {'x': b'y'}, {3}, None, '', 1.5, -2**70], protocol=4)
+        let data = b"\x80\x04\x95:\x00\x00\x00\x00\x00\x00\x00]\x94(K\x01\x8c\x01a\x94K\x01K\x02\x86\x94}\x94\x8c\x01x\x94C\x01y\x94s\x8f\x94(K\x03\x90N\x8c\x00\x94G?\xf8\x00\x00\x00\x00\x00\x00\x8a\t\x00\x00\x00\x00\x00\x00\x00\x00\xc0e.";
+        let json = convert(data, &Options::default())?;
+        assert_eq!(
+            json,
+            r#"[1,"a",[1,2],{"x":"eQ=="},[3],null,"",1.5,-1180591620717411303424]"#
+        );
+
+        let options = Options {
+            bytes: BytesRepr::Array,
+            tuples: SeqRepr::Tagged,
+            sets: SeqRepr::Tagged,
+            ..Options::default()
+        };
+        let json = convert(data, &options)?;
+        assert_eq!(
+            json,
+            r#"[1,"a",{"__tuple__":[1,2]},{"x":[121]},{"__set__":[3]},null,"",1.5,-1180591620717411303424]"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_keys_and_floats() -> Result<(), Error> {
+        // pickle.dumps({1: float('nan'), 'a"\n': float('-inf')}, protocol=2)
+        let data = b"\x80\x02}q\x00(K\x01G\x7f\xf8\x00\x00\x00\x00\x00\x00X\x03\x00\x00\x00a\"\nq\x01G\xff\xf0\x00\x00\x00\x00\x00\x00u.";
+        let json = convert(data, &Options::default())?;
+        assert_eq!(json, r#"{"1":null,"a\"\n":null}"#);
+
+        let options = Options {
+            keys: KeyRepr::Pairs,
+            floats: FloatRepr::String,
+            ..Options::default()
+        };
+        let json = convert(data, &options)?;
+        assert_eq!(json, r#"[[1,"NaN"],["a\"\n","-Infinity"]]"#);
+
+        let options = Options {
+            keys: KeyRepr::Error,
+            ..Options::default()
+        };
+        let err = convert(data, &options).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected a str dict key, found 1 at offset 36 (SETITEMS)"
+        );
+        let options = Options {
+            floats: FloatRepr::Error,
+            ..Options::default()
+        };
+        let err = convert(data, &options).unwrap_err();
+        assert_eq!(err.opcode_name(), Some("BINFLOAT"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_objects() -> Result<(), Error> {
+        // pickle.dumps(collections.OrderedDict(a=[1]), protocol=4)
+        let data = b"\x80\x04\x95,\x00\x00\x00\x00\x00\x00\x00\x8c\x0bcollections\x94\x8c\x0bOrderedDict\x94\x93\x94)R\x94\x8c\x01a\x94]\x94K\x01as.";
+        let json = convert(data, &Options::default())?;
+        assert_eq!(
+            json,
+            r#"{"__extend__":[{"__reduce__":[{"__global__":"collections.OrderedDict"},[]]},{"a":[1]}]}"#
+        );
+
+        let options = Options {
+            objects: ObjectRepr::Null,
+            ..Options::default()
+        };
+        assert_eq!(convert(data, &options)?, "null");
+
+        let options = Options {
+            objects: ObjectRepr::Error,
+            ..Options::default()
+        };
+        let err = convert(data, &options).unwrap_err();
+        assert_eq!(err.opcode_name(), Some("STACK_GLOBAL"));
+
+        // protocol 0: cos\nsystem\n(S'ls'\ntR.
+        let data = b"cos\nsystem\n(S'ls'\ntR.";
+        let json = convert(data, &Options::default())?;
+        assert_eq!(
+            json,
+            r#"{"__reduce__":[{"__global__":"os.system"},["ls"]]}"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_in_place() -> Result<(), Error> {
+        // pickletools.optimize(pickle.dumps(
+        //     [collections.OrderedDict(a=[1]), ([2], {'b': [3]})], protocol=2))
+        let data = b"\x80\x02](ccollections\nOrderedDict\n)RX\x01\x00\x00\x00a]K\x01as]K\x02a}X\x01\x00\x00\x00b]K\x03as\x86e.";
+        let mut out = Vec::new();
+        to_writer(&mut Reader::new(&data[..]), &mut out, &Options::default())?;
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"[{"__extend__":[{"__reduce__":[{"__global__":"collections.OrderedDict"},[]]},{"a":[1]}]},[[2],{"b":[3]}]]"#
+        );
+
+        let options = Options {
+            keys: KeyRepr::Pairs,
+            ..Options::default()
+        };
+        assert_eq!(
+            convert(data, &options)?,
+            r#"[{"__extend__":[{"__reduce__":[{"__global__":"collections.OrderedDict"},[]]},[["a",[1]]]]},[[2],[["b",[3]]]]]"#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_memo() -> Result<(), Error> {
+        // l = [1]; pickle.dumps([l, l, 'ab', 'ab'], protocol=2)
+        let data = b"\x80\x02]q\x00(]q\x01K\x01ah\x01X\x02\x00\x00\x00abq\x02h\x02e.";
+        assert_eq!(
+            convert(data, &Options::default())?,
+            r#"[[1],[1],"ab","ab"]"#
+        );
+
+        // l = []; l.append(l)
+        let data = b"\x80\x02]q\x00h\x00a.";
+        let err = convert(data, &Options::default()).unwrap_err();
+        assert_eq!(err.opcode_name(), Some("APPEND"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_references() -> Result<(), Error> {
+        let options = Options {
+            tuples: SeqRepr::Tagged,
+            ..Options::default()
+        };
+        for data in [
+            // DUP, then an item appended to the duplicate
+            &b"\x80\x02]2K\x01a\x86."[..],
+            // DUP of a memoized list, then an item appended through a memo reference
+            b"\x80\x02]q\x002h\x00K\x01a0K\x02a\x86.",
+            // a duplicate popped before the list is complete
+            b"\x80\x02]20K\x01a\x85.",
+            // a memo reference to a duplicate
+            b"\x80\x02]2q\x00K\x01ah\x00\x87.",
+        ] {
+            let json = convert(data, &options)?;
+            let expected = crate::Unpickler::new(data).load()?;
+            assert_eq!(from_str(&json)?, expected, "{json}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_copies() {
+        let convert = |data: &[u8], max_copies| {
+            let limits = Limits {
+                max_copies,
+                ..Limits::default()
+            };
+            to_string(
+                &mut Reader::new(data).with_limits(limits),
+                &Options::default(),
+            )
+        };
+        // a = [1]; pickle.dumps([a, a], protocol=2) copies a once
+        let data = b"\x80\x02]q\x00(]q\x01K\x01ah\x01e.";
+        assert!(convert(data, 1).is_err());
+        assert_eq!(convert(data, 2).unwrap(), "[[1],[1]]");
+
+        // 2^40 values out of nested [a, a] lists
+        let mut data = b"\x80\x02]q\x000".to_vec();
+        for i in 1..=40 {
+            data.extend_from_slice(&[b'(', b'h', i - 1, b'h', i - 1, b'l', b'q', i, b'0']);
+        }
+        data.extend_from_slice(b"h\x28.");
+        let err = convert(&data, 1 << 16).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::TooManyCopies { max } if *max == 1 << 16));
+    }
+
+    #[test]
+    fn test_depth() {
+        let convert = |data: &[u8], max_depth| {
+            let limits = Limits {
+                max_depth,
+                ..Limits::default()
+            };
+            to_string(
+                &mut Reader::new(data).with_limits(limits),
+                &Options::default(),
+            )
+        };
+        // a = [1]; pickle.dumps([a, [a]], protocol=2) copies a one level deeper
+        let data = b"\x80\x02]q\x00(]q\x01K\x01a]q\x02h\x01ae.";
+        assert!(convert(data, 2).is_err());
+        assert_eq!(convert(data, 3).unwrap(), "[[1],[[1]]]");
+
+        let err = convert(b"]]]aa.", 2).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::NestingTooDeep { max: 2 }));
+        // the state of an object nests it
+        let err = convert(b"cm\nC\n)\x81]]ab.", 2).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::NestingTooDeep { max: 2 }));
+
+        for data in [
+            [b"]".repeat(100_000), b"a".repeat(99_999), b".".to_vec()].concat(),
+            [&b"N"[..], &b"Nb".repeat(100_000), b"."].concat(),
+        ] {
+            let err = convert(&data, 1000).unwrap_err();
+            assert!(matches!(
+                err.kind(),
+                ErrorKind::NestingTooDeep { max: 1000 }
+            ));
+        }
+    }
+
+    #[test]
+    fn test_from_str() -> Result<(), Error> {
+        let json = r#" {"a": [1, -2.5e3, "\u00e9\ud83d\ude00\n", null, true, 123456789012345678901234],
+            "t": {"__tuple__": [1]}, "b": {"__bytes__": "/wD+AQ=="},
+            "d": {"__dict__": [[1, 2]]}, "s": {"__set__": []}, "__tuple__": 1} "#;
+        let value = from_str(json)?;
+        let big = BigInt::parse_decimal(b"123456789012345678901234").unwrap();
+        assert_eq!(
+            value,
+            Value::Dict(vec![
+                (
+                    Value::Str("a".into()),
+                    Value::List(vec![
+                        Value::Int(1),
+                        Value::Float(-2500.),
+                        Value::Str("é😀\n".into()),
+                        Value::None,
+                        Value::Bool(true),
+                        Value::BigInt(big),
+                    ])
+                ),
+                (Value::Str("t".into()), Value::Tuple(vec![Value::Int(1)])),
+                (
+                    Value::Str("b".into()),
+                    Value::Bytes(b"\xff\x00\xfe\x01".to_vec())
+                ),
+                (
+                    Value::Str("d".into()),
+                    Value::Dict(vec![(Value::Int(1), Value::Int(2))])
+                ),
+                (Value::Str("s".into()), Value::Set(vec![])),
+                (Value::Str("__tuple__".into()), Value::Int(1)),
+            ])
+        );
+
+        let err = from_str("[1, 2").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected ',' or ']', found the end of the input, at byte 5 of the JSON input"
+        );
+        assert!(from_str("[1] x").is_err());
+        assert!(from_str(&"[".repeat(1000)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip() -> Result<(), Error> {
+        // pickle.dumps(collections.OrderedDict(a=[1]), protocol=4)
+        let data = b"\x80\x04\x95,\x00\x00\x00\x00\x00\x00\x00\x8c\x0bcollections\x94\x8c\x0bOrderedDict\x94\x93\x94)R\x94\x8c\x01a\x94]\x94K\x01as.";
+        let options = Options {
+            tuples: SeqRepr::Tagged,
+            ..Options::default()
+        };
+        let value = from_str(&convert(data, &options)?)?;
+        assert_eq!(crate::pickler::dumps(&value, 4)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_base64() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"\xff\x00\xfe\x01"), "/wD+AQ==");
+        for bytes in [&b""[..], b"f", b"fo", b"foo", b"\xff\x00\xfe\x01"] {
+            assert_eq!(from_base64(&base64(bytes)).as_deref(), Some(bytes));
+        }
+    }
+}
@@ -1,2 +1,49 @@
+//! A fast pickle reader
+//!
+//! The primary entry point is the [`Unpickler`] which rebuilds python objects as
+//! [`Value`]s, which the [`Pickler`] writes back. The lower level [`reader::Reader`] emits
+//! the raw pickle [`reader::Event`]s, and [`slice_reader::SliceReader`] does the same over
+//! in-memory data without copying payloads.
+
+#[cfg(feature = "tokio")]
+pub mod async_reader;
+pub mod bigint;
+#[cfg(feature = "serde")]
+pub mod de;
+pub mod decoder;
+pub mod dis;
 pub mod errors;
+pub mod json;
+#[cfg(feature = "numpy")]
+pub mod numpy;
+pub mod opcodes;
+pub mod pickler;
 pub mod reader;
+pub mod safety;
+#[cfg(feature = "serde")]
+pub mod ser;
+pub mod slice_reader;
+pub mod stream;
+#[cfg(feature = "torch")]
+pub mod torch;
+pub mod unpickler;
+pub mod value;
+pub mod writer;
+
+use std::io::BufRead;
+
+#[cfg(feature = "serde")]
+pub use de::{Deserializer, from_reader, from_slice, from_value, from_value_ref};
+pub use errors::{Error, ErrorKind};
+pub use pickler::Pickler;
+pub use safety::SafetyPolicy;
+#[cfg(feature = "serde")]
+pub use ser::{EnumRepr, Options, Serializer, to_vec, to_writer};
+pub use stream::PickleStream;
+pub use unpickler::{Encoding, PersistentLoader, Unpickler};
+pub use value::Value;
+
+/// Unpickles a single object from a reader
+pub fn load<R: BufRead>(reader: R) -> Result<Value, Error> {
+    Unpickler::new(reader).load()
+}
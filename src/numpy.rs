@@ -0,0 +1,456 @@
+//! A module to read numpy arrays from unpickled [`Value`]s
+//!
+//! numpy pickles an array as `numpy.core.multiarray._reconstruct(numpy.ndarray, (0,), b'b')`
+//! followed by a BUILD with a `(version, shape, dtype, is_fortran, data)` state, where the
+//! dtype is itself a `numpy.dtype(descr, False, True)` built with a `(version, byteorder, ...)`
+//! state. From protocol 5, contiguous arrays are pickled as
+//! `numpy.core.numeric._frombuffer(buffer, dtype, shape, order)` instead, with the buffer
+//! usually out-of-band. numpy 2 uses `numpy._core` rather than `numpy.core`.
+//!
+//! ```
+//! use quick_pickle::{Unpickler, numpy::{Array, DType}};
+//!
+//! # let data = b"\x80\x05\x95m\x00\x00\x00\x00\x00\x00\x00\x8c\x13numpy._core.numeric\x94\x8c\x0b_frombuffer\x94\x93\x94(\x97\x8c\x05numpy\x94\x8c\x05dtype\x94\x93\x94\x8c\x02i4\x94\x89\x88\x87\x94R\x94(K\x03\x8c\x01>\x94NNNJ\xff\xff\xff\xffJ\xff\xff\xff\xffK\x00t\x94bK\x02K\x02\x86\x94\x8c\x01F\x94t\x94R\x94.";
+//! # let buffer = vec![0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4];
+//! // pickle.dumps(np.array([[1, 3], [2, 4]], dtype='>i4', order='F'), protocol=5, buffer_callback=...)
+//! let value = Unpickler::new(&data[..]).with_buffers([buffer]).load()?;
+//! let array = Array::from_value(&value)?.unwrap();
+//! assert_eq!((array.dtype, array.shape.as_slice()), (DType::I32, &[2, 2][..]));
+//! assert!(array.big_endian && array.fortran_order);
+//! # Ok::<(), quick_pickle::Error>(())
+//! ```
+
+use std::borrow::Cow;
+
+use crate::{
+    errors::{Error, ErrorKind},
+    value::Value,
+};
+
+/// The element type of an array
+///
+/// Only numeric and boolean dtypes are supported, not strings, records or objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DType {
+    Bool,
+    I8,
+    I16,
+    I32,
+    I64,
+    U8,
+    U16,
+    U32,
+    U64,
+    F16,
+    F32,
+    F64,
+    C64,
+    C128,
+}
+
+impl DType {
+    /// Gets the dtype of a numpy type string without byte order, e.g. `"f8"`
+    pub fn from_descr(descr: &str) -> Option<Self> {
+        Some(match descr {
+            "b1" => DType::Bool,
+            "i1" => DType::I8,
+            "i2" => DType::I16,
+            "i4" => DType::I32,
+            "i8" => DType::I64,
+            "u1" => DType::U8,
+            "u2" => DType::U16,
+            "u4" => DType::U32,
+            "u8" => DType::U64,
+            "f2" => DType::F16,
+            "f4" => DType::F32,
+            "f8" => DType::F64,
+            "c8" => DType::C64,
+            "c16" => DType::C128,
+            _ => return None,
+        })
+    }
+
+    /// Gets the size of an element in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            DType::Bool | DType::I8 | DType::U8 => 1,
+            DType::I16 | DType::U16 | DType::F16 => 2,
+            DType::I32 | DType::U32 | DType::F32 => 4,
+            DType::I64 | DType::U64 | DType::F64 | DType::C64 => 8,
+            DType::C128 => 16,
+        }
+    }
+}
+
+/// A numpy array (or scalar, with an empty shape) whose data is borrowed from a [`Value`]
+/// when possible
+#[derive(Debug, Clone, PartialEq)]
+pub struct Array<'a> {
+    pub dtype: DType,
+    /// Byte order of the elements, `false` for dtypes of a single byte
+    pub big_endian: bool,
+    pub shape: Vec<usize>,
+    /// Elements are in column-major order rather than row-major order
+    pub fortran_order: bool,
+    data: Cow<'a, [u8]>,
+}
+
+impl<'a> Array<'a> {
+    /// Reads an array pickled by numpy, or a numpy scalar
+    ///
+    /// Returns `None` if the value is not such an object.
+    pub fn from_value(value: &'a Value) -> Result<Option<Self>, Error> {
+        let array = match value {
+            // ndarray.__reduce__
+            Value::Build { object, state }
+                if matches!(&**object, Value::Reduce { callable, .. }
+                    if is_numpy(callable, "multiarray", "_reconstruct")) =>
+            {
+                let state = state
+                    .as_slice()
+                    .ok_or_else(|| Error::unexpected("array state tuple", state.type_name()))?;
+                // the version is missing in the oldest pickles
+                let (shape, dtype, fortran_order, data) = match state {
+                    [_, shape, dtype, fortran, data] | [shape, dtype, fortran, data] => {
+                        (shape, dtype, fortran, data)
+                    }
+                    _ => return Err(ErrorKind::Invalid("unknown array state").into()),
+                };
+                Array::new(
+                    dtype,
+                    shape,
+                    matches!(fortran_order, Value::Bool(true)),
+                    data,
+                )?
+            }
+            // ndarray.__reduce_ex__ from protocol 5
+            Value::Reduce { callable, args } if is_numpy(callable, "numeric", "_frombuffer") => {
+                let [data, dtype, shape, order] = reduce_args(args)? else {
+                    return Err(ErrorKind::Invalid("unknown _frombuffer arguments").into());
+                };
+                let fortran_order = order.as_str() == Some("F");
+                Array::new(dtype, shape, fortran_order, data)?
+            }
+            Value::Reduce { callable, args } if is_numpy(callable, "multiarray", "scalar") => {
+                let [dtype, data] = reduce_args(args)? else {
+                    return Err(ErrorKind::Invalid("unknown scalar arguments").into());
+                };
+                Array::new(dtype, &Value::Tuple(Vec::new()), false, data)?
+            }
+            _ => return Ok(None),
+        };
+        Ok(Some(array))
+    }
+
+    fn new(
+        dtype: &Value,
+        shape: &Value,
+        fortran_order: bool,
+        data: &'a Value,
+    ) -> Result<Self, Error> {
+        let (dtype, big_endian) = parse_dtype(dtype)?;
+        let shape = shape
+            .as_slice()
+            .ok_or_else(|| Error::unexpected("shape tuple", shape.type_name()))?
+            .iter()
+            .map(|dim| {
+                dim.as_i64()
+                    .and_then(|dim| usize::try_from(dim).ok())
+                    .ok_or_else(|| Error::unexpected("array dimension", dim.type_name()))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let data = match data {
+            Value::Bytes(_) | Value::Buffer { .. } => Cow::Borrowed(data.as_bytes().unwrap()),
+            // python 2 str, and python 3 bytes before protocol 3
+            Value::Str(s) => Cow::Owned(latin1(s)?),
+            Value::Reduce { callable, args }
+                if matches!(&**callable, Value::Global { module, name }
+                    if module == "_codecs" && name == "encode") =>
+            {
+                match reduce_args(args)? {
+                    [Value::Str(s), Value::Str(encoding)] if encoding == "latin1" => {
+                        Cow::Owned(latin1(s)?)
+                    }
+                    _ => return Err(ErrorKind::Invalid("unknown _codecs.encode arguments").into()),
+                }
+            }
+            Value::List(_) => {
+                return Err(ErrorKind::Invalid("object arrays are not supported").into());
+            }
+            data => return Err(Error::unexpected("array data", data.type_name())),
+        };
+        let len = shape
+            .iter()
+            .try_fold(dtype.size(), |len, &dim| len.checked_mul(dim));
+        if len != Some(data.len()) {
+            return Err(ErrorKind::Invalid("array data does not match its shape").into());
+        }
+        Ok(Array {
+            dtype,
+            big_endian,
+            shape,
+            fortran_order,
+            data,
+        })
+    }
+
+    /// Gets the number of elements
+    pub fn len(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Gets the raw elements, in the order given by [`Array::fortran_order`]
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Converts to an owned `ndarray` array, with elements in native byte order
+    ///
+    /// Fails if `T` does not match the dtype.
+    #[cfg(feature = "ndarray")]
+    pub fn to_ndarray<T: Element>(&self) -> Result<ndarray::ArrayD<T>, Error> {
+        use ndarray::ShapeBuilder;
+
+        if T::DTYPE != self.dtype {
+            return Err(Error::unexpected(
+                "array of the requested element type",
+                format_args!("{:?} array", self.dtype),
+            ));
+        }
+        let items = self
+            .data
+            .chunks_exact(self.dtype.size())
+            .map(|bytes| T::from_bytes(bytes, self.big_endian))
+            .collect();
+        let shape = ndarray::IxDyn(&self.shape).set_f(self.fortran_order);
+        ndarray::ArrayD::from_shape_vec(shape, items)
+            .map_err(|_| ErrorKind::Invalid("array data does not match its shape").into())
+    }
+}
+
+/// An element type of an `ndarray` array converted from an [`Array`]
+#[cfg(feature = "ndarray")]
+pub trait Element: Sized {
+    const DTYPE: DType;
+
+    /// Decodes an element from exactly [`DType::size`] bytes
+    fn from_bytes(bytes: &[u8], big_endian: bool) -> Self;
+}
+
+#[cfg(feature = "ndarray")]
+macro_rules! impl_element {
+    ($($ty:ty => $dtype:ident),*) => {
+        $(
+            impl Element for $ty {
+                const DTYPE: DType = DType::$dtype;
+
+                fn from_bytes(bytes: &[u8], big_endian: bool) -> Self {
+                    let bytes = bytes.try_into().expect("element size");
+                    if big_endian {
+                        <$ty>::from_be_bytes(bytes)
+                    } else {
+                        <$ty>::from_le_bytes(bytes)
+                    }
+                }
+            }
+        )*
+    };
+}
+
+#[cfg(feature = "ndarray")]
+impl_element!(i8 => I8, i16 => I16, i32 => I32, i64 => I64, u8 => U8, u16 => U16,
+    u32 => U32, u64 => U64, f32 => F32, f64 => F64);
+
+#[cfg(feature = "ndarray")]
+impl Element for bool {
+    const DTYPE: DType = DType::Bool;
+
+    fn from_bytes(bytes: &[u8], _big_endian: bool) -> Self {
+        bytes[0] != 0
+    }
+}
+
+/// Checks if a value is the `numpy.core.<module>.<name>` (or `numpy._core`) global
+fn is_numpy(value: &Value, module: &str, name: &str) -> bool {
+    let Value::Global { module: m, name: n } = value else {
+        return false;
+    };
+    let m = m
+        .strip_prefix("numpy.core.")
+        .or_else(|| m.strip_prefix("numpy._core."));
+    m == Some(module) && n == name
+}
+
+fn reduce_args(args: &Value) -> Result<&[Value], Error> {
+    args.as_slice()
+        .ok_or_else(|| Error::unexpected("tuple arguments", args.type_name()))
+}
+
+/// Parses a `numpy.dtype(descr, False, True)` object and its `(version, byteorder, ...)` state
+fn parse_dtype(value: &Value) -> Result<(DType, bool), Error> {
+    let (object, byte_order) = match value {
+        Value::Build { object, state } => match state.as_slice() {
+            Some([_, order, ..]) if text(order).is_some() => (&**object, text(order).unwrap()),
+            _ => return Err(ErrorKind::Invalid("unknown dtype state").into()),
+        },
+        object => (object, "="),
+    };
+    let descr = match object {
+        Value::Reduce { callable, args }
+            if matches!(&**callable, Value::Global { module, name }
+                if module == "numpy" && name == "dtype") =>
+        {
+            match reduce_args(args)?.first().and_then(text) {
+                Some(descr) => descr,
+                None => return Err(ErrorKind::Invalid("unknown dtype arguments").into()),
+            }
+        }
+        object => return Err(Error::unexpected("numpy.dtype", object.type_name())),
+    };
+    let dtype = DType::from_descr(descr)
+        .ok_or_else(|| Error::unexpected("numeric or bool dtype", descr))?;
+    let big_endian = match byte_order {
+        ">" => true,
+        "=" => cfg!(target_endian = "big"),
+        _ => false,
+    };
+    Ok((dtype, big_endian && dtype.size() > 1))
+}
+
+/// Gets a str, or a python 2 str loaded as bytes
+fn text(value: &Value) -> Option<&str> {
+    match value {
+        Value::Str(s) => Some(s),
+        Value::Bytes(bytes) => std::str::from_utf8(bytes).ok(),
+        _ => None,
+    }
+}
+
+/// Encodes a string whose chars are all below 256 as latin1
+fn latin1(s: &str) -> Result<Vec<u8>, Error> {
+    s.chars()
+        .map(|c| {
+            u8::try_from(c).map_err(|_| Error::unexpected("latin1 string", c.escape_default()))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Unpickler;
+
+    #[test]
+    fn test_reconstruct() -> Result<(), Error> {
+        // pickle.dumps([np.array([[1., 2., 3.], [4., 5., 6.]]), np.int64(-3)], protocol=2)
+        // with numpy 1
+        let data = b"\x80\x02]q\x00(cnumpy.core.multiarray\n_reconstruct\nq\x01cnumpy\nndarray\nq\x02K\x00\x85q\x03c_codecs\nencode\nq\x04X\x01\x00\x00\x00bq\x05X\x06\x00\x00\x00latin1q\x06\x86q\x07Rq\x08\x87q\tRq\n(K\x01K\x02K\x03\x86q\x0bcnumpy\ndtype\nq\x0cX\x02\x00\x00\x00f8q\r\x89\x88\x87q\x0eRq\x0f(K\x03X\x01\x00\x00\x00<q\x10NNNJ\xff\xff\xff\xffJ\xff\xff\xff\xffK\x00tq\x11b\x89h\x04X1\x00\x00\x00\x00\x00\x00\x00\x00\x00\xc3\xb0?\x00\x00\x00\x00\x00\x00\x00@\x00\x00\x00\x00\x00\x00\x08@\x00\x00\x00\x00\x00\x00\x10@\x00\x00\x00\x00\x00\x00\x14@\x00\x00\x00\x00\x00\x00\x18@q\x12h\x06\x86q\x13Rq\x14tq\x15bcnumpy.core.multiarray\nscalar\nq\x16h\x0cX\x02\x00\x00\x00i8q\x17\x89\x88\x87q\x18Rq\x19(K\x03h\x10NNNJ\xff\xff\xff\xffJ\xff\xff\xff\xffK\x00tq\x1abh\x04X\x10\x00\x00\x00\xc3\xbd\xc3\xbf\xc3\xbf\xc3\xbf\xc3\xbf\xc3\xbf\xc3\xbf\xc3\xbfq\x1bh\x06\x86q\x1cRq\x1d\x86q\x1eRq\x1fe.";
+        let value = Unpickler::new(&data[..]).load()?;
+        let items = value.as_slice().unwrap();
+
+        let array = Array::from_value(&items[0])?.unwrap();
+        assert_eq!(array.dtype, DType::F64);
+        assert_eq!(array.shape, [2, 3]);
+        assert!(!array.big_endian && !array.fortran_order);
+        let floats = [1f64, 2., 3., 4., 5., 6.];
+        let expected = floats
+            .iter()
+            .flat_map(|f| f.to_le_bytes())
+            .collect::<Vec<_>>();
+        assert_eq!(array.data(), expected);
+
+        let scalar = Array::from_value(&items[1])?.unwrap();
+        assert_eq!((scalar.dtype, scalar.len()), (DType::I64, 1));
+        assert_eq!(scalar.data(), (-3i64).to_le_bytes());
+
+        assert_eq!(Array::from_value(&value)?, None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_py2() -> Result<(), Error> {
+        use crate::unpickler::Encoding;
+
+        // numpy.int64(-3) pickled by python 2
+        let data = b"\x80\x02cnumpy.core.multiarray\nscalar\nq\x01cnumpy\ndtype\nq\x02U\x02i8K\x00K\x01\x87Rq\x03(K\x03U\x01<NNNJ\xff\xff\xff\xffJ\xff\xff\xff\xffK\x00tbU\x08\xfd\xff\xff\xff\xff\xff\xff\xff\x86Rq\x04.";
+        for encoding in [Encoding::Latin1, Encoding::Bytes] {
+            let value = Unpickler::new(&data[..]).with_encoding(encoding).load()?;
+            let scalar = Array::from_value(&value)?.unwrap();
+            assert_eq!((scalar.dtype, scalar.big_endian), (DType::I64, false));
+            assert_eq!(scalar.data(), (-3i64).to_le_bytes());
+        }
+        assert!(Unpickler::new(&data[..]).load().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_frombuffer() -> Result<(), Error> {
+        let buffer = [0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4];
+        // a = np.array([[1, 3], [2, 4]], dtype='>i4', order='F') with numpy 2
+        // pickle.dumps(a, protocol=4)
+        let data = b"\x80\x04\x95\x9b\x00\x00\x00\x00\x00\x00\x00\x8c\x16numpy._core.multiarray\x94\x8c\x0c_reconstruct\x94\x93\x94\x8c\x05numpy\x94\x8c\x07ndarray\x94\x93\x94K\x00\x85\x94C\x01b\x94\x87\x94R\x94(K\x01K\x02K\x02\x86\x94h\x03\x8c\x05dtype\x94\x93\x94\x8c\x02i4\x94\x89\x88\x87\x94R\x94(K\x03\x8c\x01>\x94NNNJ\xff\xff\xff\xffJ\xff\xff\xff\xffK\x00t\x94b\x88C\x10\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x03\x00\x00\x00\x04\x94t\x94b.";
+        let reconstructed = Unpickler::new(&data[..]).load()?;
+        // pickle.dumps(a, protocol=5)
+        let data = b"\x80\x05\x95\x86\x00\x00\x00\x00\x00\x00\x00\x8c\x13numpy._core.numeric\x94\x8c\x0b_frombuffer\x94\x93\x94(\x96\x10\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x03\x00\x00\x00\x04\x94\x8c\x05numpy\x94\x8c\x05dtype\x94\x93\x94\x8c\x02i4\x94\x89\x88\x87\x94R\x94(K\x03\x8c\x01>\x94NNNJ\xff\xff\xff\xffJ\xff\xff\xff\xffK\x00t\x94bK\x02K\x02\x86\x94\x8c\x01F\x94t\x94R\x94.";
+        let in_band = Unpickler::new(&data[..]).load()?;
+        // pickle.dumps(a, protocol=5, buffer_callback=buffers.append)
+        let data = b"\x80\x05\x95m\x00\x00\x00\x00\x00\x00\x00\x8c\x13numpy._core.numeric\x94\x8c\x0b_frombuffer\x94\x93\x94(\x97\x8c\x05numpy\x94\x8c\x05dtype\x94\x93\x94\x8c\x02i4\x94\x89\x88\x87\x94R\x94(K\x03\x8c\x01>\x94NNNJ\xff\xff\xff\xffJ\xff\xff\xff\xffK\x00t\x94bK\x02K\x02\x86\x94\x8c\x01F\x94t\x94R\x94.";
+        let out_of_band = Unpickler::new(&data[..])
+            .with_buffers([&buffer[..]])
+            .load()?;
+
+        for value in [&reconstructed, &in_band, &out_of_band] {
+            let array = Array::from_value(value)?.unwrap();
+            assert_eq!(array.dtype, DType::I32);
+            assert_eq!(array.shape, [2, 2]);
+            assert!(array.big_endian && array.fortran_order);
+            assert_eq!(array.data(), buffer);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "ndarray")]
+    #[test]
+    fn test_to_ndarray() -> Result<(), Error> {
+        let buffer = [0u8, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4];
+        let value = Value::Reduce {
+            callable: Box::new(Value::Global {
+                module: "numpy.core.numeric".into(),
+                name: "_frombuffer".into(),
+            }),
+            args: Box::new(Value::Tuple(vec![
+                Value::Bytes(buffer.to_vec()),
+                Value::Build {
+                    object: Box::new(Value::Reduce {
+                        callable: Box::new(Value::Global {
+                            module: "numpy".into(),
+                            name: "dtype".into(),
+                        }),
+                        args: Box::new(Value::Tuple(vec![
+                            Value::Str("i4".into()),
+                            Value::Bool(false),
+                            Value::Bool(true),
+                        ])),
+                    }),
+                    state: Box::new(Value::Tuple(vec![Value::Int(3), Value::Str(">".into())])),
+                },
+                Value::Tuple(vec![Value::Int(2), Value::Int(2)]),
+                Value::Str("F".into()),
+            ])),
+        };
+        let array = Array::from_value(&value)?.unwrap().to_ndarray::<i32>()?;
+        assert_eq!(array, ndarray::arr2(&[[1, 3], [2, 4]]).into_dyn());
+        assert!(
+            Array::from_value(&value)?
+                .unwrap()
+                .to_ndarray::<f32>()
+                .is_err()
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,201 @@
+//! A module describing pickle opcodes
+
+/// Static information about an opcode, as documented by python's `pickletools`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpcodeInfo {
+    pub code: u8,
+    /// Name used by `pickletools`, e.g. `"BINUNICODE"`
+    pub name: &'static str,
+    /// Protocol which introduced the opcode
+    pub proto: u8,
+    /// Number of objects popped (below the mark if the opcode pops one)
+    pub pops: u8,
+    /// Whether the opcode pops the topmost mark and the objects above it
+    pub pops_mark: bool,
+    /// Number of objects pushed (MARK pushes the mark)
+    pub pushes: u8,
+    /// Layout of the argument following the opcode
+    pub arg: Arg,
+}
+
+/// Layout of an opcode argument in the stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arg {
+    /// A fixed number of bytes, possibly none
+    Fixed(u8),
+    /// Text up to and including a newline
+    Line,
+    /// Two lines, the module and the name of GLOBAL and INST
+    TwoLines,
+    /// A little-endian length of `size` bytes followed by as many bytes
+    ///
+    /// `signed` lengths are rejected by the [`Reader`](crate::reader::Reader) when negative.
+    Counted { size: u8, signed: bool },
+}
+
+const fn op(
+    code: u8,
+    name: &'static str,
+    proto: u8,
+    pops: u8,
+    pops_mark: bool,
+    pushes: u8,
+    arg: Arg,
+) -> OpcodeInfo {
+    OpcodeInfo {
+        code,
+        name,
+        proto,
+        pops,
+        pops_mark,
+        pushes,
+        arg,
+    }
+}
+
+// argument layouts of the table, named after the reader method reading the length
+const NO_ARG: Arg = Arg::Fixed(0);
+const LEN_U8: Arg = Arg::Counted {
+    size: 1,
+    signed: false,
+};
+const LEN_U32: Arg = Arg::Counted {
+    size: 4,
+    signed: false,
+};
+const LEN_I32: Arg = Arg::Counted {
+    size: 4,
+    signed: true,
+};
+const LEN_I64: Arg = Arg::Counted {
+    size: 8,
+    signed: true,
+};
+const LEN_U64: Arg = Arg::Counted {
+    size: 8,
+    signed: false,
+};
+
+/// All opcodes, sorted by code
+pub static OPCODES: [OpcodeInfo; 68] = [
+    op(0x28, "MARK", 0, 0, false, 1, NO_ARG),
+    op(0x29, "EMPTY_TUPLE", 1, 0, false, 1, NO_ARG),
+    op(0x2e, "STOP", 0, 1, false, 0, NO_ARG),
+    op(0x30, "POP", 0, 1, false, 0, NO_ARG),
+    op(0x31, "POP_MARK", 1, 0, true, 0, NO_ARG),
+    op(0x32, "DUP", 0, 1, false, 2, NO_ARG),
+    op(0x42, "BINBYTES", 3, 0, false, 1, LEN_U32),
+    op(0x43, "SHORT_BINBYTES", 3, 0, false, 1, LEN_U8),
+    op(0x46, "FLOAT", 0, 0, false, 1, Arg::Line),
+    op(0x47, "BINFLOAT", 1, 0, false, 1, Arg::Fixed(8)),
+    op(0x49, "INT", 0, 0, false, 1, Arg::Line),
+    op(0x4a, "BININT", 1, 0, false, 1, Arg::Fixed(4)),
+    op(0x4b, "BININT1", 1, 0, false, 1, Arg::Fixed(1)),
+    op(0x4c, "LONG", 0, 0, false, 1, Arg::Line),
+    op(0x4d, "BININT2", 1, 0, false, 1, Arg::Fixed(2)),
+    op(0x4e, "NONE", 0, 0, false, 1, NO_ARG),
+    op(0x50, "PERSID", 0, 0, false, 1, Arg::Line),
+    op(0x51, "BINPERSID", 1, 1, false, 1, NO_ARG),
+    op(0x52, "REDUCE", 0, 2, false, 1, NO_ARG),
+    op(0x53, "STRING", 0, 0, false, 1, Arg::Line),
+    op(0x54, "BINSTRING", 1, 0, false, 1, LEN_I32),
+    op(0x55, "SHORT_BINSTRING", 1, 0, false, 1, LEN_U8),
+    op(0x56, "UNICODE", 0, 0, false, 1, Arg::Line),
+    op(0x58, "BINUNICODE", 1, 0, false, 1, LEN_U32),
+    op(0x5d, "EMPTY_LIST", 1, 0, false, 1, NO_ARG),
+    op(0x61, "APPEND", 0, 2, false, 1, NO_ARG),
+    op(0x62, "BUILD", 0, 2, false, 1, NO_ARG),
+    op(0x63, "GLOBAL", 0, 0, false, 1, Arg::TwoLines),
+    op(0x64, "DICT", 0, 0, true, 1, NO_ARG),
+    op(0x65, "APPENDS", 1, 1, true, 1, NO_ARG),
+    op(0x67, "GET", 0, 0, false, 1, Arg::Line),
+    op(0x68, "BINGET", 1, 0, false, 1, Arg::Fixed(1)),
+    op(0x69, "INST", 0, 0, true, 1, Arg::TwoLines),
+    op(0x6a, "LONG_BINGET", 1, 0, false, 1, Arg::Fixed(4)),
+    op(0x6c, "LIST", 0, 0, true, 1, NO_ARG),
+    op(0x6f, "OBJ", 1, 0, true, 1, NO_ARG),
+    op(0x70, "PUT", 0, 0, false, 0, Arg::Line),
+    op(0x71, "BINPUT", 1, 0, false, 0, Arg::Fixed(1)),
+    op(0x72, "LONG_BINPUT", 1, 0, false, 0, Arg::Fixed(4)),
+    op(0x73, "SETITEM", 0, 3, false, 1, NO_ARG),
+    op(0x74, "TUPLE", 0, 0, true, 1, NO_ARG),
+    op(0x75, "SETITEMS", 1, 1, true, 1, NO_ARG),
+    op(0x7d, "EMPTY_DICT", 1, 0, false, 1, NO_ARG),
+    op(0x80, "PROTO", 2, 0, false, 0, Arg::Fixed(1)),
+    op(0x81, "NEWOBJ", 2, 2, false, 1, NO_ARG),
+    op(0x82, "EXT1", 2, 0, false, 1, Arg::Fixed(1)),
+    op(0x83, "EXT2", 2, 0, false, 1, Arg::Fixed(2)),
+    op(0x84, "EXT4", 2, 0, false, 1, Arg::Fixed(4)),
+    op(0x85, "TUPLE1", 2, 1, false, 1, NO_ARG),
+    op(0x86, "TUPLE2", 2, 2, false, 1, NO_ARG),
+    op(0x87, "TUPLE3", 2, 3, false, 1, NO_ARG),
+    op(0x88, "NEWTRUE", 2, 0, false, 1, NO_ARG),
+    op(0x89, "NEWFALSE", 2, 0, false, 1, NO_ARG),
+    op(0x8a, "LONG1", 2, 0, false, 1, LEN_U8),
+    op(0x8b, "LONG4", 2, 0, false, 1, LEN_I32),
+    op(0x8c, "SHORT_BINUNICODE", 4, 0, false, 1, LEN_U8),
+    op(0x8d, "BINUNICODE8", 4, 0, false, 1, LEN_I64),
+    op(0x8e, "BINBYTES8", 4, 0, false, 1, LEN_U64),
+    op(0x8f, "EMPTY_SET", 4, 0, false, 1, NO_ARG),
+    op(0x90, "ADDITEMS", 4, 1, true, 1, NO_ARG),
+    op(0x91, "FROZENSET", 4, 0, true, 1, NO_ARG),
+    op(0x92, "NEWOBJ_EX", 4, 3, false, 1, NO_ARG),
+    op(0x93, "STACK_GLOBAL", 4, 2, false, 1, NO_ARG),
+    op(0x94, "MEMOIZE", 4, 1, false, 1, NO_ARG),
+    op(0x95, "FRAME", 4, 0, false, 0, Arg::Fixed(8)),
+    op(0x96, "BYTEARRAY8", 5, 0, false, 1, LEN_U64),
+    op(0x97, "NEXT_BUFFER", 5, 0, false, 1, NO_ARG),
+    op(0x98, "READONLY_BUFFER", 5, 1, false, 1, NO_ARG),
+];
+
+/// Gets the information about an opcode
+pub fn info(opcode: u8) -> Option<&'static OpcodeInfo> {
+    OPCODES
+        .binary_search_by_key(&opcode, |info| info.code)
+        .ok()
+        .map(|i| &OPCODES[i])
+}
+
+/// Gets the name of an opcode as used by python's `pickletools`
+pub fn name(opcode: u8) -> Option<&'static str> {
+    info(opcode).map(|info| info.name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sorted() {
+        assert!(OPCODES.windows(2).all(|w| w[0].code < w[1].code));
+        assert_eq!(name(0x8c), Some("SHORT_BINUNICODE"));
+        assert_eq!(info(0x95).map(|i| i.proto), Some(4));
+        assert_eq!(name(0xff), None);
+        assert_eq!(info(b'c').map(|i| i.arg), Some(Arg::TwoLines));
+    }
+
+    #[test]
+    fn test_args() -> Result<(), crate::Error> {
+        // the reader consumes exactly the argument described in the table
+        for info in &OPCODES {
+            let mut data = vec![info.code];
+            match info.arg {
+                Arg::Fixed(n) => data.resize(1 + n as usize, 0),
+                Arg::Line if info.code == b'S' => data.extend_from_slice(b"'a'\n"),
+                Arg::Line => data.extend_from_slice(b"1\n"),
+                Arg::TwoLines => data.extend_from_slice(b"a\nb\n"),
+                Arg::Counted { size, .. } => {
+                    data.push(1);
+                    data.resize(1 + size as usize, 0);
+                    data.push(b'a');
+                }
+            }
+            let len = data.len();
+            data.push(b'.');
+            let mut reader = crate::reader::Reader::new(&data[..]);
+            reader.read_event(&mut Vec::new())?;
+            assert_eq!(reader.position(), len, "{}", info.name);
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,484 @@
+//! A module to write [`Value`]s as pickles
+//!
+//! The [`Pickler`] is the counterpart of the [`Unpickler`](crate::Unpickler): opcodes are
+//! chosen, memoized, batched and framed as python's pickler would do for the equivalent
+//! python object, so the output can be loaded by python.
+
+use std::{
+    fs::File,
+    io::{BufWriter, Write},
+    mem,
+    path::Path,
+};
+
+use crate::{errors::Error, reader::Event, value::Value, writer::Writer};
+
+/// Number of items per APPENDS, SETITEMS or ADDITEMS, as in python
+pub(crate) const BATCH_SIZE: usize = 1000;
+
+pub struct Pickler<W> {
+    writer: Writer<W>,
+    /// Number of memoized objects
+    memo_len: u32,
+}
+
+impl Pickler<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, protocol: u8) -> Result<Self, Error> {
+        Ok(Pickler::from_writer(Writer::create(path, protocol)?))
+    }
+}
+
+impl<W: Write> Pickler<W> {
+    /// Creates a pickler for the given protocol (0 to 5)
+    pub fn new(writer: W, protocol: u8) -> Result<Self, Error> {
+        Ok(Pickler::from_writer(Writer::new(writer, protocol)?))
+    }
+
+    /// Creates a pickler emitting the events of an existing writer
+    pub fn from_writer(writer: Writer<W>) -> Self {
+        Pickler {
+            writer,
+            memo_len: 0,
+        }
+    }
+
+    /// Sets a callback deciding which bytes payloads are written out-of-band (protocol 5)
+    ///
+    /// See [`Writer::with_buffer_callback`].
+    pub fn with_buffer_callback<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&[u8]) -> bool + 'static,
+    {
+        self.writer = self.writer.with_buffer_callback(callback);
+        self
+    }
+
+    /// Writes a complete pickle, from PROTO to STOP
+    pub fn dump(&mut self, value: &Value) -> Result<(), Error> {
+        self.memo_len = 0;
+        self.writer.write_proto()?;
+        self.writer.start_framing();
+        self.save(value)?;
+        self.writer.write_stop()?;
+        self.writer.end_framing()?;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+
+    fn memoize(&mut self) -> Result<(), Error> {
+        self.writer.write_memoize(self.memo_len)?;
+        self.memo_len += 1;
+        Ok(())
+    }
+
+    fn save(&mut self, value: &Value) -> Result<(), Error> {
+        match value {
+            Value::None => self.writer.write_none()?,
+            Value::Bool(b) => self.writer.write_bool(*b)?,
+            Value::Int(i) => self.writer.write_int(*i)?,
+            Value::BigInt(i) => self.writer.write_bigint(i)?,
+            Value::Float(f) => self.writer.write_float(*f)?,
+            Value::Str(s) => {
+                self.writer.write_str(s)?;
+                self.memoize()?;
+            }
+            Value::Bytes(bytes) => {
+                self.writer.write_bytes(bytes)?;
+                self.memoize()?;
+            }
+            // as python, buffers are not memoized
+            Value::Buffer { data, readonly } if *readonly => self.writer.write_bytes(data)?,
+            Value::Buffer { data, .. } if self.writer.protocol() >= 5 => {
+                self.writer.write_bytearray(data)?
+            }
+            Value::Buffer { data, .. } => {
+                // `bytearray(bytes)` before protocol 5
+                self.save_global(builtins(self.writer.protocol()), "bytearray")?;
+                self.save_tuple(&[Value::Bytes(data.to_vec())])?;
+                self.writer.write_event(Event::Reduce, &[])?;
+                self.memoize()?;
+            }
+            Value::List(items) => {
+                self.writer.write_empty_list()?;
+                self.memoize()?;
+                self.save_items(items)?;
+            }
+            Value::Tuple(items) => self.save_tuple(items)?,
+            Value::Dict(items) => {
+                self.writer.write_empty_dict()?;
+                self.memoize()?;
+                self.save_pairs(items)?;
+            }
+            Value::Set(items) if self.writer.protocol() >= 4 => {
+                self.writer.write_event(Event::EmptySet, &[])?;
+                self.memoize()?;
+                for batch in items.chunks(BATCH_SIZE) {
+                    self.writer.write_mark()?;
+                    for item in batch {
+                        self.save(item)?;
+                    }
+                    self.writer.write_event(Event::AdditItems, &[])?;
+                }
+            }
+            Value::FrozenSet(items) if self.writer.protocol() >= 4 => {
+                self.writer.write_mark()?;
+                for item in items {
+                    self.save(item)?;
+                }
+                self.writer.write_event(Event::FrozenSet, &[])?;
+                self.memoize()?;
+            }
+            Value::Set(items) | Value::FrozenSet(items) => {
+                // `set([items])` before protocol 4
+                let name = match value {
+                    Value::Set(_) => "set",
+                    _ => "frozenset",
+                };
+                self.save_global(builtins(self.writer.protocol()), name)?;
+                let list = Value::List(items.clone());
+                self.save_tuple(std::slice::from_ref(&list))?;
+                self.writer.write_event(Event::Reduce, &[])?;
+                self.memoize()?;
+            }
+            Value::Global { module, name } => self.save_global(module, name)?,
+            Value::Reduce { callable, args } => {
+                self.save(callable)?;
+                self.save(args)?;
+                self.writer.write_event(Event::Reduce, &[])?;
+                self.memoize()?;
+            }
+            Value::Object {
+                class,
+                args,
+                kwargs,
+            } => {
+                self.save(class)?;
+                self.save(args)?;
+                match kwargs {
+                    Some(kwargs) => {
+                        self.save(kwargs)?;
+                        self.writer.write_event(Event::NewObjEx, &[])?;
+                    }
+                    None => self.writer.write_event(Event::NewObj, &[])?,
+                }
+                self.memoize()?;
+            }
+            Value::Build { object, state } => {
+                self.save(object)?;
+                self.save(state)?;
+                self.writer.write_event(Event::Build, &[])?;
+            }
+            Value::Extend { object, items } => {
+                self.save(object)?;
+                match &**items {
+                    Value::List(items) => self.save_items(items)?,
+                    Value::Dict(items) => self.save_pairs(items)?,
+                    items => {
+                        return Err(Error::unexpected("list or dict items", items.type_name()));
+                    }
+                }
+            }
+        }
+        self.writer.frame_boundary()
+    }
+
+    /// Writes a global, memoizing its module and name strings from protocol 4 as python does
+    fn save_global(&mut self, module: &str, name: &str) -> Result<(), Error> {
+        if self.writer.protocol() >= 4 {
+            for s in [module, name] {
+                self.writer.write_str(s)?;
+                self.memoize()?;
+            }
+            self.writer.write_event(Event::StackGlobal, &[])?;
+        } else {
+            self.writer.write_global(module, name)?;
+        }
+        self.memoize()
+    }
+
+    fn save_tuple(&mut self, items: &[Value]) -> Result<(), Error> {
+        if items.is_empty() {
+            return self.writer.write_empty_tuple();
+        }
+        let marked = items.len() > 3 || self.writer.protocol() < 2;
+        if marked {
+            self.writer.write_mark()?;
+        }
+        for item in items {
+            self.save(item)?;
+        }
+        self.writer.write_tuple(items.len())?;
+        self.memoize()
+    }
+
+    /// Appends items to the list on top of the stack
+    fn save_items(&mut self, items: &[Value]) -> Result<(), Error> {
+        let mut batch = Batch::list(self.writer.protocol(), Some(items.len()));
+        for item in items {
+            batch.start_item(&mut self.writer)?;
+            self.save(item)?;
+            batch.end_item(&mut self.writer)?;
+        }
+        batch.finish(&mut self.writer)
+    }
+
+    /// Sets items on the dict on top of the stack
+    fn save_pairs(&mut self, items: &[(Value, Value)]) -> Result<(), Error> {
+        let mut batch = Batch::dict(self.writer.protocol(), Some(items.len()));
+        for (key, value) in items {
+            batch.start_item(&mut self.writer)?;
+            self.save(key)?;
+            self.save(value)?;
+            batch.end_item(&mut self.writer)?;
+        }
+        batch.finish(&mut self.writer)
+    }
+}
+
+/// Groups the items appended to a list, or set on a dict, as python's `_batch_appends` and
+/// `_batch_setitems` do
+///
+/// From protocol 1, items are batched by [`BATCH_SIZE`] between MARK and APPENDS or
+/// SETITEMS, but a chunk of a single item, e.g. the last one of 1001 items, has its own
+/// APPEND or SETITEM. Protocol 0 has one APPEND or SETITEM per item.
+pub(crate) struct Batch {
+    single: Event,
+    batch: Event,
+    batched: bool,
+    /// Number of items left, if known
+    remaining: Option<usize>,
+    /// How the current chunk is written
+    chunk: Chunk,
+    /// Number of items in the current chunk
+    open: usize,
+}
+
+/// How the items of a chunk are written
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Chunk {
+    /// No chunk is open
+    Closed,
+    /// The last item, which is alone in its chunk
+    Single,
+    /// The first item, held back until it is known whether another one follows
+    Held,
+    /// Items after a MARK
+    Marked,
+}
+
+impl Batch {
+    pub(crate) fn list(protocol: u8, len: Option<usize>) -> Self {
+        Batch::new(Event::Append, Event::Appends, protocol, len)
+    }
+
+    pub(crate) fn dict(protocol: u8, len: Option<usize>) -> Self {
+        Batch::new(Event::SetItem, Event::SetItems, protocol, len)
+    }
+
+    fn new(single: Event, batch: Event, protocol: u8, len: Option<usize>) -> Self {
+        Batch {
+            single,
+            batch,
+            batched: protocol >= 1,
+            remaining: len,
+            chunk: Chunk::Closed,
+            open: 0,
+        }
+    }
+
+    /// Starts an item, before writing it
+    pub(crate) fn start_item<W: Write>(&mut self, writer: &mut Writer<W>) -> Result<(), Error> {
+        let remaining = self.remaining;
+        self.remaining = remaining.map(|n| n.saturating_sub(1));
+        if !self.batched {
+            return Ok(());
+        }
+        self.chunk = match (self.chunk, remaining) {
+            (Chunk::Closed, Some(1)) => Chunk::Single,
+            // without the length, the first item waits for the next one
+            (Chunk::Closed, None) => {
+                writer.hold();
+                Chunk::Held
+            }
+            (Chunk::Closed, _) => {
+                writer.write_mark()?;
+                Chunk::Marked
+            }
+            (Chunk::Held, _) => {
+                writer.release(true)?;
+                Chunk::Marked
+            }
+            (chunk, _) => chunk,
+        };
+        self.open += 1;
+        Ok(())
+    }
+
+    /// Ends an item, after writing it
+    pub(crate) fn end_item<W: Write>(&mut self, writer: &mut Writer<W>) -> Result<(), Error> {
+        match self.chunk {
+            Chunk::Closed | Chunk::Single => {
+                self.chunk = Chunk::Closed;
+                self.open = 0;
+                writer.write_event(self.single, &[])
+            }
+            Chunk::Held | Chunk::Marked if self.open == BATCH_SIZE => self.finish(writer),
+            Chunk::Held | Chunk::Marked => Ok(()),
+        }
+    }
+
+    /// Closes the open chunk, if any
+    pub(crate) fn finish<W: Write>(&mut self, writer: &mut Writer<W>) -> Result<(), Error> {
+        let chunk = mem::replace(&mut self.chunk, Chunk::Closed);
+        self.open = 0;
+        match chunk {
+            Chunk::Closed | Chunk::Single => Ok(()),
+            Chunk::Held => {
+                writer.release(false)?;
+                writer.write_event(self.single, &[])
+            }
+            Chunk::Marked => writer.write_event(self.batch, &[]),
+        }
+    }
+}
+
+/// Gets the module of the builtins, as named by python 2 before protocol 3
+fn builtins(protocol: u8) -> &'static str {
+    if protocol >= 3 {
+        "builtins"
+    } else {
+        "__builtin__"
+    }
+}
+
+/// Writes a value as a pickle into a writer
+pub fn dump<W: Write>(value: &Value, writer: W, protocol: u8) -> Result<(), Error> {
+    Pickler::new(writer, protocol)?.dump(value)
+}
+
+/// Writes a value as pickle bytes
+pub fn dumps(value: &Value, protocol: u8) -> Result<Vec<u8>, Error> {
+    let mut pickler = Pickler::new(Vec::new(), protocol)?;
+    pickler.dump(value)?;
+    Ok(pickler.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Unpickler;
+
+    #[test]
+    fn test_dump() -> Result<(), Error> {
+        let value = Value::List(vec![
+            Value::Int(1),
+            Value::Str("a".into()),
+            Value::Tuple(vec![Value::Int(1), Value::Int(2)]),
+            Value::Dict(vec![(Value::Str("x".into()), Value::Bytes(b"y".to_vec()))]),
+            Value::Set(vec![Value::Int(3)]),
+            Value::FrozenSet(vec![Value::Int(4)]),
+            Value::None,
+            Value::Bool(true),
+        ]);
+        // pickle.dumps([1, 'a', (1, 2), {'x': b'y'}, {3}, frozenset([4]), None, True], protocol=4)
+        let expected = b"\x80\x04\x95)\x00\x00\x00\x00\x00\x00\x00]\x94(K\x01\x8c\x01a\x94K\x01K\x02\x86\x94}\x94\x8c\x01x\x94C\x01y\x94s\x8f\x94(K\x03\x90(K\x04\x91\x94N\x88e.";
+        assert_eq!(dumps(&value, 4)?, expected);
+
+        let mut items = value.as_slice().unwrap().to_vec();
+        items.push(Value::Buffer {
+            data: std::sync::Arc::from(&b"z"[..]),
+            readonly: false,
+        });
+        let value = Value::List(items);
+        let reduced = |value: &Value, expected: &str| {
+            matches!(value, Value::Reduce { callable, .. }
+                if matches!(&**callable, Value::Global { name, .. } if name == expected))
+        };
+        for protocol in 0..=5 {
+            let data = dumps(&value, protocol)?;
+            let loaded = Unpickler::new(&data[..]).load()?;
+            let items = loaded.as_slice().unwrap();
+            if protocol >= 4 {
+                assert_eq!(items[..8], value.as_slice().unwrap()[..8]);
+            } else {
+                // sets are pickled as `set([items])`
+                assert!(reduced(&items[4], "set"));
+            }
+            if protocol >= 5 {
+                // in-band BYTEARRAY8 payloads are loaded as bytes
+                assert_eq!(items[8], Value::Bytes(b"z".to_vec()));
+            } else {
+                // mutable buffers are pickled as `bytearray(bytes)`
+                assert!(reduced(&items[8], "bytearray"), "{protocol}");
+            }
+        }
+        // pickle.dumps(bytearray(b'z'), protocol=3)
+        let buffer = &value.as_slice().unwrap()[8];
+        assert_eq!(
+            dumps(buffer, 3)?,
+            b"\x80\x03cbuiltins\nbytearray\nq\x00C\x01zq\x01\x85q\x02Rq\x03."
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_buffers() -> Result<(), Error> {
+        use std::sync::{Arc, mpsc};
+
+        let value = Value::List(vec![
+            Value::Bytes(vec![1; 2048]),
+            Value::Bytes(b"xyz".to_vec()),
+            Value::Buffer {
+                data: Arc::from(&[2; 2048][..]),
+                readonly: false,
+            },
+        ]);
+        let (tx, rx) = mpsc::channel();
+        let mut pickler = Pickler::new(Vec::new(), 5)?
+            .with_buffer_callback(move |b| b.len() > 1024 && tx.send(b.to_vec()).is_ok());
+        pickler.dump(&value)?;
+        let data = pickler.into_inner();
+        assert_eq!(
+            data,
+            b"\x80\x05\x95\x0f\x00\x00\x00\x00\x00\x00\x00]\x94(\x97\x98\x94C\x03xyz\x94\x97e."
+        );
+        let buffers = rx.try_iter().collect::<Vec<_>>();
+        assert_eq!(buffers.len(), 2);
+        let loaded = Unpickler::new(&data[..]).with_buffers(buffers).load()?;
+        let items = loaded.as_slice().unwrap();
+        assert_eq!(items[0].as_bytes(), Some(&[1; 2048][..]));
+        assert_eq!(items[1], Value::Bytes(b"xyz".to_vec()));
+        assert_eq!(items[2], value.as_slice().unwrap()[2]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_objects() -> Result<(), Error> {
+        // pickle.dumps(collections.OrderedDict(a=1), protocol=4)
+        let data = b"\x80\x04\x95)\x00\x00\x00\x00\x00\x00\x00\x8c\x0bcollections\x94\x8c\x0bOrderedDict\x94\x93\x94)R\x94\x8c\x01a\x94K\x01s.";
+        let value = Unpickler::new(&data[..]).load()?;
+        assert_eq!(dumps(&value, 4)?, data);
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump_batches() -> Result<(), Error> {
+        // pickle._dumps(list(range(1001)), protocol=2) ends with a lone APPEND
+        let value = Value::List((0..1001).map(Value::Int).collect());
+        let data = dumps(&value, 2)?;
+        assert_eq!(data.len(), 2756);
+        assert!(data.ends_with(b"M\xe6\x03M\xe7\x03eM\xe8\x03a."));
+        // pickle._dumps({i: i for i in range(1001)}, protocol=2) ends with a lone SETITEM
+        let value = Value::Dict((0..1001).map(|i| (Value::Int(i), Value::Int(i))).collect());
+        let data = dumps(&value, 2)?;
+        assert_eq!(data.len(), 5503);
+        assert!(data.ends_with(b"M\xe7\x03uM\xe8\x03M\xe8\x03s."));
+        // pickle.dumps([[1]], protocol=2)
+        let value = Value::List(vec![Value::List(vec![Value::Int(1)])]);
+        assert_eq!(dumps(&value, 2)?, b"\x80\x02]q\x00]q\x01K\x01aa.");
+        Ok(())
+    }
+}
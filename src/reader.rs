@@ -2,18 +2,30 @@
 
 use std::{
     fs::File,
-    io::{BufRead, BufReader, Cursor},
+    io::{BufRead, BufReader, Read},
+    ops::Range,
     path::Path,
     str::from_utf8,
-    sync::mpsc::channel,
     thread::{self},
 };
 
-use crate::errors::Error;
+use crate::{
+    bigint::{BigInt, decode_le},
+    errors::{Error, ErrorKind},
+    opcodes::{self, Arg},
+    slice_reader::SliceReader,
+};
 
-const FRAME_SPAWN_SIZE: u64 = 1024 * 128;
-// const FRAME_SPAWN_SIZE: u64 = 1 << 32;
+pub(crate) const FRAME_SPAWN_SIZE: u64 = 1024 * 128;
+/// Maximum allocation made for a payload before its bytes are actually read
+const PREALLOC_SIZE: usize = 1024 * 1024;
+// pub(crate) const FRAME_SPAWN_SIZE: u64 = 1 << 32;
 
+/// An opcode with its arguments
+///
+/// Text opcodes whose argument is a number (INT, LONG, FLOAT, GET and PUT) leave their line,
+/// with its line ending, as payload, so that the [`Writer`](crate::writer::Writer) can write it back
+/// as is.
 #[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Event {
     // Protocol identification
@@ -30,25 +42,52 @@ pub enum Event {
     // Basic types
     None,
     Bool(bool),
-    Int(i32),
+    /// INT, which python 2 also wrote for 64-bit integers
+    Int(i64),
     BinInt(i32),
     BinInt1(u8),
     BinInt2(u16),
     Long(i64),
+    /// Integer which doesn't fit in an i64, as little-endian two's complement bytes in the buffer
+    BigLong {
+        len: usize,
+    },
     Float(f64),
 
     // Strings and bytes
-    String { len: usize },
-    BinString { len: i32 },
-    ShortBinString { len: u8 },
-    Unicode { len: usize },
-    BinUnicode { len: i32 },
-    ShortBinUnicode { len: u8 },
-    BinUnicode8 { len: i64 },
-    BinBytes { len: i32 },
-    ShortBinBytes { len: u8 },
-    BinBytes8 { len: u64 },  // immutable
-    ByteArray8 { len: u64 }, // mutable
+    String {
+        len: usize,
+    },
+    BinString {
+        len: i32,
+    },
+    ShortBinString {
+        len: u8,
+    },
+    Unicode {
+        len: usize,
+    },
+    BinUnicode {
+        len: u32,
+    },
+    ShortBinUnicode {
+        len: u8,
+    },
+    BinUnicode8 {
+        len: i64,
+    },
+    BinBytes {
+        len: u32,
+    },
+    ShortBinBytes {
+        len: u8,
+    },
+    BinBytes8 {
+        len: u64,
+    }, // immutable
+    ByteArray8 {
+        len: u64,
+    }, // mutable
 
     // Collections
     EmptyTuple,
@@ -78,17 +117,34 @@ pub enum Event {
     Memoize,
 
     // Object construction
-    Global { module_len: u32, name_len: u32 },
+    /// The payload is the module then the name, without their newlines
+    Global {
+        module_len: u32,
+        name_len: u32,
+        /// Whether the lines end with `\r\n` rather than `\n`
+        crlf: bool,
+    },
     StackGlobal,
     Reduce,
     Build,
-    Inst { module_len: u32, name_len: u32 },
+    /// The payload is the module then the name, without their newlines
+    Inst {
+        module_len: u32,
+        name_len: u32,
+        /// Whether the lines end with `\r\n` rather than `\n`
+        crlf: bool,
+    },
     Obj,
     NewObj,
     NewObjEx,
 
     // Persistent objects
-    PersId { id_len: usize },
+    /// The payload is the id, without its newline
+    PersId {
+        id_len: usize,
+        /// Whether the line ends with `\r\n` rather than `\n`
+        crlf: bool,
+    },
     BinPersId,
 
     // Extensions
@@ -101,9 +157,73 @@ pub enum Event {
     ReadonlyBuffer,
 }
 
+/// A value with the byte offsets it spans in the input
+///
+/// For events, `start` is the offset of the opcode and `end` the offset after its payload.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Spanned<T> {
+    pub value: T,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl<T> Spanned<T> {
+    pub fn span(&self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+/// Limits protecting against hostile pickles
+///
+/// Lengths read from the stream are checked before anything is allocated. All limits
+/// default to `usize::MAX`, except [`Limits::max_copies`] and [`Limits::max_depth`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Limits {
+    /// Maximum length of a single payload (string, bytes, long or text argument)
+    pub max_payload: usize,
+    /// Maximum number of bytes read from the input
+    pub max_total: usize,
+    /// Maximum length of a FRAME
+    pub max_frame: usize,
+    /// Maximum number of memo entries
+    pub max_memo: usize,
+    /// Maximum number of objects on the stack, and of nested marks
+    pub max_stack: usize,
+    /// Maximum number of items in a single container
+    pub max_container: usize,
+    /// Maximum number of values copied to build a result with shared objects
+    ///
+    /// A value referenced several times from the memo is copied at each reference, so a
+    /// few bytes of nested references can expand exponentially. Defaults to 2^24 values.
+    pub max_copies: usize,
+    /// Maximum nesting of containers and objects in the result
+    ///
+    /// Nested values are built, compared and dropped recursively. Defaults to 1000 levels,
+    /// python's recursion limit, which also bounds what `pickle.dumps` can write.
+    pub max_depth: usize,
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Limits {
+            max_payload: usize::MAX,
+            max_total: usize::MAX,
+            max_frame: usize::MAX,
+            max_memo: usize::MAX,
+            max_stack: usize::MAX,
+            max_container: usize::MAX,
+            max_copies: 1 << 24,
+            max_depth: 1000,
+        }
+    }
+}
+
 pub struct Reader<R> {
     reader: R,
-    pos: usize,
+    pub(crate) pos: usize,
+    /// Last opcode read
+    pub(crate) opcode: u8,
+    limits: Limits,
 }
 
 impl Reader<BufReader<File>> {
@@ -113,21 +233,55 @@ impl Reader<BufReader<File>> {
     }
 }
 
+#[cfg(feature = "memmap2")]
+impl Reader<std::io::Cursor<memmap2::Mmap>> {
+    /// Memory-maps a file
+    ///
+    /// Use [`Reader::slice_reader`] to read events without copying frames or payloads.
+    ///
+    /// # Safety
+    ///
+    /// The file must not be modified or truncated, by this process or another one, while the
+    /// reader or any slice borrowed from it is alive: the map would change under borrowed
+    /// payloads, or accessing truncated pages would crash the process. See
+    /// [`memmap2::Mmap::map`].
+    pub unsafe fn open_mmap<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let file = File::open(path)?;
+        // SAFETY: the caller guarantees that the file is not modified while it is mapped
+        let mmap = unsafe { memmap2::Mmap::map(&file)? };
+        Ok(Reader::new(std::io::Cursor::new(mmap)))
+    }
+
+    /// Gets a zero-copy reader starting at the current position, with the same limits
+    ///
+    /// Its [`SliceReader::par_collect_events`] hands frames borrowed from the map to workers.
+    pub fn slice_reader(&self) -> SliceReader<'_> {
+        SliceReader::new_at(self.reader.get_ref(), self.pos).with_limits(self.limits)
+    }
+}
+
 impl<R: BufRead> Reader<R> {
     pub fn new(reader: R) -> Self {
-        Reader { reader, pos: 0 }
+        Reader::new_at(reader, 0)
     }
 
-    /// Load len bytes and create a new frame reader
-    fn frame_reader(&mut self, len: u64) -> Result<Reader<Cursor<Vec<u8>>>, Error> {
-        let start = self.pos;
-        let mut frame_buf = Vec::new();
-        self.fill_buf(len as usize, &mut frame_buf)?;
-        Ok(Reader::new_at(Cursor::new(frame_buf), start))
+    pub(crate) fn new_at(reader: R, start: usize) -> Reader<R> {
+        Reader {
+            reader,
+            pos: start,
+            opcode: 0,
+            limits: Limits::default(),
+        }
+    }
+
+    /// Sets the limits checked while reading, and by the [`Unpickler`](crate::Unpickler)
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
     }
 
-    fn new_at(reader: R, start: usize) -> Reader<R> {
-        Reader { reader, pos: start }
+    pub fn limits(&self) -> &Limits {
+        &self.limits
     }
 
     fn read_u8(&mut self) -> Result<u8, Error> {
@@ -179,30 +333,144 @@ impl<R: BufRead> Reader<R> {
         Ok(i32::from_le_bytes(bytes))
     }
 
+    /// Reads a payload of `len` bytes
     fn fill_buf(&mut self, len: usize, buf: &mut Vec<u8>) -> Result<(), Error> {
-        let buf_len = buf.len();
-        buf.resize(buf_len + len, 0);
-        self.reader.read_exact(&mut buf[buf_len..])?;
-        self.pos += len;
+        if len > self.limits.max_payload {
+            let max = self.limits.max_payload;
+            return Err(ErrorKind::PayloadTooLarge { len, max }.into());
+        }
+        self.read_into(len, buf)
+    }
+
+    /// Reads `len` bytes, without trusting `len` for the allocation
+    fn read_into(&mut self, len: usize, buf: &mut Vec<u8>) -> Result<(), Error> {
+        if self.pos.saturating_add(len) > self.limits.max_total {
+            let max = self.limits.max_total;
+            return Err(ErrorKind::InputTooLarge { max }.into());
+        }
+        if len <= PREALLOC_SIZE {
+            let buf_len = buf.len();
+            buf.resize(buf_len + len, 0);
+            self.reader.read_exact(&mut buf[buf_len..])?;
+            self.pos += len;
+            return Ok(());
+        }
+        // large payloads: the buffer grows with the data actually read
+        buf.reserve(PREALLOC_SIZE);
+        let read = (&mut self.reader).take(len as u64).read_to_end(buf)?;
+        self.pos += read;
+        if read < len {
+            return Err(std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into());
+        }
         Ok(())
     }
 
     fn fill_line(&mut self, buf: &mut Vec<u8>) -> Result<usize, Error> {
-        let len = self.reader.read_until(b'\n', buf)?;
+        let max = self.limits.max_payload;
+        let limit = max.saturating_add(1) as u64;
+        let len = (&mut self.reader).take(limit).read_until(b'\n', buf)?;
         self.pos += len;
+        if len > max {
+            return Err(ErrorKind::PayloadTooLarge { len, max }.into());
+        }
+        if self.pos > self.limits.max_total {
+            let max = self.limits.max_total;
+            return Err(ErrorKind::InputTooLarge { max }.into());
+        }
         Ok(len)
     }
 
-    pub fn read_event<'a>(&mut self, buf: &mut Vec<u8>) -> Result<Event, Error> {
+    /// Reads a line into the buffer without its newline, returning its length and whether it
+    /// ended with `\r\n`
+    fn fill_stripped_line(&mut self, buf: &mut Vec<u8>) -> Result<(usize, bool), Error> {
+        let start = buf.len();
+        self.fill_line(buf)?;
+        let crlf = buf[start..].ends_with(b"\r\n");
+        let len = trim_line(&buf[start..]).len();
+        buf.truncate(start + len);
+        Ok((len, crlf))
+    }
+
+    /// Converts the binary long at `buf[start..]` into a `Long` if it fits in an i64
+    fn big_long_in_buf(&self, start: usize, buf: &mut Vec<u8>) -> Event {
+        match decode_le::<8>(&buf[start..]) {
+            Some(bytes) => {
+                buf.truncate(start);
+                Event::Long(i64::from_le_bytes(bytes))
+            }
+            None => Event::BigLong {
+                len: buf.len() - start,
+            },
+        }
+    }
+
+    /// Gets the offset of the next opcode in the input
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Checks if the whole input has been read
+    pub(crate) fn at_eof(&mut self) -> Result<bool, Error> {
+        Ok(self.reader.fill_buf()?.is_empty())
+    }
+
+    /// Reads the next event along with the offsets of its opcode and payload end
+    ///
+    /// A STOP faked at the end of the input has an empty span.
+    pub fn read_event_with_span(&mut self, buf: &mut Vec<u8>) -> Result<Spanned<Event>, Error> {
+        let start = self.pos;
+        let value = self.read_event(buf)?;
+        Ok(Spanned {
+            value,
+            start,
+            end: self.pos,
+        })
+    }
+
+    /// Iterates over the events of a pickle, up to and including its STOP opcode
+    ///
+    /// Unlike [`Reader::read_event`], an input ending without a STOP opcode is an error.
+    ///
+    /// ```
+    /// use quick_pickle::reader::{Event, Reader};
+    ///
+    /// let mut reader = Reader::new(&b"\x80\x04K\x01."[..]);
+    /// let events = reader.events().map(|e| e.map(|e| e.event)).collect::<Result<Vec<_>, _>>()?;
+    /// assert_eq!(events, [Event::Proto(4), Event::BinInt1(1), Event::Stop]);
+    ///
+    /// assert!(Reader::new(&b"\x80\x04K\x01"[..]).events().any(|e| e.is_err()));
+    /// # Ok::<(), quick_pickle::Error>(())
+    /// ```
+    pub fn events(&mut self) -> EventIter<'_, R> {
+        EventIter {
+            reader: self,
+            buf: Vec::new(),
+            done: false,
+        }
+    }
+
+    pub fn read_event(&mut self, buf: &mut Vec<u8>) -> Result<Event, Error> {
+        let start = self.pos;
+        // the end of the input is not past the limit
+        if start >= self.limits.max_total && !self.at_eof().map_err(|e| e.at(start, 0))? {
+            let max = self.limits.max_total;
+            return Err(Error::from(ErrorKind::InputTooLarge { max }).at(start, 0));
+        }
         let opcode = match self.read_u8() {
             Ok(opcode) => opcode,
-            Err(Error::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => {
+            Err(e) if matches!(e.kind(), ErrorKind::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof) =>
+            {
                 // fake a stop event
                 return Ok(Event::Stop);
             }
-            Err(e) => return Err(e),
+            Err(e) => return Err(e.at(start, 0)),
         };
+        self.opcode = opcode;
+        self.read_args(opcode, buf).map_err(|e| e.at(start, opcode))
+    }
 
+    /// Reads the arguments of an opcode
+    fn read_args(&mut self, opcode: u8, buf: &mut Vec<u8>) -> Result<Event, Error> {
         match opcode {
             // Protocol identification
             0x80 => Ok(Event::Proto(self.read_u8()?)),
@@ -222,15 +490,15 @@ impl<R: BufRead> Reader<R> {
                 // INT - decimal string
                 let start = buf.len();
                 let _ = self.fill_line(buf)?;
-                let s = &buf[start..];
+                let s = buf[start..].trim_ascii_end();
                 let event = if s == b"01" {
                     Event::Bool(true)
                 } else if s == b"00" {
                     Event::Bool(false)
                 } else {
-                    Event::Int(atoi::atoi::<i32>(s).ok_or(Error::Protocol(0x49))?)
+                    // python 2 could write 64-bit ints with INT
+                    Event::Int(atoi::atoi::<i64>(s).ok_or_else(|| not_int(s))?)
                 };
-                buf.truncate(start);
                 Ok(event)
             }
             0x4a => Ok(Event::BinInt(self.read_i32()?)), // J
@@ -240,38 +508,39 @@ impl<R: BufRead> Reader<R> {
                 // LONG - decimal string
                 let start = buf.len();
                 let _ = self.fill_line(buf)?;
-                if buf.last() == Some(&b'L') {
-                    buf.pop();
+                let s = buf[start..].trim_ascii_end();
+                let s = s.strip_suffix(b"L").unwrap_or(s);
+                match atoi::atoi::<i64>(s) {
+                    Some(long) => Ok(Event::Long(long)),
+                    None => {
+                        // the payload of a big integer is its binary form
+                        let big = BigInt::parse_decimal(s)?;
+                        let len = big.as_signed_bytes_le().len();
+                        buf.truncate(start);
+                        buf.extend_from_slice(big.as_signed_bytes_le());
+                        Ok(Event::BigLong { len })
+                    }
                 }
-                let long = atoi::atoi(&buf[start..]).ok_or(Error::Protocol(0x4c))?;
-                buf.truncate(start);
-                Ok(Event::Long(long))
             }
             0x8a => {
-                // LONG1
+                // LONG1 - little-endian two's complement
                 let start = buf.len();
                 let len = self.read_u8()? as usize;
-                let _ = self.fill_buf(len, buf)?;
-                let long = atoi::atoi(&buf[start..]).ok_or(Error::Protocol(0x8a))?;
-                buf.truncate(start);
-                Ok(Event::Long(long))
+                self.fill_buf(len, buf)?;
+                Ok(self.big_long_in_buf(start, buf))
             }
             0x8b => {
-                // LONG4
+                // LONG4 - little-endian two's complement
                 let start = buf.len();
-                let len = self.read_i32()? as usize;
-                let _ = self.fill_buf(len, buf)?;
-                let long = atoi::atoi(&buf[start..]).ok_or(Error::Protocol(0x8a))?;
-                buf.truncate(start);
-                Ok(Event::Long(long))
+                let len = self.read_i32()?;
+                self.fill_buf(payload_len(len)?, buf)?;
+                Ok(self.big_long_in_buf(start, buf))
             }
             0x46 => {
                 // FLOAT - decimal string
                 let start = buf.len();
                 let _ = self.fill_line(buf)?;
-                let s = from_utf8(&buf[start..]).map_err(Error::Str)?;
-                let v = s.parse().map_err(|_| Error::Protocol(0x46))?;
-                buf.truncate(start);
+                let v = from_utf8(buf[start..].trim_ascii_end())?.parse()?;
                 Ok(Event::Float(v))
             }
             0x47 => Ok(Event::Float(self.read_f64()?)), // G
@@ -285,7 +554,7 @@ impl<R: BufRead> Reader<R> {
             0x54 => {
                 // BINSTRING
                 let len = self.read_i32()?;
-                self.fill_buf(len as usize, buf)?;
+                self.fill_buf(payload_len(len)?, buf)?;
                 Ok(Event::BinString { len })
             }
             0x55 => {
@@ -301,8 +570,8 @@ impl<R: BufRead> Reader<R> {
             }
             0x58 => {
                 // BINUNICODE
-                let len = self.read_i32()?;
-                self.fill_buf(len as usize, buf)?;
+                let len = self.read_u32()?;
+                self.fill_buf(payload_len(len)?, buf)?;
                 Ok(Event::BinUnicode { len })
             }
             0x8c => {
@@ -314,13 +583,13 @@ impl<R: BufRead> Reader<R> {
             0x8d => {
                 // BINUNICODE8
                 let len = self.read_i64()?;
-                self.fill_buf(len as usize, buf)?;
+                self.fill_buf(payload_len(len)?, buf)?;
                 Ok(Event::BinUnicode8 { len })
             }
             0x42 => {
                 // BINBYTES
-                let len = self.read_i32()?;
-                self.fill_buf(len as usize, buf)?;
+                let len = self.read_u32()?;
+                self.fill_buf(payload_len(len)?, buf)?;
                 Ok(Event::BinBytes { len })
             }
             0x43 => {
@@ -332,13 +601,13 @@ impl<R: BufRead> Reader<R> {
             0x8e => {
                 // BINBYTES8
                 let len = self.read_u64()?;
-                self.fill_buf(len as usize, buf)?;
+                self.fill_buf(payload_len(len)?, buf)?;
                 Ok(Event::BinBytes8 { len })
             }
             0x96 => {
                 // BYTEARRAY8
                 let len = self.read_u64()?;
-                self.fill_buf(len as usize, buf)?;
+                self.fill_buf(payload_len(len)?, buf)?;
                 Ok(Event::ByteArray8 { len })
             }
 
@@ -365,8 +634,8 @@ impl<R: BufRead> Reader<R> {
                 // GET
                 let start = buf.len();
                 let _ = self.fill_line(buf)?;
-                let id = atoi::atoi::<i32>(&buf[start..]).ok_or(Error::Protocol(0x67))?;
-                buf.truncate(start);
+                let s = buf[start..].trim_ascii_end();
+                let id = atoi::atoi::<i32>(s).ok_or_else(|| not_int(s))?;
                 Ok(Event::Get(id))
             }
             0x68 => Ok(Event::BinGet(self.read_u8()?)), // h
@@ -375,8 +644,8 @@ impl<R: BufRead> Reader<R> {
                 // PUT
                 let start = buf.len();
                 let _ = self.fill_line(buf)?;
-                let id = atoi::atoi::<i32>(&buf[start..]).ok_or(Error::Protocol(0x70))?;
-                buf.truncate(start);
+                let s = buf[start..].trim_ascii_end();
+                let id = atoi::atoi::<i32>(s).ok_or_else(|| not_int(s))?;
                 Ok(Event::Put(id))
             }
             0x71 => Ok(Event::BinPut(self.read_u8()?)), // q
@@ -386,9 +655,12 @@ impl<R: BufRead> Reader<R> {
             // Object construction
             0x63 => {
                 // GLOBAL
+                let (module_len, crlf) = self.fill_stripped_line(buf)?;
+                let (name_len, _) = self.fill_stripped_line(buf)?;
                 Ok(Event::Global {
-                    module_len: self.fill_line(buf)? as u32,
-                    name_len: self.fill_line(buf)? as u32,
+                    module_len: module_len as u32,
+                    name_len: name_len as u32,
+                    crlf,
                 })
             }
             0x93 => Ok(Event::StackGlobal), // STACK_GLOBAL
@@ -396,9 +668,12 @@ impl<R: BufRead> Reader<R> {
             0x62 => Ok(Event::Build),       // b
             0x69 => {
                 // INST
+                let (module_len, crlf) = self.fill_stripped_line(buf)?;
+                let (name_len, _) = self.fill_stripped_line(buf)?;
                 Ok(Event::Inst {
-                    module_len: self.fill_line(buf)? as u32,
-                    name_len: self.fill_line(buf)? as u32,
+                    module_len: module_len as u32,
+                    name_len: name_len as u32,
+                    crlf,
                 })
             }
             0x6f => Ok(Event::Obj),      // o
@@ -408,8 +683,8 @@ impl<R: BufRead> Reader<R> {
             // Persistent objects
             0x50 => {
                 // PERSID
-                let id_len = self.fill_line(buf)?;
-                Ok(Event::PersId { id_len })
+                let (id_len, crlf) = self.fill_stripped_line(buf)?;
+                Ok(Event::PersId { id_len, crlf })
             }
             0x51 => Ok(Event::BinPersId), // Q
 
@@ -419,68 +694,360 @@ impl<R: BufRead> Reader<R> {
             0x84 => Ok(Event::Ext4(self.read_u32()?)), // EXT4
 
             // Protocol 4
-            0x95 => Ok(Event::Frame(self.read_u64()?)), // FRAME
+            0x95 => {
+                // FRAME
+                let len = self.read_u64()?;
+                if len > self.limits.max_frame as u64 {
+                    let max = self.limits.max_frame;
+                    return Err(ErrorKind::FrameTooLarge { len, max }.into());
+                }
+                Ok(Event::Frame(len))
+            }
 
             // Protocol 5
             0x97 => Ok(Event::NextBuffer),     // NEXT_BUFFER
             0x98 => Ok(Event::ReadonlyBuffer), // READONLY_BUFFER
 
-            _ => Err(Error::OpCode(opcode)),
+            _ => Err(ErrorKind::OpCode(opcode).into()),
         }
     }
 
     /// Collect all events in parallel
-    pub fn par_collect_events(&mut self) -> Result<Vec<Event>, Error> {
-        let (tx, rx) = channel();
-        let mut events = Vec::new();
+    ///
+    /// Large frames are decoded in separate threads. Payloads are kept in a single arena,
+    /// see [`Events::payload`]. Events are read up to the first STOP, after which the reader
+    /// is positioned: call it again for the next pickle of an input holding several of them.
+    /// A STOP before the end of a large frame is only found once the frame is decoded, by which
+    /// time the input after the frame may have been consumed.
+    pub fn par_collect_events(&mut self) -> Result<Events, Error> {
+        let mut events = Events::default();
         let mut buf = Vec::new();
         let mut threads = Vec::new();
-        loop {
-            match self.read_event(&mut buf)? {
+        // span of the first STOP
+        let mut stop = loop {
+            let event = match self.read_event_with_span(&mut buf) {
+                Ok(event) => event,
+                Err(e) => {
+                    // the error may be in the next pickle, after a STOP decoded in a thread
+                    let stop = join_frames(&mut threads, &mut events)?;
+                    if stop.is_none() {
+                        return Err(e);
+                    }
+                    break stop;
+                }
+            };
+            match event.value {
                 Event::Frame(len) => {
                     // if the frame is big enough, spawn a new reader to send in parallel
                     if len >= FRAME_SPAWN_SIZE {
-                        // load the frame and spawn a new reader
-                        // dbg!(len);
-                        let mut frame_reader = self.frame_reader(len)?;
-                        let tx = tx.clone();
-                        threads.push(thread::spawn::<_, Result<(), Error>>(move || {
-                            let mut frame_buf = Vec::new();
+                        // load the frame and read it with a zero-copy reader
+                        let start = event.end;
+                        let mut frame = Vec::new();
+                        self.read_into(payload_len(len)?, &mut frame)?;
+                        let last = frame.last().copied();
+                        // offsets in the frame start at 0
+                        let limits = Limits {
+                            max_total: self.limits.max_total.saturating_sub(start),
+                            ..self.limits
+                        };
+                        threads.push(thread::spawn::<_, Result<_, Error>>(move || {
+                            let mut frame_events = Events::default();
+                            let mut frame_reader = SliceReader::new(&frame).with_limits(limits);
                             loop {
-                                let event = frame_reader.read_event(&mut frame_buf)?;
-                                if let Event::Stop = event {
-                                    break;
+                                let (mut event, payload) = frame_reader
+                                    .read_event_with_span()
+                                    .map_err(|e| e.shifted(start))?;
+                                event.start += start;
+                                event.end += start;
+                                if let Event::Stop = event.value {
+                                    // a STOP faked at the end of the frame has an empty span
+                                    let stop = (event.start < event.end).then_some(event.span());
+                                    return Ok((frame_events, stop));
                                 }
-                                frame_buf.clear();
-                                tx.send((frame_reader.pos, event)).unwrap();
+                                frame_events.push(event, &payload);
                             }
-                            Ok(())
                         }));
+                        // the last frame of a pickle ends with its STOP: don't read past it
+                        if last == Some(b'.') {
+                            let stop = join_frames(&mut threads, &mut events)?;
+                            if stop.is_some() {
+                                break stop;
+                            }
+                        }
                     }
                 }
-                Event::Stop => break,
-                event => events.push((self.pos, event)),
+                Event::Stop => break Some(event.span()),
+                _ => events.push(event, &buf),
             }
             buf.clear();
+        };
+
+        // a STOP in a frame comes before the events read after it
+        if let Some(frame_stop) = join_frames(&mut threads, &mut events)? {
+            stop = Some(frame_stop);
         }
 
-        if !threads.is_empty() {
-            // assert_eq!(threads.len(), 4);
-            drop(tx); // drop orphaned tx
+        // sort by offset
+        let mut order = (0..events.len()).collect::<Vec<_>>();
+        order.sort_by_key(|i| events.offsets[*i].0);
+        events.events = order.iter().map(|i| events.events[*i]).collect();
+        events.offsets = order.iter().map(|i| events.offsets[*i]).collect();
+        events.payloads = order.iter().map(|i| events.payloads[*i]).collect();
+
+        if let Some(stop) = stop {
+            let len = events
+                .offsets
+                .partition_point(|(start, _)| *start < stop.start);
+            events.truncate(len);
+            self.pos = stop.end;
+        }
+        Ok(events)
+    }
+}
+
+/// The events of a frame decoded in a thread, with the span of its STOP
+type FrameEvents = Result<(Events, Option<Range<usize>>), Error>;
+
+/// Waits for the frames decoded in threads and merges their events, up to the first STOP
+fn join_frames(
+    threads: &mut Vec<thread::JoinHandle<FrameEvents>>,
+    events: &mut Events,
+) -> Result<Option<Range<usize>>, Error> {
+    for th in threads.drain(..) {
+        let (frame_events, stop) = th.join().unwrap()?;
+        events.append(frame_events);
+        if stop.is_some() {
+            return Ok(stop);
+        }
+    }
+    Ok(None)
+}
+
+/// An event read by [`EventIter`], with its span and payload
+#[derive(Debug, Clone, PartialEq)]
+pub struct EventWithPayload {
+    pub event: Event,
+    /// The payload `read_event` would have left in the buffer
+    pub payload: Vec<u8>,
+    /// Offset of the opcode
+    pub start: usize,
+    /// Offset after the payload
+    pub end: usize,
+}
+
+impl EventWithPayload {
+    pub fn span(&self) -> Range<usize> {
+        self.start..self.end
+    }
+}
+
+/// Iterator over the events of a pickle, see [`Reader::events`]
+///
+/// It ends after the STOP opcode or the first error.
+pub struct EventIter<'a, R> {
+    reader: &'a mut Reader<R>,
+    buf: Vec<u8>,
+    done: bool,
+}
 
-            // wait for the threads to end
-            for th in threads {
-                th.join().unwrap()?;
+impl<R: BufRead> Iterator for EventIter<'_, R> {
+    type Item = Result<EventWithPayload, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let event = match self.reader.read_event_with_span(&mut self.buf) {
+            // a STOP faked at the end of the input has an empty span
+            Ok(event) if event.value == Event::Stop && event.start == event.end => {
+                self.done = true;
+                return Some(Err(missing_stop(event.start)));
             }
+            Ok(event) => event,
+            Err(e) => {
+                self.done = true;
+                return Some(Err(e));
+            }
+        };
+        self.done = event.value == Event::Stop;
+        Some(Ok(EventWithPayload {
+            event: event.value,
+            payload: std::mem::take(&mut self.buf),
+            start: event.start,
+            end: event.end,
+        }))
+    }
+}
+
+impl<R: BufRead> std::iter::FusedIterator for EventIter<'_, R> {}
+
+/// Length of the opcode and argument at the start of some raw input, as far as it is known
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum RawLen {
+    /// The opcode and its argument span this many bytes
+    ///
+    /// This is also the case when the argument is invalid, e.g. a payload length exceeding
+    /// the limits, so that reading it reports the error.
+    Complete(usize),
+    /// At least this many more bytes are needed
+    Needs(usize),
+}
 
-            // collect all events
-            while let Ok((id, event)) = rx.recv() {
-                events.push((id, event));
+/// Gets the length of the opcode at the start of `raw`, read at offset `pos`
+///
+/// Arguments are described by the [`opcodes::Arg`] table. Lines are bounded by
+/// [`Limits::max_payload`], and counted payloads by the limits as in [`Reader::read_event`].
+pub(crate) fn raw_len(raw: &[u8], pos: usize, limits: &Limits) -> RawLen {
+    if pos >= limits.max_total {
+        // an opcode past the limit is an error, the end of the input a faked STOP
+        return if raw.is_empty() {
+            RawLen::Needs(1)
+        } else {
+            RawLen::Complete(1)
+        };
+    }
+    let Some(info) = raw.first().map(|&opcode| opcodes::info(opcode)) else {
+        return RawLen::Needs(1);
+    };
+    let Some(info) = info else {
+        return RawLen::Complete(1);
+    };
+    let needed = |len: usize| match len.checked_sub(raw.len()) {
+        Some(needs @ 1..) => RawLen::Needs(needs),
+        _ => RawLen::Complete(len),
+    };
+    match info.arg {
+        Arg::Fixed(len) => needed(1 + len as usize),
+        Arg::Line => line_len(raw, 1, limits),
+        Arg::TwoLines => match line_len(raw, 1, limits) {
+            RawLen::Complete(end) => line_len(raw, end, limits),
+            needs => needs,
+        },
+        Arg::Counted { size, signed } => {
+            let size = size as usize;
+            let Some(bytes) = raw.get(1..1 + size) else {
+                return needed(1 + size);
+            };
+            let mut len = [0; 8];
+            len[..size].copy_from_slice(bytes);
+            let negative = signed && len[size - 1] & 0x80 != 0;
+            let len = u64::from_le_bytes(len);
+            let max = limits
+                .max_payload
+                .min(limits.max_total.saturating_sub(pos + 1 + size));
+            if negative || len > max as u64 {
+                RawLen::Complete(1 + size)
+            } else {
+                needed(1 + size + len as usize)
             }
-            events.sort_by_key(|(id, _)| *id); // stable sort by frame
         }
+    }
+}
+
+/// Gets the length of `raw` up to the end of the line starting at `start`
+fn line_len(raw: &[u8], start: usize, limits: &Limits) -> RawLen {
+    let limit = limits.max_payload.saturating_add(1);
+    let line = &raw[start..raw.len().min(start.saturating_add(limit))];
+    match line.iter().position(|&b| b == b'\n') {
+        Some(i) => RawLen::Complete(start + i + 1),
+        None if line.len() >= limit => RawLen::Complete(start + limit),
+        None => RawLen::Needs(1),
+    }
+}
+
+/// Removes the trailing line ending of text opcodes
+pub(crate) fn trim_line(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
+
+/// Error for an input ending at `offset` without a STOP opcode
+pub(crate) fn missing_stop(offset: usize) -> Error {
+    let eof = std::io::Error::new(
+        std::io::ErrorKind::UnexpectedEof,
+        "pickle ended without a STOP opcode",
+    );
+    Error::from(eof).at(offset, 0)
+}
+
+/// Converts a length read from the stream
+fn payload_len<T>(len: T) -> Result<usize, Error>
+where
+    T: TryInto<usize> + std::fmt::Display + Copy,
+{
+    len.try_into()
+        .map_err(|_| Error::unexpected("a positive length", len))
+}
+
+/// Error for a text opcode argument which is not a decimal integer
+fn not_int(s: &[u8]) -> Error {
+    Error::unexpected("an integer", format!("{:?}", String::from_utf8_lossy(s)))
+}
+
+/// Events collected by [`Reader::par_collect_events`] with their spans and payloads
+#[derive(Debug, Default, Clone)]
+pub struct Events {
+    events: Vec<Event>,
+    /// (start, end) of each event in the input
+    offsets: Vec<(usize, usize)>,
+    /// (offset, len) of each event payload in the arena
+    payloads: Vec<(usize, usize)>,
+    arena: Vec<u8>,
+}
+
+impl Events {
+    fn push(&mut self, event: Spanned<Event>, payload: &[u8]) {
+        self.events.push(event.value);
+        self.offsets.push((event.start, event.end));
+        self.payloads.push((self.arena.len(), payload.len()));
+        self.arena.extend_from_slice(payload);
+    }
+
+    fn append(&mut self, other: Events) {
+        let offset = self.arena.len();
+        self.events.extend(other.events);
+        self.offsets.extend(other.offsets);
+        self.payloads
+            .extend(other.payloads.into_iter().map(|(o, l)| (o + offset, l)));
+        self.arena.extend(other.arena);
+    }
+
+    fn truncate(&mut self, len: usize) {
+        self.events.truncate(len);
+        self.offsets.truncate(len);
+        self.payloads.truncate(len);
+    }
+
+    pub fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Gets the payload of the event at `index`, as `read_event` would have left in the buffer
+    pub fn payload(&self, index: usize) -> &[u8] {
+        let (offset, len) = self.payloads[index];
+        &self.arena[offset..offset + len]
+    }
+
+    /// Gets the byte offsets of the event at `index` in the input
+    pub fn span(&self, index: usize) -> Range<usize> {
+        let (start, end) = self.offsets[index];
+        start..end
+    }
 
-        Ok(events.into_iter().map(|(_, event)| event).collect())
+    /// Iterates over events and their payloads
+    pub fn iter(&self) -> impl Iterator<Item = (Event, &[u8])> {
+        self.events
+            .iter()
+            .enumerate()
+            .map(|(i, event)| (*event, self.payload(i)))
     }
 }
 
@@ -496,16 +1063,11 @@ mod tests {
     #[test]
     fn test_read_true() -> Result<(), Error> {
         let data: &[u8] = b"\x80\x04\x88.";
-        let mut reader = Reader::new(data);
-        let mut buf = Vec::new();
-        loop {
-            match reader.read_event(&mut buf)? {
-                Event::Bool(b) => assert!(b),
-                Event::Stop => break,
-                _ => (),
-            }
-            buf.clear();
-        }
+        let events = Reader::new(data)
+            .events()
+            .map(|e| e.map(|e| e.event))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(events, [Event::Proto(4), Event::Bool(true), Event::Stop]);
         Ok(())
     }
 
@@ -513,16 +1075,11 @@ mod tests {
     fn test_read_int() -> Result<(), Error> {
         let data: &[u8] = b"\x80\x04\x95\x06\x00\x00\x00\x00\x00\x00\x00J\x00\x00\x10\x00.";
         let mut reader = Reader::new(data);
-        let mut buf = Vec::new();
-        loop {
-            match reader.read_event(&mut buf)? {
-                Event::BinInt(v) => assert_eq!(v, 1 << 20),
-                Event::Stop => break,
-                _ => (),
+        for event in reader.events() {
+            if let Event::BinInt(v) = event?.event {
+                assert_eq!(v, 1 << 20);
             }
-            buf.clear();
         }
-
         Ok(())
     }
 
@@ -530,17 +1087,12 @@ mod tests {
     fn test_read_float() -> Result<(), Error> {
         let data: &[u8] = b"\x80\x04\x95\n\x00\x00\x00\x00\x00\x00\x00G?\xe1G\xae\x14z\xe1H.";
         let mut reader = Reader::new(data);
-        let mut buf = Vec::new();
         let mut value = 0.0;
-        loop {
-            match reader.read_event(&mut buf)? {
-                Event::Float(v) => value = v,
-                Event::Stop => break,
-                _ => (),
+        for event in reader.events() {
+            if let Event::Float(v) = event?.event {
+                value = v;
             }
-            buf.clear();
         }
-
         assert_eq!(value, 0.54);
         Ok(())
     }
@@ -553,20 +1105,235 @@ mod tests {
             0x94, b'.',
         ];
         let mut reader = Reader::new(data);
-        let mut buf = Vec::new();
         let mut s = Vec::new();
-        loop {
-            match reader.read_event(&mut buf)? {
-                Event::ShortBinUnicode { len } => {
-                    s = buf[buf.len() - len as usize..].to_vec();
+        for event in reader.events() {
+            let event = event?;
+            if let Event::ShortBinUnicode { .. } = event.event {
+                s = event.payload;
+            }
+        }
+        assert_eq!(s, b"/");
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_event_with_span() -> Result<(), Error> {
+        // pickle.dumps(['ab', 1], protocol=2)
+        let data: &[u8] = b"\x80\x02]q\x00(X\x02\x00\x00\x00abq\x01K\x01e.";
+        let mut reader = Reader::new(data);
+        let spans = reader
+            .events()
+            .map(|e| e.map(|e| e.span()))
+            .collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            spans,
+            [
+                0..2,
+                2..3,
+                3..5,
+                5..6,
+                6..13,
+                13..15,
+                15..17,
+                17..18,
+                18..19
+            ]
+        );
+
+        // end of input
+        let mut buf = Vec::new();
+        assert_eq!(reader.read_event_with_span(&mut buf)?.span(), 19..19);
+        Ok(())
+    }
+
+    #[test]
+    fn test_events_truncated() {
+        // a STOP opcode ends the iteration, even if more data follows
+        let mut reader = Reader::new(&b"N.N."[..]);
+        assert_eq!(reader.events().count(), 2);
+        assert_eq!(reader.position(), 2);
+
+        let mut reader = Reader::new(&b"\x80\x02N"[..]);
+        let mut events = reader.events();
+        assert_eq!(events.next().unwrap().unwrap().event, Event::Proto(2));
+        assert_eq!(events.next().unwrap().unwrap().event, Event::None);
+        let err = events.next().unwrap().unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+        assert_eq!(err.offset(), Some(3));
+        assert!(events.next().is_none());
+
+        // truncated in a payload
+        let mut reader = Reader::new(&b"\x80\x02X\x05\x00\x00\x00ab"[..]);
+        let results = reader.events().collect::<Vec<_>>();
+        assert_eq!(results.len(), 2);
+        assert!(results[1].is_err());
+    }
+
+    #[test]
+    fn test_limits() {
+        let read = |data: &[u8], limits: Limits| {
+            let mut reader = Reader::new(data).with_limits(limits);
+            let mut buf = Vec::new();
+            loop {
+                match reader.read_event(&mut buf) {
+                    Ok(Event::Stop) => return None,
+                    Ok(_) => buf.clear(),
+                    Err(e) => return Some(e.into_kind()),
+                }
+            }
+        };
+
+        // BINBYTES8 claiming an exabyte is not allocated
+        let data = b"\x80\x04\x8e\x00\x00\x00\x00\x00\x00\x00\x10abc.";
+        let kind = read(data, Limits::default());
+        assert!(
+            matches!(kind, Some(ErrorKind::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+        let limits = Limits {
+            max_payload: 1024,
+            ..Limits::default()
+        };
+        let kind = read(data, limits);
+        assert!(matches!(
+            kind,
+            Some(ErrorKind::PayloadTooLarge { max: 1024, .. })
+        ));
+
+        // negative BINSTRING length, BINUNICODE and BINBYTES lengths are unsigned
+        let kind = read(b"\x80\x02T\xff\xff\xff\xffabc.", Limits::default());
+        assert!(matches!(kind, Some(ErrorKind::Unexpected { .. })));
+        for opcode in [b'X', b'B'] {
+            for len in [0x8000_0000u32, u32::MAX] {
+                let mut data = vec![0x80, 0x03, opcode];
+                data.extend_from_slice(&len.to_le_bytes());
+                data.extend_from_slice(b"abc.");
+                let kind = read(&data, Limits::default());
+                assert!(
+                    matches!(kind, Some(ErrorKind::Io(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+                );
+                let kind = read(&data, limits);
+                assert!(
+                    matches!(kind, Some(ErrorKind::PayloadTooLarge { len: l, max: 1024 }) if l == len as usize)
+                );
+            }
+        }
+
+        // text lines
+        let kind = read(b"S'abcdef'\n.", limits);
+        assert!(kind.is_none());
+        let limits = Limits {
+            max_payload: 4,
+            ..Limits::default()
+        };
+        let kind = read(b"S'abcdef'\n.", limits);
+        assert!(matches!(
+            kind,
+            Some(ErrorKind::PayloadTooLarge { len: 5, max: 4 })
+        ));
+
+        // frames and total size
+        let data = b"\x80\x04\x95\x02\x00\x00\x00\x00\x00\x00\x00N.";
+        let limits = Limits {
+            max_frame: 1,
+            ..Limits::default()
+        };
+        let kind = read(data, limits);
+        assert!(matches!(
+            kind,
+            Some(ErrorKind::FrameTooLarge { len: 2, max: 1 })
+        ));
+        let limits = Limits {
+            max_total: 12,
+            ..Limits::default()
+        };
+        let kind = read(data, limits);
+        assert!(matches!(kind, Some(ErrorKind::InputTooLarge { max: 12 })));
+
+        // the end of the input is not past a limit of the input size
+        let limits = Limits {
+            max_total: data.len(),
+            ..Limits::default()
+        };
+        let mut reader = Reader::new(&data[..]).with_limits(limits);
+        assert_eq!(reader.events().count(), 4);
+        assert_eq!(reader.read_event(&mut Vec::new()).unwrap(), Event::Stop);
+    }
+
+    /// Reads the LONG1 and LONG events of a pickle as strings
+    fn read_longs(data: &[u8]) -> Result<Vec<String>, Error> {
+        let mut longs = Vec::new();
+        for event in Reader::new(data).events() {
+            let event = event?;
+            match event.event {
+                Event::Long(v) => longs.push(v.to_string()),
+                Event::BigLong { .. } => {
+                    let big = BigInt::from_signed_bytes_le(&event.payload);
+                    longs.push(big.to_string());
                 }
-                Event::Stop => break,
                 _ => (),
             }
-            buf.clear();
         }
+        Ok(longs)
+    }
 
-        assert_eq!(s, b"/");
+    #[test]
+    fn test_read_long() -> Result<(), Error> {
+        // pickle.dumps([2**40, -2**70, 2**31], protocol=2)
+        let data: &[u8] = b"\x80\x02]q\x00(\x8a\x06\x00\x00\x00\x00\x00\x01\x8a\t\x00\x00\x00\x00\x00\x00\x00\x00\xc0\x8a\x05\x00\x00\x00\x80\x00e.";
+        let longs = read_longs(data)?;
+        assert_eq!(
+            longs,
+            ["1099511627776", "-1180591620717411303424", "2147483648"]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_text_long() -> Result<(), Error> {
+        // pickle.dumps([2**40, -2**70], protocol=0)
+        let data: &[u8] = b"(lp0\nL1099511627776L\naL-1180591620717411303424L\na.";
+        let longs = read_longs(data)?;
+        assert_eq!(longs, ["1099511627776", "-1180591620717411303424"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_lines_without_newline() -> Result<(), Error> {
+        let data: &[u8] = b"c__builtin__\nlen\nP7\r\ni__main__\nC\n.";
+        let mut reader = Reader::new(data);
+        let mut buf = Vec::new();
+        let expected = [
+            (
+                Event::Global {
+                    module_len: 11,
+                    name_len: 3,
+                    crlf: false,
+                },
+                &b"__builtin__len"[..],
+            ),
+            (
+                Event::PersId {
+                    id_len: 1,
+                    crlf: true,
+                },
+                b"7",
+            ),
+            (
+                Event::Inst {
+                    module_len: 8,
+                    name_len: 1,
+                    crlf: false,
+                },
+                b"__main__C",
+            ),
+        ];
+        for (event, payload) in expected {
+            assert_eq!(reader.read_event(&mut buf)?, event);
+            assert_eq!(buf, payload);
+            buf.clear();
+        }
         Ok(())
     }
 
@@ -574,18 +1341,13 @@ mod tests {
     fn test_read_list_ints() -> Result<(), Error> {
         let data: &[u8] = b"\x80\x04\x95\x19\x00\x00\x00\x00\x00\x00\x00]\x94(K\x00K\x01K\x02K\x03K\x04K\x05K\x06K\x07K\x08K\te.";
         let mut reader = Reader::new(data);
-        let mut buf = Vec::new();
         let mut list = Vec::new();
-        loop {
-            match reader.read_event(&mut buf)? {
-                Event::BinInt1(v) => list.push(v),
-                Event::Stop => break,
-                _ => (),
+        for event in reader.events() {
+            if let Event::BinInt1(v) = event?.event {
+                list.push(v);
             }
-            buf.clear();
         }
         assert_eq!(list, (0..10).collect::<Vec<_>>());
-
         Ok(())
     }
 
@@ -603,7 +1365,7 @@ mod tests {
         let mut reader = Reader::new(data);
         let events = reader.par_collect_events()?;
         assert_eq!(
-            &events,
+            events.events(),
             &[
                 Event::Proto(4),
                 Event::ShortBinUnicode { len: 1 },
@@ -611,6 +1373,7 @@ mod tests {
             ],
             "{events:?}"
         );
+        assert_eq!(events.payload(1), b"/");
         Ok(())
     }
 
@@ -622,6 +1385,78 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_par_collect_payloads() -> Result<(), Error> {
+        // a list of strings in a frame large enough to be read in another thread
+        let mut body = b"]\x94(".to_vec();
+        for i in 0..20000 {
+            let s = format!("s{i}");
+            body.extend_from_slice(&[0x8c, s.len() as u8]);
+            body.extend_from_slice(s.as_bytes());
+        }
+        body.extend_from_slice(b"e.");
+        let mut data = vec![0x80, 0x04, 0x95];
+        data.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        data.extend_from_slice(&body);
+        assert!(body.len() as u64 >= FRAME_SPAWN_SIZE);
+
+        let events = Reader::new(&*data).par_collect_events()?;
+
+        // same events, spans and payloads as the sequential reader
+        let mut reader = Reader::new(&*data);
+        let mut buf = Vec::new();
+        let mut expected = Vec::new();
+        loop {
+            let event = reader.read_event_with_span(&mut buf)?;
+            match event.value {
+                Event::Stop => break,
+                Event::Frame(_) => (),
+                _ => expected.push((event, buf.clone())),
+            }
+            buf.clear();
+        }
+        assert_eq!(events.len(), expected.len());
+        for (i, (event, payload)) in events.iter().enumerate() {
+            let (expected, expected_payload) = &expected[i];
+            assert_eq!(event, expected.value);
+            assert_eq!(events.span(i), expected.span());
+            assert_eq!(payload, expected_payload);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_par_collect_concatenated() -> Result<(), Error> {
+        // two pickles of a list of strings, each in a frame read in another thread
+        let mut data = Vec::new();
+        for prefix in ["a", "b"] {
+            let mut body = b"]\x94(".to_vec();
+            for i in 0..20000 {
+                let s = format!("{prefix}{i}");
+                body.extend_from_slice(&[0x8c, s.len() as u8]);
+                body.extend_from_slice(s.as_bytes());
+            }
+            body.extend_from_slice(b"e.");
+            data.extend_from_slice(&[0x80, 0x04, 0x95]);
+            data.extend_from_slice(&(body.len() as u64).to_le_bytes());
+            data.extend_from_slice(&body);
+        }
+
+        let mut reader = Reader::new(&*data);
+        let first = reader.par_collect_events()?;
+        assert_eq!(reader.position(), data.len() / 2);
+        let second = reader.par_collect_events()?;
+        assert_eq!(reader.position(), data.len());
+        for (events, prefix) in [(&first, "a"), (&second, "b")] {
+            assert_eq!(events.len(), 20005);
+            assert_eq!(events.payload(4), format!("{prefix}0").as_bytes());
+            assert_eq!(events.events().last(), Some(&Event::Appends));
+        }
+        assert_eq!(second.span(0).start, data.len() / 2);
+        assert!(reader.par_collect_events()?.is_empty());
+        Ok(())
+    }
+
     #[test]
     fn test_read_dict_from_file() -> Result<(), Error> {
         let mut reader = Reader::open(concat!(env!("CARGO_MANIFEST_DIR"), "/dict.pickle"))?;
@@ -629,4 +1464,20 @@ mod tests {
         assert_eq!(events.len(), 20057);
         Ok(())
     }
+
+    #[cfg(feature = "memmap2")]
+    #[test]
+    fn test_read_dict_mmap() -> Result<(), Error> {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/dict.pickle");
+        // SAFETY: the test files are not modified
+        let reader = unsafe { Reader::open_mmap(path)? };
+        let events = reader.slice_reader().par_collect_events()?;
+        let expected = Reader::open(path)?.par_collect_events()?;
+        assert_eq!(events.len(), expected.len());
+        for ((event, payload), (expected, expected_payload)) in events.iter().zip(expected.iter()) {
+            assert_eq!(*event, expected);
+            assert_eq!(&**payload, expected_payload);
+        }
+        Ok(())
+    }
 }
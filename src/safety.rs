@@ -0,0 +1,76 @@
+//! A module to restrict the globals a pickle may reference
+//!
+//! GLOBAL, STACK_GLOBAL and INST are the only way for a pickle to reach python callables
+//! (which REDUCE, NEWOBJ or BUILD then invoke), so checking them is enough to reject
+//! pickles which would construct unexpected objects.
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+};
+
+type Hook = Box<dyn Fn(&str, &str) -> bool + Send + Sync>;
+
+/// A policy deciding which `module.name` globals an [`Unpickler`] accepts
+///
+/// Globals are denied unless they are in the allowlist or accepted by the hook.
+///
+/// ```
+/// use quick_pickle::{SafetyPolicy, Unpickler};
+///
+/// let policy = SafetyPolicy::new()
+///     .allow("collections", "OrderedDict")
+///     .with_hook(|module, _name| module == "numpy");
+/// let mut unpickler = Unpickler::new(&b"\x80\x02cos\nsystem\n."[..]).with_policy(policy);
+/// assert!(unpickler.load().is_err());
+/// ```
+///
+/// [`Unpickler`]: crate::Unpickler
+#[derive(Default)]
+pub struct SafetyPolicy {
+    /// Allowed names, by module
+    allowed: HashMap<String, HashSet<String>>,
+    hook: Option<Hook>,
+}
+
+impl SafetyPolicy {
+    /// Creates a policy denying all globals
+    pub fn new() -> Self {
+        SafetyPolicy::default()
+    }
+
+    /// Allows the `module.name` global
+    pub fn allow(mut self, module: &str, name: &str) -> Self {
+        self.allowed
+            .entry(module.to_string())
+            .or_default()
+            .insert(name.to_string());
+        self
+    }
+
+    /// Sets a hook deciding on the globals which are not in the allowlist
+    pub fn with_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&str, &str) -> bool + Send + Sync + 'static,
+    {
+        self.hook = Some(Box::new(hook));
+        self
+    }
+
+    /// Checks if the `module.name` global is allowed
+    pub fn is_allowed(&self, module: &str, name: &str) -> bool {
+        self.allowed
+            .get(module)
+            .is_some_and(|names| names.contains(name))
+            || self.hook.as_ref().is_some_and(|hook| hook(module, name))
+    }
+}
+
+impl fmt::Debug for SafetyPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SafetyPolicy")
+            .field("allowed", &self.allowed)
+            .field("hook", &self.hook.is_some())
+            .finish()
+    }
+}
@@ -0,0 +1,721 @@
+//! A module to serialize rust types into pickles with serde
+//!
+//! The serde data model is mapped to python objects as follows:
+//! - unit, unit structs and `None` are `None`
+//! - sequences are lists, tuples and tuple structs are tuples
+//! - maps and structs are dicts, struct fields being str keys
+//! - strings and chars are str, byte buffers (`serialize_bytes`) are bytes
+//! - enum variants follow [`EnumRepr`]
+//!
+//! Opcodes are chosen, memoized, batched and framed as python's pickler would do for the
+//! equivalent python object.
+
+use std::io::Write;
+
+use serde::ser::{self, Serialize};
+
+use crate::{
+    bigint::BigInt,
+    errors::{Error, ErrorKind},
+    pickler::Batch,
+    reader::Event,
+    writer::{DEFAULT_PROTOCOL, Writer},
+};
+
+impl ser::Error for Error {
+    fn custom<T: std::fmt::Display>(msg: T) -> Self {
+        ErrorKind::Custom(msg.to_string()).into()
+    }
+}
+
+/// How enum variants are represented in python
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EnumRepr {
+    /// `'Variant'` for unit variants, `{'Variant': content}` otherwise
+    #[default]
+    External,
+    /// `('Variant',)` for unit variants, `('Variant', content)` otherwise
+    Tuple,
+}
+
+/// Serialization options
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Options {
+    /// Pickle protocol, from 0 to 5
+    pub protocol: u8,
+    /// Representation of enum variants
+    pub enum_repr: EnumRepr,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            protocol: DEFAULT_PROTOCOL,
+            enum_repr: EnumRepr::default(),
+        }
+    }
+}
+
+/// A serde serializer writing pickles
+pub struct Serializer<W> {
+    writer: Writer<W>,
+    enum_repr: EnumRepr,
+    /// Number of memoized objects
+    memo_len: u32,
+}
+
+impl<W: Write> Serializer<W> {
+    pub fn new(writer: W, options: Options) -> Result<Self, Error> {
+        Ok(Serializer {
+            writer: Writer::new(writer, options.protocol)?,
+            enum_repr: options.enum_repr,
+            memo_len: 0,
+        })
+    }
+
+    /// Serializes a complete pickle, from PROTO to STOP
+    pub fn dump<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.memo_len = 0;
+        self.writer.write_proto()?;
+        self.writer.start_framing();
+        value.serialize(&mut *self)?;
+        self.writer.write_stop()?;
+        self.writer.end_framing()?;
+        self.writer.flush()
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer.into_inner()
+    }
+
+    fn memoize(&mut self) -> Result<(), Error> {
+        self.writer.write_memoize(self.memo_len)?;
+        self.memo_len += 1;
+        Ok(())
+    }
+
+    /// Ends an object, committing the frame if needed
+    fn end(&mut self) -> Result<(), Error> {
+        self.writer.frame_boundary()
+    }
+
+    fn write_variant_start(&mut self, variant: &str) -> Result<(), Error> {
+        match self.enum_repr {
+            EnumRepr::External => {
+                self.writer.write_empty_dict()?;
+                self.memoize()?;
+                self.write_str(variant)?;
+            }
+            EnumRepr::Tuple => {
+                if self.writer.protocol() < 2 {
+                    self.writer.write_mark()?;
+                }
+                self.write_str(variant)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn write_variant_end(&mut self) -> Result<(), Error> {
+        match self.enum_repr {
+            EnumRepr::External => self.writer.write_event(Event::SetItem, &[])?,
+            EnumRepr::Tuple => {
+                self.writer.write_tuple(2)?;
+                self.memoize()?;
+            }
+        }
+        self.end()
+    }
+
+    fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        self.writer.write_str(s)?;
+        self.memoize()?;
+        self.end()
+    }
+}
+
+/// Serializes a value as a pickle into a writer
+pub fn to_writer<W: Write, T: Serialize + ?Sized>(
+    value: &T,
+    writer: W,
+    options: Options,
+) -> Result<(), Error> {
+    Serializer::new(writer, options)?.dump(value)
+}
+
+/// Serializes a value as pickle bytes
+pub fn to_vec<T: Serialize + ?Sized>(value: &T, options: Options) -> Result<Vec<u8>, Error> {
+    let mut serializer = Serializer::new(Vec::new(), options)?;
+    serializer.dump(value)?;
+    Ok(serializer.into_inner())
+}
+
+impl<'a, W: Write> ser::Serializer for &'a mut Serializer<W> {
+    type Ok = ();
+    type Error = Error;
+    type SerializeSeq = Compound<'a, W>;
+    type SerializeTuple = Compound<'a, W>;
+    type SerializeTupleStruct = Compound<'a, W>;
+    type SerializeTupleVariant = Compound<'a, W>;
+    type SerializeMap = Compound<'a, W>;
+    type SerializeStruct = Compound<'a, W>;
+    type SerializeStructVariant = Compound<'a, W>;
+
+    fn serialize_bool(self, v: bool) -> Result<(), Error> {
+        self.writer.write_bool(v)?;
+        self.end()
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<(), Error> {
+        self.writer.write_int(v)?;
+        self.end()
+    }
+
+    fn serialize_i128(self, v: i128) -> Result<(), Error> {
+        self.writer.write_bigint(&BigInt::from(v))?;
+        self.end()
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<(), Error> {
+        self.serialize_i64(v as i64)
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<(), Error> {
+        self.serialize_i128(v as i128)
+    }
+
+    fn serialize_u128(self, v: u128) -> Result<(), Error> {
+        let v = i128::try_from(v)
+            .map_err(|_| <Error as ser::Error>::custom(format!("{v} overflows an i128")))?;
+        self.serialize_i128(v)
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<(), Error> {
+        self.serialize_f64(v as f64)
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<(), Error> {
+        self.writer.write_float(v)?;
+        self.end()
+    }
+
+    fn serialize_char(self, v: char) -> Result<(), Error> {
+        self.write_str(v.encode_utf8(&mut [0; 4]))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<(), Error> {
+        self.write_str(v)
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), Error> {
+        self.writer.write_bytes(v)?;
+        self.memoize()?;
+        self.end()
+    }
+
+    fn serialize_none(self) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_some<T: Serialize + ?Sized>(self, value: &T) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<(), Error> {
+        self.writer.write_none()?;
+        self.end()
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Error> {
+        self.serialize_unit()
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<(), Error> {
+        match self.enum_repr {
+            EnumRepr::External => self.write_str(variant),
+            EnumRepr::Tuple => {
+                if self.writer.protocol() < 2 {
+                    self.writer.write_mark()?;
+                }
+                self.write_str(variant)?;
+                self.writer.write_tuple(1)?;
+                self.memoize()?;
+                self.end()
+            }
+        }
+    }
+
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.write_variant_start(variant)?;
+        value.serialize(&mut *self)?;
+        self.write_variant_end()
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<Compound<'a, W>, Error> {
+        self.writer.write_empty_list()?;
+        self.memoize()?;
+        Ok(Compound::new(self, Kind::List, len))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a, W>, Error> {
+        if len == 0 {
+            self.writer.write_empty_tuple()?;
+            return Ok(Compound::new(self, Kind::EmptyTuple, Some(0)));
+        }
+        if len > 3 || self.writer.protocol() < 2 {
+            self.writer.write_mark()?;
+        }
+        Ok(Compound::new(self, Kind::Tuple, Some(len)))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a, W>, Error> {
+        self.serialize_tuple(len)
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a, W>, Error> {
+        self.write_variant_start(variant)?;
+        let tuple = self.serialize_tuple(len)?;
+        Ok(Compound {
+            variant: true,
+            ..tuple
+        })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a, W>, Error> {
+        self.writer.write_empty_dict()?;
+        self.memoize()?;
+        Ok(Compound::new(self, Kind::Dict, len))
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<Compound<'a, W>, Error> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a, W>, Error> {
+        self.write_variant_start(variant)?;
+        let map = self.serialize_map(Some(len))?;
+        Ok(Compound {
+            variant: true,
+            ..map
+        })
+    }
+}
+
+enum Kind {
+    List,
+    EmptyTuple,
+    Tuple,
+    Dict,
+}
+
+/// A list, tuple or dict being serialized
+pub struct Compound<'a, W> {
+    ser: &'a mut Serializer<W>,
+    kind: Kind,
+    /// Number of items (or key-value pairs), if known
+    len: Option<usize>,
+    /// Batching of list items and dict pairs
+    batch: Option<Batch>,
+    /// Number of items written
+    count: usize,
+    /// Whether this is the content of an enum variant
+    variant: bool,
+}
+
+impl<'a, W: Write> Compound<'a, W> {
+    fn new(ser: &'a mut Serializer<W>, kind: Kind, len: Option<usize>) -> Self {
+        let protocol = ser.writer.protocol();
+        let batch = match kind {
+            Kind::List => Some(Batch::list(protocol, len)),
+            Kind::Dict => Some(Batch::dict(protocol, len)),
+            Kind::EmptyTuple | Kind::Tuple => None,
+        };
+        Compound {
+            ser,
+            kind,
+            len,
+            batch,
+            count: 0,
+            variant: false,
+        }
+    }
+
+    fn start_item(&mut self) -> Result<(), Error> {
+        match &mut self.batch {
+            Some(batch) => batch.start_item(&mut self.ser.writer),
+            None => Ok(()),
+        }
+    }
+
+    fn end_item(&mut self) -> Result<(), Error> {
+        self.count += 1;
+        match &mut self.batch {
+            Some(batch) => batch.end_item(&mut self.ser.writer),
+            None => Ok(()),
+        }
+    }
+
+    fn finish(mut self) -> Result<(), Error> {
+        match self.kind {
+            Kind::List | Kind::Dict => {
+                if let Some(batch) = &mut self.batch {
+                    batch.finish(&mut self.ser.writer)?;
+                }
+            }
+            Kind::EmptyTuple => (),
+            Kind::Tuple => {
+                if self.len != Some(self.count) {
+                    return Err(<Error as ser::Error>::custom(format!(
+                        "tuple of {:?} items got {} items",
+                        self.len, self.count
+                    )));
+                }
+                self.ser.writer.write_tuple(self.count)?;
+                self.ser.memoize()?;
+            }
+        }
+        self.ser.end()?;
+        if self.variant {
+            self.ser.write_variant_end()?;
+        }
+        Ok(())
+    }
+
+    fn item<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.start_item()?;
+        value.serialize(&mut *self.ser)?;
+        self.end_item()
+    }
+}
+
+impl<W: Write> ser::SerializeSeq for Compound<'_, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<W: Write> ser::SerializeTuple for Compound<'_, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<W: Write> ser::SerializeTupleStruct for Compound<'_, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<W: Write> ser::SerializeTupleVariant for Compound<'_, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        self.item(value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<W: Write> ser::SerializeMap for Compound<'_, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, key: &T) -> Result<(), Error> {
+        self.start_item()?;
+        key.serialize(&mut *self.ser)
+    }
+
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, value: &T) -> Result<(), Error> {
+        value.serialize(&mut *self.ser)?;
+        self.end_item()
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<W: Write> ser::SerializeStruct for Compound<'_, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        self.start_item()?;
+        self.ser.write_str(key)?;
+        value.serialize(&mut *self.ser)?;
+        self.end_item()
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+impl<W: Write> ser::SerializeStructVariant for Compound<'_, W> {
+    type Ok = ();
+    type Error = Error;
+
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<(), Error> {
+        ser::SerializeStruct::serialize_field(self, key, value)
+    }
+
+    fn end(self) -> Result<(), Error> {
+        self.finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::reader::Reader;
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    enum Mode {
+        Fast,
+        Slow { factor: u8 },
+    }
+
+    #[derive(Debug, Serialize, Deserialize, PartialEq)]
+    struct Config {
+        name: String,
+        weights: Vec<f64>,
+        shape: (u32, u32),
+        bias: Option<i64>,
+        mode: Mode,
+    }
+
+    struct Bytes<'a>(&'a [u8]);
+
+    impl Serialize for Bytes<'_> {
+        fn serialize<S: ser::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+            s.serialize_bytes(self.0)
+        }
+    }
+
+    #[test]
+    fn test_to_vec_matches_python() -> Result<(), Error> {
+        let config = Config {
+            name: "a".into(),
+            weights: vec![0.5, 1.5],
+            shape: (2, 3),
+            bias: None,
+            mode: Mode::Slow { factor: 3 },
+        };
+        // pickle.dumps({'name': 'a', 'weights': [0.5, 1.5], 'shape': (2, 3), 'bias': None,
+        //               'mode': {'Slow': {'factor': 3}}}, protocol=4)
+        let expected = b"\x80\x04\x95e\x00\x00\x00\x00\x00\x00\x00}\x94(\x8c\x04name\x94\x8c\x01a\x94\x8c\x07weights\x94]\x94(G?\xe0\x00\x00\x00\x00\x00\x00G?\xf8\x00\x00\x00\x00\x00\x00e\x8c\x05shape\x94K\x02K\x03\x86\x94\x8c\x04bias\x94N\x8c\x04mode\x94}\x94\x8c\x04Slow\x94}\x94\x8c\x06factor\x94K\x03ssu.";
+        let data = to_vec(&config, Options::default())?;
+        assert_eq!(data, expected);
+
+        let back: Config = crate::from_slice(&data)?;
+        assert_eq!(back, config);
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_vec_protocol_2() -> Result<(), Error> {
+        // pickle.dumps([(1,), 'Fast'], protocol=2)
+        let value = ((1,), Mode::Fast);
+        let data = to_vec(
+            &value,
+            Options {
+                protocol: 2,
+                ..Options::default()
+            },
+        )?;
+        assert_eq!(
+            data,
+            b"\x80\x02K\x01\x85q\x00X\x04\x00\x00\x00Fastq\x01\x86q\x02."
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_protocol_0() -> Result<(), Error> {
+        let options = Options {
+            protocol: 0,
+            ..Options::default()
+        };
+        // pickle.dumps([1, 2], protocol=0)
+        let seq = vec![1i64, 2];
+        let data = to_vec(&seq, options)?;
+        assert_eq!(data, b"(lp0\nI1\naI2\na.");
+        assert_eq!(crate::from_slice::<Vec<i64>>(&data)?, seq);
+        // pickle.dumps({'a': 1, 'b': 2}, protocol=0)
+        let map = std::collections::BTreeMap::from([("a".to_string(), 1i64), ("b".into(), 2)]);
+        let data = to_vec(&map, options)?;
+        assert_eq!(data, b"(dp0\nVa\np1\nI1\nsVb\np2\nI2\ns.");
+        assert_eq!(
+            crate::from_slice::<std::collections::BTreeMap<String, i64>>(&data)?,
+            map
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_enum_tuple() -> Result<(), Error> {
+        let options = Options {
+            enum_repr: EnumRepr::Tuple,
+            ..Options::default()
+        };
+        let data = to_vec(&Mode::Slow { factor: 3 }, options)?;
+        // pickle.dumps(('Slow', {'factor': 3}), protocol=4)
+        assert_eq!(
+            data,
+            b"\x80\x04\x95\x18\x00\x00\x00\x00\x00\x00\x00\x8c\x04Slow\x94}\x94\x8c\x06factor\x94K\x03s\x86\x94."
+        );
+        let back: Mode = crate::from_slice(&data)?;
+        assert_eq!(back, Mode::Slow { factor: 3 });
+        Ok(())
+    }
+
+    #[test]
+    fn test_batches() -> Result<(), Error> {
+        // pickle._dumps(list(range(1001)), protocol=4), the last item has its own APPEND
+        let expected = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/range.pickle"));
+        let seq = (0..1001).collect::<Vec<i64>>();
+        assert_eq!(to_vec(&seq, Options::default())?, expected);
+
+        // an iterator of unknown length
+        struct Unsized(u16);
+        impl Serialize for Unsized {
+            fn serialize<S: ser::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                s.collect_seq((0..self.0).filter(|_| true))
+            }
+        }
+        assert_eq!(to_vec(&Unsized(1001), Options::default())?, expected);
+        // pickle._dumps([[0]], protocol=4)
+        assert_eq!(
+            to_vec(&vec![Unsized(1)], Options::default())?,
+            b"\x80\x04\x95\t\x00\x00\x00\x00\x00\x00\x00]\x94]\x94K\x00aa."
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_frames() -> Result<(), Error> {
+        // d = {str(i): list(range(i % 50)) for i in range(2000)}; d['raw'] = bytes(70000)
+        // [e.arg for e in pickletools.genops(pickle._dumps(d, 4)) if e.name == 'FRAME']
+        let order = (0..2000)
+            .map(|i| (i.to_string(), (0..i % 50).collect::<Vec<i64>>()))
+            .collect::<Vec<_>>();
+        struct Ordered<'a>(&'a [(String, Vec<i64>)], &'a [u8]);
+        impl Serialize for Ordered<'_> {
+            fn serialize<S: ser::Serializer>(&self, s: S) -> Result<S::Ok, S::Error> {
+                use ser::SerializeMap;
+                let mut m = s.serialize_map(Some(self.0.len() + 1))?;
+                for (k, v) in self.0 {
+                    m.serialize_entry(k, v)?;
+                }
+                m.serialize_entry("raw", &Bytes(self.1))?;
+                m.end()
+            }
+        }
+        let raw = vec![0; 70000];
+        let data = to_vec(&Ordered(&order, &raw), Options::default())?;
+
+        let mut reader = Reader::new(&*data);
+        let mut buf = Vec::new();
+        let mut frames = Vec::new();
+        loop {
+            match reader.read_event(&mut buf)? {
+                Event::Frame(len) => frames.push(len),
+                Event::Stop => break,
+                _ => (),
+            }
+            buf.clear();
+        }
+        assert_eq!(frames, [65537, 53245]);
+        assert_eq!(data.len(), 188810);
+        Ok(())
+    }
+}
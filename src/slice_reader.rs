@@ -0,0 +1,612 @@
+//! A module to read pickle events out of an in-memory slice without copying payloads
+
+use std::{borrow::Cow, thread};
+
+use crate::{
+    bigint::decode_le,
+    errors::{Error, ErrorKind},
+    reader::{Event, FRAME_SPAWN_SIZE, Limits, Reader, Spanned, trim_line},
+};
+
+/// An event with its payload, borrowed from the input when possible
+pub type BorrowedEvent<'a> = (Event, Cow<'a, [u8]>);
+
+/// A pickle reader over a byte slice
+///
+/// Unlike [`Reader::read_event`], payloads (strings, bytes, big integers, persistent ids) are
+/// borrowed from the input instead of being copied into a buffer.
+pub struct SliceReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    /// Last opcode read
+    pub(crate) opcode: u8,
+    limits: Limits,
+    /// Buffer for the opcodes decoded by the [`Reader`]
+    scratch: Vec<u8>,
+}
+
+impl<'a> SliceReader<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        SliceReader::new_at(data, 0)
+    }
+
+    /// Creates a reader over `data[start..]` reporting positions relative to `data`
+    pub(crate) fn new_at(data: &'a [u8], start: usize) -> Self {
+        SliceReader {
+            data,
+            pos: start,
+            opcode: 0,
+            limits: Limits::default(),
+            scratch: Vec::new(),
+        }
+    }
+
+    /// Sets the limits checked while reading, see [`Reader::with_limits`]
+    pub fn with_limits(mut self, limits: Limits) -> Self {
+        self.limits = limits;
+        self
+    }
+
+    pub fn limits(&self) -> &Limits {
+        &self.limits
+    }
+
+    /// Gets the position of the next opcode in the input
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    /// Checks if the whole input has been read
+    pub fn at_eof(&self) -> bool {
+        self.pos >= self.data.len()
+    }
+
+    fn eof() -> Error {
+        std::io::Error::from(std::io::ErrorKind::UnexpectedEof).into()
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Error> {
+        if self.pos.saturating_add(len) > self.limits.max_total {
+            let max = self.limits.max_total;
+            return Err(ErrorKind::InputTooLarge { max }.into());
+        }
+        let end = self.pos.checked_add(len).ok_or_else(Self::eof)?;
+        let bytes = self.data.get(self.pos..end).ok_or_else(Self::eof)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const N: usize>(&mut self) -> Result<[u8; N], Error> {
+        Ok(self.take(N)?.try_into().expect("N bytes"))
+    }
+
+    /// Takes a line, including its `\n`
+    fn take_line(&mut self) -> Result<&'a [u8], Error> {
+        // like the reader, look no further than the payload limit
+        let max = self.limits.max_payload;
+        let rest = &self.data[self.pos..];
+        let rest = &rest[..rest.len().min(max.saturating_add(1))];
+        let len = rest
+            .iter()
+            .position(|b| *b == b'\n')
+            .map_or(rest.len(), |i| i + 1);
+        if len > max {
+            return Err(ErrorKind::PayloadTooLarge { len, max }.into());
+        }
+        self.take(len)
+    }
+
+    /// Takes a payload whose length has been read from the stream
+    fn take_len<T>(&mut self, len: T) -> Result<&'a [u8], Error>
+    where
+        T: TryInto<usize> + std::fmt::Display + Copy,
+    {
+        let len = len
+            .try_into()
+            .map_err(|_| Error::unexpected("a positive length", len))?;
+        if len > self.limits.max_payload {
+            let max = self.limits.max_payload;
+            return Err(ErrorKind::PayloadTooLarge { len, max }.into());
+        }
+        self.take(len)
+    }
+
+    /// Reads the next event with its span, see [`Reader::read_event_with_span`]
+    pub fn read_event_with_span(&mut self) -> Result<(Spanned<Event>, Cow<'a, [u8]>), Error> {
+        let start = self.pos;
+        let (value, payload) = self.read_event()?;
+        let event = Spanned {
+            value,
+            start,
+            end: self.pos,
+        };
+        Ok((event, payload))
+    }
+
+    /// Reads the next event and its payload
+    ///
+    /// The payload is what [`Reader::read_event`] would have left in the buffer, and is
+    /// only owned for the few opcodes whose payload is not verbatim in the input.
+    pub fn read_event(&mut self) -> Result<BorrowedEvent<'a>, Error> {
+        let Some(&opcode) = self.data.get(self.pos) else {
+            // fake a stop event
+            return Ok((Event::Stop, Cow::Borrowed(&[])));
+        };
+        if self.pos >= self.limits.max_total {
+            let max = self.limits.max_total;
+            return Err(Error::from(ErrorKind::InputTooLarge { max }).at(self.pos, 0));
+        }
+        let start = self.pos;
+        self.pos += 1;
+        self.opcode = opcode;
+        self.read_args(opcode, start)
+            .map_err(|e| e.at(start, opcode))
+    }
+
+    /// Reads the arguments of the opcode at `start`
+    fn read_args(&mut self, opcode: u8, start: usize) -> Result<BorrowedEvent<'a>, Error> {
+        let (event, payload) = match opcode {
+            // Basic types
+            0x8a | 0x8b => {
+                // LONG1, LONG4
+                let bytes = if opcode == 0x8a {
+                    let len = self.take_array::<1>()?[0];
+                    self.take_len(len)?
+                } else {
+                    let len = i32::from_le_bytes(self.take_array()?);
+                    self.take_len(len)?
+                };
+                match decode_le::<8>(bytes) {
+                    Some(v) => (Event::Long(i64::from_le_bytes(v)), &[][..]),
+                    None => (Event::BigLong { len: bytes.len() }, bytes),
+                }
+            }
+
+            // Strings and bytes
+            0x53 => {
+                // STRING
+                let line = self.take_line()?;
+                (Event::String { len: line.len() }, line)
+            }
+            0x54 => {
+                // BINSTRING
+                let len = i32::from_le_bytes(self.take_array()?);
+                (Event::BinString { len }, self.take_len(len)?)
+            }
+            0x55 => {
+                // SHORT_BINSTRING
+                let len = self.take_array::<1>()?[0];
+                (Event::ShortBinString { len }, self.take_len(len)?)
+            }
+            0x56 => {
+                // UNICODE
+                let line = self.take_line()?;
+                (Event::Unicode { len: line.len() }, line)
+            }
+            0x58 => {
+                // BINUNICODE
+                let len = u32::from_le_bytes(self.take_array()?);
+                (Event::BinUnicode { len }, self.take_len(len)?)
+            }
+            0x8c => {
+                // SHORT_BINUNICODE
+                let len = self.take_array::<1>()?[0];
+                (Event::ShortBinUnicode { len }, self.take_len(len)?)
+            }
+            0x8d => {
+                // BINUNICODE8
+                let len = i64::from_le_bytes(self.take_array()?);
+                (Event::BinUnicode8 { len }, self.take_len(len)?)
+            }
+            0x42 => {
+                // BINBYTES
+                let len = u32::from_le_bytes(self.take_array()?);
+                (Event::BinBytes { len }, self.take_len(len)?)
+            }
+            0x43 => {
+                // SHORT_BINBYTES
+                let len = self.take_array::<1>()?[0];
+                (Event::ShortBinBytes { len }, self.take_len(len)?)
+            }
+            0x8e => {
+                // BINBYTES8
+                let len = u64::from_le_bytes(self.take_array()?);
+                (Event::BinBytes8 { len }, self.take_len(len)?)
+            }
+            0x96 => {
+                // BYTEARRAY8
+                let len = u64::from_le_bytes(self.take_array()?);
+                (Event::ByteArray8 { len }, self.take_len(len)?)
+            }
+
+            // Object construction
+            0x63 | 0x69 => {
+                // GLOBAL, INST
+                let module = self.take_line()?;
+                let crlf = module.ends_with(b"\r\n");
+                let module = trim_line(module);
+                let name = trim_line(self.take_line()?);
+                let (module_len, name_len) = (module.len() as u32, name.len() as u32);
+                let event = if opcode == 0x63 {
+                    Event::Global {
+                        module_len,
+                        name_len,
+                        crlf,
+                    }
+                } else {
+                    Event::Inst {
+                        module_len,
+                        name_len,
+                        crlf,
+                    }
+                };
+                // the lines are not contiguous once stripped
+                return Ok((event, Cow::Owned([module, name].concat())));
+            }
+
+            // Persistent objects
+            0x50 => {
+                // PERSID
+                let line = self.take_line()?;
+                let crlf = line.ends_with(b"\r\n");
+                let line = trim_line(line);
+                (
+                    Event::PersId {
+                        id_len: line.len(),
+                        crlf,
+                    },
+                    line,
+                )
+            }
+
+            // Opcodes without payload, or whose payload is decoded by the reader
+            _ => {
+                self.scratch.clear();
+                let mut reader =
+                    Reader::new_at(&self.data[start..], start).with_limits(self.limits);
+                let event = reader.read_event(&mut self.scratch)?;
+                self.pos = reader.pos;
+                // the lines of text opcodes are borrowed as well
+                let line = &self.data[(start + 1).min(self.pos)..self.pos];
+                let payload = if self.scratch.is_empty() {
+                    Cow::Borrowed(&[][..])
+                } else if self.scratch == line {
+                    Cow::Borrowed(line)
+                } else {
+                    Cow::Owned(self.scratch.clone())
+                };
+                return Ok((event, payload));
+            }
+        };
+        Ok((event, Cow::Borrowed(payload)))
+    }
+
+    /// Collects all events in parallel, borrowing payloads from the input
+    ///
+    /// Large frames are decoded in separate threads, without being copied, with the same
+    /// limits. Events are read up to the first STOP, after which the reader is positioned.
+    pub fn par_collect_events(&mut self) -> Result<Vec<BorrowedEvent<'a>>, Error> {
+        let data = self.data;
+        thread::scope(|scope| {
+            let mut events = Vec::new();
+            let mut threads = Vec::new();
+            // end of the first STOP
+            let stop = loop {
+                let event = match self.read_event() {
+                    Ok(event) => event,
+                    Err(e) => {
+                        // the error may be in the next pickle, after a STOP decoded in a thread
+                        let (joined, stop) = join_frames(events, &mut threads)?;
+                        if stop.is_none() {
+                            return Err(e);
+                        }
+                        events = joined;
+                        break stop;
+                    }
+                };
+                match event {
+                    (Event::Frame(len), _) => {
+                        // if the frame is big enough, read it in another thread
+                        if len >= FRAME_SPAWN_SIZE {
+                            let end = self.pos.saturating_add(len as usize);
+                            let frame = data.get(..end).ok_or_else(Self::eof)?;
+                            let frame_reader =
+                                SliceReader::new_at(frame, self.pos).with_limits(self.limits);
+                            threads.push(scope.spawn(move || frame_reader.collect_frame()));
+                            // push a placeholder the frame events will replace
+                            events.push(None);
+                            self.pos = end;
+                            // the last frame of a pickle ends with its STOP: don't read past it
+                            if frame.last() == Some(&b'.') {
+                                let (joined, stop) = join_frames(events, &mut threads)?;
+                                events = joined;
+                                if stop.is_some() {
+                                    break stop;
+                                }
+                            }
+                        }
+                    }
+                    (Event::Stop, _) => break Some(self.pos),
+                    event => events.push(Some(event)),
+                }
+            };
+
+            // a STOP in a frame comes before the events read after it
+            let (events, frame_stop) = join_frames(events, &mut threads)?;
+            if let Some(end) = frame_stop.or(stop) {
+                self.pos = end;
+            }
+            Ok(events.into_iter().flatten().collect())
+        })
+    }
+
+    /// Reads all the events up to the end of the data, or up to a STOP whose end is returned
+    fn collect_frame(mut self) -> FrameEvents<'a> {
+        let mut events = Vec::new();
+        loop {
+            let start = self.pos;
+            match self.read_event()? {
+                (Event::Stop, _) => return Ok((events, (self.pos > start).then_some(self.pos))),
+                event => events.push(event),
+            }
+        }
+    }
+}
+
+/// The events of a frame decoded in a thread, with the end of its STOP
+type FrameEvents<'a> = Result<(Vec<BorrowedEvent<'a>>, Option<usize>), Error>;
+
+/// Replaces the placeholders with the events of the frames decoded in threads, in order
+///
+/// The events are cut at the first STOP in a frame, whose end is returned.
+fn join_frames<'a>(
+    events: Vec<Option<BorrowedEvent<'a>>>,
+    threads: &mut Vec<thread::ScopedJoinHandle<'_, FrameEvents<'a>>>,
+) -> Result<(Vec<Option<BorrowedEvent<'a>>>, Option<usize>), Error> {
+    if threads.is_empty() {
+        return Ok((events, None));
+    }
+    let mut threads = threads.drain(..);
+    let mut all = Vec::with_capacity(events.len());
+    for event in events {
+        match event {
+            Some(event) => all.push(Some(event)),
+            None => {
+                let th = threads.next().expect("one thread per placeholder");
+                let (frame_events, stop) = th.join().unwrap()?;
+                all.extend(frame_events.into_iter().map(Some));
+                if stop.is_some() {
+                    return Ok((all, stop));
+                }
+            }
+        }
+    }
+    Ok((all, None))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::errors::ErrorKind;
+
+    /// Reads all events with both readers and checks they are the same
+    fn compare_readers(data: &[u8]) -> Result<usize, Error> {
+        let mut slice_reader = SliceReader::new(data);
+        let mut reader = Reader::new(data);
+        let mut buf = Vec::new();
+        let mut count = 0;
+        loop {
+            let (event, payload) = slice_reader.read_event_with_span()?;
+            assert_eq!(event, reader.read_event_with_span(&mut buf)?);
+            let event = event.value;
+            assert_eq!(&*payload, &*buf, "{event:?}");
+            if let Event::Stop = event {
+                break;
+            }
+            buf.clear();
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    #[test]
+    fn test_borrowed_str() -> Result<(), Error> {
+        let data: &[u8] = b"\x80\x04\x95\x05\x00\x00\x00\x00\x00\x00\x00\x8c\x01/\x94.";
+        let mut reader = SliceReader::new(data);
+        reader.read_event()?;
+        reader.read_event()?;
+        let (event, payload) = reader.read_event()?;
+        assert_eq!(event, Event::ShortBinUnicode { len: 1 });
+        assert!(matches!(payload, Cow::Borrowed(b"/")));
+
+        // the lines of text opcodes
+        let mut reader = SliceReader::new(b"I5\r\n.");
+        let (event, payload) = reader.read_event()?;
+        assert_eq!(event, Event::Int(5));
+        assert!(matches!(payload, Cow::Borrowed(b"5\r\n")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_same_as_reader() -> Result<(), Error> {
+        // pickle.dumps([2**70, 'a', b'b', collections.OrderedDict], protocol=2) and protocol=0
+        compare_readers(b"\x80\x02]q\x00(\x8a\t\x00\x00\x00\x00\x00\x00\x00\x00@X\x01\x00\x00\x00aq\x01c_codecs\nencode\nq\x02X\x01\x00\x00\x00bq\x03X\x06\x00\x00\x00latin1q\x04\x86q\x05Rq\x06ccollections\nOrderedDict\nq\x07e.")?;
+        compare_readers(b"(lp0\nL1180591620717411303424L\naVa\np1\naF0.5\na.")?;
+        compare_readers(b"(lp0\r\nI1099511627776\r\nacos\r\nsystem\r\np1\r\naP7\r\na.")?;
+        for path in [
+            "/ints.pickle",
+            "/dict.pickle",
+            "/benches/data/manystrings.pickle",
+        ] {
+            let data = std::fs::read(format!("{}{path}", env!("CARGO_MANIFEST_DIR")))?;
+            compare_readers(&data)?;
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated() {
+        let data: &[u8] = b"\x80\x04\x8c\x05ab";
+        let mut reader = SliceReader::new(data);
+        reader.read_event().unwrap();
+        let err = reader.read_event().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Io(_)));
+        assert_eq!(err.offset(), Some(2));
+        assert_eq!(err.opcode_name(), Some("SHORT_BINUNICODE"));
+
+        // a BINUNICODE of 2 GiB is truncated, not negative
+        let err = SliceReader::new(b"X\x00\x00\x00\x80abc")
+            .read_event()
+            .unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Io(_)));
+    }
+
+    #[test]
+    fn test_par_collect_events() -> Result<(), Error> {
+        // a list of strings in a frame large enough to be read in another thread
+        let mut body = b"]\x94(".to_vec();
+        for i in 0..20000 {
+            let s = format!("s{i}");
+            body.extend_from_slice(&[0x8c, s.len() as u8]);
+            body.extend_from_slice(s.as_bytes());
+        }
+        body.extend_from_slice(b"e.");
+        let mut data = vec![0x80, 0x04, 0x95];
+        data.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        data.extend_from_slice(&body);
+
+        let events = SliceReader::new(&data).par_collect_events()?;
+        let expected = Reader::new(&*data).par_collect_events()?;
+        assert_eq!(events.len(), expected.len());
+        for ((event, payload), (expected, expected_payload)) in events.iter().zip(expected.iter()) {
+            assert_eq!(*event, expected);
+            assert_eq!(&**payload, expected_payload);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_par_collect_concatenated() -> Result<(), Error> {
+        // two pickles of a list of strings, each in a frame read in another thread, the
+        // first one with a byte left in its frame after the STOP
+        let mut data = Vec::new();
+        let mut stop = None;
+        for (prefix, rest) in [("a", &b"N"[..]), ("b", b"")] {
+            let mut body = b"]\x94(".to_vec();
+            for i in 0..20000 {
+                let s = format!("{prefix}{i}");
+                body.extend_from_slice(&[0x8c, s.len() as u8]);
+                body.extend_from_slice(s.as_bytes());
+            }
+            body.extend_from_slice(b"e.");
+            data.extend_from_slice(&[0x80, 0x04, 0x95]);
+            data.extend_from_slice(&((body.len() + rest.len()) as u64).to_le_bytes());
+            data.extend_from_slice(&body);
+            stop.get_or_insert(data.len());
+            data.extend_from_slice(rest);
+        }
+
+        let mut reader = SliceReader::new(&data);
+        let events = reader.par_collect_events()?;
+        assert_eq!(events.len(), 20005);
+        assert_eq!(&*events[4].1, b"a0");
+        assert_eq!(events.last().map(|e| e.0), Some(Event::Appends));
+        assert_eq!(Some(reader.position()), stop);
+
+        // the byte after the STOP, then the second pickle
+        let events = reader.par_collect_events()?;
+        assert_eq!(events.len(), 20006);
+        assert_eq!(events[0].0, Event::None);
+        assert_eq!(&*events[5].1, b"b0");
+        assert_eq!(reader.position(), data.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            max_payload: 4,
+            max_frame: 1024,
+            ..Limits::default()
+        };
+        let kind = |data: &[u8], limits: Limits| {
+            let err = SliceReader::new(data)
+                .with_limits(limits)
+                .par_collect_events()
+                .unwrap_err();
+            // same error as the reader
+            let expected = Reader::new(data)
+                .with_limits(limits)
+                .par_collect_events()
+                .unwrap_err();
+            assert_eq!(err.to_string(), expected.to_string());
+            err.into_kind()
+        };
+
+        // payloads, counted or on a line
+        for data in [
+            &b"\x80\x04\x8c\x05abcde."[..],
+            b"\x80\x03B\x05\x00\x00\x00abcde.",
+            b"S'abc'\n.",
+        ] {
+            assert!(matches!(
+                kind(data, limits),
+                ErrorKind::PayloadTooLarge { max: 4, .. }
+            ));
+        }
+        let events = SliceReader::new(b"\x80\x04\x8c\x04abcd.")
+            .with_limits(limits)
+            .par_collect_events()
+            .unwrap();
+        assert_eq!(events.len(), 2);
+
+        // a frame claiming more than the limit
+        let data = b"\x80\x04\x95\x00\x00\x01\x00\x00\x00\x00\x00N.";
+        assert!(matches!(
+            kind(data, limits),
+            ErrorKind::FrameTooLarge {
+                len: 0x10000,
+                max: 1024
+            }
+        ));
+
+        // a payload in a frame read in another thread
+        let mut body = b"]\x94(".to_vec();
+        for _ in 0..30000 {
+            body.extend_from_slice(b"\x8c\x04abcd");
+        }
+        body.extend_from_slice(b"\x8c\x05abcdee.");
+        let mut data = vec![0x80, 0x04, 0x95];
+        data.extend_from_slice(&(body.len() as u64).to_le_bytes());
+        data.extend_from_slice(&body);
+        let limits = Limits {
+            max_payload: 4,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            kind(&data, limits),
+            ErrorKind::PayloadTooLarge { len: 5, max: 4 }
+        ));
+
+        // the input size
+        let limits = Limits {
+            max_total: 4,
+            ..Limits::default()
+        };
+        assert!(matches!(
+            kind(b"\x80\x04\x8c\x05abcde.", limits),
+            ErrorKind::InputTooLarge { max: 4 }
+        ));
+        let data = b"\x80\x04N.";
+        let limits = Limits {
+            max_total: data.len(),
+            ..Limits::default()
+        };
+        let mut reader = SliceReader::new(data).with_limits(limits);
+        for _ in 0..3 {
+            reader.read_event().unwrap();
+        }
+        assert_eq!(reader.read_event().unwrap().0, Event::Stop);
+        assert_eq!(reader.read_event().unwrap().0, Event::Stop);
+    }
+}
@@ -0,0 +1,220 @@
+//! A module to load several pickles written one after the other
+//!
+//! Repeated `pickle.dump(obj, f)` calls, as well as logs or pipes of pickled messages,
+//! produce a stream of complete pickles. A [`PickleStream`] loads them one at a time until
+//! the end of the input, where a [`Reader`] or an [`Unpickler`] only read the first one.
+//!
+//! ```
+//! use quick_pickle::{PickleStream, Value};
+//!
+//! // pickle.dump(1, f); pickle.dump('a', f)
+//! let data = b"\x80\x04K\x01.\x80\x04\x95\x05\x00\x00\x00\x00\x00\x00\x00\x8c\x01a\x94.";
+//! let values = PickleStream::new(&data[..]).collect::<Result<Vec<_>, _>>()?;
+//! assert_eq!(values, [Value::Int(1), Value::Str("a".into())]);
+//! # Ok::<(), quick_pickle::Error>(())
+//! ```
+//!
+//! [`Reader`]: crate::reader::Reader
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    iter::FusedIterator,
+    path::Path,
+};
+
+use crate::{
+    errors::{Error, ErrorKind},
+    unpickler::Unpickler,
+    value::Value,
+};
+
+/// An iterator over the pickles of an input, until its end
+///
+/// Each pickle is loaded with a fresh stack and memo. A trailing pickle without its STOP
+/// opcode is an `UnexpectedEof` error. After an error in a pickle, the rest of it is skipped
+/// and the next one is loaded; the iteration ends at IO errors, or if the rest of the
+/// pickle cannot be read.
+pub struct PickleStream<R> {
+    unpickler: Unpickler<R>,
+    state: State,
+}
+
+enum State {
+    /// At the start of a pickle
+    Load,
+    /// The last pickle failed before its STOP, the rest of it must be skipped
+    Resync,
+    Done,
+}
+
+impl PickleStream<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(PickleStream::from_unpickler(Unpickler::open(path)?))
+    }
+}
+
+impl<R: BufRead> PickleStream<R> {
+    pub fn new(reader: R) -> Self {
+        PickleStream::from_unpickler(Unpickler::new(reader))
+    }
+
+    /// Creates a stream loading each pickle with an existing unpickler
+    ///
+    /// Its policy, persistent loader and out-of-band buffers apply to the whole stream, as do
+    /// the limits of its reader.
+    pub fn from_unpickler(unpickler: Unpickler<R>) -> Self {
+        PickleStream {
+            unpickler,
+            state: State::Load,
+        }
+    }
+
+    /// Gets the offset of the next pickle in the input, or of the failed one
+    pub fn position(&self) -> usize {
+        self.unpickler.reader().position()
+    }
+
+    pub fn into_inner(self) -> Unpickler<R> {
+        self.unpickler
+    }
+
+    /// Reads the remaining events of a pickle, up to its STOP
+    fn skip(&mut self) -> Result<(), Error> {
+        for event in self.unpickler.reader_mut().events() {
+            event?;
+        }
+        Ok(())
+    }
+
+    fn load_next(&mut self) -> Result<Option<Value>, Error> {
+        if self.unpickler.reader_mut().at_eof()? {
+            return Ok(None);
+        }
+        self.unpickler.load().map(Some)
+    }
+}
+
+impl<R: BufRead> Iterator for PickleStream<R> {
+    type Item = Result<Value, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.state {
+            State::Load => (),
+            State::Resync => {
+                if let Err(e) = self.skip() {
+                    // the stream cannot be resynchronized
+                    self.state = State::Done;
+                    return Some(Err(e));
+                }
+                self.state = State::Load;
+            }
+            State::Done => return None,
+        }
+        match self.load_next() {
+            Ok(Some(value)) => Some(Ok(value)),
+            Ok(None) => {
+                self.state = State::Done;
+                None
+            }
+            Err(e) => {
+                // a truncated pickle can only be the last one
+                self.state = if matches!(e.kind(), ErrorKind::Io(_)) {
+                    State::Done
+                } else if self.unpickler.reader().opcode != b'.' {
+                    State::Resync
+                } else {
+                    State::Load
+                };
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl<R: BufRead> FusedIterator for PickleStream<R> {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        reader::{Limits, Reader},
+        safety::SafetyPolicy,
+    };
+
+    #[test]
+    fn test_stream() -> Result<(), Error> {
+        // for p in (0, 2, 4): pickle.dump([p, 'x'], f, protocol=p)
+        let data = b"(lp0\nI0\naVx\np1\na.\x80\x02]q\x00(K\x02X\x01\x00\x00\x00xq\x01e.\x80\x04\x95\x0b\x00\x00\x00\x00\x00\x00\x00]\x94(K\x04\x8c\x01x\x94e.";
+        let values = PickleStream::new(&data[..]).collect::<Result<Vec<_>, _>>()?;
+        let expected = [0, 2, 4].map(|p| Value::List(vec![Value::Int(p), Value::Str("x".into())]));
+        assert_eq!(values, expected);
+
+        // the whole input, up to a limit of its size
+        let limits = Limits {
+            max_total: data.len(),
+            ..Limits::default()
+        };
+        let reader = Reader::new(&data[..]).with_limits(limits);
+        let stream = PickleStream::from_unpickler(Unpickler::from_reader(reader));
+        assert_eq!(stream.collect::<Result<Vec<_>, _>>()?, expected);
+
+        assert_eq!(PickleStream::new(&b""[..]).count(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_truncated() {
+        // the last pickle lacks its STOP, then its last bytes
+        for len in [1, 3] {
+            let data = b"\x80\x02K\x01.\x80\x02K\x02.\x80\x02K\x03.";
+            let mut stream = PickleStream::new(&data[..data.len() - len]);
+            assert_eq!(stream.next().unwrap().unwrap(), Value::Int(1));
+            assert_eq!(stream.next().unwrap().unwrap(), Value::Int(2));
+            let err = stream.next().unwrap().unwrap_err();
+            assert!(
+                matches!(err.kind(), ErrorKind::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+            );
+            assert!(stream.next().is_none());
+        }
+    }
+
+    #[test]
+    fn test_skip_after_error() -> Result<(), Error> {
+        // pickle.dump((os.system, b'.'), f, protocol=4); pickle.dump(b'.', f, protocol=4)
+        // then a pickle failing at its STOP, and 7
+        let data = b"\x80\x04\x95\x1a\x00\x00\x00\x00\x00\x00\x00\x8c\x05posix\x94\x8c\x06system\x94\x93\x94C\x01.\x94\x86\x94.\
+            \x80\x04\x95\x05\x00\x00\x00\x00\x00\x00\x00C\x01.\x94.\
+            \x80\x02K\x01K\x02.\
+            \x80\x02K\x07.";
+        let unpickler = Unpickler::new(&data[..]).with_policy(SafetyPolicy::new());
+        let mut stream = PickleStream::from_unpickler(unpickler);
+        let err = stream.next().unwrap().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Forbidden { .. }));
+        assert_eq!(stream.next().unwrap()?, Value::Bytes(b".".to_vec()));
+        assert!(stream.next().unwrap().is_err());
+        assert_eq!(stream.next().unwrap()?, Value::Int(7));
+        assert!(stream.next().is_none());
+        assert_eq!(stream.position(), data.len());
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_then_truncated() {
+        // pickle.dump((os.system, b'.'), f, protocol=4); pickle.dump(b'.', f, protocol=4)
+        // then a pickle without its STOP
+        let data = b"\x80\x04\x95\x1a\x00\x00\x00\x00\x00\x00\x00\x8c\x05posix\x94\x8c\x06system\x94\x93\x94C\x01.\x94\x86\x94.\
+            \x80\x04\x95\x05\x00\x00\x00\x00\x00\x00\x00C\x01.\x94.\
+            \x80\x02K\x03";
+        let unpickler = Unpickler::new(&data[..]).with_policy(SafetyPolicy::new());
+        let mut stream = PickleStream::from_unpickler(unpickler);
+        let err = stream.next().unwrap().unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Forbidden { .. }));
+        assert_eq!(stream.next().unwrap().unwrap(), Value::Bytes(b".".to_vec()));
+        let err = stream.next().unwrap().unwrap_err();
+        assert!(
+            matches!(err.kind(), ErrorKind::Io(e) if e.kind() == std::io::ErrorKind::UnexpectedEof)
+        );
+        assert!(stream.next().is_none());
+    }
+}
@@ -0,0 +1,471 @@
+//! A module to load PyTorch checkpoints (`.pt` files) without python
+//!
+//! A checkpoint saved by `torch.save` is a zip archive holding a `<name>/data.pkl` pickle
+//! and one `<name>/data/<key>` entry per storage. The pickle references storages with
+//! BINPERSID `('storage', storage_type, key, location, numel)` ids and rebuilds tensors
+//! with `torch._utils._rebuild_tensor_v2(storage, offset, size, stride, ...)`.
+//!
+//! ```no_run
+//! for (name, tensor) in quick_pickle::torch::load("model.pt")? {
+//!     println!("{name}: {:?} {:?}", tensor.dtype, tensor.shape);
+//! }
+//! # Ok::<(), quick_pickle::Error>(())
+//! ```
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufReader, Read, Seek},
+    path::Path,
+    sync::Arc,
+};
+
+use zip::ZipArchive;
+
+use crate::{
+    errors::{Error, ErrorKind},
+    unpickler::{PersistentLoader, Unpickler},
+    value::Value,
+};
+
+/// The element type of a tensor, given by its storage type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum DType {
+    F64,
+    F32,
+    F16,
+    BF16,
+    I64,
+    I32,
+    I16,
+    I8,
+    U8,
+    Bool,
+    C64,
+    C128,
+}
+
+impl DType {
+    /// Gets the dtype of a `torch.<Name>Storage` class
+    pub fn from_storage(name: &str) -> Option<Self> {
+        Some(match name {
+            "DoubleStorage" => DType::F64,
+            "FloatStorage" => DType::F32,
+            "HalfStorage" => DType::F16,
+            "BFloat16Storage" => DType::BF16,
+            "LongStorage" => DType::I64,
+            "IntStorage" => DType::I32,
+            "ShortStorage" => DType::I16,
+            "CharStorage" => DType::I8,
+            "ByteStorage" | "UntypedStorage" => DType::U8,
+            "BoolStorage" => DType::Bool,
+            "ComplexFloatStorage" => DType::C64,
+            "ComplexDoubleStorage" => DType::C128,
+            _ => return None,
+        })
+    }
+
+    /// Gets the size of an element in bytes
+    pub fn size(&self) -> usize {
+        match self {
+            DType::I8 | DType::U8 | DType::Bool => 1,
+            DType::F16 | DType::BF16 | DType::I16 => 2,
+            DType::F32 | DType::I32 => 4,
+            DType::F64 | DType::I64 | DType::C64 => 8,
+            DType::C128 => 16,
+        }
+    }
+}
+
+/// A tensor whose data is a view into a storage of the checkpoint
+///
+/// Shape, strides and offset are in elements, as in python. Data is in the byte order of
+/// the machine which saved the checkpoint, little endian in practice.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Tensor {
+    pub dtype: DType,
+    pub shape: Vec<usize>,
+    pub strides: Vec<usize>,
+    /// Offset of the first element in the storage
+    pub offset: usize,
+    pub requires_grad: bool,
+    storage: Arc<[u8]>,
+}
+
+impl Tensor {
+    /// Builds a tensor from a `torch._utils._rebuild_tensor_v2` (or `_rebuild_tensor`,
+    /// `_rebuild_parameter`) call whose storage has been resolved by [`load_value`]
+    ///
+    /// Returns `None` if the value is not such a call.
+    pub fn from_value(value: &Value) -> Result<Option<Self>, Error> {
+        let Value::Reduce { callable, args } = value else {
+            return Ok(None);
+        };
+        let Value::Global { module, name } = &**callable else {
+            return Ok(None);
+        };
+        if module != "torch._utils" {
+            return Ok(None);
+        }
+        let args = args
+            .as_slice()
+            .ok_or_else(|| Error::unexpected("tuple arguments", args.type_name()))?;
+        match (name.as_str(), args) {
+            (
+                "_rebuild_tensor_v2" | "_rebuild_tensor",
+                [storage, offset, shape, strides, rest @ ..],
+            ) => {
+                let Value::Reduce { callable, args } = storage else {
+                    return Err(Error::unexpected("torch storage", storage.type_name()));
+                };
+                let dtype = match &**callable {
+                    Value::Global { module, name } if module == "torch" => {
+                        DType::from_storage(name)
+                            .ok_or_else(|| Error::unexpected("torch storage type", name))?
+                    }
+                    callable => {
+                        return Err(Error::unexpected(
+                            "torch storage type",
+                            callable.type_name(),
+                        ));
+                    }
+                };
+                let storage = match args.as_slice() {
+                    Some([Value::Buffer { data, .. }]) => data.clone(),
+                    _ => return Err(Error::unexpected("storage data", args.type_name())),
+                };
+                let tensor = Tensor {
+                    dtype,
+                    offset: usize_value(offset)?,
+                    shape: usizes(shape)?,
+                    strides: usizes(strides)?,
+                    requires_grad: matches!(rest.first(), Some(Value::Bool(true))),
+                    storage,
+                };
+                if tensor.shape.len() != tensor.strides.len() {
+                    return Err(
+                        ErrorKind::Invalid("tensor shape and strides lengths differ").into(),
+                    );
+                }
+                if tensor.end().is_none_or(|end| end > tensor.storage.len()) {
+                    return Err(ErrorKind::Invalid("tensor exceeds its storage").into());
+                }
+                Ok(Some(tensor))
+            }
+            ("_rebuild_parameter" | "_rebuild_parameter_with_state", [data, requires_grad, ..]) => {
+                let Some(mut tensor) = Tensor::from_value(data)? else {
+                    return Err(Error::unexpected("tensor", data.type_name()));
+                };
+                tensor.requires_grad = matches!(requires_grad, Value::Bool(true));
+                Ok(Some(tensor))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    /// Gets the number of elements
+    pub fn numel(&self) -> usize {
+        self.shape.iter().product()
+    }
+
+    /// Checks if elements are laid out in row-major order, without gaps
+    pub fn is_contiguous(&self) -> bool {
+        let mut expected = 1;
+        for (&dim, &stride) in self.shape.iter().zip(&self.strides).rev() {
+            if dim != 1 && stride != expected {
+                return false;
+            }
+            expected *= dim;
+        }
+        true
+    }
+
+    /// Gets the whole storage of the tensor, which may be shared with other tensors
+    pub fn storage(&self) -> &[u8] {
+        &self.storage
+    }
+
+    /// Gets the bytes spanned by the tensor, from its first to its last element
+    ///
+    /// For a contiguous tensor, these are exactly its elements in row-major order. Empty if
+    /// the tensor does not fit in its storage.
+    pub fn data(&self) -> &[u8] {
+        // bounds are checked when loading, but fields may have been changed since
+        let start = self.offset.saturating_mul(self.dtype.size());
+        self.end()
+            .and_then(|end| self.storage.get(start..end))
+            .unwrap_or_default()
+    }
+
+    /// Gets the byte offset past the last element in the storage
+    fn end(&self) -> Option<usize> {
+        let span = if self.shape.contains(&0) {
+            0
+        } else {
+            self.shape
+                .iter()
+                .zip(&self.strides)
+                .try_fold(1usize, |span, (&dim, &stride)| {
+                    span.checked_add((dim - 1).checked_mul(stride)?)
+                })?
+        };
+        self.offset
+            .checked_add(span)?
+            .checked_mul(self.dtype.size())
+    }
+}
+
+fn usize_value(value: &Value) -> Result<usize, Error> {
+    value
+        .as_i64()
+        .and_then(|i| usize::try_from(i).ok())
+        .ok_or_else(|| Error::unexpected("non-negative int", value.type_name()))
+}
+
+fn usizes(value: &Value) -> Result<Vec<usize>, Error> {
+    value
+        .as_slice()
+        .ok_or_else(|| Error::unexpected("tuple of ints", value.type_name()))?
+        .iter()
+        .map(usize_value)
+        .collect()
+}
+
+/// Resolves storage ids to `torch.<Name>Storage(data)` calls, reading each storage once
+struct StorageLoader<R> {
+    archive: ZipArchive<R>,
+    /// Directory of `data.pkl`, with its trailing `/`
+    prefix: String,
+    storages: HashMap<String, Arc<[u8]>>,
+}
+
+impl<R: Read + Seek> PersistentLoader for StorageLoader<R> {
+    fn persistent_load(&mut self, pid: Value) -> Result<Value, Error> {
+        let Value::Tuple(mut items) = pid else {
+            return Err(Error::unexpected("storage id tuple", pid.type_name()));
+        };
+        let (storage_type, key) = match items.as_mut_slice() {
+            [Value::Str(tag), storage_type, Value::Str(key), ..] if tag == "storage" => (
+                std::mem::replace(storage_type, Value::None),
+                std::mem::take(key),
+            ),
+            _ => return Err(ErrorKind::Invalid("persistent id is not a storage id").into()),
+        };
+        let data = match self.storages.get(&key) {
+            Some(data) => data.clone(),
+            None => {
+                let name = format!("{}data/{key}", self.prefix);
+                let data: Arc<[u8]> = read_entry(&mut self.archive, &name)
+                    .map_err(|e| e.with_context(format!("while loading storage '{key}'")))?
+                    .into();
+                self.storages.insert(key, data.clone());
+                data
+            }
+        };
+        Ok(Value::Reduce {
+            callable: Box::new(storage_type),
+            args: Box::new(Value::Tuple(vec![Value::Buffer {
+                data,
+                readonly: true,
+            }])),
+        })
+    }
+}
+
+fn read_entry<R: Read + Seek>(archive: &mut ZipArchive<R>, name: &str) -> Result<Vec<u8>, Error> {
+    let mut entry = archive.by_name(name)?;
+    // the size comes from the archive: grow with the data actually read, up to that size
+    let size = entry.size();
+    let mut data = Vec::new();
+    (&mut entry).take(size).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+/// Unpickles the `data.pkl` of a checkpoint
+///
+/// Storages are resolved to `torch.<Name>Storage(data)` [`Value::Reduce`]s whose argument is a
+/// [`Value::Buffer`] shared by all the tensors viewing it. Use [`tensors`] to extract them.
+pub fn load_value<R: Read + Seek + 'static>(reader: R) -> Result<Value, Error> {
+    let mut archive = ZipArchive::new(reader)?;
+    let pickle = archive
+        .file_names()
+        .find(|name| *name == "data.pkl" || name.ends_with("/data.pkl"))
+        .ok_or(ErrorKind::Invalid("checkpoint has no data.pkl"))?
+        .to_string();
+    let prefix = pickle.trim_end_matches("data.pkl").to_string();
+    match read_entry(&mut archive, &format!("{prefix}byteorder")) {
+        Ok(order) if order != b"little" => {
+            return Err(ErrorKind::Invalid("big endian checkpoints are not supported").into());
+        }
+        _ => (),
+    }
+    let data = read_entry(&mut archive, &pickle)?;
+    let loader = StorageLoader {
+        archive,
+        prefix,
+        storages: HashMap::new(),
+    };
+    Unpickler::new(&data[..])
+        .with_persistent_loader(loader)
+        .load()
+        .map_err(|e| e.with_context("while loading data.pkl"))
+}
+
+/// Collects the tensors of an unpickled checkpoint with their names
+///
+/// Names are the dot-separated keys (or indices) leading to the tensors, e.g.
+/// `model.fc.weight` for `{'model': {'fc.weight': tensor}}`, in stream order.
+pub fn tensors(value: &Value) -> Result<Vec<(String, Tensor)>, Error> {
+    let mut tensors = Vec::new();
+    collect(value, String::new(), &mut tensors)?;
+    Ok(tensors)
+}
+
+fn collect(value: &Value, name: String, tensors: &mut Vec<(String, Tensor)>) -> Result<(), Error> {
+    if let Some(tensor) = Tensor::from_value(value).map_err(|e| match name.as_str() {
+        "" => e,
+        _ => e.with_context(format!("while loading tensor '{name}'")),
+    })? {
+        tensors.push((name, tensor));
+        return Ok(());
+    }
+    let join = |key: &dyn std::fmt::Display| match name.as_str() {
+        "" => key.to_string(),
+        _ => format!("{name}.{key}"),
+    };
+    match value {
+        Value::Dict(items) => {
+            for (key, value) in items {
+                let key = match key {
+                    Value::Str(s) => join(s),
+                    Value::Int(i) => join(i),
+                    _ => continue,
+                };
+                collect(value, key, tensors)?;
+            }
+        }
+        Value::List(items) | Value::Tuple(items) => {
+            for (i, item) in items.iter().enumerate() {
+                collect(item, join(&i), tensors)?;
+            }
+        }
+        // e.g. an `OrderedDict` and its items, or a module and its `__dict__`
+        Value::Extend {
+            object,
+            items: other,
+        }
+        | Value::Build {
+            object,
+            state: other,
+        } => {
+            collect(object, name.clone(), tensors)?;
+            collect(other, name, tensors)?;
+        }
+        _ => (),
+    }
+    Ok(())
+}
+
+/// Loads the named tensors of a checkpoint
+pub fn from_reader<R: Read + Seek + 'static>(reader: R) -> Result<Vec<(String, Tensor)>, Error> {
+    tensors(&load_value(reader)?)
+}
+
+/// Loads the named tensors of a checkpoint file
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Vec<(String, Tensor)>, Error> {
+    from_reader(BufReader::new(File::open(path)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Write};
+
+    use zip::{CompressionMethod, ZipWriter, write::SimpleFileOptions};
+
+    use super::*;
+
+    /// Zips entries as `torch.save` does, without compression
+    fn archive(entries: &[(&str, &[u8])]) -> Cursor<Vec<u8>> {
+        let mut zip = ZipWriter::new(Cursor::new(Vec::new()));
+        let options = SimpleFileOptions::default().compression_method(CompressionMethod::Stored);
+        for (name, data) in entries {
+            zip.start_file(*name, options).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        let mut cursor = zip.finish().unwrap();
+        cursor.set_position(0);
+        cursor
+    }
+
+    /// `data.pkl` of `torch.save` for an `OrderedDict` with `fc.weight` (2x3 float),
+    /// `fc.bias` (3 floats), `fc.weight_t` (`fc.weight.t()`) and `steps` (int64 scalar)
+    const STATE_DICT: &[u8] = b"\x80\x02ccollections\nOrderedDict\nq\x00)Rq\x01(X\t\x00\x00\x00fc.weightq\x02ctorch._utils\n_rebuild_tensor_v2\nq\x03((X\x07\x00\x00\x00storageq\x04ctorch\nFloatStorage\nq\x05X\x01\x00\x00\x000q\x06X\x03\x00\x00\x00cpuq\x07K\x06tq\x08QK\x00K\x02K\x03\x86q\tK\x03K\x01\x86q\n\x89h\x00)Rq\x0btq\x0cRq\rX\x07\x00\x00\x00fc.biasq\x0eh\x03((h\x04h\x05X\x01\x00\x00\x001q\x0fh\x07K\x03tq\x10QK\x00K\x03\x85q\x11K\x01\x85q\x12\x89h\x00)Rq\x13tq\x14Rq\x15X\x0b\x00\x00\x00fc.weight_tq\x16h\x03((h\x04h\x05h\x06h\x07K\x06tq\x17QK\x00K\x03K\x02\x86q\x18K\x01K\x03\x86q\x19\x89h\x00)Rq\x1atq\x1bRq\x1cX\x05\x00\x00\x00stepsq\x1dh\x03((h\x04ctorch\nLongStorage\nq\x1eX\x01\x00\x00\x002q\x1fh\x07K\x01tq QK\x00))\x89h\x00)Rq!tq\"Rq#u}q$X\t\x00\x00\x00_metadataq%h\x00)Rq&X\x00\x00\x00\x00q'}q(X\x07\x00\x00\x00versionq)K\x01sssb.";
+
+    fn floats(values: &[f32]) -> Vec<u8> {
+        values.iter().flat_map(|f| f.to_le_bytes()).collect()
+    }
+
+    #[test]
+    fn test_load() -> Result<(), Error> {
+        let weight = floats(&[1., 2., 3., 4., 5., 6.]);
+        let bias = floats(&[0.5; 3]);
+        let steps = 7i64.to_le_bytes();
+        let reader = archive(&[
+            ("model/data.pkl", STATE_DICT),
+            ("model/byteorder", b"little"),
+            ("model/data/0", &weight),
+            ("model/data/1", &bias),
+            ("model/data/2", &steps),
+            ("model/version", b"3\n"),
+        ]);
+        let tensors = from_reader(reader)?;
+        let names = tensors.iter().map(|(n, _)| n.as_str()).collect::<Vec<_>>();
+        assert_eq!(names, ["fc.weight", "fc.bias", "fc.weight_t", "steps"]);
+
+        let weight_tensor = &tensors[0].1;
+        assert_eq!(weight_tensor.dtype, DType::F32);
+        assert_eq!(weight_tensor.shape, [2, 3]);
+        assert_eq!(weight_tensor.strides, [3, 1]);
+        assert!(weight_tensor.is_contiguous());
+        assert_eq!(weight_tensor.data(), weight);
+        assert_eq!(tensors[1].1.data(), bias);
+
+        // views share their storage
+        let transposed = &tensors[2].1;
+        assert!(!transposed.is_contiguous());
+        assert_eq!(transposed.data(), weight);
+        assert!(std::ptr::eq(transposed.storage(), weight_tensor.storage()));
+
+        let scalar = &tensors[3].1;
+        assert_eq!((scalar.dtype, scalar.numel()), (DType::I64, 1));
+        assert_eq!(scalar.data(), steps);
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_errors() {
+        let weight = floats(&[1.; 6]);
+        let missing = archive(&[("model/data.pkl", STATE_DICT), ("model/data/0", &weight)]);
+        let err = from_reader(missing).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Zip(_)), "{err}");
+        assert!(
+            err.to_string().contains("while loading storage '1'"),
+            "{err}"
+        );
+
+        let short = archive(&[
+            ("model/data.pkl", STATE_DICT),
+            ("model/data/0", &weight[..8]),
+            ("model/data/1", &weight),
+            ("model/data/2", &weight),
+        ]);
+        let err = from_reader(short).unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Invalid(_)), "{err}");
+        assert!(err.to_string().contains("'fc.weight'"), "{err}");
+
+        let big_endian = archive(&[("data.pkl", STATE_DICT), ("byteorder", b"big")]);
+        assert!(from_reader(big_endian).is_err());
+        assert!(from_reader(archive(&[("model/version", b"3\n")])).is_err());
+    }
+}
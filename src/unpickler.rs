@@ -0,0 +1,1549 @@
+//! A module to rebuild python objects out of pickle events
+//!
+//! The [`Unpickler`] is the pickle virtual machine: it consumes the [`Event`]s
+//! of a [`Reader`] and maintains the stack, the metastack (one stack per mark) and
+//! the memo, until a STOP opcode leaves the final [`Value`] on the stack.
+
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fs::File,
+    io::{BufRead, BufReader},
+    mem,
+    path::Path,
+    str::from_utf8,
+    sync::Arc,
+};
+
+use crate::{
+    bigint::BigInt,
+    errors::{Error, ErrorKind},
+    reader::{Event, Reader, missing_stop, trim_line},
+    safety::SafetyPolicy,
+    value::Value,
+};
+
+/// An object on the unpickler stack
+///
+/// Mutable objects may be modified after having been memoized (a list is memoized
+/// before its items are appended) so they are moved into a table of shared objects, and
+/// the stack and the memo only hold their index until the final value is resolved.
+#[derive(Debug)]
+enum Obj {
+    Value(Value),
+    /// Index of a shared object, which is never itself a reference
+    Ref(usize),
+    List(Vec<Obj>),
+    Tuple(Vec<Obj>),
+    Dict(Vec<(Obj, Obj)>),
+    Set(Vec<Obj>),
+    FrozenSet(Vec<Obj>),
+    Reduce {
+        callable: Box<Obj>,
+        args: Box<Obj>,
+    },
+    Object {
+        class: Box<Obj>,
+        args: Box<Obj>,
+        kwargs: Option<Box<Obj>>,
+    },
+    Build {
+        object: Box<Obj>,
+        state: Box<Obj>,
+    },
+    Extend {
+        object: Box<Obj>,
+        items: Box<Obj>,
+    },
+}
+
+impl Obj {
+    fn type_name(&self) -> &'static str {
+        match self {
+            Obj::Value(v) => v.type_name(),
+            Obj::Ref(_) => "memo reference",
+            Obj::List(_) => "list",
+            Obj::Tuple(_) => "tuple",
+            Obj::Dict(_) => "dict",
+            Obj::Set(_) => "set",
+            Obj::FrozenSet(_) => "frozenset",
+            Obj::Reduce { .. } => "reduced object",
+            Obj::Object { .. } => "object",
+            Obj::Build { .. } => "built object",
+            Obj::Extend { .. } => "extended object",
+        }
+    }
+
+    /// Gets the `index`th object nested in this one
+    fn nested_mut(&mut self, index: usize) -> Option<&mut Obj> {
+        match self {
+            Obj::Value(_) | Obj::Ref(_) => None,
+            Obj::List(items) | Obj::Tuple(items) | Obj::Set(items) | Obj::FrozenSet(items) => {
+                items.get_mut(index)
+            }
+            Obj::Dict(items) => items
+                .get_mut(index / 2)
+                .map(|(key, value)| if index.is_multiple_of(2) { key } else { value }),
+            Obj::Reduce {
+                callable: first,
+                args: second,
+            }
+            | Obj::Build {
+                object: first,
+                state: second,
+            }
+            | Obj::Extend {
+                object: first,
+                items: second,
+            } => match index {
+                0 => Some(first),
+                1 => Some(second),
+                _ => None,
+            },
+            Obj::Object {
+                class,
+                args,
+                kwargs,
+            } => match index {
+                0 => Some(class),
+                1 => Some(args),
+                2 => kwargs.as_deref_mut(),
+                _ => None,
+            },
+        }
+    }
+
+    /// Moves the objects nested in this one to `out`
+    fn take_nested(&mut self, out: &mut Vec<Obj>) {
+        let mut take =
+            |obj: &mut Box<Obj>| out.push(mem::replace(&mut **obj, Obj::Value(Value::None)));
+        match self {
+            Obj::Value(_) | Obj::Ref(_) => (),
+            Obj::List(items) | Obj::Tuple(items) | Obj::Set(items) | Obj::FrozenSet(items) => {
+                out.append(items)
+            }
+            Obj::Dict(items) => {
+                for (key, value) in items.drain(..) {
+                    out.push(key);
+                    out.push(value);
+                }
+            }
+            Obj::Reduce { callable, args } => {
+                take(callable);
+                take(args);
+            }
+            Obj::Object {
+                class,
+                args,
+                kwargs,
+            } => {
+                take(class);
+                take(args);
+                if let Some(kwargs) = kwargs {
+                    take(kwargs);
+                }
+            }
+            Obj::Build { object, state } => {
+                take(object);
+                take(state);
+            }
+            Obj::Extend { object, items } => {
+                take(object);
+                take(items);
+            }
+        }
+    }
+}
+
+impl Drop for Obj {
+    fn drop(&mut self) {
+        // a pickle can nest objects deeper than dropping them recursively allows
+        let mut nested = Vec::new();
+        self.take_nested(&mut nested);
+        while let Some(mut obj) = nested.pop() {
+            obj.take_nested(&mut nested);
+        }
+    }
+}
+
+/// Resolves the persistent ids of a pickle, as python's `Unpickler.persistent_load`
+///
+/// Persistent ids reference objects stored outside of the pickle, e.g. the tensor storages
+/// of a PyTorch checkpoint or the records of a ZODB database. The id is a string for
+/// PERSID and an arbitrary object for BINPERSID, and is replaced by the returned value.
+///
+/// Closures taking and returning a [`Value`] are loaders:
+///
+/// ```
+/// use quick_pickle::{Unpickler, Value};
+///
+/// // PERSID 'key'
+/// let mut unpickler = Unpickler::new(&b"Pkey\n."[..])
+///     .with_persistent_loader(|pid: Value| Ok(Value::Str(format!("<{}>", pid.as_str().unwrap()))));
+/// assert_eq!(unpickler.load()?, Value::Str("<key>".into()));
+/// # Ok::<(), quick_pickle::Error>(())
+/// ```
+pub trait PersistentLoader {
+    fn persistent_load(&mut self, pid: Value) -> Result<Value, Error>;
+}
+
+impl<F> PersistentLoader for F
+where
+    F: FnMut(Value) -> Result<Value, Error>,
+{
+    fn persistent_load(&mut self, pid: Value) -> Result<Value, Error> {
+        self(pid)
+    }
+}
+
+/// Decoding of the python 2 str of STRING, BINSTRING and SHORT_BINSTRING opcodes, as the
+/// `encoding` argument of python's `pickle.load`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    /// A [`Value::Str`], non-ASCII bytes are an error
+    #[default]
+    Ascii,
+    /// A [`Value::Str`] of the bytes decoded as latin-1
+    ///
+    /// This loads python 2 numpy arrays and datetimes: the latin-1 state of
+    /// `datetime.{date,datetime,time}` is turned back into [`Value::Bytes`].
+    Latin1,
+    /// A [`Value::Str`], bytes which are not UTF-8 are an error
+    Utf8,
+    /// A [`Value::Bytes`]
+    Bytes,
+}
+
+pub struct Unpickler<R> {
+    reader: Reader<R>,
+    buf: Vec<u8>,
+    stack: Vec<Obj>,
+    metastack: Vec<Vec<Obj>>,
+    /// Objects referenced from the memo
+    shared: Vec<Obj>,
+    /// Index of the shared object of each memo key
+    memo: HashMap<u32, usize>,
+    policy: Option<SafetyPolicy>,
+    /// Out-of-band buffers, if given
+    buffers: Option<std::vec::IntoIter<Arc<[u8]>>>,
+    persistent_loader: Option<Box<dyn PersistentLoader>>,
+    encoding: Encoding,
+}
+
+impl Unpickler<BufReader<File>> {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        Ok(Unpickler::from_reader(Reader::open(path)?))
+    }
+}
+
+impl<R: BufRead> Unpickler<R> {
+    pub fn new(reader: R) -> Self {
+        Unpickler::from_reader(Reader::new(reader))
+    }
+
+    /// Creates an unpickler consuming the events of an existing reader
+    pub fn from_reader(reader: Reader<R>) -> Self {
+        Unpickler {
+            reader,
+            buf: Vec::new(),
+            stack: Vec::new(),
+            metastack: Vec::new(),
+            shared: Vec::new(),
+            memo: HashMap::new(),
+            policy: None,
+            buffers: None,
+            persistent_loader: None,
+            encoding: Encoding::default(),
+        }
+    }
+
+    /// Rejects the globals which the policy does not allow
+    ///
+    /// Without a policy, all globals are accepted.
+    pub fn with_policy(mut self, policy: SafetyPolicy) -> Self {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Sets the loader resolving persistent ids
+    ///
+    /// Without a loader, persistent ids are an error.
+    pub fn with_persistent_loader<L: PersistentLoader + 'static>(mut self, loader: L) -> Self {
+        self.persistent_loader = Some(Box::new(loader));
+        self
+    }
+
+    /// Sets how python 2 str are decoded, ASCII by default
+    pub fn with_encoding(mut self, encoding: Encoding) -> Self {
+        self.encoding = encoding;
+        self
+    }
+
+    /// Sets the out-of-band buffers consumed, in order, by NEXT_BUFFER opcodes (protocol 5)
+    ///
+    /// Buffers are shared with the resulting [`Value::Buffer`]s, not copied.
+    pub fn with_buffers<I, B>(mut self, buffers: I) -> Self
+    where
+        I: IntoIterator<Item = B>,
+        B: Into<Arc<[u8]>>,
+    {
+        let buffers = buffers.into_iter().map(Into::into).collect::<Vec<_>>();
+        self.buffers = Some(buffers.into_iter());
+        self
+    }
+
+    pub(crate) fn reader(&self) -> &Reader<R> {
+        &self.reader
+    }
+
+    /// Gets the underlying reader
+    pub(crate) fn reader_mut(&mut self) -> &mut Reader<R> {
+        &mut self.reader
+    }
+
+    /// Runs the pickle machine until STOP and returns the top level object
+    ///
+    /// An input ending before STOP is an `UnexpectedEof` error.
+    pub fn load(&mut self) -> Result<Value, Error> {
+        self.stack.clear();
+        self.metastack.clear();
+        self.shared.clear();
+        self.memo.clear();
+        loop {
+            self.buf.clear();
+            let start = self.reader.position();
+            let event = self.reader.read_event(&mut self.buf)?;
+            if let Event::Stop = event {
+                // a STOP faked at the end of the input is not consumed
+                if self.reader.position() == start {
+                    return Err(missing_stop(start));
+                }
+                break;
+            }
+            let opcode = self.reader.opcode;
+            self.process(event).map_err(|e| e.at(start, opcode))?;
+        }
+        let mut obj = self.pop()?;
+        if !self.stack.is_empty() || !self.metastack.is_empty() {
+            return Err(ErrorKind::Invalid("stack not empty after STOP").into());
+        }
+        let mut resolver = Resolver {
+            consume: true,
+            ..Resolver::default()
+        };
+        self.resolve(&mut obj, &mut resolver)
+    }
+
+    fn process(&mut self, event: Event) -> Result<(), Error> {
+        let limits = *self.reader.limits();
+        let max_container = limits.max_container;
+        match event {
+            Event::Proto(_) | Event::Frame(_) | Event::Stop => (),
+
+            // Stack manipulation
+            Event::Mark => {
+                let stack = mem::take(&mut self.stack);
+                self.metastack.push(stack);
+            }
+            Event::Pop => {
+                if self.stack.pop().is_none() {
+                    self.pop_mark()?;
+                }
+            }
+            Event::PopMark => {
+                self.pop_mark()?;
+            }
+            Event::Dup => {
+                // both copies are the same object, as with a memo GET
+                let index = self.share_top()?;
+                self.stack.push(Obj::Ref(index));
+            }
+
+            // Basic types
+            Event::None => self.push_value(Value::None),
+            Event::Bool(b) => self.push_value(Value::Bool(b)),
+            Event::Int(i) => self.push_value(Value::Int(i)),
+            Event::BinInt(i) => self.push_value(Value::Int(i as i64)),
+            Event::BinInt1(i) => self.push_value(Value::Int(i as i64)),
+            Event::BinInt2(i) => self.push_value(Value::Int(i as i64)),
+            Event::Long(i) => self.push_value(Value::Int(i)),
+            Event::BigLong { .. } => {
+                let big = BigInt::from_signed_bytes_le(&self.buf);
+                self.push_value(Value::BigInt(big));
+            }
+            Event::Float(f) => self.push_value(Value::Float(f)),
+
+            // Strings and bytes
+            Event::String { .. } | Event::BinString { .. } | Event::ShortBinString { .. } => {
+                let value = decode_py2_str(&event, &self.buf, self.encoding)?;
+                self.push_value(value);
+            }
+            Event::Unicode { .. }
+            | Event::BinUnicode { .. }
+            | Event::ShortBinUnicode { .. }
+            | Event::BinUnicode8 { .. } => {
+                let s = decode_str(&event, &self.buf)?.into_owned();
+                self.push_value(Value::Str(s));
+            }
+            Event::BinBytes { .. }
+            | Event::ShortBinBytes { .. }
+            | Event::BinBytes8 { .. }
+            | Event::ByteArray8 { .. } => {
+                let bytes = self.buf.clone();
+                self.push_value(Value::Bytes(bytes));
+            }
+
+            // Collections
+            Event::EmptyTuple => self.stack.push(Obj::Tuple(Vec::new())),
+            Event::Tuple => {
+                let items = self.pop_mark()?;
+                check_container(items.len(), max_container)?;
+                self.stack.push(Obj::Tuple(items));
+            }
+            Event::Tuple1 => {
+                let items = self.pop_n(1)?;
+                self.stack.push(Obj::Tuple(items));
+            }
+            Event::Tuple2 => {
+                let items = self.pop_n(2)?;
+                self.stack.push(Obj::Tuple(items));
+            }
+            Event::Tuple3 => {
+                let items = self.pop_n(3)?;
+                self.stack.push(Obj::Tuple(items));
+            }
+            Event::EmptyList => self.stack.push(Obj::List(Vec::new())),
+            Event::List => {
+                let items = self.pop_mark()?;
+                check_container(items.len(), max_container)?;
+                self.stack.push(Obj::List(items));
+            }
+            Event::Append => {
+                let item = self.pop()?;
+                let list = self.top_list()?;
+                list.push(item);
+                check_container(list.len(), max_container)?;
+            }
+            Event::Appends => {
+                let items = self.pop_mark()?;
+                let list = self.top_list()?;
+                list.extend(items);
+                check_container(list.len(), max_container)?;
+            }
+            Event::EmptyDict => self.stack.push(Obj::Dict(Vec::new())),
+            Event::Dict => {
+                let items = pairs(self.pop_mark()?)?;
+                check_container(items.len(), max_container)?;
+                self.stack.push(Obj::Dict(items));
+            }
+            Event::SetItem => {
+                let value = self.pop()?;
+                let key = self.pop()?;
+                let dict = self.top_dict()?;
+                dict.push((key, value));
+                check_container(dict.len(), max_container)?;
+            }
+            Event::SetItems => {
+                let items = pairs(self.pop_mark()?)?;
+                let dict = self.top_dict()?;
+                dict.extend(items);
+                check_container(dict.len(), max_container)?;
+            }
+            Event::EmptySet => self.stack.push(Obj::Set(Vec::new())),
+            Event::AdditItems => {
+                let items = self.pop_mark()?;
+                match self.top_mut()? {
+                    Obj::Set(set) => {
+                        set.extend(items);
+                        check_container(set.len(), max_container)?;
+                    }
+                    obj => return Err(Error::unexpected("a set", obj.type_name())),
+                }
+            }
+            Event::FrozenSet => {
+                let items = self.pop_mark()?;
+                check_container(items.len(), max_container)?;
+                self.stack.push(Obj::FrozenSet(items));
+            }
+
+            // Memo operations
+            Event::Get(id) => self.get(id as u32)?,
+            Event::BinGet(id) => self.get(id as u32)?,
+            Event::LongBinGet(id) => self.get(id)?,
+            Event::Put(id) => self.put(id as u32)?,
+            Event::BinPut(id) => self.put(id as u32)?,
+            Event::LongBinPut(id) => self.put(id)?,
+            Event::Memoize => self.put(self.memo.len() as u32)?,
+
+            // Object construction
+            Event::Global { module_len, .. } => {
+                let global = self.global(module_len as usize)?;
+                self.push_value(global);
+            }
+            Event::StackGlobal => {
+                let name = self.pop()?;
+                let module = self.pop()?;
+                let (Some(module), Some(name)) = (self.as_str(&module), self.as_str(&name)) else {
+                    let found =
+                        format!("{} and {}", self.type_name(&module), self.type_name(&name));
+                    return Err(Error::unexpected("str module and name", found));
+                };
+                let global = self.check_global(module, name)?;
+                self.push_value(global);
+            }
+            Event::Reduce => {
+                let args = self.pop()?;
+                let callable = self.pop()?;
+                self.stack.push(Obj::Reduce {
+                    callable: Box::new(callable),
+                    args: Box::new(args),
+                });
+            }
+            Event::Build => {
+                let state = self.pop()?;
+                let object = self.top_mut()?;
+                let inner = mem::replace(object, Obj::Value(Value::None));
+                *object = Obj::Build {
+                    object: Box::new(inner),
+                    state: Box::new(state),
+                };
+            }
+            Event::Inst { module_len, .. } => {
+                let class = self.global(module_len as usize)?;
+                let args = self.pop_mark()?;
+                self.stack.push(Obj::Object {
+                    class: Box::new(Obj::Value(class)),
+                    args: Box::new(Obj::Tuple(args)),
+                    kwargs: None,
+                });
+            }
+            Event::Obj => {
+                let mut args = self.pop_mark()?.into_iter();
+                let class = args.next().ok_or(ErrorKind::StackUnderflow)?;
+                self.stack.push(Obj::Object {
+                    class: Box::new(class),
+                    args: Box::new(Obj::Tuple(args.collect())),
+                    kwargs: None,
+                });
+            }
+            Event::NewObj => {
+                let args = self.pop()?;
+                let class = self.pop()?;
+                self.stack.push(Obj::Object {
+                    class: Box::new(class),
+                    args: Box::new(args),
+                    kwargs: None,
+                });
+            }
+            Event::NewObjEx => {
+                let kwargs = self.pop()?;
+                let args = self.pop()?;
+                let class = self.pop()?;
+                self.stack.push(Obj::Object {
+                    class: Box::new(class),
+                    args: Box::new(args),
+                    kwargs: Some(Box::new(kwargs)),
+                });
+            }
+
+            // Persistent objects
+            Event::PersId { .. } => {
+                let pid = from_utf8(&self.buf)?.to_string();
+                self.persistent_load(Value::Str(pid))?;
+            }
+            Event::BinPersId => {
+                let mut pid = self.pop()?;
+                let pid = self.resolve(&mut pid, &mut Resolver::default())?;
+                self.persistent_load(pid)?;
+            }
+
+            // Extensions
+            Event::Ext1(_) => return Err(ErrorKind::OpCode(0x82).into()),
+            Event::Ext2(_) => return Err(ErrorKind::OpCode(0x83).into()),
+            Event::Ext4(_) => return Err(ErrorKind::OpCode(0x84).into()),
+
+            // Protocol 5
+            Event::NextBuffer => {
+                let buffers = self.buffers.as_mut().ok_or(ErrorKind::Invalid(
+                    "out-of-band buffer referenced but no buffers were given",
+                ))?;
+                let data = buffers
+                    .next()
+                    .ok_or(ErrorKind::Invalid("not enough out-of-band buffers"))?;
+                self.push_value(Value::Buffer {
+                    data,
+                    readonly: false,
+                });
+            }
+            Event::ReadonlyBuffer => match self.top_mut()? {
+                Obj::Value(Value::Buffer { readonly, .. }) => *readonly = true,
+                Obj::Value(Value::Bytes(_)) => (),
+                obj => return Err(Error::unexpected("a buffer", obj.type_name())),
+            },
+        }
+        if self.stack.len() > limits.max_stack || self.metastack.len() > limits.max_stack {
+            let max = limits.max_stack;
+            return Err(ErrorKind::StackTooDeep { max }.into());
+        }
+        Ok(())
+    }
+
+    fn persistent_load(&mut self, pid: Value) -> Result<(), Error> {
+        let loader = self.persistent_loader.as_mut().ok_or(ErrorKind::Invalid(
+            "persistent id found but no persistent loader was given",
+        ))?;
+        let value = loader.persistent_load(pid)?;
+        self.push_value(value);
+        Ok(())
+    }
+
+    fn push_value(&mut self, value: Value) {
+        self.stack.push(Obj::Value(value));
+    }
+
+    fn pop(&mut self) -> Result<Obj, Error> {
+        self.stack.pop().ok_or(ErrorKind::StackUnderflow.into())
+    }
+
+    fn pop_n(&mut self, n: usize) -> Result<Vec<Obj>, Error> {
+        let start = self
+            .stack
+            .len()
+            .checked_sub(n)
+            .ok_or(ErrorKind::StackUnderflow)?;
+        Ok(self.stack.split_off(start))
+    }
+
+    /// Pops all the objects pushed since the last mark
+    fn pop_mark(&mut self) -> Result<Vec<Obj>, Error> {
+        let stack = self.metastack.pop().ok_or(ErrorKind::MissingMark)?;
+        Ok(mem::replace(&mut self.stack, stack))
+    }
+
+    /// Gets the top of the stack, following a memo reference
+    fn top_mut(&mut self) -> Result<&mut Obj, Error> {
+        match self.stack.last_mut().ok_or(ErrorKind::StackUnderflow)? {
+            Obj::Ref(index) => Ok(&mut self.shared[*index]),
+            top => Ok(top),
+        }
+    }
+
+    /// Gets the list to append to, wrapping non-list objects into an `Extend`
+    fn top_list(&mut self) -> Result<&mut Vec<Obj>, Error> {
+        let top = self.top_mut()?;
+        match top {
+            Obj::List(_) | Obj::Extend { .. } => (),
+            Obj::Reduce { .. } | Obj::Object { .. } | Obj::Build { .. } => {
+                let object = mem::replace(top, Obj::Value(Value::None));
+                *top = Obj::Extend {
+                    object: Box::new(object),
+                    items: Box::new(Obj::List(Vec::new())),
+                };
+            }
+            obj => return Err(Error::unexpected("a list or an object", obj.type_name())),
+        }
+        match top {
+            Obj::List(list) => Ok(list),
+            Obj::Extend { items, .. } => match &mut **items {
+                Obj::List(list) => Ok(list),
+                _ => Err(ErrorKind::Invalid("cannot append to object").into()),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Gets the dict to set items on, wrapping non-dict objects into an `Extend`
+    fn top_dict(&mut self) -> Result<&mut Vec<(Obj, Obj)>, Error> {
+        let top = self.top_mut()?;
+        match top {
+            Obj::Dict(_) | Obj::Extend { .. } => (),
+            Obj::Reduce { .. } | Obj::Object { .. } | Obj::Build { .. } => {
+                let object = mem::replace(top, Obj::Value(Value::None));
+                *top = Obj::Extend {
+                    object: Box::new(object),
+                    items: Box::new(Obj::Dict(Vec::new())),
+                };
+            }
+            obj => return Err(Error::unexpected("a dict or an object", obj.type_name())),
+        }
+        match top {
+            Obj::Dict(dict) => Ok(dict),
+            Obj::Extend { items, .. } => match &mut **items {
+                Obj::Dict(dict) => Ok(dict),
+                _ => Err(ErrorKind::Invalid("cannot set item on object").into()),
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    /// Gets an object, following a memo reference
+    fn deref<'a>(&'a self, obj: &'a Obj) -> &'a Obj {
+        match obj {
+            Obj::Ref(index) => &self.shared[*index],
+            obj => obj,
+        }
+    }
+
+    fn as_str<'a>(&'a self, obj: &'a Obj) -> Option<&'a str> {
+        match self.deref(obj) {
+            Obj::Value(Value::Str(s)) => Some(s),
+            _ => None,
+        }
+    }
+
+    fn type_name(&self, obj: &Obj) -> &'static str {
+        self.deref(obj).type_name()
+    }
+
+    fn get(&mut self, id: u32) -> Result<(), Error> {
+        let index = *self.memo.get(&id).ok_or(ErrorKind::Memo(id))?;
+        self.stack.push(Obj::Ref(index));
+        Ok(())
+    }
+
+    /// Moves the top of the stack into the shared objects, unless it is already a
+    /// reference, and memoizes it
+    fn put(&mut self, id: u32) -> Result<(), Error> {
+        let max = self.reader.limits().max_memo;
+        if self.memo.len() >= max && !self.memo.contains_key(&id) {
+            return Err(ErrorKind::MemoTooLarge { max }.into());
+        }
+        let index = self.share_top()?;
+        self.memo.insert(id, index);
+        Ok(())
+    }
+
+    /// Gets the index of the shared object on top of the stack, sharing it if needed
+    fn share_top(&mut self) -> Result<usize, Error> {
+        let top = self.stack.last_mut().ok_or(ErrorKind::StackUnderflow)?;
+        if let Obj::Ref(index) = top {
+            return Ok(*index);
+        }
+        let index = self.shared.len();
+        self.shared.push(mem::replace(top, Obj::Ref(index)));
+        Ok(index)
+    }
+
+    /// Builds a global out of the module and name in the buffer
+    fn global(&self, module_len: usize) -> Result<Value, Error> {
+        let module = from_utf8(&self.buf[..module_len])?;
+        let name = from_utf8(&self.buf[module_len..])?;
+        self.check_global(module, name)
+    }
+
+    /// Builds a global if the safety policy allows it
+    fn check_global(&self, module: &str, name: &str) -> Result<Value, Error> {
+        if let Some(policy) = &self.policy
+            && !policy.is_allowed(module, name)
+        {
+            return Err(ErrorKind::Forbidden {
+                module: module.to_string(),
+                name: name.to_string(),
+            }
+            .into());
+        }
+        Ok(Value::Global {
+            module: module.to_string(),
+            name: name.to_string(),
+        })
+    }
+
+    /// Converts a stack object into a value, replacing memo references by their content
+    ///
+    /// Each shared object is resolved once, then copied at its other references. The values
+    /// of `obj` are moved out of it, those of shared objects only once the pickle is loaded.
+    /// Nested objects are resolved with an explicit stack, as a pickle can nest them deeper
+    /// than recursion allows.
+    fn resolve(&mut self, obj: &mut Obj, resolver: &mut Resolver) -> Result<Value, Error> {
+        let mut stack = match self.visit(obj, true, 0, resolver)? {
+            Visit::Value(value) => return Ok(value),
+            Visit::Frame(frame) => vec![frame],
+        };
+        loop {
+            let depth = stack.len();
+            let frame = stack.last_mut().unwrap();
+            if let Some(nested) = frame.obj.nested_mut(frame.values.len()) {
+                match self.visit(nested, frame.owned, depth, resolver)? {
+                    Visit::Value(value) => frame.values.push(value),
+                    Visit::Frame(frame) => stack.push(frame),
+                }
+                continue;
+            }
+            let Frame {
+                mut obj,
+                values,
+                owned,
+                shared,
+            } = stack.pop().unwrap();
+            let value = self.build(&mut obj, values, owned);
+            let depth = stack.len();
+            if let Some(shared) = shared {
+                resolver.resolving.pop();
+                let len = resolver.len - shared.len;
+                let height = resolver.deepest - depth;
+                resolver.deepest = resolver.deepest.max(shared.deepest);
+                resolver
+                    .resolved
+                    .insert(shared.index, (value.clone(), len, height));
+                self.shared[shared.index] = obj;
+            } else if let Some(parent) = stack.last_mut() {
+                // put back, so that the shared objects stay intact for later persistent ids
+                *parent.obj.nested_mut(parent.values.len()).unwrap() = obj;
+            }
+            match stack.last_mut() {
+                Some(parent) => parent.values.push(value),
+                None => return Ok(value),
+            }
+        }
+    }
+
+    /// Starts resolving an object nested `depth` levels deep
+    fn visit(
+        &mut self,
+        obj: &mut Obj,
+        owned: bool,
+        depth: usize,
+        resolver: &mut Resolver,
+    ) -> Result<Visit, Error> {
+        let max_depth = self.reader.limits().max_depth;
+        let index = match *obj {
+            Obj::Value(ref mut value) => {
+                resolver.len += 1;
+                let value = if owned {
+                    mem::replace(value, Value::None)
+                } else {
+                    value.clone()
+                };
+                return Ok(Visit::Value(value));
+            }
+            Obj::Ref(index) => index,
+            _ => {
+                resolver.len += 1;
+                resolver.reach(depth + 1, max_depth)?;
+                return Ok(Visit::Frame(Frame {
+                    obj: mem::replace(obj, Obj::Value(Value::None)),
+                    values: Vec::new(),
+                    owned,
+                    shared: None,
+                }));
+            }
+        };
+        if let Some(&(_, len, height)) = resolver.resolved.get(&index) {
+            let max = self.reader.limits().max_copies;
+            resolver.copies = resolver.copies.saturating_add(len);
+            if resolver.copies > max {
+                return Err(ErrorKind::TooManyCopies { max }.into());
+            }
+            resolver.len += len;
+            resolver.reach(depth + height, max_depth)?;
+            return Ok(Visit::Value(resolver.resolved[&index].0.clone()));
+        }
+        if resolver.resolving.contains(&index) {
+            return Err(ErrorKind::Invalid("recursive objects are not supported").into());
+        }
+        resolver.resolving.push(index);
+        let shared = Shared {
+            index,
+            len: resolver.len,
+            deepest: resolver.deepest,
+        };
+        resolver.deepest = depth;
+        // the shared object is taken out of the table while it is resolved, references to it
+        // are caught by `resolving`
+        let obj = mem::replace(&mut self.shared[index], Obj::Value(Value::None));
+        resolver.len += 1;
+        if !matches!(obj, Obj::Value(_)) {
+            resolver.reach(depth + 1, max_depth)?;
+        }
+        Ok(Visit::Frame(Frame {
+            obj,
+            values: Vec::new(),
+            owned: resolver.consume,
+            shared: Some(shared),
+        }))
+    }
+
+    /// Builds the value of an object out of the values of the objects nested in it
+    fn build(&self, obj: &mut Obj, values: Vec<Value>, owned: bool) -> Value {
+        let mut values = values.into_iter();
+        let mut next = || Box::new(values.next().unwrap());
+        match obj {
+            Obj::Value(value) if owned => mem::replace(value, Value::None),
+            Obj::Value(value) => value.clone(),
+            Obj::Ref(_) => unreachable!("references are resolved as values"),
+            Obj::List(_) => Value::List(values.collect()),
+            Obj::Tuple(_) => Value::Tuple(values.collect()),
+            Obj::Set(_) => Value::Set(values.collect()),
+            Obj::FrozenSet(_) => Value::FrozenSet(values.collect()),
+            Obj::Dict(items) => {
+                let mut dict = Vec::with_capacity(items.len());
+                while let (Some(key), Some(value)) = (values.next(), values.next()) {
+                    dict.push((key, value));
+                }
+                Value::Dict(dict)
+            }
+            Obj::Reduce { .. } => {
+                let callable = next();
+                let mut args = next();
+                // python 2 datetimes are reduced to their bytes state, as datetime does
+                if self.encoding == Encoding::Latin1
+                    && is_datetime(&callable)
+                    && let Value::Tuple(args) = &mut *args
+                    && let Some(state @ Value::Str(_)) = args.first_mut()
+                    && let Ok(bytes) = state.as_str().unwrap().chars().map(u8::try_from).collect()
+                {
+                    *state = Value::Bytes(bytes);
+                }
+                Value::Reduce { callable, args }
+            }
+            Obj::Object { kwargs, .. } => Value::Object {
+                class: next(),
+                args: next(),
+                kwargs: kwargs.as_ref().map(|_| next()),
+            },
+            Obj::Build { .. } => Value::Build {
+                object: next(),
+                state: next(),
+            },
+            Obj::Extend { .. } => Value::Extend {
+                object: next(),
+                items: next(),
+            },
+        }
+    }
+}
+
+/// State of [`Unpickler::resolve`]
+#[derive(Default)]
+struct Resolver {
+    /// Whether the values of shared objects may be moved out, once the pickle is loaded
+    consume: bool,
+    /// Indexes of the shared objects being resolved
+    resolving: Vec<usize>,
+    /// Shared objects already resolved, with their number of values and their nesting depth
+    resolved: HashMap<usize, (Value, usize, usize)>,
+    /// Number of values resolved
+    len: usize,
+    /// Number of values copied from `resolved`
+    copies: usize,
+    /// Deepest nesting reached, since the start of the shared object being resolved
+    deepest: usize,
+}
+
+impl Resolver {
+    /// Records a nesting depth reached by the result
+    fn reach(&mut self, depth: usize, max: usize) -> Result<(), Error> {
+        if depth > max {
+            return Err(ErrorKind::NestingTooDeep { max }.into());
+        }
+        self.deepest = self.deepest.max(depth);
+        Ok(())
+    }
+}
+
+/// The result of [`Unpickler::visit`]
+enum Visit {
+    /// A value resolved at once
+    Value(Value),
+    /// An object to resolve after the objects nested in it
+    Frame(Frame),
+}
+
+/// An object being resolved, with the values of the objects nested in it resolved so far
+struct Frame {
+    obj: Obj,
+    values: Vec<Value>,
+    /// Whether the values of `obj` may be moved out
+    owned: bool,
+    /// The shared object `obj` was taken from
+    shared: Option<Shared>,
+}
+
+/// A shared object being resolved, with the state of the [`Resolver`] before it
+struct Shared {
+    index: usize,
+    len: usize,
+    deepest: usize,
+}
+
+/// Checks if a callable is the `date`, `datetime` or `time` class of the `datetime` module
+fn is_datetime(callable: &Value) -> bool {
+    matches!(callable, Value::Global { module, name }
+        if module == "datetime" && matches!(name.as_str(), "date" | "datetime" | "time"))
+}
+
+pub(crate) fn check_container(len: usize, max: usize) -> Result<(), Error> {
+    if len > max {
+        return Err(ErrorKind::ContainerTooLarge { max }.into());
+    }
+    Ok(())
+}
+
+/// Groups the items of a mark into key-value pairs
+pub(crate) fn pairs<T>(items: Vec<T>) -> Result<Vec<(T, T)>, Error> {
+    if !items.len().is_multiple_of(2) {
+        return Err(ErrorKind::Invalid("odd number of items for dict").into());
+    }
+    let mut items = items.into_iter();
+    let mut pairs = Vec::with_capacity(items.len() / 2);
+    while let (Some(k), Some(v)) = (items.next(), items.next()) {
+        pairs.push((k, v));
+    }
+    Ok(pairs)
+}
+
+/// Decodes the payload of a str opcode
+///
+/// UNICODE, the text opcode of protocol 0, holds the `raw-unicode-escape` encoding of a str.
+pub(crate) fn decode_str<'a>(event: &Event, buf: &'a [u8]) -> Result<Cow<'a, str>, Error> {
+    match event {
+        Event::Unicode { .. } => raw_unicode_unescape(trim_line(buf)).map(Cow::Owned),
+        _ => Ok(Cow::Borrowed(from_utf8(buf)?)),
+    }
+}
+
+/// Decodes the payload of a python 2 str opcode (STRING, BINSTRING or SHORT_BINSTRING)
+///
+/// The argument of STRING is a quoted str literal, with escapes.
+pub(crate) fn decode_py2_str(
+    event: &Event,
+    buf: &[u8],
+    encoding: Encoding,
+) -> Result<Value, Error> {
+    let bytes = match event {
+        Event::String { .. } => unescape_string(unquote(buf)?)?,
+        _ => Cow::Borrowed(buf),
+    };
+    let s = match encoding {
+        Encoding::Bytes => return Ok(Value::Bytes(bytes.into_owned())),
+        Encoding::Latin1 => bytes.iter().map(|&b| b as char).collect(),
+        Encoding::Ascii if !bytes.is_ascii() => {
+            return Err(ErrorKind::Invalid("python 2 str is not ASCII").into());
+        }
+        Encoding::Ascii | Encoding::Utf8 => match bytes {
+            Cow::Borrowed(bytes) => from_utf8(bytes)?.to_string(),
+            Cow::Owned(bytes) => String::from_utf8(bytes).map_err(|e| e.utf8_error())?,
+        },
+    };
+    Ok(Value::Str(s))
+}
+
+/// Removes the newline and quotes of a STRING argument
+pub(crate) fn unquote(line: &[u8]) -> Result<&[u8], Error> {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    match line {
+        [q @ (b'\'' | b'"'), s @ .., end] if q == end => Ok(s),
+        _ => Err(ErrorKind::Invalid("STRING is not quoted").into()),
+    }
+}
+
+/// Processes the escapes of a python 2 str literal, as python's `codecs.escape_decode`
+pub(crate) fn unescape_string(s: &[u8]) -> Result<Cow<'_, [u8]>, Error> {
+    if !s.contains(&b'\\') {
+        return Ok(Cow::Borrowed(s));
+    }
+    let mut out = Vec::with_capacity(s.len());
+    let mut bytes = s.iter().copied().peekable();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b);
+            continue;
+        }
+        let Some(c) = bytes.next() else {
+            return Err(ErrorKind::Invalid("trailing \\ in STRING").into());
+        };
+        match c {
+            b'\n' => (),
+            b'\\' | b'\'' | b'"' => out.push(c),
+            b'a' => out.push(0x07),
+            b'b' => out.push(0x08),
+            b'f' => out.push(0x0c),
+            b'n' => out.push(b'\n'),
+            b'r' => out.push(b'\r'),
+            b't' => out.push(b'\t'),
+            b'v' => out.push(0x0b),
+            b'0'..=b'7' => {
+                let mut n = u32::from(c - b'0');
+                for _ in 0..2 {
+                    match bytes.next_if(|d| matches!(d, b'0'..=b'7')) {
+                        Some(d) => n = n * 8 + u32::from(d - b'0'),
+                        None => break,
+                    }
+                }
+                out.push(n as u8);
+            }
+            b'x' => {
+                let hex = [bytes.next(), bytes.next()];
+                let n = match hex {
+                    [Some(h), Some(l)] => hex_digit(h).zip(hex_digit(l)).map(|(h, l)| h * 16 + l),
+                    _ => None,
+                };
+                out.push(n.ok_or(ErrorKind::Invalid("invalid \\x escape in STRING"))? as u8);
+            }
+            // unknown escapes are kept
+            c => out.extend_from_slice(&[b'\\', c]),
+        }
+    }
+    Ok(Cow::Owned(out))
+}
+
+/// Decodes python's `raw-unicode-escape` codec: latin-1 with `\\uXXXX` and `\\UXXXXXXXX` escapes
+pub(crate) fn raw_unicode_unescape(s: &[u8]) -> Result<String, Error> {
+    let mut out = String::with_capacity(s.len());
+    let mut bytes = s.iter().copied();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            out.push(b as char);
+            continue;
+        }
+        let digits = match bytes.next() {
+            Some(b'u') => 4,
+            Some(b'U') => 8,
+            Some(c) => {
+                out.push('\\');
+                out.push(c as char);
+                continue;
+            }
+            None => {
+                out.push('\\');
+                break;
+            }
+        };
+        let mut n = 0;
+        for _ in 0..digits {
+            let digit = bytes.next().and_then(hex_digit);
+            n = n * 16 + digit.ok_or(ErrorKind::Invalid("truncated \\u escape in UNICODE"))?;
+        }
+        out.push(char::from_u32(n).ok_or(ErrorKind::Invalid("\\U escape out of range"))?);
+    }
+    Ok(out)
+}
+
+fn hex_digit(b: u8) -> Option<u32> {
+    char::from(b).to_digit(16)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::Limits;
+
+    fn load(data: &[u8]) -> Result<Value, Error> {
+        Unpickler::new(data).load()
+    }
+
+    #[test]
+    fn test_load_list() -> Result<(), Error> {
+        // pickle.dumps([1, 'a', (1, 2), {'x': b'y'}], protocol=4)
+        let data = b"\x80\x04\x95\x1c\x00\x00\x00\x00\x00\x00\x00]\x94(K\x01\x8c\x01a\x94K\x01K\x02\x86\x94}\x94\x8c\x01x\x94C\x01y\x94se.";
+        let value = load(data)?;
+        assert_eq!(
+            value,
+            Value::List(vec![
+                Value::Int(1),
+                Value::Str("a".into()),
+                Value::Tuple(vec![Value::Int(1), Value::Int(2)]),
+                Value::Dict(vec![(Value::Str("x".into()), Value::Bytes(b"y".to_vec()))]),
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_error_position() {
+        // memo 1 is never stored
+        let err = load(b"\x80\x02]q\x00h\x01a.").unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Memo(1)));
+        assert_eq!(err.offset(), Some(5));
+        assert_eq!(err.opcode_name(), Some("BINGET"));
+    }
+
+    #[test]
+    fn test_safety_policy() -> Result<(), Error> {
+        // pickle.dumps(collections.OrderedDict(a=1), protocol=4)
+        let data = b"\x80\x04\x95)\x00\x00\x00\x00\x00\x00\x00\x8c\x0bcollections\x94\x8c\x0bOrderedDict\x94\x93\x94)R\x94\x8c\x01a\x94K\x01s.";
+        let policy = SafetyPolicy::new().allow("collections", "OrderedDict");
+        Unpickler::new(&data[..]).with_policy(policy).load()?;
+
+        let policy = SafetyPolicy::new().allow("collections", "defaultdict");
+        let err = Unpickler::new(&data[..])
+            .with_policy(policy)
+            .load()
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            ErrorKind::Forbidden { module, name } if module == "collections" && name == "OrderedDict"
+        ));
+        assert_eq!(err.opcode_name(), Some("STACK_GLOBAL"));
+
+        // protocol 0 GLOBAL
+        let data = b"cos\nsystem\n(S'ls'\ntR.";
+        let policy = SafetyPolicy::new().with_hook(|module, _| module == "os");
+        Unpickler::new(&data[..]).with_policy(policy).load()?;
+        let err = Unpickler::new(&data[..])
+            .with_policy(SafetyPolicy::new())
+            .load()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Global os.system is not allowed at offset 0 (GLOBAL)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_buffers() -> Result<(), Error> {
+        // pickle.dumps([PickleBuffer(b'abc'), PickleBuffer(bytearray(b'de'))], protocol=5,
+        //              buffer_callback=buffers.append)
+        let data = b"\x80\x05\x95\x08\x00\x00\x00\x00\x00\x00\x00]\x94(\x97\x98\x97e.";
+        let abc: Arc<[u8]> = Arc::from(&b"abc"[..]);
+        let value = Unpickler::new(&data[..])
+            .with_buffers([abc.clone(), Arc::from(&b"de"[..])])
+            .load()?;
+        let items = value.as_slice().unwrap();
+        assert_eq!(
+            items[0],
+            Value::Buffer {
+                data: abc.clone(),
+                readonly: true
+            }
+        );
+        assert!(matches!(&items[0], Value::Buffer { data, .. } if Arc::ptr_eq(data, &abc)));
+        assert!(matches!(
+            &items[1],
+            Value::Buffer {
+                readonly: false,
+                ..
+            }
+        ));
+        assert_eq!(items[1].as_bytes(), Some(&b"de"[..]));
+
+        let err = load(data).unwrap_err();
+        assert_eq!(err.opcode_name(), Some("NEXT_BUFFER"));
+        let err = Unpickler::new(&data[..])
+            .with_buffers([abc])
+            .load()
+            .unwrap_err();
+        assert_eq!(err.offset(), Some(16));
+        Ok(())
+    }
+
+    #[test]
+    fn test_persistent_load() -> Result<(), Error> {
+        struct Storage(Vec<Value>);
+
+        impl PersistentLoader for Storage {
+            fn persistent_load(&mut self, pid: Value) -> Result<Value, Error> {
+                self.0.push(pid.clone());
+                match pid.as_slice() {
+                    Some([_, key]) => Ok(Value::Str(format!("loaded {}", key.as_str().unwrap()))),
+                    _ => Err(Error::unexpected("a (kind, key) tuple", pid.type_name())),
+                }
+            }
+        }
+
+        // Pickler.persistent_id returns ('storage', 'key') for b'key'
+        // dumps([1, b'key', 'x'], protocol=2)
+        let data = b"\x80\x02]q\x00(K\x01X\x07\x00\x00\x00storageq\x01X\x03\x00\x00\x00keyq\x02\x86q\x03QX\x01\x00\x00\x00xq\x04e.";
+        let value = Unpickler::new(&data[..])
+            .with_persistent_loader(Storage(Vec::new()))
+            .load()?;
+        assert_eq!(
+            value.as_slice().unwrap()[1],
+            Value::Str("loaded key".into())
+        );
+
+        // Pickler.persistent_id returns 'ext-key' for b'key', protocol 0
+        let data = b"(lp0\nI1\naPext-key\na.";
+        let value = Unpickler::new(&data[..])
+            .with_persistent_loader(|pid: Value| Ok(Value::Tuple(vec![pid])))
+            .load()?;
+        assert_eq!(
+            value.as_slice().unwrap()[1],
+            Value::Tuple(vec![Value::Str("ext-key".into())])
+        );
+
+        let err = load(data).unwrap_err();
+        assert_eq!(err.opcode_name(), Some("PERSID"));
+        let err = Unpickler::new(&data[..])
+            .with_persistent_loader(Storage(Vec::new()))
+            .load()
+            .unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "Expected a (kind, key) tuple, found str at offset 9 (PERSID)"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_limits() {
+        let load = |data: &[u8], limits: Limits| {
+            let reader = Reader::new(data).with_limits(limits);
+            Unpickler::from_reader(reader)
+                .load()
+                .map_err(Error::into_kind)
+        };
+        // pickle.dumps([[1, 2, 3], [4]], protocol=2)
+        let data = b"\x80\x02]q\x00(]q\x01(K\x01K\x02K\x03e]q\x02K\x04ae.";
+        assert!(load(data, Limits::default()).is_ok());
+
+        let limits = Limits {
+            max_container: 2,
+            ..Limits::default()
+        };
+        let kind = load(data, limits).unwrap_err();
+        assert!(matches!(kind, ErrorKind::ContainerTooLarge { max: 2 }));
+
+        let limits = Limits {
+            max_memo: 2,
+            ..Limits::default()
+        };
+        let kind = load(data, limits).unwrap_err();
+        assert!(matches!(kind, ErrorKind::MemoTooLarge { max: 2 }));
+
+        let limits = Limits {
+            max_stack: 2,
+            ..Limits::default()
+        };
+        let kind = load(data, limits).unwrap_err();
+        assert!(matches!(kind, ErrorKind::StackTooDeep { max: 2 }));
+
+        // a = [1]; pickle.dumps([a, a], protocol=2) copies a once
+        let data = b"\x80\x02]q\x00(]q\x01K\x01ah\x01e.";
+        for (max_copies, ok) in [(1, false), (2, true)] {
+            let limits = Limits {
+                max_copies,
+                ..Limits::default()
+            };
+            assert_eq!(load(data, limits).is_ok(), ok);
+        }
+
+        // 2^40 values out of nested [a, a] lists
+        let mut data = b"\x80\x02]q\x000".to_vec();
+        for i in 1..=40 {
+            data.extend_from_slice(&[b'(', b'h', i - 1, b'h', i - 1, b'l', b'q', i, b'0']);
+        }
+        data.extend_from_slice(b"h\x28.");
+        let limits = Limits {
+            max_copies: 1 << 16,
+            ..Limits::default()
+        };
+        let kind = load(&data, limits).unwrap_err();
+        assert!(matches!(kind, ErrorKind::TooManyCopies { max } if max == 1 << 16));
+        assert_eq!(Limits::default().max_copies, 1 << 24);
+
+        // a = [1]; pickle.dumps([a, [a]], protocol=2) copies a one level deeper
+        let data = b"\x80\x02]q\x00(]q\x01K\x01a]q\x02h\x01ae.";
+        for (max_depth, ok) in [(2, false), (3, true)] {
+            let limits = Limits {
+                max_depth,
+                ..Limits::default()
+            };
+            assert_eq!(load(data, limits).is_ok(), ok);
+        }
+        let kind = load(
+            b"]]]aa.",
+            Limits {
+                max_depth: 2,
+                ..Limits::default()
+            },
+        )
+        .unwrap_err();
+        assert!(matches!(kind, ErrorKind::NestingTooDeep { max: 2 }));
+        assert_eq!(Limits::default().max_depth, 1000);
+    }
+
+    #[test]
+    fn test_deep_nesting() -> Result<(), Error> {
+        let nested = |open: &[u8], close: &[u8], depth| {
+            let mut data = open.repeat(depth);
+            data.extend(close.repeat(depth - 1));
+            data.push(b'.');
+            data
+        };
+        // as deep as python can dump
+        let value = load(&nested(b"]", b"a", 1000))?;
+        assert!(matches!(value, Value::List(items) if items.len() == 1));
+        let value = load(&nested(b"]q\x00", b"a", 1000))?;
+        assert!(matches!(value, Value::List(items) if items.len() == 1));
+
+        for data in [
+            nested(b"]", b"a", 100_000),
+            nested(b"]q\x00", b"a", 100_000),
+            // objects whose state is set, nested in each other
+            [&b"N"[..], &b"Nb".repeat(100_000), b"."].concat(),
+        ] {
+            let err = load(&data).unwrap_err();
+            assert!(matches!(
+                err.kind(),
+                ErrorKind::NestingTooDeep { max: 1000 }
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_shared_ref() -> Result<(), Error> {
+        // a = [1]; pickle.dumps([a, a], protocol=2)
+        let data = b"\x80\x02]q\x00(]q\x01K\x01ah\x01e.";
+        let value = load(data)?;
+        let inner = Value::List(vec![Value::Int(1)]);
+        assert_eq!(value, Value::List(vec![inner.clone(), inner]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_put_reference() -> Result<(), Error> {
+        // the open list is memoized again at the same key, then appended to
+        let err = load(b"\x80\x02]q\x00h\x00q\x00K\x01a.").unwrap_err();
+        assert!(matches!(err.kind(), ErrorKind::Invalid(_)));
+        let value = load(b"\x80\x02]q\x00h\x00q\x00K\x01a0.")?;
+        assert_eq!(value, Value::List(vec![Value::Int(1)]));
+        // and at another key
+        let value = load(b"\x80\x02]q\x00h\x00q\x01K\x01a00h\x01K\x02a.")?;
+        assert_eq!(value, Value::List(vec![Value::Int(1), Value::Int(2)]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dup() -> Result<(), Error> {
+        // the list is appended to after being duplicated
+        let value = load(b"\x80\x02]2K\x01a\x86.")?;
+        let list = Value::List(vec![Value::Int(1)]);
+        assert_eq!(value, Value::Tuple(vec![list.clone(), list]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_protocol_0() -> Result<(), Error> {
+        // pickle.dumps({'a': (1, 0.5, None, True)}, protocol=0)
+        let data = b"(dp0\nVa\np1\n(I1\nF0.5\nNI01\ntp2\ns.";
+        let value = load(data)?;
+        assert_eq!(
+            value,
+            Value::Dict(vec![(
+                Value::Str("a".into()),
+                Value::Tuple(vec![
+                    Value::Int(1),
+                    Value::Float(0.5),
+                    Value::None,
+                    Value::Bool(true)
+                ])
+            )])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_text_strings() -> Result<(), Error> {
+        // python 2 str literals, and raw-unicode-escape str
+        let data = b"(lp0\nS'it\\'s \\x41\\101\\n\\q'\np1\naS\"\\\\\"\np2\naVcaf\xe9\\u20ac\\U0001f600\\u005c\\x\np3\nac__builtin__\nlen\np4\na.";
+        let value = load(data)?;
+        let items = value.as_slice().unwrap();
+        let strs = items[..3]
+            .iter()
+            .map(|s| s.as_str().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(strs, ["it's AA\n\\q", "\\", "café€😀\\\\x"]);
+        assert_eq!(
+            items[3],
+            Value::Global {
+                module: "__builtin__".into(),
+                name: "len".into()
+            }
+        );
+
+        // round trip through the pickler
+        let value = Value::Str("café€\\\n".into());
+        let data = crate::pickler::dumps(&value, 0)?;
+        assert_eq!(data, b"Vcaf\xe9\\u20ac\\u005c\\u000a\np0\n.");
+        assert_eq!(load(&data)?, value);
+
+        for data in [&b"S'\\x4'\n."[..], b"S'a\\'\n.", b"S'a\n.", b"V\\u12\n."] {
+            assert!(matches!(
+                load(data).unwrap_err().kind(),
+                ErrorKind::Invalid(_)
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_encoding() -> Result<(), Error> {
+        // ['caf\xc3\xa9', datetime.datetime(2000, 1, 1)] pickled by python 2
+        let data = b"\x80\x02]q\x00(U\x05caf\xc3\xa9q\x01cdatetime\ndatetime\nq\x02U\n\x07\xd0\x01\x01\x00\x00\x00\x00\x00\x00\x85q\x03Rq\x04e.";
+        let state = b"\x07\xd0\x01\x01\x00\x00\x00\x00\x00\x00".to_vec();
+        let load = |encoding| {
+            let value = Unpickler::new(&data[..]).with_encoding(encoding).load()?;
+            let items = value.as_slice().unwrap();
+            let Value::Reduce { args, .. } = &items[1] else {
+                panic!("expecting a reduced datetime, got {:?}", items[1]);
+            };
+            Ok::<_, Error>((items[0].clone(), args.as_slice().unwrap()[0].clone()))
+        };
+
+        assert_eq!(
+            load(Encoding::Latin1)?,
+            (Value::Str("cafÃ©".into()), Value::Bytes(state.clone()))
+        );
+        assert_eq!(
+            load(Encoding::Bytes)?,
+            (Value::Bytes(b"caf\xc3\xa9".to_vec()), Value::Bytes(state))
+        );
+        let err = load(Encoding::Ascii).unwrap_err();
+        assert_eq!(
+            (err.offset(), err.opcode_name()),
+            (Some(6), Some("SHORT_BINSTRING"))
+        );
+        assert!(matches!(err.kind(), ErrorKind::Invalid(_)));
+        assert!(matches!(
+            load(Encoding::Utf8).unwrap_err().kind(),
+            ErrorKind::Str(_)
+        ));
+
+        // only python 2 str are decoded
+        let data = b"\x80\x02X\x02\x00\x00\x00\xc3\xa9U\x01aq\x00\x86.";
+        let value = Unpickler::new(&data[..])
+            .with_encoding(Encoding::Bytes)
+            .load()?;
+        assert_eq!(
+            value,
+            Value::Tuple(vec![Value::Str("é".into()), Value::Bytes(b"a".to_vec())])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_object() -> Result<(), Error> {
+        // pickle.dumps(collections.OrderedDict(a=1), protocol=4)
+        let data = b"\x80\x04\x95)\x00\x00\x00\x00\x00\x00\x00\x8c\x0bcollections\x94\x8c\x0bOrderedDict\x94\x93\x94)R\x94\x8c\x01a\x94K\x01s.";
+        let value = load(data)?;
+        let Value::Extend { object, items } = value else {
+            panic!("expecting an extended object, got {value:?}");
+        };
+        assert_eq!(
+            *items,
+            Value::Dict(vec![(Value::Str("a".into()), Value::Int(1))])
+        );
+        let Value::Reduce { callable, args } = *object else {
+            panic!("expecting a reduce, got {object:?}");
+        };
+        assert_eq!(
+            *callable,
+            Value::Global {
+                module: "collections".into(),
+                name: "OrderedDict".into()
+            }
+        );
+        assert_eq!(*args, Value::Tuple(vec![]));
+        Ok(())
+    }
+
+    #[test]
+    fn test_load_dict_from_file() -> Result<(), Error> {
+        let value = Unpickler::open(concat!(env!("CARGO_MANIFEST_DIR"), "/dict.pickle"))?.load()?;
+        assert!(matches!(value, Value::Dict(_)));
+        Ok(())
+    }
+}
@@ -0,0 +1,136 @@
+//! A module to represent unpickled python objects
+
+use std::sync::Arc;
+
+use crate::bigint::BigInt;
+
+/// A python object, as rebuilt by the [`Unpickler`](crate::unpickler::Unpickler)
+///
+/// Objects which cannot be built without a python interpreter (class instances,
+/// function calls) are kept as a description of how python would build them.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    None,
+    Bool(bool),
+    Int(i64),
+    /// Integer which doesn't fit in an `i64`
+    BigInt(BigInt),
+    Float(f64),
+    Str(String),
+    Bytes(Vec<u8>),
+    /// An out-of-band buffer (protocol 5), shared with the buffers given to the unpickler
+    ///
+    /// `readonly` is set by READONLY_BUFFER, i.e. the object was `bytes` rather than `bytearray`.
+    Buffer {
+        data: Arc<[u8]>,
+        readonly: bool,
+    },
+
+    // Collections
+    List(Vec<Value>),
+    Tuple(Vec<Value>),
+    /// Key-value pairs, in stream order
+    Dict(Vec<(Value, Value)>),
+    Set(Vec<Value>),
+    FrozenSet(Vec<Value>),
+
+    // Object construction
+    /// A reference to a class or a function, `module.name`
+    Global {
+        module: String,
+        name: String,
+    },
+    /// The result of `callable(*args)`
+    Reduce {
+        callable: Box<Value>,
+        args: Box<Value>,
+    },
+    /// An instance created with `class.__new__(class, *args, **kwargs)`
+    Object {
+        class: Box<Value>,
+        args: Box<Value>,
+        kwargs: Option<Box<Value>>,
+    },
+    /// An object whose state has been set with `object.__setstate__(state)`
+    Build {
+        object: Box<Value>,
+        state: Box<Value>,
+    },
+    /// An object which is not a builtin container but has been filled with
+    /// `object.append(item)` (`items` is a `List`) or `object[key] = value` (`items` is a `Dict`),
+    /// e.g. a `collections.OrderedDict`
+    Extend {
+        object: Box<Value>,
+        items: Box<Value>,
+    },
+}
+
+impl Value {
+    /// Gets the name of the python type of this value, for error messages
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Value::None => "None",
+            Value::Bool(_) => "bool",
+            Value::Int(_) | Value::BigInt(_) => "int",
+            Value::Float(_) => "float",
+            Value::Str(_) => "str",
+            Value::Bytes(_) => "bytes",
+            Value::Buffer { .. } => "buffer",
+            Value::List(_) => "list",
+            Value::Tuple(_) => "tuple",
+            Value::Dict(_) => "dict",
+            Value::Set(_) => "set",
+            Value::FrozenSet(_) => "frozenset",
+            Value::Global { .. } => "global",
+            Value::Reduce { .. } => "reduced object",
+            Value::Object { .. } => "object",
+            Value::Build { .. } => "built object",
+            Value::Extend { .. } => "extended object",
+        }
+    }
+
+    /// Gets the string if this value is a `Str`
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    /// Gets the bytes if this value is a `Bytes` or a `Buffer`
+    pub fn as_bytes(&self) -> Option<&[u8]> {
+        match self {
+            Value::Bytes(b) => Some(b),
+            Value::Buffer { data, .. } => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Gets the integer if this value is an `Int` or a `Bool`
+    pub fn as_i64(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
+            Value::Bool(b) => Some(*b as i64),
+            _ => None,
+        }
+    }
+
+    /// Gets the items if this value is a `List` or a `Tuple`
+    pub fn as_slice(&self) -> Option<&[Value]> {
+        match self {
+            Value::List(v) | Value::Tuple(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// Gets the value associated to a string key if this value is a `Dict`
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Dict(items) => items
+                .iter()
+                .find(|(k, _)| k.as_str() == Some(key))
+                .map(|(_, v)| v),
+            _ => None,
+        }
+    }
+}
@@ -0,0 +1,880 @@
+//! A module to write pickle events
+
+use std::{fs::File, io::BufWriter, io::Write, path::Path};
+
+use crate::{
+    bigint::{BigInt, minimal},
+    errors::{Error, ErrorKind},
+    reader::Event,
+};
+
+/// The highest protocol the writer can emit
+pub const HIGHEST_PROTOCOL: u8 = 5;
+
+/// The protocol used by python by default
+pub const DEFAULT_PROTOCOL: u8 = 4;
+
+/// Frames are committed once they reach this size, and larger payloads bypass frames
+const FRAME_SIZE_TARGET: usize = 64 * 1024;
+/// Smaller frames are written without a FRAME opcode
+const FRAME_SIZE_MIN: usize = 4;
+
+type BufferCallback = Box<dyn FnMut(&[u8]) -> bool>;
+
+pub struct Writer<W> {
+    writer: W,
+    protocol: u8,
+    /// Content of the current frame, if framing
+    frame: Option<Vec<u8>>,
+    /// Opcodes held back until released, innermost last
+    held: Vec<Vec<u8>>,
+    buffer_callback: Option<BufferCallback>,
+}
+
+impl Writer<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, protocol: u8) -> Result<Self, Error> {
+        let file = File::create(path)?;
+        Writer::new(BufWriter::new(file), protocol)
+    }
+}
+
+impl<W: Write> Writer<W> {
+    /// Creates a new writer for the given protocol (0 to 5)
+    pub fn new(writer: W, protocol: u8) -> Result<Self, Error> {
+        if protocol > HIGHEST_PROTOCOL {
+            return Err(ErrorKind::Protocol(protocol).into());
+        }
+        Ok(Writer {
+            writer,
+            protocol,
+            frame: None,
+            held: Vec::new(),
+            buffer_callback: None,
+        })
+    }
+
+    /// Sets a callback deciding which bytes payloads are written out-of-band (protocol 5)
+    ///
+    /// The callback is offered the payloads of [`Writer::write_bytes`] and
+    /// [`Writer::write_bytearray`]. When it returns `true`, it has taken the payload and
+    /// NEXT_BUFFER (followed by READONLY_BUFFER for bytes) is written instead. The unpickler
+    /// must then be given the payloads in the same order, see
+    /// [`Unpickler::with_buffers`](crate::Unpickler::with_buffers).
+    ///
+    /// ```
+    /// use std::sync::mpsc;
+    /// use quick_pickle::writer::Writer;
+    ///
+    /// let (tx, rx) = mpsc::channel();
+    /// let mut writer = Writer::new(Vec::new(), 5)?
+    ///     .with_buffer_callback(move |payload| payload.len() >= 1024 && tx.send(payload.to_vec()).is_ok());
+    /// writer.write_bytes(&[0; 4096])?;
+    /// assert_eq!(writer.get_ref(), b"\x97\x98");
+    /// assert_eq!(rx.try_recv().unwrap().len(), 4096);
+    /// # Ok::<(), quick_pickle::Error>(())
+    /// ```
+    pub fn with_buffer_callback<F>(mut self, callback: F) -> Self
+    where
+        F: FnMut(&[u8]) -> bool + 'static,
+    {
+        self.buffer_callback = Some(Box::new(callback));
+        self
+    }
+
+    pub fn protocol(&self) -> u8 {
+        self.protocol
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.writer
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        match (self.held.last_mut(), &mut self.frame) {
+            (Some(held), _) => held.extend_from_slice(bytes),
+            (None, Some(frame)) => frame.extend_from_slice(bytes),
+            (None, None) => self.writer.write_all(bytes)?,
+        }
+        Ok(())
+    }
+
+    /// Holds the next opcodes back until [`Writer::release`], e.g. until it is known whether
+    /// an item is batched
+    pub(crate) fn hold(&mut self) {
+        self.held.push(Vec::new());
+    }
+
+    /// Writes the opcodes held back by the last [`Writer::hold`], after a MARK if `mark`
+    pub(crate) fn release(&mut self, mark: bool) -> Result<(), Error> {
+        let held = self.held.pop().expect("opcodes are held");
+        if mark {
+            self.write_mark()?;
+        }
+        self.write(&held)
+    }
+
+    /// Writes a payload and its header, out of any frame if the payload is large
+    fn write_large(&mut self, header: &[u8], payload: &[u8]) -> Result<(), Error> {
+        if self.held.is_empty() && self.frame.is_some() && payload.len() >= FRAME_SIZE_TARGET {
+            self.commit_frame()?;
+            self.writer.write_all(header)?;
+            self.writer.write_all(payload)?;
+            return Ok(());
+        }
+        self.write(header)?;
+        self.write(payload)
+    }
+
+    /// Starts buffering opcodes into FRAMEs (protocol 4 and above)
+    ///
+    /// Frames are committed at object boundaries, see [`Writer::frame_boundary`],
+    /// as python does.
+    pub fn start_framing(&mut self) {
+        if self.protocol >= 4 && self.frame.is_none() {
+            self.frame = Some(Vec::new());
+        }
+    }
+
+    /// Commits the current frame and stops framing
+    pub fn end_framing(&mut self) -> Result<(), Error> {
+        self.commit_frame()?;
+        self.frame = None;
+        Ok(())
+    }
+
+    /// Commits the current frame if it is large enough, to be called after each object
+    pub fn frame_boundary(&mut self) -> Result<(), Error> {
+        match &self.frame {
+            Some(frame) if frame.len() >= FRAME_SIZE_TARGET && self.held.is_empty() => {
+                self.commit_frame()
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn commit_frame(&mut self) -> Result<(), Error> {
+        let Some(frame) = &mut self.frame else {
+            return Ok(());
+        };
+        if frame.len() >= FRAME_SIZE_MIN {
+            self.writer.write_all(&[0x95])?;
+            self.writer.write_all(&(frame.len() as u64).to_le_bytes())?;
+        }
+        self.writer.write_all(frame)?;
+        frame.clear();
+        Ok(())
+    }
+
+    fn write_op(&mut self, opcode: u8) -> Result<(), Error> {
+        self.write(&[opcode])
+    }
+
+    /// Fails if the opcode is not available in the writer protocol
+    fn require(&self, protocol: u8, opcode: u8) -> Result<(), Error> {
+        if self.protocol < protocol {
+            return Err(ErrorKind::OpCode(opcode).into());
+        }
+        Ok(())
+    }
+
+    fn write_line(&mut self, opcode: u8, line: &[u8]) -> Result<(), Error> {
+        self.write_op(opcode)?;
+        self.write(line)?;
+        self.write(b"\n")
+    }
+
+    /// Writes the argument of a text opcode as read, with its line ending
+    fn write_text(&mut self, opcode: u8, line: &[u8]) -> Result<(), Error> {
+        self.write_op(opcode)?;
+        self.write(line)
+    }
+
+    /// Writes the module and name of a GLOBAL or INST on their own lines
+    fn write_lines(
+        &mut self,
+        opcode: u8,
+        module_len: u32,
+        payload: &[u8],
+        crlf: bool,
+    ) -> Result<(), Error> {
+        let (module, name) = payload.split_at((module_len as usize).min(payload.len()));
+        let end = line_end(crlf);
+        self.write_op(opcode)?;
+        self.write(module)?;
+        self.write(end)?;
+        self.write(name)?;
+        self.write(end)
+    }
+
+    /// Writes an event as read by the [`Reader`](crate::reader::Reader)
+    ///
+    /// `payload` is the content the reader left in its buffer for this event (strings, bytes,
+    /// big integers, globals module and name, persistent ids, lines of text opcodes), and is
+    /// ignored otherwise. Text opcodes without their line are formatted as python does.
+    ///
+    /// A `Proto` event switches the writer to that protocol. Events whose opcode is not
+    /// available in the current protocol are rejected.
+    pub fn write_event(&mut self, event: Event, payload: &[u8]) -> Result<(), Error> {
+        match event {
+            // Protocol identification
+            Event::Proto(protocol) => {
+                if protocol > HIGHEST_PROTOCOL {
+                    return Err(ErrorKind::Protocol(protocol).into());
+                }
+                self.protocol = protocol;
+                self.write(&[0x80, protocol])?;
+            }
+            Event::Frame(len) => {
+                self.require(4, 0x95)?;
+                self.write_op(0x95)?;
+                self.write(&len.to_le_bytes())?;
+            }
+
+            // Stack manipulation
+            Event::Mark => self.write_op(0x28)?,
+            Event::Stop => self.write_op(0x2e)?,
+            Event::Pop => self.write_op(0x30)?,
+            Event::PopMark => self.write_op(0x31)?,
+            Event::Dup => self.write_op(0x32)?,
+
+            // Basic types
+            Event::None => self.write_op(0x4e)?,
+            Event::Bool(_) | Event::Int(_) if !payload.is_empty() => {
+                self.write_text(0x49, payload)?
+            }
+            Event::Bool(b) => self.write_bool(b)?,
+            Event::Int(i) => self.write_line(0x49, i.to_string().as_bytes())?,
+            Event::BinInt(i) => {
+                self.require(1, 0x4a)?;
+                self.write_op(0x4a)?;
+                self.write(&i.to_le_bytes())?;
+            }
+            Event::BinInt1(i) => {
+                self.require(1, 0x4b)?;
+                self.write(&[0x4b, i])?;
+            }
+            Event::BinInt2(i) => {
+                self.require(1, 0x4d)?;
+                self.write_op(0x4d)?;
+                self.write(&i.to_le_bytes())?;
+            }
+            Event::Long(_) if !payload.is_empty() => self.write_text(0x4c, payload)?,
+            Event::Long(i) => self.write_long(minimal(&i.to_le_bytes()))?,
+            Event::BigLong { .. } => self.write_long(payload)?,
+            Event::Float(_) if !payload.is_empty() => self.write_text(0x46, payload)?,
+            Event::Float(f) => self.write_float(f)?,
+
+            // Strings and bytes
+            Event::String { .. } => self.write_payload(0, 0x53, &[], payload)?,
+            Event::BinString { len } => self.write_payload(1, 0x54, &len.to_le_bytes(), payload)?,
+            Event::ShortBinString { len } => self.write_payload(1, 0x55, &[len], payload)?,
+            Event::Unicode { .. } => self.write_payload(0, 0x56, &[], payload)?,
+            Event::BinUnicode { len } => {
+                self.write_payload(1, 0x58, &len.to_le_bytes(), payload)?
+            }
+            Event::ShortBinUnicode { len } => self.write_payload(4, 0x8c, &[len], payload)?,
+            Event::BinUnicode8 { len } => {
+                self.write_payload(4, 0x8d, &len.to_le_bytes(), payload)?
+            }
+            Event::BinBytes { len } => self.write_payload(3, 0x42, &len.to_le_bytes(), payload)?,
+            Event::ShortBinBytes { len } => self.write_payload(3, 0x43, &[len], payload)?,
+            Event::BinBytes8 { len } => self.write_payload(4, 0x8e, &len.to_le_bytes(), payload)?,
+            Event::ByteArray8 { len } => {
+                self.write_payload(5, 0x96, &len.to_le_bytes(), payload)?
+            }
+
+            // Collections
+            Event::EmptyTuple => self.write_simple(1, 0x29)?,
+            Event::Tuple => self.write_op(0x74)?,
+            Event::Tuple1 => self.write_simple(2, 0x85)?,
+            Event::Tuple2 => self.write_simple(2, 0x86)?,
+            Event::Tuple3 => self.write_simple(2, 0x87)?,
+            Event::EmptyList => self.write_simple(1, 0x5d)?,
+            Event::List => self.write_op(0x6c)?,
+            Event::Append => self.write_op(0x61)?,
+            Event::Appends => self.write_simple(1, 0x65)?,
+            Event::EmptyDict => self.write_simple(1, 0x7d)?,
+            Event::Dict => self.write_op(0x64)?,
+            Event::SetItem => self.write_op(0x73)?,
+            Event::SetItems => self.write_simple(1, 0x75)?,
+            Event::EmptySet => self.write_simple(4, 0x8f)?,
+            Event::AdditItems => self.write_simple(4, 0x90)?,
+            Event::FrozenSet => self.write_simple(4, 0x91)?,
+
+            // Memo operations
+            Event::Get(_) if !payload.is_empty() => self.write_text(0x67, payload)?,
+            Event::Get(id) => self.write_line(0x67, id.to_string().as_bytes())?,
+            Event::BinGet(id) => {
+                self.require(1, 0x68)?;
+                self.write(&[0x68, id])?;
+            }
+            Event::LongBinGet(id) => {
+                self.require(1, 0x6a)?;
+                self.write_op(0x6a)?;
+                self.write(&id.to_le_bytes())?;
+            }
+            Event::Put(_) if !payload.is_empty() => self.write_text(0x70, payload)?,
+            Event::Put(id) => self.write_line(0x70, id.to_string().as_bytes())?,
+            Event::BinPut(id) => {
+                self.require(1, 0x71)?;
+                self.write(&[0x71, id])?;
+            }
+            Event::LongBinPut(id) => {
+                self.require(1, 0x72)?;
+                self.write_op(0x72)?;
+                self.write(&id.to_le_bytes())?;
+            }
+            Event::Memoize => self.write_simple(4, 0x94)?,
+
+            // Object construction
+            Event::Global {
+                module_len, crlf, ..
+            } => self.write_lines(0x63, module_len, payload, crlf)?,
+            Event::StackGlobal => self.write_simple(4, 0x93)?,
+            Event::Reduce => self.write_op(0x52)?,
+            Event::Build => self.write_op(0x62)?,
+            Event::Inst {
+                module_len, crlf, ..
+            } => self.write_lines(0x69, module_len, payload, crlf)?,
+            Event::Obj => self.write_simple(1, 0x6f)?,
+            Event::NewObj => self.write_simple(2, 0x81)?,
+            Event::NewObjEx => self.write_simple(4, 0x92)?,
+
+            // Persistent objects
+            Event::PersId { crlf, .. } => {
+                self.write_text(0x50, payload)?;
+                self.write(line_end(crlf))?;
+            }
+            Event::BinPersId => self.write_simple(1, 0x51)?,
+
+            // Extensions
+            Event::Ext1(code) => {
+                self.require(2, 0x82)?;
+                self.write(&[0x82, code])?;
+            }
+            Event::Ext2(code) => {
+                self.require(2, 0x83)?;
+                self.write_op(0x83)?;
+                self.write(&code.to_le_bytes())?;
+            }
+            Event::Ext4(code) => {
+                self.require(2, 0x84)?;
+                self.write_op(0x84)?;
+                self.write(&code.to_le_bytes())?;
+            }
+
+            // Protocol 5
+            Event::NextBuffer => self.write_simple(5, 0x97)?,
+            Event::ReadonlyBuffer => self.write_simple(5, 0x98)?,
+        }
+        Ok(())
+    }
+
+    fn write_simple(&mut self, protocol: u8, opcode: u8) -> Result<(), Error> {
+        self.require(protocol, opcode)?;
+        self.write_op(opcode)
+    }
+
+    fn write_payload(
+        &mut self,
+        protocol: u8,
+        opcode: u8,
+        len: &[u8],
+        payload: &[u8],
+    ) -> Result<(), Error> {
+        self.require(protocol, opcode)?;
+        self.write_op(opcode)?;
+        self.write(len)?;
+        self.write(payload)
+    }
+
+    /// Writes the protocol header (protocol 2 and above)
+    pub fn write_proto(&mut self) -> Result<(), Error> {
+        if self.protocol >= 2 {
+            self.write(&[0x80, self.protocol])?;
+        }
+        Ok(())
+    }
+
+    pub fn write_stop(&mut self) -> Result<(), Error> {
+        self.write_op(0x2e)
+    }
+
+    pub fn write_mark(&mut self) -> Result<(), Error> {
+        self.write_op(0x28)
+    }
+
+    pub fn write_none(&mut self) -> Result<(), Error> {
+        self.write_op(0x4e)
+    }
+
+    /// Writes a bool with NEWTRUE / NEWFALSE, or INT in protocols 0 and 1
+    pub fn write_bool(&mut self, b: bool) -> Result<(), Error> {
+        match (self.protocol >= 2, b) {
+            (true, true) => self.write_op(0x88),
+            (true, false) => self.write_op(0x89),
+            (false, true) => self.write(b"I01\n"),
+            (false, false) => self.write(b"I00\n"),
+        }
+    }
+
+    /// Writes an integer with the smallest opcode available in the protocol
+    ///
+    /// BININT1, BININT2 or BININT (INT in protocol 0), then LONG1 (LONG in protocols 0 and 1)
+    pub fn write_int(&mut self, i: i64) -> Result<(), Error> {
+        if self.protocol >= 1 {
+            if let Ok(i) = u8::try_from(i) {
+                return self.write(&[0x4b, i]);
+            }
+            if let Ok(i) = u16::try_from(i) {
+                self.write_op(0x4d)?;
+                return self.write(&i.to_le_bytes());
+            }
+            if let Ok(i) = i32::try_from(i) {
+                self.write_op(0x4a)?;
+                return self.write(&i.to_le_bytes());
+            }
+        } else if let Ok(i) = i32::try_from(i) {
+            return self.write_line(0x49, i.to_string().as_bytes());
+        }
+        self.write_long(minimal(&i.to_le_bytes()))
+    }
+
+    /// Writes an integer of arbitrary size
+    pub fn write_bigint(&mut self, i: &BigInt) -> Result<(), Error> {
+        match i.to_i64() {
+            Some(i) => self.write_int(i),
+            None => self.write_long(minimal(i.as_signed_bytes_le())),
+        }
+    }
+
+    /// Writes little-endian two's complement bytes with LONG1 / LONG4, or LONG before protocol 2
+    fn write_long(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if self.protocol >= 2 {
+            if let Ok(len) = u8::try_from(bytes.len()) {
+                self.write(&[0x8a, len])?;
+            } else {
+                self.write_op(0x8b)?;
+                self.write(&(bytes.len() as i32).to_le_bytes())?;
+            }
+            self.write(bytes)
+        } else {
+            let decimal = BigInt::from_signed_bytes_le(bytes).to_string();
+            self.write_op(0x4c)?;
+            self.write(decimal.as_bytes())?;
+            self.write(b"L\n")
+        }
+    }
+
+    /// Writes a float with BINFLOAT, or FLOAT in protocol 0
+    pub fn write_float(&mut self, f: f64) -> Result<(), Error> {
+        if self.protocol >= 1 {
+            self.write_op(0x47)?;
+            self.write(&f.to_be_bytes())
+        } else {
+            self.write_line(0x46, float_repr(f).as_bytes())
+        }
+    }
+
+    /// Writes a str with SHORT_BINUNICODE, BINUNICODE or BINUNICODE8 (UNICODE in protocol 0)
+    pub fn write_str(&mut self, s: &str) -> Result<(), Error> {
+        let len = s.len();
+        if self.protocol >= 4 && len < 256 {
+            self.write(&[0x8c, len as u8])?;
+            self.write(s.as_bytes())
+        } else if self.protocol >= 1 && len <= u32::MAX as usize {
+            let mut header = [0x58; 5];
+            header[1..].copy_from_slice(&(len as u32).to_le_bytes());
+            self.write_large(&header, s.as_bytes())
+        } else if self.protocol >= 4 {
+            let mut header = [0x8d; 9];
+            header[1..].copy_from_slice(&(len as u64).to_le_bytes());
+            self.write_large(&header, s.as_bytes())
+        } else if self.protocol >= 1 {
+            Err(ErrorKind::OpCode(0x8d).into())
+        } else {
+            self.write_op(0x56)?;
+            self.write(&raw_unicode_escape(s))?;
+            self.write(b"\n")
+        }
+    }
+
+    /// Writes bytes with SHORT_BINBYTES, BINBYTES or BINBYTES8
+    ///
+    /// Before protocol 3, python has no bytes opcodes and encodes bytes as
+    /// `_codecs.encode(str, 'latin1')` (`__builtin__.bytes()` when empty).
+    pub fn write_bytes(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        if self.write_out_of_band(bytes, true)? {
+            return Ok(());
+        }
+        let len = bytes.len();
+        if self.protocol >= 3 {
+            if len < 256 {
+                self.write(&[0x43, len as u8])?;
+                return self.write(bytes);
+            } else if len <= u32::MAX as usize {
+                let mut header = [0x42; 5];
+                header[1..].copy_from_slice(&(len as u32).to_le_bytes());
+                return self.write_large(&header, bytes);
+            } else if self.protocol >= 4 {
+                let mut header = [0x8e; 9];
+                header[1..].copy_from_slice(&(len as u64).to_le_bytes());
+                return self.write_large(&header, bytes);
+            } else {
+                return Err(ErrorKind::OpCode(0x8e).into());
+            }
+        }
+        if bytes.is_empty() {
+            self.write_global("__builtin__", "bytes")?;
+            self.write_empty_tuple()?;
+        } else {
+            self.write_global("_codecs", "encode")?;
+            let latin1 = bytes.iter().map(|b| *b as char).collect::<String>();
+            if self.protocol < 2 {
+                self.write_mark()?;
+            }
+            self.write_str(&latin1)?;
+            self.write_str("latin1")?;
+            self.write_tuple(2)?;
+        }
+        self.write_op(0x52)
+    }
+
+    /// Writes a bytearray with BYTEARRAY8 (protocol 5)
+    pub fn write_bytearray(&mut self, bytes: &[u8]) -> Result<(), Error> {
+        self.require(5, 0x96)?;
+        if self.write_out_of_band(bytes, false)? {
+            return Ok(());
+        }
+        let mut header = [0x96; 9];
+        header[1..].copy_from_slice(&(bytes.len() as u64).to_le_bytes());
+        self.write_large(&header, bytes)
+    }
+
+    /// Offers a payload to the buffer callback, and writes NEXT_BUFFER if it takes it
+    fn write_out_of_band(&mut self, bytes: &[u8], readonly: bool) -> Result<bool, Error> {
+        let Some(callback) = &mut self.buffer_callback else {
+            return Ok(false);
+        };
+        if self.protocol < 5 || !callback(bytes) {
+            return Ok(false);
+        }
+        self.write_op(0x97)?;
+        if readonly {
+            self.write_op(0x98)?;
+        }
+        Ok(true)
+    }
+
+    /// Writes a reference to `module.name` with STACK_GLOBAL, or GLOBAL before protocol 4
+    pub fn write_global(&mut self, module: &str, name: &str) -> Result<(), Error> {
+        if self.protocol >= 4 {
+            self.write_str(module)?;
+            self.write_str(name)?;
+            self.write_op(0x93)
+        } else {
+            self.write_op(0x63)?;
+            self.write(module.as_bytes())?;
+            self.write(b"\n")?;
+            self.write(name.as_bytes())?;
+            self.write(b"\n")
+        }
+    }
+
+    /// Writes an empty tuple with EMPTY_TUPLE, or MARK TUPLE in protocol 0
+    pub fn write_empty_tuple(&mut self) -> Result<(), Error> {
+        if self.protocol >= 1 {
+            self.write_op(0x29)
+        } else {
+            self.write(b"(t")
+        }
+    }
+
+    /// Closes a tuple of `len` items
+    ///
+    /// Uses TUPLE1, TUPLE2 or TUPLE3 from protocol 2 when `len <= 3`, in which case no mark
+    /// must have been written before the items. Otherwise writes TUPLE, which expects a mark.
+    pub fn write_tuple(&mut self, len: usize) -> Result<(), Error> {
+        match len {
+            0 => self.write_empty_tuple(),
+            1..=3 if self.protocol >= 2 => self.write_op(0x84 + len as u8),
+            _ => self.write_op(0x74),
+        }
+    }
+
+    /// Writes an empty list with EMPTY_LIST, or MARK LIST in protocol 0
+    pub fn write_empty_list(&mut self) -> Result<(), Error> {
+        if self.protocol >= 1 {
+            self.write_op(0x5d)
+        } else {
+            self.write(b"(l")
+        }
+    }
+
+    /// Writes an empty dict with EMPTY_DICT, or MARK DICT in protocol 0
+    pub fn write_empty_dict(&mut self) -> Result<(), Error> {
+        if self.protocol >= 1 {
+            self.write_op(0x7d)
+        } else {
+            self.write(b"(d")
+        }
+    }
+
+    /// Stores the top of the stack in the memo with MEMOIZE, or BINPUT / PUT before protocol 4
+    pub fn write_memoize(&mut self, id: u32) -> Result<(), Error> {
+        if self.protocol >= 4 {
+            self.write_op(0x94)
+        } else if self.protocol >= 1 {
+            match u8::try_from(id) {
+                Ok(id) => self.write(&[0x71, id]),
+                Err(_) => {
+                    self.write_op(0x72)?;
+                    self.write(&id.to_le_bytes())
+                }
+            }
+        } else {
+            self.write_line(0x70, id.to_string().as_bytes())
+        }
+    }
+
+    /// Pushes a memoized object with BINGET / LONG_BINGET, or GET in protocol 0
+    pub fn write_get(&mut self, id: u32) -> Result<(), Error> {
+        if self.protocol >= 1 {
+            match u8::try_from(id) {
+                Ok(id) => self.write(&[0x68, id]),
+                Err(_) => {
+                    self.write_op(0x6a)?;
+                    self.write(&id.to_le_bytes())
+                }
+            }
+        } else {
+            self.write_line(0x67, id.to_string().as_bytes())
+        }
+    }
+
+    /// Flushes the underlying writer, the current frame, if any, is not committed
+    pub fn flush(&mut self) -> Result<(), Error> {
+        self.writer.flush()?;
+        Ok(())
+    }
+}
+
+/// Gets the line ending of text opcodes
+fn line_end(crlf: bool) -> &'static [u8] {
+    if crlf { b"\r\n" } else { b"\n" }
+}
+
+/// Formats a float as python's `repr` does
+pub(crate) fn float_repr(f: f64) -> String {
+    if f.is_nan() {
+        return "nan".to_string();
+    }
+    if f.is_infinite() {
+        return if f > 0.0 { "inf" } else { "-inf" }.to_string();
+    }
+    // shortest round-trip digits and exponent, e.g. "-1.2345e-7"
+    let sci = format!("{f:e}");
+    let (mantissa, exp) = sci.split_once('e').unwrap_or((&sci, "0"));
+    let exp: i32 = exp.parse().unwrap_or(0);
+    let (sign, mantissa) = match mantissa.strip_prefix('-') {
+        Some(m) => ("-", m),
+        None => ("", mantissa),
+    };
+    let digits = mantissa.replace('.', "");
+    if (-4..16).contains(&exp) {
+        let point = exp + 1;
+        if point <= 0 {
+            let zeros = "0".repeat(-point as usize);
+            format!("{sign}0.{zeros}{digits}")
+        } else if point as usize >= digits.len() {
+            let zeros = "0".repeat(point as usize - digits.len());
+            format!("{sign}{digits}{zeros}.0")
+        } else {
+            let (int, frac) = digits.split_at(point as usize);
+            format!("{sign}{int}.{frac}")
+        }
+    } else {
+        let exp_sign = if exp < 0 { '-' } else { '+' };
+        format!("{sign}{mantissa}e{exp_sign}{:02}", exp.abs())
+    }
+}
+
+/// Encodes a str as python's `raw-unicode-escape` codec, escaping the characters that would
+/// break the UNICODE opcode line
+fn raw_unicode_escape(s: &str) -> Vec<u8> {
+    let mut out = Vec::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' | '\0' | '\n' | '\r' | '\x1a' => {
+                out.extend_from_slice(format!("\\u{:04x}", c as u32).as_bytes())
+            }
+            c if (c as u32) < 0x100 => out.push(c as u8),
+            c if (c as u32) < 0x10000 => {
+                out.extend_from_slice(format!("\\u{:04x}", c as u32).as_bytes())
+            }
+            c => out.extend_from_slice(format!("\\U{:08x}", c as u32).as_bytes()),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::reader::Reader;
+
+    fn write(protocol: u8, f: impl FnOnce(&mut Writer<Vec<u8>>) -> Result<(), Error>) -> Vec<u8> {
+        let mut writer = Writer::new(Vec::new(), protocol).unwrap();
+        writer.write_proto().unwrap();
+        f(&mut writer).unwrap();
+        writer.write_stop().unwrap();
+        writer.into_inner()
+    }
+
+    fn round_trip(path: &str) -> Result<(), Error> {
+        let data = std::fs::read(path)?;
+        let mut reader = Reader::new(&*data);
+        let mut writer = Writer::new(Vec::new(), 0)?;
+        let mut buf = Vec::new();
+        loop {
+            let event = reader.read_event(&mut buf)?;
+            writer.write_event(event, &buf)?;
+            if let Event::Stop = event {
+                break;
+            }
+            buf.clear();
+        }
+        assert!(writer.get_ref() == &data, "{path} differs");
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_files() -> Result<(), Error> {
+        round_trip(concat!(env!("CARGO_MANIFEST_DIR"), "/ints.pickle"))?;
+        round_trip(concat!(env!("CARGO_MANIFEST_DIR"), "/dict.pickle"))?;
+        round_trip(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/benches/data/manyrefs.pickle"
+        ))?;
+        round_trip(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/benches/data/manystrings.pickle"
+        ))?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_round_trip_protocol_0() -> Result<(), Error> {
+        for data in [
+            // pickle.dumps([1e16, 2**70, 'a', True], protocol=0)
+            &b"(lp0\nF1e+16\naL1180591620717411303424L\naVa\np1\naI01\na."[..],
+            // python 2 on 64-bit platforms: pickle.dumps([2**40, 5L], protocol=0)
+            b"(lp0\nI1099511627776\naL5L\na.",
+            // written in text mode on windows, with INST and PERSID
+            b"(lp0\r\nI1\r\nacos\r\nsystem\r\np1\r\nag1\r\na(i__main__\r\nC\r\naF0.1\r\naP7\r\na.",
+        ] {
+            let mut reader = Reader::new(data);
+            let mut writer = Writer::new(Vec::new(), 0)?;
+            let mut buf = Vec::new();
+            loop {
+                let event = reader.read_event(&mut buf)?;
+                writer.write_event(event, &buf)?;
+                if let Event::Stop = event {
+                    break;
+                }
+                buf.clear();
+            }
+            assert_eq!(writer.get_ref(), data);
+        }
+        // without their lines, text opcodes are formatted as python does
+        let data = write(0, |w| {
+            w.write_event(Event::Int(1 << 40), &[])?;
+            w.write_event(
+                Event::Global {
+                    module_len: 2,
+                    name_len: 6,
+                    crlf: true,
+                },
+                b"ossystem",
+            )
+        });
+        assert_eq!(data, b"I1099511627776\ncos\r\nsystem\r\n.");
+        Ok(())
+    }
+
+    #[test]
+    fn test_write_int() {
+        // pickle.dumps(x, protocol=2) for 1, 300, -1, 2**40
+        assert_eq!(write(2, |w| w.write_int(1)), b"\x80\x02K\x01.");
+        assert_eq!(write(2, |w| w.write_int(300)), b"\x80\x02M,\x01.");
+        assert_eq!(write(2, |w| w.write_int(-1)), b"\x80\x02J\xff\xff\xff\xff.");
+        assert_eq!(
+            write(2, |w| w.write_int(1 << 40)),
+            b"\x80\x02\x8a\x06\x00\x00\x00\x00\x00\x01."
+        );
+        assert_eq!(write(0, |w| w.write_int(1 << 40)), b"L1099511627776L\n.");
+        assert_eq!(write(0, |w| w.write_int(-5)), b"I-5\n.");
+    }
+
+    #[test]
+    fn test_write_str() {
+        assert_eq!(write(4, |w| w.write_str("a")), b"\x80\x04\x8c\x01a.");
+        assert_eq!(
+            write(3, |w| w.write_str("a")),
+            b"\x80\x03X\x01\x00\x00\x00a."
+        );
+        // pickle.dumps('a\xe9€\\\n', protocol=0) without the memo
+        assert_eq!(
+            write(0, |w| w.write_str("a\u{e9}\u{20ac}\\\n")),
+            b"Va\xe9\\u20ac\\u005c\\u000a\n."
+        );
+    }
+
+    #[test]
+    fn test_write_bytes() {
+        assert_eq!(write(3, |w| w.write_bytes(b"ab")), b"\x80\x03C\x02ab.");
+        // pickle.dumps(b'ab', protocol=2) without the memo
+        assert_eq!(
+            write(2, |w| w.write_bytes(b"ab")),
+            b"\x80\x02c_codecs\nencode\nX\x02\x00\x00\x00abX\x06\x00\x00\x00latin1\x86R."
+        );
+        assert!(
+            Writer::new(Vec::new(), 2)
+                .unwrap()
+                .write_bytearray(b"a")
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_float_repr() {
+        let repr = [
+            1e16,
+            1e-5,
+            0.1,
+            1.0,
+            -0.0,
+            123456789012345680.0,
+            1e-4,
+            12345.678,
+        ]
+        .map(float_repr);
+        assert_eq!(
+            repr,
+            [
+                "1e+16",
+                "1e-05",
+                "0.1",
+                "1.0",
+                "-0.0",
+                "1.2345678901234568e+17",
+                "0.0001",
+                "12345.678"
+            ]
+        );
+    }
+}